@@ -1,6 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use llvm_profparser::*;
 use std::fs;
+use std::path::PathBuf;
 
 // This benchmark is a bit faffy as first you need to generate the binaries and profraws and pop
 // them in the appropriate folder structure. After that it will work as expected
@@ -47,6 +48,21 @@ pub fn report_generation(c: &mut Criterion) {
     });
 }
 
+pub fn mismatched_report_generation(c: &mut Criterion) {
+    // None of the functions in this binary have records in the cargo profile so every one of them
+    // is checked for a hash mismatch. Unlike the other benchmarks this only uses committed data
+    let profile = parse("./benches/data/cargo_testsuite.profdata").unwrap();
+    let binaries = [PathBuf::from("./tests/data/matches/matches_bin")];
+
+    let mapping = CoverageMapping::new(&binaries, &profile, true).unwrap();
+
+    c.bench_function("mismatched report generation", |b| {
+        b.iter(|| {
+            let _a = mapping.generate_report();
+        })
+    });
+}
+
 pub fn subreport_generation(c: &mut Criterion) {
     let files = fs::read_dir("./benches/data/mapping/profraws")
         .unwrap()
@@ -77,6 +93,7 @@ criterion_group!(
     benches,
     coverage_mapping,
     report_generation,
+    mismatched_report_generation,
     subreport_generation
 );
 
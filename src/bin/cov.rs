@@ -1,7 +1,11 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use llvm_profparser::instrumentation_profile::types::InstrumentationProfile;
 use llvm_profparser::*;
+use regex::RegexSet;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::{Layer, Registry};
@@ -18,65 +22,488 @@ pub enum Command {
         #[structopt(flatten)]
         show: ShowCommand,
     },
+    /// Print a summary of the coverage for each file similar to `llvm-cov report`
+    Report {
+        #[structopt(flatten)]
+        report: ReportCommand,
+    },
+    /// Export the coverage data to stdout in a machine readable format
+    Export {
+        #[structopt(flatten)]
+        export: ExportCommand,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    Text,
+    Lcov,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "lcov" => Ok(Self::Lcov),
+            s => Err(format!("Unsupported export format: {}", s)),
+        }
+    }
+}
+
+/// Output format of `cov show`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShowFormat {
+    Text,
+    Html,
+}
+
+impl FromStr for ShowFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "html" => Ok(Self::Html),
+            s => Err(format!("Unsupported show format: {}", s)),
+        }
+    }
+}
+
+/// When to colour the output of `cov show`, like `llvm-cov show --use-color`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorChoice {
+    /// Colour if stdout is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(&self) -> bool {
+        match self {
+            Self::Auto => io::stdout().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            s => Err(format!("Unsupported color choice: {}", s)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
 pub struct ShowCommand {
     /// File with the profile data obtained after an instrumented run. This differs from llvm-cov
     /// in that if multiple profiles are given it will do the equivalent of a llvm-profdata merge
-    /// on them.
+    /// on them. Directories are searched for profraw and profdata files to merge. A single
+    /// profile can be read from stdin with `-`.
     #[structopt(long = "instr-profile")]
     instr_profile: Vec<PathBuf>,
     /// Coverage executable or object file
     #[structopt(long = "object")]
     objects: Vec<PathBuf>,
     /// Pair of paths for a remapping to allow loading files after move. Comma separated in the
-    /// order `source,dest`
-    #[structopt(long = "path-equivalence")]
-    path_remapping: Option<PathRemapping>,
+    /// order `source,dest`. Can be given multiple times, if more than one matches a path the
+    /// longest source path is used
+    #[structopt(long = "path-equivalence", number_of_values = 1)]
+    path_remapping: Vec<PathRemapping>,
+    /// Directory to resolve relative paths in the coverage mapping against, instead of the
+    /// directory the object was compiled in
+    #[structopt(long = "compilation-dir")]
+    compilation_dir: Option<PathBuf>,
+    /// Mark the start of regions within a line that have a different count to the line, like
+    /// `llvm-cov show --show-regions`
+    #[structopt(long = "show-regions")]
+    show_regions: bool,
+    /// Skip source files with names matching the regex, can be given multiple times
+    #[structopt(long = "ignore-filename-regex", number_of_values = 1)]
+    ignore_filename_regex: Vec<String>,
+    /// Colour covered lines cyan, uncovered lines red and lines without coverage information dim.
+    /// Either `auto`, `always` or `never`, without a value it's `always` and if not given colours
+    /// are only used when writing to a terminal
+    #[structopt(long = "use-color", require_equals = true)]
+    use_color: Option<Option<ColorChoice>>,
+    /// Show the line counts of source files which can't be read instead of skipping them
+    #[structopt(long = "show-missing-sources")]
+    show_missing_sources: bool,
+    /// Format of the output, either `text` or `html`. The html report is written to
+    /// `--output-dir` with an index page and a page per source file
+    #[structopt(long = "format", default_value = "text")]
+    format: ShowFormat,
+    /// Directory to write the html report to
+    #[structopt(long = "output-dir", required_if("format", "html"))]
+    output_dir: Option<PathBuf>,
     /// Turn on debug logging
     #[structopt(long)]
     debug: bool,
 }
 
-impl ShowCommand {
+#[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
+pub struct ReportCommand {
+    /// File with the profile data obtained after an instrumented run. If multiple profiles are
+    /// given they're merged, directories are searched for profraw and profdata files to merge and
+    /// a single profile can be read from stdin with `-`
+    #[structopt(long = "instr-profile")]
+    instr_profile: Vec<PathBuf>,
+    /// Coverage executable or object file
+    #[structopt(long = "object")]
+    objects: Vec<PathBuf>,
+    /// Pair of paths for a remapping to allow loading files after move. Comma separated in the
+    /// order `source,dest`. Can be given multiple times, if more than one matches a path the
+    /// longest source path is used
+    #[structopt(long = "path-equivalence", number_of_values = 1)]
+    path_remapping: Vec<PathRemapping>,
+    /// Directory to resolve relative paths in the coverage mapping against, instead of the
+    /// directory the object was compiled in
+    #[structopt(long = "compilation-dir")]
+    compilation_dir: Option<PathBuf>,
+    /// Turn on debug logging
+    #[structopt(long)]
+    debug: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
+pub struct ExportCommand {
+    /// File with the profile data obtained after an instrumented run. If multiple profiles are
+    /// given they're merged, directories are searched for profraw and profdata files to merge and
+    /// a single profile can be read from stdin with `-`
+    #[structopt(long = "instr-profile")]
+    instr_profile: Vec<PathBuf>,
+    /// Coverage executable or object file
+    #[structopt(long = "object")]
+    objects: Vec<PathBuf>,
+    /// Pair of paths for a remapping to allow loading files after move. Comma separated in the
+    /// order `source,dest`. Can be given multiple times, if more than one matches a path the
+    /// longest source path is used
+    #[structopt(long = "path-equivalence", number_of_values = 1)]
+    path_remapping: Vec<PathRemapping>,
+    /// Directory to resolve relative paths in the coverage mapping against, instead of the
+    /// directory the object was compiled in
+    #[structopt(long = "compilation-dir")]
+    compilation_dir: Option<PathBuf>,
+    /// Format of the exported data, either `text` for llvm-cov's JSON format or `lcov`
+    #[structopt(long = "format", default_value = "text")]
+    format: ExportFormat,
+    /// Turn on debug logging
+    #[structopt(long)]
+    debug: bool,
+}
+
+impl ExportCommand {
+    fn run(&self) -> Result<()> {
+        if self.debug {
+            let _ = enable_debug_logging();
+        }
+        let report = generate_report(
+            &self.instr_profile,
+            &self.objects,
+            &self.path_remapping,
+            self.compilation_dir.as_deref(),
+        )?;
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        match self.format {
+            ExportFormat::Text => report.to_json(&mut stdout)?,
+            ExportFormat::Lcov => report.to_lcov(&mut stdout)?,
+        }
+        Ok(())
+    }
+}
+
+/// Widths of the columns in the report table, matching llvm-cov's. The filename column is widened
+/// to fit the longest filename
+const MIN_FILENAME_WIDTH: usize = 25;
+const COLUMNS: [(&str, usize); 12] = [
+    ("Regions", 12),
+    ("Missed Regions", 18),
+    ("Cover", 10),
+    ("Functions", 12),
+    ("Missed Functions", 18),
+    ("Executed", 10),
+    ("Lines", 12),
+    ("Missed Lines", 18),
+    ("Cover", 10),
+    ("Branches", 12),
+    ("Missed Branches", 18),
+    ("Cover", 10),
+];
+
+/// Parses the profile at the path, or from stdin if the path is `-`
+fn parse_input(path: &Path) -> io::Result<InstrumentationProfile> {
+    if path == Path::new("-") {
+        parse_reader(io::stdin().lock())
+    } else {
+        parse(path)
+    }
+}
+
+fn generate_report(
+    instr_profile: &[PathBuf],
+    objects: &[PathBuf],
+    path_remapping: &[PathRemapping],
+    compilation_dir: Option<&Path>,
+) -> Result<CoverageReport> {
+    let (profiles, skipped) = expand_profile_dirs(instr_profile)?;
+    for path in &skipped {
+        eprintln!("warning: skipping {} as it isn't a profile", path.display());
+    }
+    let instr_prof = match profiles.as_slice() {
+        [] => {
+            let inputs = instr_profile
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+            bail!("no profiles found in {}", inputs.join(", "));
+        }
+        [profile] => parse_input(profile)?,
+        profiles => merge_profiles(profiles)?,
+    };
+    let mapping = match compilation_dir {
+        Some(dir) => CoverageMapping::with_compilation_dir(objects, &instr_prof, false, dir)?,
+        None => CoverageMapping::new(objects, &instr_prof, false)?,
+    };
+    let binary_id_mismatches = mapping.binary_id_mismatches();
+    if !binary_id_mismatches.is_empty() {
+        eprintln!(
+            "warning: {} objects have build ids that aren't in the profile",
+            binary_id_mismatches.len()
+        );
+    }
+    let mut report = mapping.generate_report();
+    if !report.hash_mismatches.is_empty() {
+        eprintln!(
+            "warning: {} functions have mismatched data",
+            report.hash_mismatches.len()
+        );
+    }
+    if !report.unresolved_expressions.is_empty() {
+        eprintln!(
+            "warning: {} functions have expressions that couldn't be evaluated",
+            report.unresolved_expressions.len()
+        );
+    }
+    report.apply_remappings(path_remapping);
+    Ok(report)
+}
+
+fn summary_row(name: &str, width: usize, summary: &CoverageSummary) -> String {
+    let mut row = format!("{:<width$}", name, width = width);
+    let counts = [
+        summary.regions,
+        summary.functions,
+        summary.lines,
+        summary.branches,
+    ];
+    for (count, widths) in counts.iter().zip(COLUMNS.chunks(3)) {
+        let percent = count
+            .percent()
+            .map(|x| format!("{:.2}%", x))
+            .unwrap_or_else(|| "-".to_string());
+        let values = [count.total.to_string(), count.missed().to_string(), percent];
+        for (value, (_, width)) in values.iter().zip(widths) {
+            row.push_str(&format!("{:>width$}", value, width = width));
+        }
+    }
+    row
+}
+
+impl ReportCommand {
     fn run(&self) -> Result<()> {
         if self.debug {
             let _ = enable_debug_logging();
         }
-        let instr_prof = if self.instr_profile.len() == 1 {
-            parse(&self.instr_profile[0])?
-        } else if self.instr_profile.len() > 1 {
-            merge_profiles(&self.instr_profile)?
+        let report = generate_report(
+            &self.instr_profile,
+            &self.objects,
+            &self.path_remapping,
+            self.compilation_dir.as_deref(),
+        )?;
+        let summary = report.summary();
+
+        // Like llvm-cov the common directory of all the files is removed from the names
+        let prefix = if summary.files.len() > 1 {
+            let mut paths = summary.files.keys();
+            let mut prefix = paths
+                .next()
+                .and_then(|x| x.parent())
+                .map(|x| x.to_path_buf())
+                .unwrap_or_default();
+            for path in paths {
+                while !path.starts_with(&prefix) {
+                    if !prefix.pop() {
+                        break;
+                    }
+                }
+            }
+            prefix
         } else {
-            panic!("Must provide an instrumentation profile");
+            PathBuf::new()
         };
-        let mapping = CoverageMapping::new(&self.objects, &instr_prof, false)?;
-        let mut report = mapping.generate_report();
-        if let Some(remapping) = self.path_remapping.as_ref() {
-            report.apply_remapping(remapping);
+        let names = summary
+            .files
+            .iter()
+            .map(|(path, summary)| {
+                let name = path.strip_prefix(&prefix).unwrap_or(path);
+                (name.display().to_string(), summary)
+            })
+            .collect::<Vec<_>>();
+
+        let width = names
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or_default()
+            .max(MIN_FILENAME_WIDTH);
+        let mut header = format!("{:<width$}", "Filename", width = width);
+        for (title, column_width) in COLUMNS.iter() {
+            header.push_str(&format!("{:>width$}", title, width = column_width));
         }
+        let rule = "-".repeat(header.len());
+        println!("{}", header);
+        println!("{}", rule);
+        for (name, file) in &names {
+            println!("{}", summary_row(name, width, file));
+        }
+        println!("{}", rule);
+        println!("{}", summary_row("TOTAL", width, &summary.total));
+        Ok(())
+    }
+}
+
+impl ShowCommand {
+    fn run(&self) -> Result<()> {
+        if self.debug {
+            let _ = enable_debug_logging();
+        }
+        let ignore = RegexSet::new(&self.ignore_filename_regex)?;
+        let mut report = generate_report(
+            &self.instr_profile,
+            &self.objects,
+            &self.path_remapping,
+            self.compilation_dir.as_deref(),
+        )?;
+        report.retain_files(|path| !ignore.is_match(&path.to_string_lossy()));
+        if self.format == ShowFormat::Html {
+            let output_dir = self
+                .output_dir
+                .as_ref()
+                .expect("output dir is required for html");
+            html::write_report(&report, output_dir).with_context(|| {
+                format!("Failed to write html report to {}", output_dir.display())
+            })?;
+            return Ok(());
+        } else if self.output_dir.is_some() {
+            bail!("--output-dir is only supported with --format=html");
+        }
+        let color = match self.use_color {
+            None => ColorChoice::Auto,
+            Some(None) => ColorChoice::Always,
+            Some(Some(choice)) => choice,
+        }
+        .enabled();
         for (path, result) in report.files.iter() {
             // Read file to string
-            if let Ok(source) = fs::read_to_string(path) {
-                if report.files.len() > 1 {
-                    println!("{}", path.display());
-                }
-                for (line, source) in source.lines().enumerate() {
-                    print!("{: >5}|", line + 1);
-                    if let Some(hits) = result.hits_for_line(line + 1) {
-                        println!("{: >7}|{}", hits, source);
-                    } else {
-                        println!("       |{}", source);
+            let source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(e) if self.show_missing_sources => {
+                    println!("{}: {}", path.display(), e);
+                    for (line, hits) in result.line_hits() {
+                        println!("{}", source_line(line, Some(hits), "", color));
                     }
+                    println!();
+                    continue;
                 }
-                println!();
+                Err(_) => continue,
+            };
+            if report.files.len() > 1 {
+                println!("{}", path.display());
             }
+            let segments = if self.show_regions {
+                report.segments_for_file(path)
+            } else {
+                vec![]
+            };
+            for (line, source) in source.lines().enumerate() {
+                let hits = result.hits_for_line(line + 1);
+                println!("{}", source_line(line + 1, hits, source, color));
+                let markers =
+                    segments::region_markers(&segments, line + 1, hits.unwrap_or_default());
+                if !markers.is_empty() {
+                    println!("{}", region_marker_line(&markers));
+                }
+            }
+            println!();
         }
         Ok(())
     }
 }
 
+const RED: &str = "\x1b[31m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// A line of source with its line number and count. When coloured the count of covered lines is
+/// cyan, uncovered lines are red and lines without a count are dimmed.
+fn source_line(line: usize, hits: Option<usize>, source: &str, color: bool) -> String {
+    match hits {
+        Some(0) if color => format!("{: >5}|{}{: >7}|{}{}", line, RED, 0, source, RESET),
+        Some(hits) if color => format!("{: >5}|{}{: >7}{}|{}", line, CYAN, hits, RESET, source),
+        Some(hits) => format!("{: >5}|{: >7}|{}", line, hits, source),
+        None if color => format!("{}{: >5}|       |{}{}", DIM, line, source, RESET),
+        None => format!("{: >5}|       |{}", line, source),
+    }
+}
+
+/// Formats a count like llvm-cov, counts over 999 are shortened to 3 significant figures with a
+/// metric suffix
+fn format_count(count: usize) -> String {
+    let number = count.to_string();
+    if number.len() <= 3 {
+        return number;
+    }
+    let int_len = match number.len() % 3 {
+        0 => 3,
+        x => x,
+    };
+    let mut result = number[..int_len].to_string();
+    if int_len != 3 {
+        result.push('.');
+        result.push_str(&number[int_len..3]);
+    }
+    result.push(b" kMGTPEZY"[(number.len() - 1) / 3] as char);
+    result
+}
+
+/// Line with a caret and the count under the start of each region. This is indented past the line
+/// number and count columns so the markers line up with the source.
+fn region_marker_line(markers: &[&CoverageSegment]) -> String {
+    let mut line = " ".repeat(14);
+    let mut column = 1;
+    for marker in markers {
+        if marker.col > column {
+            line.push_str(&" ".repeat(marker.col - column));
+        }
+        let count = format_count(marker.count);
+        column = marker.col + 1 + count.len();
+        line.push('^');
+        line.push_str(&count);
+    }
+    line
+}
+
 fn enable_debug_logging() -> anyhow::Result<()> {
     let fmt = tracing_subscriber::fmt::Layer::default();
     let subscriber = fmt
@@ -92,5 +519,7 @@ fn main() -> Result<()> {
     let opts = Opts::from_args();
     match opts.cmd {
         Command::Show { show } => show.run(),
+        Command::Report { report } => report.run(),
+        Command::Export { export } => export.run(),
     }
 }
@@ -1,10 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use llvm_profparser::instrumentation_profile::overlap::*;
+use llvm_profparser::instrumentation_profile::show::{ShowFilters, ShowReport};
+use llvm_profparser::instrumentation_profile::stats::ValueSiteStats;
 use llvm_profparser::instrumentation_profile::summary::*;
+use llvm_profparser::instrumentation_profile::text_writer::TextProfWriter;
 use llvm_profparser::instrumentation_profile::types::*;
+use llvm_profparser::instrumentation_profile::writer_for;
+use llvm_profparser::summary::CUTOFF_SCALE;
 use llvm_profparser::*;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::{Layer, Registry};
@@ -23,11 +30,16 @@ pub enum Command {
         #[structopt(flatten)]
         overlap: OverlapCommand,
     },
+    /// Check a profile for problems such as missing names or counters not matching a binary
+    Validate {
+        #[structopt(flatten)]
+        validate: ValidateCommand,
+    },
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
 pub struct ShowCommand {
-    /// Input profraw file to show some information about
+    /// Input profraw file to show some information about, `-` reads the profile from stdin
     #[structopt(name = "<filename...>", long = "input", short = "i")]
     input: PathBuf,
     /// Show counter values for shown functions
@@ -36,21 +48,37 @@ pub struct ShowCommand {
     /// Details for every function
     #[structopt(long = "all-functions")]
     all_functions: bool,
+    /// Show indirect call site target values for shown functions
+    #[structopt(long = "ic-targets")]
+    ic_targets: bool,
+    /// Show the profiled sizes of the memory intrinsic calls for shown functions
+    #[structopt(long = "memop-sizes")]
+    memop_sizes: bool,
     /// Show instr profile data in text dump format
     #[structopt(long = "text")]
     text: bool,
+    /// Output the shown functions and summary as JSON
+    #[structopt(long = "json", conflicts_with_all = &["text", "only_list_below", "topn"])]
+    json: bool,
     /// Show detailed profile summary
     #[structopt(long = "show_detailed_summary")]
     show_detailed_summary: bool,
     /// Cutoff percentages (times 10000) for generating detailed summary
-    #[structopt(long = "detailed_summary_cutoffs")]
-    detailed_summary_cutoffs: Vec<usize>,
+    #[structopt(long = "detailed_summary_cutoffs", use_delimiter = true)]
+    detailed_summary_cutoffs: Vec<u64>,
     /// Show profile summary of a list of hot functions
     #[structopt(long = "show_hot_fn_list")]
     show_hot_fn_list: bool,
     /// Show context sensitive counts
     #[structopt(long = "showcs")]
     showcs: bool,
+    /// Demangle Rust and C++ function names
+    #[structopt(long = "demangle")]
+    demangle: bool,
+    /// Show functions sorted by name instead of the order they're stored in the profile, this makes
+    /// the output stable for diffing
+    #[structopt(long = "sort-by-name")]
+    sort_by_name: bool,
     /// Details for matching functions
     #[structopt(long = "function")]
     function: Option<String>,
@@ -82,7 +110,7 @@ pub struct ShowCommand {
 
 #[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
 pub struct MergeCommand {
-    /// Input files to merge
+    /// Input files to merge, directories are searched for profraw and profdata files
     #[structopt(name = "<filename...>", long = "input", short = "i")]
     input: Vec<PathBuf>,
     /// Output file
@@ -91,6 +119,18 @@ pub struct MergeCommand {
     /// List of weights and filenames in `<weight>,<filename>` format
     #[structopt(long = "weighted-input", parse(try_from_str=try_parse_weighted))]
     weighted_input: Vec<(u64, String)>,
+    /// Write the merged profile in the text format
+    #[structopt(long = "text", conflicts_with_all = &["binary", "format"])]
+    text: bool,
+    /// Write the merged profile in the indexed binary format (default)
+    #[structopt(long = "binary", conflicts_with = "format")]
+    binary: bool,
+    /// Format of the merged profile, either `binary` or `text`
+    #[structopt(long = "format")]
+    format: Option<ProfileFormat>,
+    /// Don't write out records where all the counts are zero
+    #[structopt(long = "sparse")]
+    sparse: bool,
     /// Number of merge threads to use (will autodetect by default)
     #[structopt(long = "num-threads", short = "j")]
     jobs: Option<usize>,
@@ -120,6 +160,22 @@ pub struct OverlapCommand {
     /// Generate a sparse profile
     #[structopt(long = "sparse")]
     sparse: bool,
+    /// Output the overlap as JSON
+    #[structopt(long = "json")]
+    json: bool,
+    /// Turn on debug logging
+    #[structopt(long)]
+    debug: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
+pub struct ValidateCommand {
+    /// Profile to validate, `-` reads the profile from stdin
+    #[structopt(long = "input", short = "i")]
+    input: PathBuf,
+    /// Coverage executable or object file to check the number of counters against
+    #[structopt(long = "object")]
+    objects: Vec<PathBuf>,
     /// Turn on debug logging
     #[structopt(long)]
     debug: bool,
@@ -134,13 +190,49 @@ pub struct Opts {
 impl Opts {
     fn debug(&self) -> bool {
         match &self.cmd {
-            &Command::Show { ref show } => show.debug,
-            &Command::Merge { ref merge } => merge.debug,
-            &Command::Overlap { ref overlap } => overlap.debug,
+            Command::Show { show } => show.debug,
+            Command::Merge { merge } => merge.debug,
+            Command::Overlap { overlap } => overlap.debug,
+            Command::Validate { validate } => validate.debug,
         }
     }
 }
 
+/// Formats a number like printf's `%g`, using the given number of significant figures and
+/// stripping any trailing zeroes
+fn format_significant(value: f64, figures: i32) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    let exponent = value.abs().log10().floor() as i32;
+    if exponent < -4 || exponent >= figures {
+        return format!("{:.*e}", (figures - 1) as usize, value);
+    }
+    let decimals = (figures - 1 - exponent).max(0) as usize;
+    let result = format!("{:.*}", decimals, value);
+    if result.contains('.') {
+        result
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    } else {
+        result
+    }
+}
+
+/// `-` is used as the input path to read a profile from stdin
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn parse_input(path: &Path) -> io::Result<InstrumentationProfile> {
+    if is_stdin(path) {
+        parse_reader(io::stdin().lock())
+    } else {
+        parse(path)
+    }
+}
+
 fn try_parse_weighted(input: &str) -> Result<(u64, String), String> {
     if !input.contains(',') {
         Ok((1, input.to_string()))
@@ -161,107 +253,163 @@ fn try_parse_weighted(input: &str) -> Result<(u64, String), String> {
     }
 }
 
-fn check_function(name: Option<&String>, pattern: Option<&String>) -> bool {
-    match pattern {
-        Some(pat) => name.map(|x| x.contains(pat)).unwrap_or(false),
-        None => false,
-    }
-}
-
-#[derive(Clone, Debug, Eq)]
-struct HotFn {
-    name: String,
-    count: u64,
-}
-
-impl PartialOrd for HotFn {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl Ord for HotFn {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // Do the reverse here
-        other.count.cmp(&self.count)
+impl ShowCommand {
+    fn filters(&self) -> ShowFilters {
+        ShowFilters {
+            all_functions: self.all_functions,
+            function: self.function.clone(),
+            context_sensitive: self.showcs,
+            value_cutoff: self.value_cutoff,
+            demangle: self.demangle,
+            sort_by_name: self.sort_by_name,
+        }
     }
-}
 
-impl PartialEq for HotFn {
-    fn eq(&self, other: &Self) -> bool {
-        self.count == other.count
+    fn show_sample_profile(&self, stdin: Option<&[u8]>) -> Result<()> {
+        let profile = match stdin {
+            Some(data) => sample_profile::parse_bytes(data)?,
+            None => sample_profile::parse(&self.input)?,
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        if self.show_section_info_only && profile.sections.is_empty() {
+            eprintln!("warning: --show_section_info_only is only supported for sample profiles in extbinary format and is ignored for other formats");
+        } else if self.show_section_info_only {
+            for section in &profile.sections {
+                writeln!(stdout, "{}", section)?;
+            }
+            writeln!(stdout, "Header Size: {}", profile.header_size())?;
+            writeln!(
+                stdout,
+                "Total Sections Size: {}",
+                profile.total_sections_size()
+            )?;
+            writeln!(stdout, "File Size: {}", profile.file_size)?;
+            return Ok(());
+        }
+        for function in profile.sorted_functions() {
+            let show = match self.function.as_ref() {
+                Some(name) if !self.all_functions => &function.name == name,
+                _ => true,
+            };
+            if show {
+                write!(stdout, "Function: {}: ", function.name)?;
+                function.write(0, &mut stdout)?;
+            }
+        }
+        if self.show_profile_sym_list {
+            writeln!(stdout, "======== Dump profile symbol list ========")?;
+            let mut symbols = profile.symbol_list.iter().collect::<Vec<_>>();
+            symbols.sort();
+            for symbol in symbols {
+                writeln!(stdout, "{}", symbol)?;
+            }
+        }
+        Ok(())
     }
-}
 
-impl ShowCommand {
     pub fn run(&self) -> Result<()> {
-        let profile = parse(&self.input)?;
+        // stdin can only be read once so it's buffered to check the format and then parse it
+        let stdin = if is_stdin(&self.input) {
+            let mut buffer = vec![];
+            io::stdin().read_to_end(&mut buffer)?;
+            Some(buffer)
+        } else {
+            None
+        };
+        let is_sample_profile = match stdin.as_ref() {
+            Some(data) => sample_profile::has_format(data.as_slice()),
+            None => sample_profile::has_format(File::open(&self.input)?),
+        };
+        if is_sample_profile {
+            return self.show_sample_profile(stdin.as_deref());
+        } else if self.show_section_info_only {
+            bail!("--show_section_info_only is only usable with extbinary sample profiles");
+        }
+        let profile = match stdin.as_ref() {
+            Some(data) => parse_bytes(data)?,
+            None => parse(&self.input)?,
+        };
+        if self.json {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            ShowReport::new(&profile, &self.filters()).to_json(&mut stdout)?;
+            writeln!(stdout)?;
+            return Ok(());
+        }
+        let filters = self.filters();
         let mut summary = ProfileSummary::new();
 
         let is_ir_instr = profile.is_ir_level_profile();
-        let mut hotties =
-            BinaryHeap::<HotFn>::with_capacity(self.topn.unwrap_or_default() as usize);
         let mut shown_funcs = 0;
         let mut below_cutoff_funcs = 0;
-        let topn = self.topn.unwrap_or_default();
-        for func in profile.records() {
-            if func.name.is_none() || func.hash.is_none() {
+        let text_writer = TextProfWriter::new();
+        let mut ic_stats = ValueSiteStats::new();
+        let mut memop_stats = ValueSiteStats::new();
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        if self.text && is_ir_instr {
+            writeln!(stdout, ":ir")?;
+        }
+        let mut functions = profile.iter_named().collect::<Vec<_>>();
+        if self.sort_by_name {
+            functions.sort_by(|a, b| a.0.cmp(b.0).then_with(|| a.1.hash.cmp(&b.1.hash)));
+        }
+        for (name, func) in functions {
+            if func.hash.is_none() {
                 continue;
             }
             if is_ir_instr && func.has_cs_flag() != self.showcs {
                 continue;
             }
-            let show =
-                self.all_functions || check_function(func.name.as_ref(), self.function.as_ref());
+            let show = filters.shows(name);
 
             if show && self.text {
-                // TODO text format dump
+                text_writer.write_record(name, func, &profile.symtab, &mut stdout)?;
                 continue;
             }
             summary.add_record(&func.record);
+            let name = if self.demangle {
+                demangle(name)
+                    .map(Cow::Owned)
+                    .unwrap_or(Cow::Borrowed(name))
+            } else {
+                Cow::Borrowed(name)
+            };
 
-            let (func_max, func_sum) = func.counts().iter().fold((0, 0u64), |acc, x| {
-                (*x.max(&acc.0), acc.1.saturating_add(*x))
-            });
+            let func_max = func.max_count();
             if func_max < self.value_cutoff {
                 below_cutoff_funcs += 1;
                 if self.only_list_below {
                     println!(
                         "  {}: (Max = {} Sum = {})",
-                        func.name.as_ref().unwrap(),
+                        name,
                         func_max,
-                        func_sum
+                        func.total_count()
                     );
                     continue;
                 }
             } else if self.only_list_below {
                 continue;
             }
-            if topn > 0 {
-                if hotties.len() == topn {
-                    let top = hotties.peek().unwrap();
-                    if top.count < func_max {
-                        hotties.pop();
-                        hotties.push(HotFn {
-                            name: func.name.as_ref().unwrap().to_string(),
-                            count: func_max,
-                        });
-                    }
-                } else {
-                    hotties.push(HotFn {
-                        name: func.name.as_ref().unwrap().to_string(),
-                        count: func_max,
-                    });
-                }
-            }
             if show {
                 if shown_funcs == 0 {
                     println!("Counters:");
                 }
                 shown_funcs += 1;
-                println!("  {}:", func.name.as_ref().unwrap());
+                println!("  {}:", name);
                 println!("    Hash: {:#018x}", func.hash.unwrap());
                 println!("    Counters: {}", func.counts().len());
+                if self.ic_targets {
+                    println!(
+                        "    Indirect Call Site Count: {}",
+                        func.num_value_sites(ValueKind::IndirectCallTarget)
+                    );
+                }
+                let num_memop_calls = func.num_value_sites(ValueKind::MemOpSize);
+                if self.memop_sizes && num_memop_calls > 0 {
+                    println!("    Number of Memory Intrinsics Calls: {}", num_memop_calls);
+                }
                 if !is_ir_instr {
                     let counts = if func.counts().is_empty() {
                         0
@@ -281,8 +429,29 @@ impl ShowCommand {
                         .join(", ");
                     println!("    Block counts: [{}]", counts);
                 }
+                if self.ic_targets {
+                    println!("    Indirect Target Results:");
+                    ic_stats.traverse_sites(
+                        &func.record,
+                        ValueKind::IndirectCallTarget,
+                        Some(&profile.symtab),
+                        &mut stdout,
+                    )?;
+                }
+                if self.memop_sizes && num_memop_calls > 0 {
+                    println!("    Memory Intrinsic Size Results:");
+                    memop_stats.traverse_sites(
+                        &func.record,
+                        ValueKind::MemOpSize,
+                        None,
+                        &mut stdout,
+                    )?;
+                }
             }
         }
+        if self.text {
+            return Ok(());
+        }
         if profile.get_level() == InstrumentationLevel::Ir {
             // This is just to enable same printout in older versions with llvm 11
             #[cfg(not(llvm_11))]
@@ -321,33 +490,175 @@ impl ShowCommand {
                 "Top {} functions with the largest internal block counts: ",
                 topn
             );
-            let hotties = hotties.into_sorted_vec();
-            for f in hotties.iter() {
-                println!("  {}, max count = {}", f.name, f.count);
+            let hotties = profile.top_functions_by(topn, |func| {
+                func.hash.is_some()
+                    && !(is_ir_instr && func.has_cs_flag() != self.showcs)
+                    && !self.only_list_below
+            });
+            for (name, count) in hotties {
+                let name = if self.demangle {
+                    demangle(name)
+                        .map(Cow::Owned)
+                        .unwrap_or(Cow::Borrowed(name))
+                } else {
+                    Cow::Borrowed(name)
+                };
+                println!("  {}, max count = {}", name, count);
             }
         }
 
         if self.show_detailed_summary {
-            println!("Total number of blocks: ?");
-            println!("Total count: ?");
+            let num_counts = summary.num_counts();
+            println!("Total number of blocks: {}", num_counts);
+            println!("Total count: {}", summary.total_count());
+            println!("Detailed summary:");
+            for entry in summary.detailed_summary(&self.detailed_summary_cutoffs) {
+                let block_percent = if num_counts > 0 {
+                    (entry.num_counts as f64 * 100.0) / num_counts as f64
+                } else {
+                    0.0
+                };
+                let cutoff_percent = (entry.cutoff as f32 / CUTOFF_SCALE as f32) * 100.0;
+                println!(
+                    "{} blocks ({:.2}%) with count >= {} account for {}% of the total counts.",
+                    entry.num_counts,
+                    block_percent,
+                    entry.min_count,
+                    format_significant(cutoff_percent as f64, 6)
+                );
+            }
+        }
+        if shown_funcs > 0 && self.ic_targets {
+            println!("Statistics for indirect call sites profile:");
+            print!("{}", ic_stats);
+        }
+        if shown_funcs > 0 && self.memop_sizes {
+            println!("Statistics for memory intrinsic calls sizes profile:");
+            print!("{}", memop_stats);
         }
         Ok(())
     }
 }
 
 impl MergeCommand {
+    fn output_format(&self) -> ProfileFormat {
+        if self.text {
+            ProfileFormat::Text
+        } else {
+            self.format.unwrap_or(ProfileFormat::Binary)
+        }
+    }
+
     fn run(&self) -> Result<()> {
         assert!(
-            !self.input.is_empty(),
+            !self.input.is_empty() || !self.weighted_input.is_empty(),
             "No input files selected. See merge --help"
         );
-        let profile = merge_profiles(&self.input)?;
-        // Now to write it out?
-        println!("{:#?}", profile);
+        let mut inputs = vec![];
+        let mut skipped = vec![];
+        let weighted_inputs = self.input.iter().map(|x| (1, x.clone())).chain(
+            self.weighted_input
+                .iter()
+                .map(|(weight, x)| (*weight, PathBuf::from(x))),
+        );
+        for (weight, input) in weighted_inputs {
+            let (profiles, mut not_profiles) = expand_profile_dirs(&[input])?;
+            inputs.extend(profiles.into_iter().map(|x| (weight, x)));
+            skipped.append(&mut not_profiles);
+        }
+        for path in &skipped {
+            eprintln!("warning: skipping {} as it isn't a profile", path.display());
+        }
+        let (profile, stats) = merge_profiles_with_stats(&inputs)?;
+        if stats.count_mismatches > 0 {
+            eprintln!(
+                "warning: {} functions have mismatched counters, make sure that all profile data to be merged is generated from the same binary",
+                stats.count_mismatches
+            );
+        }
+        if stats.counter_overflows > 0 {
+            eprintln!("warning: {} counters overflowed", stats.counter_overflows);
+        }
+        let mut output = File::create(&self.output)
+            .with_context(|| format!("Failed to create output file {}", self.output.display()))?;
+        let writer = writer_for(self.output_format(), self.sparse)?;
+        writer.write(&profile, &mut output)?;
         Ok(())
     }
 }
 
+impl OverlapCommand {
+    fn run(&self) -> Result<()> {
+        let base = parse(&self.base_file)?;
+        let test = parse(&self.test_file)?;
+        let filter = OverlapFuncFilters {
+            value_cutoff: self.value_cutoff.map(|x| x as u64),
+            name_filter: self.function.clone(),
+        };
+        let overlap = overlap_profiles(&base, &test, &filter, self.context_sensitive_counts);
+
+        let mut output: Box<dyn Write> = match self.output.as_ref() {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(io::stdout()),
+        };
+        if self.json {
+            overlap.to_json(&mut output)?;
+            return Ok(());
+        }
+        for (sum, file) in [
+            (overlap.program.base.count_sum, &self.base_file),
+            (overlap.program.test.count_sum, &self.test_file),
+        ] {
+            if sum < 1.0 {
+                writeln!(
+                    output,
+                    "Sum of edge counts for profile {} is 0.",
+                    file.display()
+                )?;
+                return Ok(());
+            }
+        }
+        for func in &overlap.functions {
+            write!(output, "{}", func)?;
+        }
+        writeln!(
+            output,
+            "Profile overlap information for base_profile: {} and test_profile: {}",
+            self.base_file.display(),
+            self.test_file.display()
+        )?;
+        write!(output, "{}", overlap.program)?;
+        Ok(())
+    }
+}
+
+impl ValidateCommand {
+    fn run(&self) -> Result<()> {
+        let profile = parse_input(&self.input)?;
+        let result = if self.objects.is_empty() {
+            profile.validate()
+        } else {
+            CoverageMapping::new(&self.objects, &profile, false)?.validate()
+        };
+        match result {
+            Ok(()) => {
+                println!("No problems found in {}", self.input.display());
+                Ok(())
+            }
+            Err(errors) => {
+                for error in &errors {
+                    println!("{}", error);
+                }
+                bail!(
+                    "{} problems found in {}",
+                    errors.len(),
+                    self.input.display()
+                );
+            }
+        }
+    }
+}
+
 fn enable_debug_logging() -> anyhow::Result<()> {
     let fmt = tracing_subscriber::fmt::Layer::default();
     let subscriber = fmt
@@ -367,9 +678,8 @@ fn main() -> Result<()> {
     match opts.cmd {
         Command::Show { show } => show.run(),
         Command::Merge { merge } => merge.run(),
-        _ => {
-            panic!("Unsupported command");
-        }
+        Command::Overlap { overlap } => overlap.run(),
+        Command::Validate { validate } => validate.run(),
     }
 }
 
@@ -398,4 +708,13 @@ mod tests {
         assert!(try_parse_weighted("foo.profdata,1").is_err());
         assert!(try_parse_weighted("1,1,foo.profdata").is_err());
     }
+
+    #[test]
+    fn significant_figures() {
+        assert_eq!(format_significant(1.0, 6), "1");
+        assert_eq!(format_significant(99.9, 6), "99.9");
+        assert_eq!(format_significant(99.9999, 6), "99.9999");
+        assert_eq!(format_significant(0.0001, 6), "0.0001");
+        assert_eq!(format_significant(0.0, 6), "0");
+    }
 }
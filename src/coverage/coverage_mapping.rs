@@ -1,10 +1,17 @@
 use crate::coverage::reporting::*;
 use crate::coverage::*;
+use crate::instrumentation_profile::get_num_padding_bytes;
 use crate::instrumentation_profile::types::*;
 use crate::util::*;
 use anyhow::{bail, Result};
-use nom::error::Error as NomError;
+use nom::error::{
+    ContextError, Error as NomError, ErrorKind, ParseError, VerboseError, VerboseErrorKind,
+};
 use object::{Endian, Endianness, Object, ObjectSection, Section};
+use rayon::prelude::*;
+use rustc_hash::FxHashSet;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
@@ -19,18 +26,20 @@ use tracing::{debug, error, trace, warn};
 ///
 /// So what the LLVM one has that this one doesn't yet:
 ///
-/// 1. DenseMap<size_t, DenseSet<size_t>> RecordProvenance
-/// 2. std::vector<FunctionRecord> functions (this is probably taken straight from
-/// InstrumentationProfile
-/// 3. DenseMap<size_t, SmallVector<unsigned, 0>> FilenameHash2RecordIndices
-/// 4. Vec<Pair<String, u64>> FuncHashMismatches
+/// 1. std::vector<FunctionRecord> functions (this is probably taken straight from
+///    InstrumentationProfile
+/// 2. DenseMap<size_t, SmallVector<unsigned, 0>> FilenameHash2RecordIndices
+///
+/// Function hash mismatches are stored in the generated `CoverageReport` instead. Instead of the
+/// `RecordProvenance` map duplicate function records are skipped when the reports for each object
+/// file are merged.
 #[derive(Debug)]
 pub struct CoverageMapping<'a> {
-    profile: &'a InstrumentationProfile,
+    profile: Cow<'a, InstrumentationProfile>,
     pub mapping_info: Vec<CoverageMappingInfo>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum LlvmSection {
     CoverageMap,
     ProfileNames,
@@ -39,11 +48,88 @@ pub enum LlvmSection {
     CoverageFunctions,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl LlvmSection {
+    /// Every section, in the order they're listed in a `SectionAvailability`
+    pub const ALL: [LlvmSection; 5] = [
+        Self::CoverageMap,
+        Self::CoverageFunctions,
+        Self::ProfileNames,
+        Self::ProfileCounts,
+        Self::ProfileData,
+    ];
+
+    /// The name of the section in ELF and Mach-O object files and then in COFF ones
+    pub fn names(&self) -> [&'static str; 2] {
+        match self {
+            Self::CoverageMap => ["__llvm_covmap", ".lcovmap"],
+            Self::ProfileNames => ["__llvm_prf_names", ".lprfn"],
+            Self::ProfileCounts => ["__llvm_prf_cnts", ".lprfc"],
+            Self::ProfileData => ["__llvm_prf_data", ".lprfd"],
+            Self::CoverageFunctions => ["__llvm_covfun", ".lcovfun"],
+        }
+    }
+
+    /// Checks a section name against the names for this section. Mach-O names may be qualified by
+    /// their segment such as `__DATA,__llvm_prf_data`, and COFF object files have a `$M` suffix
+    /// used to order sections which the linker removes.
+    fn matches(&self, name: &str) -> bool {
+        let name = name.rsplit(',').next().unwrap_or(name);
+        let name = name.split('$').next().unwrap_or(name);
+        self.names().contains(&name)
+    }
+}
+
+/// The llvm sections found in an object file. This helps to explain an empty report, the coverage
+/// sections are missing if the binary wasn't built with coverage instrumentation and the profile
+/// sections can be removed by stripping.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SectionAvailability {
+    pub coverage_map: bool,
+    pub coverage_functions: bool,
+    pub profile_names: bool,
+    pub profile_counts: bool,
+    pub profile_data: bool,
+}
+
+impl SectionAvailability {
+    pub fn contains(&self, section: LlvmSection) -> bool {
+        match section {
+            LlvmSection::CoverageMap => self.coverage_map,
+            LlvmSection::CoverageFunctions => self.coverage_functions,
+            LlvmSection::ProfileNames => self.profile_names,
+            LlvmSection::ProfileCounts => self.profile_counts,
+            LlvmSection::ProfileData => self.profile_data,
+        }
+    }
+
+    /// The sections which aren't in the object file
+    pub fn missing(&self) -> Vec<LlvmSection> {
+        LlvmSection::ALL
+            .iter()
+            .copied()
+            .filter(|x| !self.contains(*x))
+            .collect()
+    }
+
+    /// Whether the coverage mapping sections needed to generate a report are present
+    pub fn has_coverage_mapping(&self) -> bool {
+        self.coverage_map && self.coverage_functions
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SectionReadError {
     EmptySection(LlvmSection),
     MissingSection(LlvmSection),
     InvalidPathList,
+    /// The section ended part way through a record
+    TruncatedSection(LlvmSection),
+    /// A coverage function record couldn't be parsed, contains the offset in the section of the
+    /// failure
+    MalformedCoverageFunction(usize),
+    /// The profile data records don't match any known layout, or refer to counters outside of the
+    /// counters section
+    InvalidProfileData,
 }
 
 impl fmt::Display for SectionReadError {
@@ -52,21 +138,148 @@ impl fmt::Display for SectionReadError {
             Self::EmptySection(s) => write!(f, "empty section: {:?}", s),
             Self::MissingSection(s) => write!(f, "missing section: {:?}", s),
             Self::InvalidPathList => write!(f, "unable to read path list"),
+            Self::TruncatedSection(s) => write!(f, "truncated section: {:?}", s),
+            Self::MalformedCoverageFunction(offset) => {
+                write!(f, "malformed coverage function record at byte {}", offset)
+            }
+            Self::InvalidProfileData => write!(f, "profile data doesn't match the counters"),
         }
     }
 }
 
 impl Error for SectionReadError {}
 
+/// State of an expression while evaluating the expressions of a function
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Evaluation {
+    Pending,
+    InProgress,
+    Done(Option<i64>),
+}
+
+/// Evaluates the expressions of a function adding their counts to `region_ids`. Expressions are
+/// evaluated after the expressions they refer to, using an explicit stack so long chains of
+/// expressions don't overflow. Instrumentation counters missing from `region_ids` have been
+/// optimised out and count as 0. The indexes of expressions that can't be evaluated, because they
+/// form a cycle or refer to an expression that doesn't exist, are returned.
+fn evaluate_expressions(
+    expressions: &[Expression],
+    region_ids: &mut FxHashMap<Counter, i64>,
+) -> Vec<usize> {
+    let mut states = vec![Evaluation::Pending; expressions.len()];
+    let mut stack = vec![];
+    for root in 0..expressions.len() {
+        stack.push(root);
+        while let Some(&index) = stack.last() {
+            let expr = &expressions[index];
+            let operands = [expr.lhs, expr.rhs];
+            match states[index] {
+                Evaluation::Pending => {
+                    states[index] = Evaluation::InProgress;
+                    for operand in operands.iter().filter(|x| x.is_expression()) {
+                        let id = operand.id as usize;
+                        // Operands already in progress are a cycle and are left unresolved
+                        if states.get(id) == Some(&Evaluation::Pending) {
+                            stack.push(id);
+                        }
+                    }
+                }
+                Evaluation::InProgress => {
+                    stack.pop();
+                    let value = |counter: &Counter| match counter.kind {
+                        CounterType::Zero => Some(0),
+                        CounterType::ProfileInstrumentation => {
+                            Some(region_ids.get(counter).copied().unwrap_or_default())
+                        }
+                        CounterType::Expression(_) => match states.get(counter.id as usize) {
+                            Some(Evaluation::Done(count)) => *count,
+                            _ => None,
+                        },
+                    };
+                    let count = match (value(&expr.lhs), value(&expr.rhs)) {
+                        (Some(lhs), Some(rhs)) => {
+                            trace!("{:?} counts: {} {}", expr.kind, lhs, rhs);
+                            let count = match expr.kind {
+                                ExprKind::Subtract => lhs.saturating_sub(rhs),
+                                ExprKind::Add => lhs.saturating_add(rhs),
+                            };
+                            let counter = Counter {
+                                kind: CounterType::Expression(expr.kind),
+                                id: index as _,
+                            };
+                            region_ids.insert(counter, count);
+                            Some(count)
+                        }
+                        _ => None,
+                    };
+                    states[index] = Evaluation::Done(count);
+                }
+                Evaluation::Done(_) => {
+                    stack.pop();
+                }
+            }
+        }
+    }
+    states
+        .iter()
+        .enumerate()
+        .filter(|(_, state)| **state == Evaluation::Done(None))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Finds an llvm section in an object file using the naming conventions of any object format
+fn find_section<'data, 'file>(
+    object_file: &'file object::File<'data>,
+    section: LlvmSection,
+) -> Option<Section<'data, 'file>> {
+    object_file
+        .sections()
+        .find(|x| x.name().map(|name| section.matches(name)).unwrap_or(false))
+}
+
+/// Reports which of the llvm sections are in an object file without parsing them
+pub fn sections_report(object: &Path) -> Result<SectionAvailability> {
+    let binary_data = fs::read(object)?;
+    sections_report_bytes(&binary_data)
+}
+
+/// Reports which of the llvm sections are in an object file that's already in memory
+pub fn sections_report_bytes(data: &[u8]) -> Result<SectionAvailability> {
+    let object_file = object::File::parse(data)?;
+    let has = |section| find_section(&object_file, section).is_some();
+    Ok(SectionAvailability {
+        coverage_map: has(LlvmSection::CoverageMap),
+        coverage_functions: has(LlvmSection::CoverageFunctions),
+        profile_names: has(LlvmSection::ProfileNames),
+        profile_counts: has(LlvmSection::ProfileCounts),
+        profile_data: has(LlvmSection::ProfileData),
+    })
+}
+
 pub fn read_object_file(object: &Path, version: u64) -> Result<CoverageMappingInfo> {
+    let binary_data = fs::read(object)?;
+    read_object_bytes(&binary_data, object, version)
+}
+
+/// Reads the coverage information from an object file that's already in memory, such as one from
+/// a build cache or archive. The name is only used for logging.
+pub fn read_object_bytes(data: &[u8], name: &Path, version: u64) -> Result<CoverageMappingInfo> {
+    read_object(data, name, version, None)
+}
+
+fn read_object(
+    data: &[u8],
+    name: &Path,
+    version: u64,
+    compilation_dir: Option<&Path>,
+) -> Result<CoverageMappingInfo> {
     // I believe vnode sections added by llvm are unnecessary
 
-    let binary_data = fs::read(object)?;
-    let object_file = object::File::parse(&*binary_data)?;
+    debug!("Reading coverage information from {}", name.display());
+    let object_file = object::File::parse(data)?;
 
-    let cov_fun = object_file
-        .section_by_name("__llvm_covfun")
-        .or(object_file.section_by_name(".lcovfun"))
+    let cov_fun = find_section(&object_file, LlvmSection::CoverageFunctions)
         .map(|x| parse_coverage_functions(object_file.endianness(), &x))
         .ok_or(SectionReadError::MissingSection(
             LlvmSection::CoverageFunctions,
@@ -74,50 +287,158 @@ pub fn read_object_file(object: &Path, version: u64) -> Result<CoverageMappingIn
 
     debug!("Parsed covfun section: {:?}", cov_fun);
 
-    let cov_map = object_file
-        .section_by_name("__llvm_covmap")
-        .or(object_file.section_by_name(".lcovmap"))
-        .map(|x| parse_coverage_mapping(object_file.endianness(), &x, version))
+    let cov_map = find_section(&object_file, LlvmSection::CoverageMap)
+        .map(|x| parse_coverage_mapping(object_file.endianness(), &x, version, compilation_dir))
         .ok_or(SectionReadError::MissingSection(LlvmSection::CoverageMap))??;
 
     debug!("Parsed covmap section: {:?}", cov_map);
 
-    let prof_counts = object_file
-        .section_by_name("__llvm_prf_cnts")
-        .or(object_file.section_by_name(".lprfc"))
-        .and_then(|x| parse_profile_counters(object_file.endianness(), &x).ok());
+    let counters_section = find_section(&object_file, LlvmSection::ProfileCounts);
+    let prof_counts = counters_section
+        .as_ref()
+        .and_then(|x| parse_profile_counters(object_file.endianness(), x).ok());
 
     debug!("Parsed prf_cnts: {:?}", prof_counts);
 
-    let prof_data = object_file
-        .section_by_name("__llvm_prf_data")
-        .or(object_file.section_by_name(".lprfd"))
-        .and_then(|x| parse_profile_data(object_file.endianness(), &x).ok());
+    let prof_names = find_section(&object_file, LlvmSection::ProfileNames)
+        .and_then(|x| parse_profile_names(&x).ok())
+        .unwrap_or_default();
+
+    let pointer_size = if object_file.is_64() { 8 } else { 4 };
+    let prof_data = find_section(&object_file, LlvmSection::ProfileData)
+        .zip(counters_section.as_ref())
+        .and_then(|(data, counters)| {
+            parse_profile_data(object_file.endianness(), &data, counters, pointer_size).ok()
+        });
 
     debug!("Parsed prf_data section: {:?}", prof_data);
 
+    let binary_id = object_file.build_id().ok().flatten().map(|x| x.to_vec());
+
+    let compilation_dirs = if has_compilation_dir(version) {
+        cov_map
+            .iter()
+            .filter_map(|(id, paths)| paths.first().map(|dir| (*id, dir.clone())))
+            .collect()
+    } else {
+        FxHashMap::default()
+    };
+
     Ok(CoverageMappingInfo {
         cov_map,
         cov_fun,
         prof_counts,
         prof_data,
+        prof_names,
+        binary_id,
+        compilation_dirs,
     })
 }
 
+/// Returns false if both the object and profile have binary ids and the object's isn't one of the
+/// profile's
+fn binary_id_in_profile(profile: &InstrumentationProfile, info: &CoverageMappingInfo) -> bool {
+    match info.binary_id.as_ref() {
+        Some(id) => profile.binary_ids().is_empty() || profile.binary_ids().contains(id),
+        None => true,
+    }
+}
+
+/// Lazily reads the object files so only one is in memory at a time
+fn read_objects(object_files: &[PathBuf]) -> impl Iterator<Item = (&Path, Result<Vec<u8>>)> {
+    object_files
+        .iter()
+        .map(|file| (file.as_path(), fs::read(file).map_err(anyhow::Error::from)))
+}
+
+/// Profile version for profiles built from the counters in object files. The version is only used
+/// to read the coverage mapping path lists and any version from 3 reads them the same way.
+const EMBEDDED_PROFILE_VERSION: u64 = 10;
+
 impl<'a> CoverageMapping<'a> {
     pub fn new(
         object_files: &[PathBuf],
         profile: &'a InstrumentationProfile,
         allow_parsing_failures: bool,
     ) -> Result<Self> {
+        Self::load(
+            read_objects(object_files),
+            profile,
+            allow_parsing_failures,
+            None,
+        )
+    }
+
+    /// Like `CoverageMapping::new` but relative paths in the coverage mapping are joined onto
+    /// `compilation_dir` instead of the directory the object was compiled in, like
+    /// `llvm-cov -compilation-dir`. This is useful when the sources have been moved. A relative
+    /// `compilation_dir` is taken as relative to the current directory.
+    pub fn with_compilation_dir(
+        object_files: &[PathBuf],
+        profile: &'a InstrumentationProfile,
+        allow_parsing_failures: bool,
+        compilation_dir: &Path,
+    ) -> Result<Self> {
+        Self::load(
+            read_objects(object_files),
+            profile,
+            allow_parsing_failures,
+            Some(compilation_dir),
+        )
+    }
+
+    /// Creates a mapping from object files that are already in memory, each object is paired
+    /// with the path it's reported as in errors. The options are the same as for
+    /// `CoverageMapping::new` and `CoverageMapping::with_compilation_dir`.
+    pub fn from_bytes(
+        objects: &[(PathBuf, &[u8])],
+        profile: &'a InstrumentationProfile,
+        allow_parsing_failures: bool,
+        compilation_dir: Option<&Path>,
+    ) -> Result<Self> {
+        let objects = objects
+            .iter()
+            .map(|(path, data)| (path.as_path(), Ok(*data)));
+        Self::load(objects, profile, allow_parsing_failures, compilation_dir)
+    }
+
+    /// Parses each object paired with the path used to report it, an object which couldn't be
+    /// read is treated like one which couldn't be parsed
+    fn load<'b, I, D>(
+        objects: I,
+        profile: &'a InstrumentationProfile,
+        allow_parsing_failures: bool,
+        compilation_dir: Option<&Path>,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'b Path, Result<D>)>,
+        D: AsRef<[u8]>,
+    {
         let mut mapping_info = vec![];
         let version = match profile.version() {
             Some(v) => v,
             None => bail!("Invalid profile instrumentation, no version number provided"),
         };
-        for file in object_files {
-            match read_object_file(file.as_path(), version) {
-                Ok(info) => mapping_info.push(info),
+        // Relative paths in the mapping are joined onto the first absolute path in the list so the
+        // directory has to be absolute
+        let compilation_dir = match compilation_dir {
+            Some(dir) => Some(std::env::current_dir()?.join(dir)),
+            None => None,
+        };
+        for (file, data) in objects {
+            let info = data.and_then(|data| {
+                read_object(data.as_ref(), file, version, compilation_dir.as_deref())
+            });
+            match info {
+                Ok(info) => {
+                    if !binary_id_in_profile(profile, &info) {
+                        warn!(
+                            "{} has a build id that isn't in the profile, it may not be the binary that was run",
+                            file.display()
+                        );
+                    }
+                    mapping_info.push(info);
+                }
                 Err(e) => {
                     error!("{} couldn't be interpretted: {}", file.display(), e);
                     if !allow_parsing_failures {
@@ -127,7 +448,49 @@ impl<'a> CoverageMapping<'a> {
             };
         }
         Ok(Self {
-            profile,
+            profile: Cow::Borrowed(profile),
+            mapping_info,
+        })
+    }
+
+    /// Creates a mapping using the counters embedded in the object files instead of a separate
+    /// profile. This is for binaries where the counters section has been written back after a
+    /// run. The counters for functions in more than one object file are summed.
+    pub fn from_object_only(object_files: &[PathBuf]) -> Result<CoverageMapping<'static>> {
+        let mut mapping_info = vec![];
+        for file in object_files {
+            mapping_info.push(read_object_file(file.as_path(), EMBEDDED_PROFILE_VERSION)?);
+        }
+        let mut profile =
+            InstrumentationProfile::new(Some(EMBEDDED_PROFILE_VERSION), false, false, false);
+        for info in &mapping_info {
+            for name in &info.prof_names {
+                profile.symtab.add_func_name(name.clone(), None);
+            }
+        }
+        for info in &mapping_info {
+            let data = match info.prof_data.as_ref() {
+                Some(data) => data,
+                None => bail!("Object file has no profile data"),
+            };
+            for record in data {
+                let counts = match info.counters(record) {
+                    Some(counts) => counts.to_vec(),
+                    None => bail!("Object file has no counters for {:x}", record.name_md5),
+                };
+                let stats = profile.merge_record(&NamedInstrProfRecord {
+                    name: profile.symtab.get(record.name_md5).cloned(),
+                    name_hash: Some(record.name_md5),
+                    hash: Some(record.structural_hash),
+                    record: InstrProfRecord { counts, data: None },
+                });
+                if !stats.is_clean() {
+                    warn!("{:x}: counters couldn't be merged", record.name_md5);
+                }
+            }
+        }
+        Ok(CoverageMapping {
+            profile: Cow::Owned(profile),
             mapping_info,
         })
     }
@@ -138,277 +501,552 @@ impl<'a> CoverageMapping<'a> {
     pub(crate) fn get_simple_counters(&self, func: &FunctionRecordV3) -> FxHashMap<Counter, i64> {
         let mut result = FxHashMap::default();
         result.insert(Counter::default(), 0);
-        let record = self.profile.records().iter().find(|x| {
-            x.hash == Some(func.header.fn_hash) && Some(func.header.name_hash) == x.name_hash
-        });
-        if let Some(func_record) = record.as_ref() {
+        let record = self
+            .profile
+            .find_by_hashes(func.header.name_hash, func.header.fn_hash);
+        if let Some(func_record) = record {
             for (id, count) in func_record.record.counts.iter().enumerate() {
-                result.insert(Counter::instrumentation(id as u64), *count as i64);
+                // Counters past `i64::MAX` are saturated instead of wrapping to negative counts
+                let count = i64::try_from(*count).unwrap_or(i64::MAX);
+                result.insert(Counter::instrumentation(id as u64), count);
             }
         }
         result
     }
 
-    pub fn generate_subreport<P>(&self, mut predicate: P) -> CoverageReport
-    where
-        P: FnMut(&[PathBuf]) -> bool,
-    {
-        let mut report = CoverageReport::default();
-        //let base_region_ids = info.get_simple_counters(self.profile);
-        for info in &self.mapping_info {
-            for func in &info.cov_fun {
-                let base_region_ids = self.get_simple_counters(func);
-                let paths = info.get_files_from_id(func.header.filenames_ref);
-                if paths.is_empty() || !predicate(&paths) {
-                    continue;
-                }
+    /// If the profile has a record for the function but none of the records with that name have
+    /// the same function hash as the mapping then the profile is for a different build
+    fn hash_mismatch(&self, func: &FunctionRecordV3) -> Option<HashMismatch> {
+        if self
+            .profile
+            .find_by_hashes(func.header.name_hash, func.header.fn_hash)
+            .is_some()
+        {
+            return None;
+        }
+        let profile_hash = self
+            .profile
+            .find_by_name_hash(func.header.name_hash)
+            .and_then(|record| record.hash);
+        profile_hash.map(|profile_hash| HashMismatch {
+            name: self.function_name(func),
+            profile_hash,
+            mapping_hash: func.header.fn_hash,
+        })
+    }
+
+    fn function_name(&self, func: &FunctionRecordV3) -> String {
+        self.profile
+            .symtab
+            .get(func.header.name_hash)
+            .cloned()
+            .unwrap_or_else(|| format!("{:x}", func.header.name_hash))
+    }
 
-                let mut region_ids = base_region_ids.clone();
+    /// Evaluates the counters and expressions of a function to get the counts for its regions
+    fn function_record(
+        &self,
+        func: &FunctionRecordV3,
+        paths: &[PathBuf],
+    ) -> FunctionCoverageRecord {
+        let base_region_ids = self.get_simple_counters(func);
+        let mut region_ids = base_region_ids.clone();
+        let unresolved_expressions = evaluate_expressions(&func.expressions, &mut region_ids);
 
-                for region in func.regions.iter().filter(|x| !x.count.is_expression()) {
-                    let count = region_ids.get(&region.count).copied().unwrap_or_default();
-                    let result = report
-                        .files
-                        .entry(paths[region.file_id].clone())
-                        .or_default();
-                    result.insert(region.loc.clone(), count as usize);
+        // Now all the counters are evaluated every region can be given a count. Expansion
+        // regions share the counter of the first region in the file they expand so they're
+        // treated like code regions.
+        let name = self.function_name(func);
+        // Function entry only profiles just count the function entry so every region gets
+        // that count
+        let entry_count = base_region_ids
+            .get(&Counter::instrumentation(0))
+            .copied()
+            .unwrap_or_default();
+        let single_byte = self.profile.is_byte_coverage();
+        let mut function = FunctionCoverageRecord {
+            name,
+            filenames: paths.iter().map(|x| x.display().to_string()).collect(),
+            counted_regions: vec![],
+            counted_branch_regions: vec![],
+            execution_count: 0,
+            unresolved_expressions,
+        };
+        for region in &func.regions {
+            if region.file_id >= paths.len() {
+                warn!(
+                    "Region has file id {} outside of the filenames",
+                    region.file_id
+                );
+                continue;
+            }
+            let count = if self.profile.fn_entry_only() && region.kind != RegionKind::Branch {
+                entry_count
+            } else {
+                region_ids.get(&region.count).copied().unwrap_or_default()
+            };
+            let count = count.max(0) as usize;
+            if region.kind == RegionKind::Branch {
+                let false_count = region_ids
+                    .get(&region.false_count)
+                    .copied()
+                    .unwrap_or_default();
+                function.counted_branch_regions.push(CountedRegion {
+                    execution_count: count,
+                    false_execution_count: false_count.max(0) as usize,
+                    folded: region.count.is_zero() && region.false_count.is_zero(),
+                    has_single_byte_coverage: single_byte,
+                    region: region.clone(),
+                });
+            } else {
+                if function.counted_regions.is_empty() {
+                    function.execution_count = count;
                 }
+                function.counted_regions.push(CountedRegion {
+                    execution_count: count,
+                    false_execution_count: 0,
+                    folded: false,
+                    has_single_byte_coverage: single_byte,
+                    region: region.clone(),
+                });
+            }
+        }
+        function
+    }
 
-                let mut pending_exprs = vec![];
+    /// Gets the coverage of a single function without generating a report for every function. If
+    /// the function is in more than one object file the first one with a matching function hash in
+    /// the profile is used.
+    pub fn function_coverage(&self, name: &str) -> Option<FunctionCoverageRecord> {
+        let name_hash = compute_hash(name);
+        self.mapping_info.iter().find_map(|info| {
+            info.cov_fun
+                .iter()
+                .filter(|func| func.header.name_hash == name_hash)
+                .find(|func| self.hash_mismatch(func).is_none())
+                .map(|func| {
+                    let paths = info.get_files_from_id(func.header.filenames_ref);
+                    self.function_record(func, &paths)
+                })
+        })
+    }
 
-                for (expr_index, expr) in func.expressions.iter().enumerate() {
-                    let lhs = region_ids.get(&expr.lhs);
-                    let rhs = region_ids.get(&expr.rhs);
-                    match (lhs, rhs) {
-                        (Some(lhs), Some(rhs)) => {
-                            let count: i64 = match expr.kind {
-                                ExprKind::Subtract => {
-                                    trace!("Subtracting counts: {} - {}", lhs, rhs);
-                                    lhs - rhs
-                                }
-                                ExprKind::Add => {
-                                    trace!("Adding counts: {} + {}", lhs, rhs);
-                                    lhs + rhs
-                                }
-                            };
+    pub fn generate_subreport<P>(&self, mut predicate: P) -> CoverageReport
+    where
+        P: FnMut(&[PathBuf]) -> bool,
+    {
+        // The functions are picked out first so the predicate doesn't have to be thread safe,
+        // each object file is then reported on in parallel and the reports merged in order
+        let selected = self
+            .mapping_info
+            .iter()
+            .map(|info| self.select_functions(info, &mut predicate))
+            .collect::<Vec<_>>();
+        let report = |(functions, hash_mismatches)| self.object_report(functions, hash_mismatches);
+        let merge = |mut report: CoverageReport, other| {
+            report.merge(other);
+            report
+        };
+        // Handing the objects to the thread pool costs more than it saves when there's only one
+        // object or one thread to run them on
+        if selected.len() < 2 || rayon::current_num_threads() < 2 {
+            selected.into_iter().map(report).reduce(merge)
+        } else {
+            selected.into_par_iter().map(report).reduce_with(merge)
+        }
+        .unwrap_or_default()
+    }
 
-                            let counter = Counter {
-                                kind: CounterType::Expression(expr.kind),
-                                id: expr_index as _,
-                            };
+    /// The paths referenced by the coverage maps of all the object files, sorted and without
+    /// duplicates. This doesn't need a report to be generated so is useful to work out the path
+    /// remappings or filename filters to use.
+    pub fn referenced_files(&self) -> Vec<PathBuf> {
+        self.mapping_info
+            .iter()
+            .flat_map(|info| info.all_files())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
 
-                            region_ids.insert(counter, count);
-                            if let Some(expr_region) = func.regions.iter().find(|x| {
-                                x.count.is_expression() && x.count.id == expr_index as u64
-                            }) {
-                                let result = report
-                                    .files
-                                    .entry(paths[expr_region.file_id].clone())
-                                    .or_default();
-                                result.insert(expr_region.loc.clone(), count as _);
-                            }
-                        }
-                        _ => {
-                            let lhs_none = lhs.is_none();
-                            let rhs_none = rhs.is_none();
-                            // These counters have been optimised out, so just add then in as 0
-                            if lhs_none && expr.lhs.is_instrumentation() {
-                                region_ids.insert(expr.lhs, 0);
-                            }
-                            if rhs_none && expr.rhs.is_instrumentation() {
-                                region_ids.insert(expr.rhs, 0);
-                            }
-                            pending_exprs.push((expr_index, expr));
-                            continue;
-                        }
-                    }
-                }
-                let mut index = 0;
-                let mut tries_left = pending_exprs.len() + 1;
-                while !pending_exprs.is_empty() {
-                    assert!(tries_left > 0);
-                    if index >= pending_exprs.len() {
-                        index = 0;
-                        tries_left -= 1;
-                    }
-                    let (expr_index, expr) = pending_exprs[index];
-                    let lhs = region_ids.get(&expr.lhs);
-                    let rhs = region_ids.get(&expr.rhs);
-                    match (lhs, rhs) {
-                        (Some(lhs), Some(rhs)) => {
-                            pending_exprs.remove(index);
-                            let count = match expr.kind {
-                                ExprKind::Subtract => lhs - rhs,
-                                ExprKind::Add => lhs + rhs,
-                            };
+    /// Every counted region, including branch regions, of the functions in the report paired with
+    /// the file it's in. Unlike the report this keeps the region kinds, the false counts of
+    /// branches and which counters are folded.
+    pub fn counted_regions(&self) -> Vec<(PathBuf, CountedRegion)> {
+        self.generate_report()
+            .functions
+            .into_iter()
+            .flat_map(|function| {
+                let FunctionCoverageRecord {
+                    filenames,
+                    counted_regions,
+                    counted_branch_regions,
+                    ..
+                } = function;
+                counted_regions
+                    .into_iter()
+                    .chain(counted_branch_regions)
+                    .map(move |x| (PathBuf::from(&filenames[x.region.file_id]), x))
+            })
+            .collect()
+    }
 
-                            let counter = Counter {
-                                kind: CounterType::Expression(expr.kind),
-                                id: expr_index as _,
-                            };
+    /// The functions in an object file with files matching the predicate paired with their paths,
+    /// and the hash mismatches of those functions which are left out.
+    fn select_functions<'b, P>(
+        &self,
+        info: &'b CoverageMappingInfo,
+        predicate: &mut P,
+    ) -> (Vec<(&'b FunctionRecordV3, Vec<PathBuf>)>, Vec<HashMismatch>)
+    where
+        P: FnMut(&[PathBuf]) -> bool,
+    {
+        let mut functions = vec![];
+        let mut hash_mismatches = vec![];
+        let mut seen = FxHashSet::default();
+        for func in &info.cov_fun {
+            // The paths are checked first so filtered out functions skip the profile lookup
+            let paths = info.get_files_from_id(func.header.filenames_ref);
+            if paths.is_empty() || !predicate(&paths) {
+                continue;
+            }
+            if !seen.insert((func.header.name_hash, func.header.fn_hash)) {
+                debug!("Skipping duplicate record for {}", self.function_name(func));
+                continue;
+            }
+            // Like llvm-cov functions with mismatched hashes are reported and left out
+            if let Some(mismatch) = self.hash_mismatch(func) {
+                warn!(
+                    "{}: hash mismatch, profile {:#x}, coverage mapping {:#x}",
+                    mismatch.name, mismatch.profile_hash, mismatch.mapping_hash
+                );
+                hash_mismatches.push(mismatch);
+                continue;
+            }
+            functions.push((func, paths));
+        }
+        (functions, hash_mismatches)
+    }
 
-                            region_ids.insert(counter, count);
-                            if let Some(expr_region) = func.regions.iter().find(|x| {
-                                x.count.is_expression() && x.count.id == expr_index as u64
-                            }) {
-                                let result = report
-                                    .files
-                                    .entry(paths[expr_region.file_id].clone())
-                                    .or_default();
-                                result.insert(expr_region.loc.clone(), count as _);
-                            }
-                        }
-                        _ => {
-                            index += 1;
-                            continue;
-                        }
-                    }
-                }
+    /// Evaluates the regions of the functions picked out of an object file and creates the report
+    /// for them. Functions shared with other object files are deduplicated when the reports are
+    /// merged.
+    fn object_report(
+        &self,
+        functions: Vec<(&FunctionRecordV3, Vec<PathBuf>)>,
+        hash_mismatches: Vec<HashMismatch>,
+    ) -> CoverageReport {
+        let mut report = CoverageReport {
+            hash_mismatches,
+            ..Default::default()
+        };
+        for (func, paths) in &functions {
+            let function = self.function_record(func, paths);
+            if !function.unresolved_expressions.is_empty() {
+                warn!(
+                    "{}: unable to evaluate expressions {:?}",
+                    function.name, function.unresolved_expressions
+                );
+                report.unresolved_expressions.push(UnresolvedExpressions {
+                    name: function.name.clone(),
+                    expressions: function.unresolved_expressions.clone(),
+                });
             }
+            report.insert_function_counts(&function, |_| true);
+            report.functions.push(function);
         }
         report
     }
 
+    /// Validates the profile like [`InstrumentationProfile::validate`] and checks the number of
+    /// counters in each record matches the profile data embedded in the object files.
+    pub fn validate(&self) -> std::result::Result<(), Vec<ValidationError>> {
+        let mut errors = self.profile.validate().err().unwrap_or_default();
+        let prof_data = self
+            .mapping_info
+            .iter()
+            .filter_map(|info| info.prof_data.as_ref())
+            .flatten();
+        for data in prof_data {
+            let record = match self
+                .profile
+                .find_by_hashes(data.name_md5, data.structural_hash)
+            {
+                Some(record) => record,
+                None => continue,
+            };
+            if record.counts().len() != data.counters_len as usize {
+                let name = match record.name.as_ref() {
+                    Some(name) => name.clone(),
+                    None => self
+                        .profile
+                        .symtab
+                        .get(data.name_md5)
+                        .cloned()
+                        .unwrap_or_else(|| format!("{:#x}", data.name_md5)),
+                };
+                errors.push(ValidationError::CounterMismatch {
+                    name,
+                    counters: record.counts().len(),
+                    expected: data.counters_len as usize,
+                });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The build ids of the object files which aren't in the profile's binary ids, this often
+    /// means the profile came from a different build of the binary. Objects without a build id are
+    /// skipped as are profiles without binary ids. `new` only warns about mismatches so callers
+    /// wanting to be strict can check this is empty.
+    pub fn binary_id_mismatches(&self) -> Vec<&[u8]> {
+        self.mapping_info
+            .iter()
+            .filter(|info| !binary_id_in_profile(&self.profile, info))
+            .filter_map(|info| info.binary_id.as_deref())
+            .collect()
+    }
+
     pub fn generate_report(&self) -> CoverageReport {
         self.generate_subreport(|_| true)
     }
+
+    /// Generates a report for only the given source files. Functions without regions in any of
+    /// the files are skipped, a function with regions in other files (such as from macro
+    /// expansions) is still evaluated in full but only the requested files are kept in the report.
+    pub fn generate_report_for(&self, files: &[PathBuf]) -> CoverageReport {
+        let mut report =
+            self.generate_subreport(|paths| paths.iter().any(|path| files.contains(path)));
+        report.files.retain(|path, _| files.contains(path));
+        report
+    }
+
+    /// Builds the coverage segments for a source file from the counted regions of every function
+    /// with regions in that file. Branch regions don't affect the segments so are ignored.
+    pub fn segments_for_file(&self, path: &Path) -> Vec<CoverageSegment> {
+        self.generate_subreport(|paths| paths.iter().any(|x| x == path))
+            .segments_for_file(path)
+    }
 }
 
 fn parse_coverage_mapping(
     endian: Endianness,
     section: &Section<'_, '_>,
     version: u64,
+    compilation_dir: Option<&Path>,
 ) -> Result<FxHashMap<u64, Vec<PathBuf>>, SectionReadError> {
-    if let Ok(mut data) = section.data() {
-        let mut result = FxHashMap::default();
-        while !data.is_empty() {
-            let data_len = data.len();
-            // Read the number of affixed function records (now just 0 as not in this header)
-            debug_assert_eq!(endian.read_i32_bytes(data[0..4].try_into().unwrap()), 0);
-            let filename_data_len = endian.read_i32_bytes(data[4..8].try_into().unwrap());
-            // Read the length of the affixed string that contains encoded coverage mapping data (now 0
-            // as not in this header)
-            debug_assert_eq!(endian.read_i32_bytes(data[8..12].try_into().unwrap()), 0);
-            let _format_version = endian.read_i32_bytes(data[12..16].try_into().unwrap());
-
-            let hash = md5::compute(&data[16..(filename_data_len as usize + 16)]);
-            let hash = endian.read_u64_bytes(hash.0[..8].try_into().unwrap());
-
-            //let bytes = &data[16..(16 + filename_data_len as usize)];
-            let bytes = &data[16..];
-            let (bytes, file_strings) = parse_path_list(bytes, version)
-                .map_err(|_: nom::Err<NomError<_>>| SectionReadError::InvalidPathList)?;
-            result.insert(hash, file_strings);
-            let read_len = data_len - bytes.len();
-            let padding = if !bytes.is_empty() && (read_len & 0x07) != 0 {
-                8 - (read_len & 0x07)
-            } else {
-                0
-            };
-            if padding > bytes.len() {
-                break;
-            }
-            data = &bytes[padding..];
-        }
-        Ok(result)
+    if let Ok(data) = section.data() {
+        parse_coverage_mapping_data(endian, data, version, compilation_dir)
     } else {
         Err(SectionReadError::EmptySection(LlvmSection::CoverageMap))
     }
 }
 
+/// Size of the header at the start of each coverage mapping record
+const COVERAGE_MAPPING_HEADER_LEN: usize = 16;
+
+fn parse_coverage_mapping_data(
+    endian: Endianness,
+    mut data: &[u8],
+    version: u64,
+    compilation_dir: Option<&Path>,
+) -> Result<FxHashMap<u64, Vec<PathBuf>>, SectionReadError> {
+    let mut result = FxHashMap::default();
+    while !data.is_empty() {
+        let data_len = data.len();
+        if data_len < COVERAGE_MAPPING_HEADER_LEN {
+            return Err(SectionReadError::TruncatedSection(LlvmSection::CoverageMap));
+        }
+        // Read the number of affixed function records (now just 0 as not in this header)
+        debug_assert_eq!(endian.read_i32_bytes(data[0..4].try_into().unwrap()), 0);
+        let filename_data_len = endian.read_u32_bytes(data[4..8].try_into().unwrap()) as usize;
+        // Read the length of the affixed string that contains encoded coverage mapping data (now 0
+        // as not in this header)
+        debug_assert_eq!(endian.read_i32_bytes(data[8..12].try_into().unwrap()), 0);
+        let _format_version = endian.read_i32_bytes(data[12..16].try_into().unwrap());
+
+        let filename_data = data[COVERAGE_MAPPING_HEADER_LEN..]
+            .get(..filename_data_len)
+            .ok_or(SectionReadError::TruncatedSection(LlvmSection::CoverageMap))?;
+        let hash = md5::compute(filename_data);
+        let hash = endian.read_u64_bytes(hash.0[..8].try_into().unwrap());
+
+        let bytes = &data[COVERAGE_MAPPING_HEADER_LEN..];
+        let (bytes, file_strings) =
+            parse_path_list_with_compilation_dir(bytes, version, compilation_dir)
+                .map_err(|_: nom::Err<NomError<_>>| SectionReadError::InvalidPathList)?;
+        result.insert(hash, file_strings);
+        let read_len = data_len - bytes.len();
+        let padding = if !bytes.is_empty() && (read_len & 0x07) != 0 {
+            8 - (read_len & 0x07)
+        } else {
+            0
+        };
+        if padding > bytes.len() {
+            break;
+        }
+        data = &bytes[padding..];
+    }
+    Ok(result)
+}
+
+/// Size of the fixed length header at the start of each function record: the name hash, data
+/// length, function hash and filenames reference
+const FUNCTION_RECORD_HEADER_LEN: usize = 28;
+
 fn parse_coverage_functions(
     endian: Endianness,
     section: &Section<'_, '_>,
 ) -> Result<Vec<FunctionRecordV3>, SectionReadError> {
     debug!("Parsing coverage functions");
-    if let Ok(original_data) = section.data() {
-        let mut bytes = original_data;
-        let mut res = vec![];
-        let section_len = bytes.len();
-        while !bytes.is_empty() {
-            let name_hash = endian.read_u64_bytes(bytes[0..8].try_into().unwrap());
-            let data_len = endian.read_u32_bytes(bytes[8..12].try_into().unwrap());
-            let fn_hash = endian.read_u64_bytes(bytes[12..20].try_into().unwrap());
-            let filenames_ref = endian.read_u64_bytes(bytes[20..28].try_into().unwrap());
-            let header = FunctionRecordHeader {
-                name_hash,
-                data_len,
-                fn_hash,
-                filenames_ref,
-            };
-            let _start_len = bytes[28..].len();
-            bytes = &bytes[28..];
+    if let Ok(data) = section.data() {
+        parse_function_records(endian, data)
+    } else {
+        error!("Can't read data for coverage function section");
+        Err(SectionReadError::EmptySection(
+            LlvmSection::CoverageFunctions,
+        ))
+    }
+}
+
+fn parse_function_records(
+    endian: Endianness,
+    data: &[u8],
+) -> Result<Vec<FunctionRecordV3>, SectionReadError> {
+    let mut bytes = data;
+    let mut res = vec![];
+    let section_len = bytes.len();
+    let malformed = |err: nom::Err<VerboseError<&[u8]>>| {
+        let offset = match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e
+                .errors
+                .first()
+                .map(|(input, _)| section_len - input.len())
+                .unwrap_or_default(),
+            nom::Err::Incomplete(_) => section_len,
+        };
+        SectionReadError::MalformedCoverageFunction(offset)
+    };
+    while !bytes.is_empty() {
+        if bytes.len() < FUNCTION_RECORD_HEADER_LEN {
+            return Err(SectionReadError::MalformedCoverageFunction(
+                section_len - bytes.len(),
+            ));
+        }
+        let name_hash = endian.read_u64_bytes(bytes[0..8].try_into().unwrap());
+        let data_len = endian.read_u32_bytes(bytes[8..12].try_into().unwrap());
+        let fn_hash = endian.read_u64_bytes(bytes[12..20].try_into().unwrap());
+        let filenames_ref = endian.read_u64_bytes(bytes[20..28].try_into().unwrap());
+        let header = FunctionRecordHeader {
+            name_hash,
+            data_len,
+            fn_hash,
+            filenames_ref,
+        };
+        let record = bytes;
+        bytes = &bytes[FUNCTION_RECORD_HEADER_LEN..];
 
-            let (data, id_len) = parse_leb128::<NomError<_>>(bytes).unwrap();
+        let (data, id_len) = parse_leb128::<VerboseError<_>>(bytes).map_err(malformed)?;
+        bytes = data;
+        let mut filename_indices = vec![];
+        for _ in 0..id_len {
+            let (data, id) = parse_leb128::<VerboseError<_>>(bytes).map_err(malformed)?;
+            filename_indices.push(id);
             bytes = data;
-            let mut filename_indices = vec![];
-            for _ in 0..id_len {
-                let (data, id) = parse_leb128::<NomError<_>>(bytes).unwrap(); // Issue
-                filename_indices.push(id);
-                bytes = data;
-            }
+        }
 
-            let (data, expr_len) = parse_leb128::<NomError<_>>(bytes).unwrap();
-            let expr_len = expr_len as usize;
+        let (data, expr_len) = parse_leb128::<VerboseError<_>>(bytes).map_err(malformed)?;
+        let expr_len = expr_len as usize;
+        bytes = data;
+        // The length comes from the file so the vector is grown as expressions are read
+        // instead of trusting it for the allocation
+        let mut exprs = vec![];
+        for i in 0..expr_len {
+            let (data, lhs) = parse_leb128::<VerboseError<_>>(bytes).map_err(malformed)?;
+            let (data, rhs) = parse_leb128::<VerboseError<_>>(data).map_err(malformed)?;
+            if exprs.len() <= i {
+                exprs.resize(i + 1, Expression::default());
+            }
+            let lhs = parse_counter(lhs, &mut exprs);
+            let rhs = parse_counter(rhs, &mut exprs);
+            exprs[i].lhs = lhs;
+            exprs[i].rhs = rhs;
             bytes = data;
-            let mut exprs = vec![Expression::default(); expr_len];
-            for i in 0..expr_len {
-                let (data, lhs) = parse_leb128::<NomError<_>>(bytes).unwrap();
-                let (data, rhs) = parse_leb128::<NomError<_>>(data).unwrap();
-                let lhs = parse_counter(lhs, &mut exprs);
-                let rhs = parse_counter(rhs, &mut exprs);
-                exprs[i].lhs = lhs;
-                exprs[i].rhs = rhs;
+        }
+        if exprs.len() < expr_len {
+            exprs.resize(expr_len, Expression::default());
+        }
+
+        let record_len = FUNCTION_RECORD_HEADER_LEN + data_len as usize;
+        match parse_mapping_regions(bytes, &filename_indices, &mut exprs) {
+            Ok((data, regions)) => {
+                res.push(FunctionRecordV3 {
+                    header,
+                    regions,
+                    expressions: exprs,
+                });
                 bytes = data;
             }
+            // The data length in the header lets a function with invalid regions be skipped
+            // without losing the rest of the section
+            Err(nom::Err::Failure(e)) if record.len() >= record_len => {
+                let reason = e
+                    .errors
+                    .iter()
+                    .find_map(|(_, kind)| match kind {
+                        VerboseErrorKind::Context(context) => Some(*context),
+                        _ => None,
+                    })
+                    .unwrap_or("invalid regions");
+                warn!(
+                    "Skipping malformed coverage function {:x}: {}",
+                    name_hash, reason
+                );
+                bytes = &record[record_len..];
+            }
+            Err(e) => return Err(malformed(e)),
+        }
+        let function_len = section_len - bytes.len(); // this should match header
 
-            let (data, regions) =
-                parse_mapping_regions(bytes, &filename_indices, &mut exprs).unwrap();
-
-            res.push(FunctionRecordV3 {
-                header,
-                regions,
-                expressions: exprs,
-            });
-
-            // Todo set couners for expansion regions - counter of expansion region is the counter
-            // of the first region from the expanded file. This requires multiple passes to
-            // correctly propagate across all nested regions. N.B. I haven't seen any expansion
-            // regions in use so may not be an issue!
-
-            bytes = data;
-            let function_len = section_len - bytes.len(); // this should match header
-
-            let padding = if function_len < section_len && (function_len & 0x07) != 0 {
-                8 - (function_len & 0x07)
-            } else {
-                0
-            };
+        let padding = if function_len < section_len && (function_len & 0x07) != 0 {
+            8 - (function_len & 0x07)
+        } else {
+            0
+        };
 
-            if padding > bytes.len() {
-                break;
-            }
-            // Now apply padding, and if hash is 0 move on as it's a dummy otherwise add to result
-            // And decide what end type will be
-            bytes = &bytes[padding..];
+        if padding > bytes.len() {
+            break;
         }
-        Ok(res)
-    } else {
-        error!("Can't read data for coverage function section");
-        Err(SectionReadError::EmptySection(
-            LlvmSection::CoverageFunctions,
-        ))
+        // Now apply padding, and if hash is 0 move on as it's a dummy otherwise add to result
+        // And decide what end type will be
+        bytes = &bytes[padding..];
     }
+    Ok(res)
+}
+
+fn region_failure<'a>(input: &'a [u8], context: &'static str) -> nom::Err<VerboseError<&'a [u8]>> {
+    let error = VerboseError::from_error_kind(input, ErrorKind::Satisfy);
+    nom::Err::Failure(VerboseError::add_context(input, context, error))
 }
 
+/// Set in the end column of a code region to mark it as a gap region
+const GAP_REGION_BIT: u64 = 1 << 31;
+
 /// This code is ported from `RawCoverageMappingReader::readMappingRegionsSubArray`
 fn parse_mapping_regions<'a>(
     mut bytes: &'a [u8],
     file_indices: &[u64],
     expressions: &mut Vec<Expression>,
-) -> IResult<&'a [u8], Vec<CounterMappingRegion>> {
+) -> IResult<&'a [u8], Vec<CounterMappingRegion>, VerboseError<&'a [u8]>> {
     let mut mapping = vec![];
-    for i in file_indices {
+    // The index of the file in `file_indices` for each region, this is the ID expansion regions
+    // use to refer to the file they expand
+    let mut virtual_file_ids = vec![];
+    for (virtual_file_id, i) in file_indices.iter().enumerate() {
         let (data, regions_len) = parse_leb128(bytes)?;
         bytes = data;
         let mut last_line = 0;
@@ -424,12 +1062,13 @@ fn parse_mapping_regions<'a>(
                     kind = RegionKind::Expansion;
                     expanded_file_id = raw_header >> Counter::ENCODING_TAG_AND_EXP_REGION_BITS;
                     if expanded_file_id >= file_indices.len() as u64 {
-                        todo!()
+                        return Err(region_failure(bytes, "expanded file id is invalid"));
                     }
                 } else {
                     let shifted_counter = raw_header >> Counter::ENCODING_TAG_AND_EXP_REGION_BITS;
                     match shifted_counter.try_into() {
-                        Ok(RegionKind::Code) | Ok(RegionKind::Skipped) => {}
+                        Ok(RegionKind::Code) => {}
+                        Ok(RegionKind::Skipped) => kind = RegionKind::Skipped,
                         Ok(RegionKind::Branch) => {
                             kind = RegionKind::Branch;
                             let (data, c1) = parse_leb128(bytes)?;
@@ -439,7 +1078,7 @@ fn parse_mapping_regions<'a>(
                             false_count = parse_counter(c2, expressions);
                             bytes = data;
                         }
-                        e => panic!("Malformed: {:?}", e),
+                        _ => return Err(region_failure(bytes, "region kind is incorrect")),
                     }
                 }
             }
@@ -447,11 +1086,17 @@ fn parse_mapping_regions<'a>(
             let (data, delta_line) = parse_leb128(bytes)?;
             let (data, column_start) = parse_leb128(data)?;
             let (data, lines_len) = parse_leb128(data)?;
-            let (data, column_end) = parse_leb128(data)?;
+            let (data, mut column_end) = parse_leb128(data)?;
             bytes = data;
 
+            // Gap regions are code regions with the top bit of the 32 bit end column set
+            if kind == RegionKind::Code && column_end & GAP_REGION_BIT != 0 {
+                kind = RegionKind::Gap;
+                column_end &= !GAP_REGION_BIT;
+            }
+
             let (column_start, column_end) = if column_start == 0 && column_end == 0 {
-                (1usize, usize::MAX)
+                (1usize, WHOLE_LINE_COLUMN)
             } else {
                 (column_start as usize, column_end as usize)
             };
@@ -461,6 +1106,7 @@ fn parse_mapping_regions<'a>(
             last_line = line_start;
 
             // Add region working-out-stuff
+            virtual_file_ids.push(virtual_file_id);
             mapping.push(CounterMappingRegion {
                 kind,
                 count: counter,
@@ -476,50 +1122,161 @@ fn parse_mapping_regions<'a>(
             });
         }
     }
+    propagate_expansion_counters(&mut mapping, &virtual_file_ids, file_indices.len());
     Ok((bytes, mapping))
 }
 
+/// The counter of an expansion region is the counter of the first region from the file it expands.
+/// Expansions can be nested so this takes multiple passes to propagate the counters through to the
+/// outermost expansion. The equivalent llvm code is in `RawCoverageMappingReader::read`.
+fn propagate_expansion_counters(
+    regions: &mut [CounterMappingRegion],
+    virtual_file_ids: &[usize],
+    num_files: usize,
+) {
+    for _ in 1..num_files {
+        let mut expansions = vec![None; num_files];
+        for (index, region) in regions.iter().enumerate() {
+            if region.kind == RegionKind::Expansion && region.expanded_file_id < num_files {
+                expansions[region.expanded_file_id] = Some(index);
+            }
+        }
+        for (index, file_id) in virtual_file_ids.iter().enumerate() {
+            if let Some(expansion) = expansions[*file_id].take() {
+                regions[expansion].count = regions[index].count;
+            }
+        }
+    }
+}
+
+/// Layout of the records in the profile data section, this depends on the llvm version and the
+/// pointer width of the target
+#[derive(Clone, Copy, Debug)]
+struct ProfileDataLayout {
+    pointer_size: usize,
+    /// From raw profile version 9 the records have a bitmap pointer and number of bitmap bytes
+    has_bitmap: bool,
+    /// From raw profile version 8 the counter pointer is relative to the record
+    relative_counters: bool,
+}
+
+impl ProfileDataLayout {
+    /// Size of a record including the padding to an 8 byte boundary
+    fn len(&self) -> usize {
+        let pointers = if self.has_bitmap { 4 } else { 3 };
+        let bitmap_bytes = if self.has_bitmap { 4 } else { 0 };
+        let len = 16 + pointers * self.pointer_size + 8 + bitmap_bytes;
+        len + get_num_padding_bytes(len as u64) as usize
+    }
+
+    fn read_pointer(&self, endian: Endianness, bytes: &[u8]) -> i64 {
+        if self.pointer_size == 8 {
+            endian.read_i64_bytes(bytes[..8].try_into().unwrap())
+        } else {
+            endian.read_i32_bytes(bytes[..4].try_into().unwrap()) as i64
+        }
+    }
+
+    /// Parses the records returning `None` if the section isn't a whole number of records or any
+    /// of the records have counters outside of the counters section
+    // `is_multiple_of` isn't available on our MSRV
+    #[allow(clippy::manual_is_multiple_of)]
+    fn parse(
+        &self,
+        endian: Endianness,
+        data: &[u8],
+        data_address: u64,
+        counters_address: u64,
+        counters_size: u64,
+    ) -> Option<Vec<ProfileData>> {
+        let len = self.len();
+        if data.is_empty() || data.len() % len != 0 {
+            return None;
+        }
+        let mut res = vec![];
+        for (index, record) in data.chunks(len).enumerate() {
+            let name_md5 = endian.read_u64_bytes(record[..8].try_into().unwrap());
+            let structural_hash = endian.read_u64_bytes(record[8..16].try_into().unwrap());
+            let counter_ptr = self.read_pointer(endian, &record[16..]);
+            let pointers = if self.has_bitmap { 4 } else { 3 };
+            let counters_len_offset = 16 + pointers * self.pointer_size;
+            let counters_len = endian.read_u32_bytes(
+                record[counters_len_offset..(counters_len_offset + 4)]
+                    .try_into()
+                    .unwrap(),
+            );
+            let counters_start = if self.relative_counters {
+                (data_address + (index * len) as u64).wrapping_add(counter_ptr as u64)
+            } else {
+                counter_ptr as u64
+            };
+            let counters_offset = counters_start.wrapping_sub(counters_address);
+            let end = counters_offset.checked_add(counters_len as u64 * 8)?;
+            if counters_len == 0 || end > counters_size {
+                return None;
+            }
+            res.push(ProfileData {
+                name_md5,
+                structural_hash,
+                counters_len,
+                counters_offset,
+            });
+        }
+        Some(res)
+    }
+}
+
+/// Parses the profile data section. The layout of the records isn't stored in the object file so
+/// each layout from the newest to the oldest is tried until one has every record's counters in
+/// the counters section.
 fn parse_profile_data(
     endian: Endianness,
     section: &Section<'_, '_>,
+    counters: &Section<'_, '_>,
+    pointer_size: usize,
 ) -> Result<Vec<ProfileData>, SectionReadError> {
-    if let Ok(data) = section.data() {
-        let mut bytes = data;
-        let mut res = vec![];
-        while !bytes.is_empty() {
-            // bytes.len() >= 24 {
-            let name_md5 = endian.read_u64_bytes(bytes[..8].try_into().unwrap());
-            let structural_hash = endian.read_u64_bytes(bytes[8..16].try_into().unwrap());
-
-            let _counter_ptr = endian.read_u64_bytes(bytes[16..24].try_into().unwrap());
-            let counters_location = 24 + 16;
-            if bytes.len() <= counters_location {
-                bytes = &bytes[counters_location..];
-                let counters_len = endian.read_u32_bytes(bytes[..4].try_into().unwrap());
-                // TODO Might need to get the counter offset and get the list of counters from this?
-                // And potentially check against the maximum number of counters just to make sure that
-                // it's not being exceeded?
-                //
-                // Also counters_len >= 1 so this should be checked to make sure it's not malformed
-
-                bytes = &bytes[8..];
-
-                res.push(ProfileData {
-                    name_md5,
-                    structural_hash,
-                    counters_len,
-                });
-            } else {
-                bytes = &[];
+    let data = match section.data() {
+        Ok(data) if !data.is_empty() => data,
+        _ => return Err(SectionReadError::EmptySection(LlvmSection::ProfileData)),
+    };
+    let layouts = [(true, true), (false, true), (false, false)];
+    layouts
+        .iter()
+        .map(|(has_bitmap, relative_counters)| ProfileDataLayout {
+            pointer_size,
+            has_bitmap: *has_bitmap,
+            relative_counters: *relative_counters,
+        })
+        .find_map(|layout| {
+            let res = layout.parse(
+                endian,
+                data,
+                section.address(),
+                counters.address(),
+                counters.size(),
+            );
+            if res.is_some() {
+                debug!("Profile data layout: {:?}", layout);
             }
-        }
-        if !bytes.is_empty() {
-            warn!("{} bytes left in profile data", bytes.len());
-        }
-        Ok(res)
-    } else {
-        Err(SectionReadError::EmptySection(LlvmSection::ProfileData))
+            res
+        })
+        .ok_or(SectionReadError::InvalidProfileData)
+}
+
+/// Parses the function names from the profile names section, this is a list of optionally
+/// compressed strings each containing names separated by `\u{1}`
+fn parse_profile_names(section: &Section<'_, '_>) -> Result<Vec<String>, SectionReadError> {
+    let mut bytes = section
+        .data()
+        .map_err(|_| SectionReadError::EmptySection(LlvmSection::ProfileNames))?;
+    let mut names = vec![];
+    while !bytes.is_empty() && bytes.iter().any(|x| *x != 0) {
+        let (remaining, string) = parse_string_ref::<VerboseError<&[u8]>>(bytes)
+            .map_err(|_| SectionReadError::TruncatedSection(LlvmSection::ProfileNames))?;
+        names.extend(string.split('\u{1}').map(|x| x.to_string()));
+        bytes = remaining;
     }
+    Ok(names)
 }
 
 fn parse_profile_counters(
@@ -545,7 +1302,7 @@ fn parse_profile_counters(
 /// repopulating for every function record.
 fn parse_counter(input: u64, exprs: &mut Vec<Expression>) -> Counter {
     let ty = (Counter::ENCODING_TAG_MASK & input) as u8;
-    let id = input >> 2; // For zero we don't actually care about this but we'll still do it
+    let id = input >> Counter::ENCODING_TAG_BITS; // For zero we don't actually care about this but we'll still do it
     let kind = match ty {
         0 => CounterType::Zero,
         1 => CounterType::ProfileInstrumentation,
@@ -571,3 +1328,599 @@ fn parse_counter(input: u64, exprs: &mut Vec<Expression>) -> Counter {
     };
     Counter { kind, id }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(
+        kind: RegionKind,
+        count: Counter,
+        file_id: usize,
+        expanded_file_id: usize,
+    ) -> CounterMappingRegion {
+        CounterMappingRegion {
+            kind,
+            count,
+            false_count: Counter::default(),
+            file_id,
+            expanded_file_id,
+            loc: SourceLocation {
+                line_start: 1,
+                column_start: 1,
+                line_end: 1,
+                column_end: 10,
+            },
+        }
+    }
+
+    /// A function record for the function `name` which uses the files with reference 1
+    fn function_record(
+        name: &str,
+        fn_hash: u64,
+        regions: Vec<CounterMappingRegion>,
+        expressions: Vec<Expression>,
+    ) -> FunctionRecordV3 {
+        FunctionRecordV3 {
+            header: FunctionRecordHeader {
+                name_hash: compute_hash(name),
+                data_len: 0,
+                fn_hash,
+                filenames_ref: 1,
+            },
+            regions,
+            expressions,
+        }
+    }
+
+    /// Mapping info for an object with a single list of files, given the reference 1, and no
+    /// profile sections
+    fn mapping_info(files: Vec<PathBuf>, cov_fun: Vec<FunctionRecordV3>) -> CoverageMappingInfo {
+        let mut cov_map = FxHashMap::default();
+        cov_map.insert(1, files);
+        CoverageMappingInfo {
+            cov_map,
+            cov_fun,
+            prof_counts: None,
+            prof_data: None,
+            prof_names: vec![],
+            binary_id: None,
+            compilation_dirs: FxHashMap::default(),
+        }
+    }
+
+    #[test]
+    fn nested_expansion_counters() {
+        let zero = Counter::default();
+        let mut regions = vec![
+            // File 0 contains an expansion of file 1 and one of a file not in the filenames
+            region(RegionKind::Code, Counter::instrumentation(0), 0, 0),
+            region(RegionKind::Expansion, zero, 0, 1),
+            region(RegionKind::Expansion, zero, 0, 7),
+            // File 1 starts with an expansion of file 2
+            region(RegionKind::Expansion, zero, 1, 2),
+            region(RegionKind::Code, Counter::instrumentation(1), 1, 0),
+            // File 2 is the same source file as file 1, i.e. a macro defined and used in one file
+            region(RegionKind::Code, Counter::instrumentation(2), 1, 0),
+        ];
+        let virtual_file_ids = [0, 0, 0, 1, 1, 2];
+
+        propagate_expansion_counters(&mut regions, &virtual_file_ids, 3);
+
+        assert_eq!(regions[1].count, Counter::instrumentation(2));
+        assert_eq!(regions[2].count, zero);
+        assert_eq!(regions[3].count, Counter::instrumentation(2));
+    }
+
+    fn section_data(name: &str) -> (Endianness, Vec<u8>) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/branches/branches_bin");
+        let binary_data = fs::read(path).unwrap();
+        let object_file = object::File::parse(&*binary_data).unwrap();
+        let section = object_file.section_by_name(name).unwrap();
+        (object_file.endianness(), section.data().unwrap().to_vec())
+    }
+
+    #[test]
+    fn skip_malformed_functions() {
+        let (endian, data) = section_data("__llvm_covfun");
+        assert!(endian.is_little_endian());
+        // One file, no expressions and a single region, the region header has a zero counter with
+        // an invalid region kind and then an expansion of a file that doesn't exist
+        for raw_header in &[5 << 3, (5 << 3) | 4] {
+            let region = [1, 0, 0, 1, *raw_header, 1, 1, 0, 2];
+            let mut section = vec![];
+            section.extend_from_slice(&0x1234u64.to_le_bytes());
+            section.extend_from_slice(&(region.len() as u32).to_le_bytes());
+            section.extend_from_slice(&0u64.to_le_bytes());
+            section.extend_from_slice(&0u64.to_le_bytes());
+            section.extend_from_slice(&region);
+            section.resize(40, 0);
+            section.extend_from_slice(&data);
+
+            let records = parse_function_records(endian, &section).unwrap();
+            assert_eq!(records, parse_function_records(endian, &data).unwrap());
+        }
+    }
+
+    #[test]
+    fn gap_region_encoding() {
+        // A code region for counter 0 on line 3 and a gap region with the top bit of the end
+        // column set
+        let mut bytes = vec![2, 1, 3, 9, 0, 17];
+        for value in &[1, 0, 17, 2, GAP_REGION_BIT | 5] {
+            leb128::write::unsigned(&mut bytes, *value).unwrap();
+        }
+        let mut expressions = vec![];
+        let (_, regions) = parse_mapping_regions(&bytes, &[0], &mut expressions).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].kind, RegionKind::Code);
+        assert_eq!(regions[1].kind, RegionKind::Gap);
+        assert_eq!(regions[1].count, Counter::instrumentation(0));
+        assert_eq!(
+            regions[1].loc,
+            SourceLocation {
+                line_start: 3,
+                column_start: 17,
+                line_end: 5,
+                column_end: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn explicit_compilation_dir() {
+        // An empty compilation directory, like one from a remapped path prefix, then a relative
+        // file, an absolute file outside the directory and another relative file
+        let mut bytes = vec![];
+        leb128::write::unsigned(&mut bytes, 4).unwrap();
+        leb128::write::unsigned(&mut bytes, 0).unwrap();
+        leb128::write::unsigned(&mut bytes, 0).unwrap();
+        for path in [
+            "",
+            "main.rs",
+            "/rustc/library/std/src/thread/local.rs",
+            "lib.rs",
+        ] {
+            leb128::write::unsigned(&mut bytes, path.len() as u64).unwrap();
+            bytes.extend_from_slice(path.as_bytes());
+        }
+        let (_, paths) = parse_path_list::<NomError<_>>(&bytes, 8).unwrap();
+        let expected = paths.clone();
+
+        let mut info = mapping_info(paths, vec![]);
+        // Guessing the first absolute path is the directory gets the last file wrong
+        assert_eq!(
+            info.get_files_from_id(1)[3],
+            PathBuf::from("/rustc/library/std/src/thread/local.rs/lib.rs")
+        );
+
+        info.compilation_dirs.insert(1, PathBuf::new());
+        assert_eq!(info.get_files_from_id(1), expected);
+        assert_eq!(
+            info.all_files(),
+            vec![
+                PathBuf::from("/rustc/library/std/src/thread/local.rs"),
+                PathBuf::from("lib.rs"),
+                PathBuf::from("main.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn whole_line_regions() {
+        // A code region on line 2 with a start and end column of 0 covers the whole line
+        let bytes = vec![1, 1, 2, 0, 0, 0];
+        let mut expressions = vec![];
+        let (_, regions) = parse_mapping_regions(&bytes, &[0], &mut expressions).unwrap();
+        assert_eq!(
+            regions[0].loc,
+            SourceLocation {
+                line_start: 2,
+                column_start: 1,
+                line_end: 2,
+                column_end: WHOLE_LINE_COLUMN,
+            }
+        );
+
+        let name = "whole_line";
+        let fn_hash = 0x1234;
+        let mut profile = InstrumentationProfile::new(Some(8), false, false, false);
+        profile.push_record(NamedInstrProfRecord {
+            name: Some(name.to_string()),
+            name_hash: Some(compute_hash(name)),
+            hash: Some(fn_hash),
+            record: InstrProfRecord {
+                counts: vec![3],
+                data: None,
+            },
+        });
+        let info = mapping_info(
+            vec![PathBuf::from("/src/main.rs")],
+            vec![function_record(name, fn_hash, regions, expressions)],
+        );
+        let mapping = CoverageMapping {
+            profile: Cow::Borrowed(&profile),
+            mapping_info: vec![info],
+        };
+        let report = mapping.generate_report();
+        let file = &report.files[&PathBuf::from("/src/main.rs")];
+        assert_eq!(file.hits_for_line(2), Some(3));
+        assert_eq!(file.hits_for_position(2, 1000), Some(3));
+
+        // Exported like llvm-cov with the largest 32 bit column
+        let mut json = vec![];
+        report.to_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let file = &json["data"][0]["files"][0];
+        assert_eq!(file["segments"][1][1], WHOLE_LINE_COLUMN);
+        assert_eq!(
+            json["data"][0]["functions"][0]["regions"][0][3],
+            4294967295u64
+        );
+    }
+
+    #[test]
+    fn skipped_region_encoding() {
+        // A code region for counter 0 and a skipped region inside it, skipped regions have a zero
+        // counter with the region kind in the remaining bits
+        let skipped = (RegionKind::Skipped as u64) << Counter::ENCODING_TAG_AND_EXP_REGION_BITS;
+        let mut bytes = vec![2, 1, 1, 1, 9, 2];
+        for value in &[skipped, 3, 1, 2, 7] {
+            leb128::write::unsigned(&mut bytes, *value).unwrap();
+        }
+        let mut expressions = vec![];
+        let (_, regions) = parse_mapping_regions(&bytes, &[0], &mut expressions).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].kind, RegionKind::Code);
+        assert_eq!(regions[1].kind, RegionKind::Skipped);
+        assert!(regions[1].count.is_zero());
+        assert_eq!(
+            regions[1].loc,
+            SourceLocation {
+                line_start: 4,
+                column_start: 1,
+                line_end: 6,
+                column_end: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_coverage_mapping() {
+        let (endian, data) = section_data("__llvm_covmap");
+        let version = 8;
+        let files = parse_coverage_mapping_data(endian, &data, version, None).unwrap();
+        assert_eq!(files.len(), 1);
+
+        assert_eq!(
+            parse_coverage_mapping_data(endian, &data[..12], version, None).unwrap_err(),
+            SectionReadError::TruncatedSection(LlvmSection::CoverageMap)
+        );
+        assert_eq!(
+            parse_coverage_mapping_data(endian, &data[..20], version, None).unwrap_err(),
+            SectionReadError::TruncatedSection(LlvmSection::CoverageMap)
+        );
+        for len in 1..data.len() {
+            let _ = parse_coverage_mapping_data(endian, &data[..len], version, None);
+        }
+    }
+
+    #[test]
+    fn truncated_coverage_functions() {
+        let (endian, data) = section_data("__llvm_covfun");
+        let records = parse_function_records(endian, &data).unwrap();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(
+            parse_function_records(endian, &data[..10]).unwrap_err(),
+            SectionReadError::MalformedCoverageFunction(0)
+        );
+        // Truncating in the middle of a record is an error instead of a panic
+        for len in 1..data.len() {
+            match parse_function_records(endian, &data[..len]) {
+                Ok(records) => assert!(records.len() < 2),
+                Err(SectionReadError::MalformedCoverageFunction(offset)) => assert!(offset <= len),
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn expression_evaluation_order() {
+        let expression = |kind, id| Counter {
+            kind: CounterType::Expression(kind),
+            id,
+        };
+        let expr = |kind, lhs, rhs| Expression { kind, lhs, rhs };
+        let add = ExprKind::Add;
+        let sub = ExprKind::Subtract;
+        let expressions = vec![
+            // Refers to expressions after it so can't be evaluated in order
+            expr(sub, expression(add, 1), Counter::instrumentation(1)),
+            expr(add, expression(add, 2), Counter::instrumentation(1)),
+            // Counter 2 has been optimised out so counts as 0
+            expr(
+                add,
+                Counter::instrumentation(0),
+                Counter::instrumentation(2),
+            ),
+            // A cycle and an expression depending on it
+            expr(add, expression(sub, 4), Counter::instrumentation(0)),
+            expr(sub, expression(add, 3), Counter::default()),
+            expr(add, expression(add, 3), Counter::instrumentation(0)),
+            // Refers to an expression that doesn't exist
+            expr(add, expression(add, 10), Counter::instrumentation(0)),
+        ];
+        let mut region_ids = FxHashMap::default();
+        region_ids.insert(Counter::instrumentation(0), 5);
+        region_ids.insert(Counter::instrumentation(1), 2);
+
+        let unresolved = evaluate_expressions(&expressions, &mut region_ids);
+        assert_eq!(unresolved, vec![3, 4, 5, 6]);
+        assert_eq!(region_ids.get(&expression(sub, 0)), Some(&5));
+        assert_eq!(region_ids.get(&expression(add, 1)), Some(&7));
+        assert_eq!(region_ids.get(&expression(add, 2)), Some(&5));
+        for (index, expr) in expressions.iter().enumerate().skip(3) {
+            let counter = expression(expr.kind, index as u64);
+            assert!(!region_ids.contains_key(&counter));
+        }
+    }
+
+    #[test]
+    fn report_with_unresolved_expressions() {
+        let name = "cyclic";
+        let fn_hash = 0x1234;
+        let mut profile = InstrumentationProfile::new(Some(8), false, false, false);
+        profile.push_record(NamedInstrProfRecord {
+            name: Some(name.to_string()),
+            name_hash: Some(compute_hash(name)),
+            hash: Some(fn_hash),
+            record: InstrProfRecord {
+                counts: vec![4],
+                data: None,
+            },
+        });
+        profile
+            .symtab
+            .names
+            .insert(compute_hash(name), name.to_string());
+        let cyclic = Counter {
+            kind: CounterType::Expression(ExprKind::Add),
+            id: 0,
+        };
+        let mut regions = vec![
+            region(RegionKind::Code, Counter::instrumentation(0), 0, 0),
+            region(RegionKind::Code, cyclic, 0, 0),
+        ];
+        regions[1].loc.line_start = 2;
+        regions[1].loc.line_end = 2;
+        let expressions = vec![Expression {
+            kind: ExprKind::Add,
+            lhs: cyclic,
+            rhs: Counter::instrumentation(0),
+        }];
+        let info = mapping_info(
+            vec![PathBuf::from("/src/main.rs")],
+            vec![function_record(name, fn_hash, regions, expressions)],
+        );
+        let mapping = CoverageMapping {
+            profile: Cow::Borrowed(&profile),
+            mapping_info: vec![info],
+        };
+
+        let report = mapping.generate_report();
+        assert_eq!(
+            report.unresolved_expressions,
+            vec![UnresolvedExpressions {
+                name: name.to_string(),
+                expressions: vec![0],
+            }]
+        );
+        let counts = &report.functions[0].counted_regions;
+        assert_eq!(counts[0].execution_count, 4);
+        assert_eq!(counts[1].execution_count, 0);
+        let file = &report.files[&PathBuf::from("/src/main.rs")];
+        assert_eq!(file.hits_for_line(1), Some(4));
+        assert_eq!(file.hits_for_line(2), Some(0));
+    }
+
+    #[test]
+    fn merged_object_reports() {
+        // Every object file has its own function and `shared` which is only counted once, like a
+        // library linked into several binaries. The last object's `shared` is from another build
+        let mut profile = InstrumentationProfile::new(Some(8), false, false, false);
+        let records = (0..4)
+            .map(|i| (format!("function_{}", i), 10 + i, i))
+            .chain(std::iter::once(("shared".to_string(), 1, 3)));
+        for (name, hash, count) in records {
+            profile.push_record(NamedInstrProfRecord {
+                name: Some(name.clone()),
+                name_hash: Some(compute_hash(&name)),
+                hash: Some(hash),
+                record: InstrProfRecord {
+                    counts: vec![count],
+                    data: None,
+                },
+            });
+            profile.symtab.names.insert(compute_hash(&name), name);
+        }
+        let mut infos = vec![];
+        for i in 0..4 {
+            let name = format!("function_{}", i);
+            let mut own = region(RegionKind::Code, Counter::instrumentation(0), 0, 0);
+            own.loc.line_start = i as usize + 2;
+            own.loc.line_end = i as usize + 2;
+            let shared = region(RegionKind::Code, Counter::instrumentation(0), 0, 0);
+            let cov_fun = vec![
+                function_record(&name, 10 + i, vec![own], vec![]),
+                function_record("shared", if i == 3 { 2 } else { 1 }, vec![shared], vec![]),
+            ];
+            infos.push(mapping_info(vec![PathBuf::from("/src/lib.rs")], cov_fun));
+        }
+        let mapping = CoverageMapping {
+            profile: Cow::Borrowed(&profile),
+            mapping_info: infos,
+        };
+
+        let report = mapping.generate_report();
+        let names = report
+            .functions
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "function_0",
+                "shared",
+                "function_1",
+                "function_2",
+                "function_3"
+            ]
+        );
+        assert_eq!(report.hash_mismatches.len(), 1);
+        let file = &report.files[&PathBuf::from("/src/lib.rs")];
+        assert_eq!(file.hits_for_line(1), Some(3));
+        assert_eq!(file.hits_for_line(5), Some(3));
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let parallel = pool.install(|| mapping.generate_report());
+        assert_eq!(parallel.functions, report.functions);
+        assert_eq!(parallel.hash_mismatches, report.hash_mismatches);
+        assert_eq!(parallel.summary(), report.summary());
+
+        let reports = mapping
+            .mapping_info
+            .iter()
+            .map(|info| {
+                let (functions, mismatches) = mapping.select_functions(info, &mut |_| true);
+                mapping.object_report(functions, mismatches)
+            })
+            .collect::<Vec<_>>();
+        let merge = |a: &CoverageReport, b: &CoverageReport| {
+            let mut merged = a.clone();
+            merged.merge(b.clone());
+            merged
+        };
+        let in_order = merge(
+            &merge(&merge(&reports[0], &reports[1]), &reports[2]),
+            &reports[3],
+        );
+        let grouped = merge(
+            &merge(&reports[0], &reports[1]),
+            &merge(&reports[2], &reports[3]),
+        );
+        let reversed = merge(
+            &merge(&reports[3], &reports[2]),
+            &merge(&reports[1], &reports[0]),
+        );
+        for merged in [&in_order, &grouped] {
+            assert_eq!(merged.functions, report.functions);
+            assert_eq!(merged.hash_mismatches, report.hash_mismatches);
+        }
+        assert_eq!(reversed.functions.len(), report.functions.len());
+        for merged in [&in_order, &grouped, &reversed] {
+            assert_eq!(merged.summary(), report.summary());
+        }
+    }
+
+    #[test]
+    fn negative_expression_counts() {
+        let name = "inconsistent";
+        let fn_hash = 0x1234;
+        let mut profile = InstrumentationProfile::new(Some(8), false, false, false);
+        profile.push_record(NamedInstrProfRecord {
+            name: Some(name.to_string()),
+            name_hash: Some(compute_hash(name)),
+            hash: Some(fn_hash),
+            record: InstrProfRecord {
+                counts: vec![2, 5, u64::MAX],
+                data: None,
+            },
+        });
+        profile
+            .symtab
+            .names
+            .insert(compute_hash(name), name.to_string());
+        let expression = |id| Counter {
+            kind: CounterType::Expression(ExprKind::Subtract),
+            id,
+        };
+        let mut regions = vec![
+            region(RegionKind::Code, Counter::instrumentation(0), 0, 0),
+            region(RegionKind::Code, expression(0), 0, 0),
+            region(RegionKind::Code, expression(1), 0, 0),
+            region(RegionKind::Code, Counter::instrumentation(2), 0, 0),
+        ];
+        for (line, region) in regions.iter_mut().enumerate() {
+            region.loc.line_start = line + 1;
+            region.loc.line_end = line + 1;
+        }
+        let expressions = vec![
+            // 2 - 5 is negative
+            Expression {
+                kind: ExprKind::Subtract,
+                lhs: Counter::instrumentation(0),
+                rhs: Counter::instrumentation(1),
+            },
+            // The negative count is still used in other expressions, -3 - -5 is 2
+            Expression {
+                kind: ExprKind::Subtract,
+                lhs: expression(0),
+                rhs: Counter {
+                    kind: CounterType::Expression(ExprKind::Subtract),
+                    id: 2,
+                },
+            },
+            // 0 - 5
+            Expression {
+                kind: ExprKind::Subtract,
+                lhs: Counter::default(),
+                rhs: Counter::instrumentation(1),
+            },
+        ];
+        let info = mapping_info(
+            vec![PathBuf::from("/src/main.rs")],
+            vec![function_record(name, fn_hash, regions, expressions)],
+        );
+        let mapping = CoverageMapping {
+            profile: Cow::Borrowed(&profile),
+            mapping_info: vec![info],
+        };
+
+        let report = mapping.generate_report();
+        assert!(report.unresolved_expressions.is_empty());
+        let counts = report.functions[0]
+            .counted_regions
+            .iter()
+            .map(|x| x.execution_count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![2, 0, 2, i64::MAX as usize]);
+        let file = &report.files[&PathBuf::from("/src/main.rs")];
+        assert_eq!(file.hits_for_line(2), Some(0));
+        assert_eq!(file.hits_for_line(3), Some(2));
+    }
+
+    #[test]
+    fn section_names() {
+        let matches = [
+            (LlvmSection::CoverageMap, "__llvm_covmap"),
+            (LlvmSection::CoverageMap, "__LLVM_COV,__llvm_covmap"),
+            (LlvmSection::CoverageMap, ".lcovmap$M"),
+            (LlvmSection::CoverageFunctions, ".lcovfun"),
+            (LlvmSection::ProfileCounts, "__DATA,__llvm_prf_cnts"),
+            (LlvmSection::ProfileCounts, ".lprfc$M"),
+            (LlvmSection::ProfileData, ".lprfd"),
+            (LlvmSection::ProfileNames, "__llvm_prf_names"),
+        ];
+        for (section, name) in &matches {
+            assert!(section.matches(name), "{:?} {}", section, name);
+        }
+        assert!(!LlvmSection::CoverageMap.matches(".lcovfun"));
+        assert!(!LlvmSection::CoverageMap.matches(".lcovmapx"));
+        assert!(!LlvmSection::ProfileData.matches("__llvm_prf_cnts"));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::coverage::reporting::*;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+const STYLE: &str = "body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+td, th { padding: 2px 8px; text-align: left; }
+.summary td, .summary th { border: 1px solid #ccc; }
+.source td { padding: 0 8px; vertical-align: top; }
+.source pre { margin: 0; }
+.line-number, .count { text-align: right; color: #666; }
+.covered .count { background-color: #d6f5d6; }
+.uncovered .count, .uncovered pre { background-color: #ffd6d6; }
+.high { background-color: #d6f5d6; }
+.medium { background-color: #fff2cc; }
+.low { background-color: #ffd6d6; }
+.missing { color: #a00; }
+";
+
+/// Writes an HTML report to `output_dir` like `llvm-cov show -format=html`. `index.html` has the
+/// function, line, region and branch coverage of each file and links to a page per file under
+/// `coverage/` with the source lines coloured by their count. Files whose source can't be read
+/// still get a page with the line counts from the coverage regions.
+pub fn write_report(report: &CoverageReport, output_dir: &Path) -> io::Result<()> {
+    let summary = report.summary();
+    for (path, file_summary) in &summary.files {
+        let page = output_dir.join(file_page_path(path));
+        if let Some(parent) = page.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Invalid UTF-8 is replaced rather than treating the source as missing
+        let source = fs::read(path)
+            .ok()
+            .map(|x| String::from_utf8_lossy(&x).into_owned());
+        fs::write(
+            page,
+            file_page(report, path, file_summary, source.as_deref()),
+        )?;
+    }
+    fs::create_dir_all(output_dir)?;
+    fs::write(output_dir.join("index.html"), index_page(&summary))
+}
+
+/// Path of the page for a source file relative to the output directory, this is the source path
+/// under `coverage/` with `.html` appended. Parent directories in relative paths become `_parent`
+/// so pages can't be written outside the output directory.
+pub fn file_page_path(path: &Path) -> PathBuf {
+    let mut page = PathBuf::from("coverage");
+    for component in path.components() {
+        match component {
+            Component::Normal(x) => page.push(x),
+            Component::ParentDir => page.push("_parent"),
+            _ => {}
+        }
+    }
+    let mut name = page.file_name().unwrap_or_default().to_os_string();
+    name.push(".html");
+    page.set_file_name(name);
+    page
+}
+
+/// Escapes text for use in HTML element content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent encodes a relative path to use as a link, the separators are always `/`
+fn encode_link(path: &Path) -> String {
+    let mut link = String::new();
+    for (i, component) in path.components().enumerate() {
+        if i > 0 {
+            link.push('/');
+        }
+        for byte in component.as_os_str().to_string_lossy().bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                link.push(byte as char);
+            } else {
+                let _ = write!(link, "%{:02X}", byte);
+            }
+        }
+    }
+    link
+}
+
+fn page_start(title: &str) -> String {
+    format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n",
+        escape(title),
+        STYLE
+    )
+}
+
+const PAGE_END: &str = "</body>\n</html>\n";
+
+/// Table cell with the percentage covered and the counts, coloured like llvm-cov
+fn coverage_cell(count: &CoverageCount) -> String {
+    match count.percent() {
+        Some(percent) => {
+            let class = if percent < 80.0 {
+                "low"
+            } else if percent < 100.0 {
+                "medium"
+            } else {
+                "high"
+            };
+            format!(
+                "<td class=\"{}\">{:.2}% ({}/{})</td>",
+                class, percent, count.covered, count.total
+            )
+        }
+        None => "<td>- (0/0)</td>".to_string(),
+    }
+}
+
+fn summary_cells(summary: &CoverageSummary) -> String {
+    [
+        summary.functions,
+        summary.lines,
+        summary.regions,
+        summary.branches,
+    ]
+    .iter()
+    .map(coverage_cell)
+    .collect()
+}
+
+const SUMMARY_HEADER: &str = "<tr><th>Filename</th><th>Function Coverage</th><th>Line Coverage</th><th>Region Coverage</th><th>Branch Coverage</th></tr>\n";
+
+fn index_page(summary: &ReportSummary) -> String {
+    let mut html = page_start("Coverage Report");
+    html.push_str("<h1>Coverage Report</h1>\n<table class=\"summary\">\n");
+    html.push_str(SUMMARY_HEADER);
+    for (path, file) in &summary.files {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">{}</a></td>{}</tr>",
+            encode_link(&file_page_path(path)),
+            escape(&path.display().to_string()),
+            summary_cells(file)
+        );
+    }
+    let _ = writeln!(
+        html,
+        "<tr><td>Totals</td>{}</tr>",
+        summary_cells(&summary.total)
+    );
+    html.push_str("</table>\n");
+    html.push_str(PAGE_END);
+    html
+}
+
+fn file_page(
+    report: &CoverageReport,
+    path: &Path,
+    summary: &CoverageSummary,
+    source: Option<&str>,
+) -> String {
+    let name = path.display().to_string();
+    let mut html = page_start(&name);
+    // Pages are nested in directories matching the source path so the index is a few levels up
+    let depth = file_page_path(path).components().count() - 1;
+    let _ = writeln!(
+        html,
+        "<p><a href=\"{}index.html\">Index</a></p>\n<h1>{}</h1>\n<table class=\"summary\">",
+        "../".repeat(depth),
+        escape(&name)
+    );
+    html.push_str(SUMMARY_HEADER);
+    let _ = writeln!(
+        html,
+        "<tr><td>{}</td>{}</tr>\n</table>",
+        escape(&name),
+        summary_cells(summary)
+    );
+
+    let counts = report
+        .line_counts(path)
+        .map(|(_, count)| count)
+        .collect::<Vec<_>>();
+    let lines = match source {
+        Some(source) => source.lines().collect(),
+        None => {
+            html.push_str(
+                "<p class=\"missing\">The source file couldn't be read, only the line counts are shown</p>\n",
+            );
+            vec![""; counts.len()]
+        }
+    };
+    html.push_str("<table class=\"source\">\n");
+    for (i, text) in lines.iter().enumerate() {
+        let (class, count) = match counts.get(i).copied().flatten() {
+            Some(0) => (" class=\"uncovered\"", "0".to_string()),
+            Some(count) => (" class=\"covered\"", count.to_string()),
+            None => ("", String::new()),
+        };
+        let _ = writeln!(
+            html,
+            "<tr{}><td class=\"line-number\">{}</td><td class=\"count\">{}</td><td><pre>{}</pre></td></tr>",
+            class,
+            i + 1,
+            count,
+            escape(text)
+        );
+    }
+    html.push_str("</table>\n");
+    html.push_str(PAGE_END);
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_html() {
+        assert_eq!(
+            escape("fn foo<'a>(x: &'a str) -> \"&\""),
+            "fn foo&lt;&#39;a&gt;(x: &amp;&#39;a str) -&gt; &quot;&amp;&quot;"
+        );
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn page_paths() {
+        assert_eq!(
+            file_page_path(Path::new("/home/user/src/main.rs")),
+            Path::new("coverage/home/user/src/main.rs.html")
+        );
+        assert_eq!(
+            file_page_path(Path::new("src/lib.rs")),
+            Path::new("coverage/src/lib.rs.html")
+        );
+        assert_eq!(
+            file_page_path(Path::new("../src/lib.rs")),
+            Path::new("coverage/_parent/src/lib.rs.html")
+        );
+        assert_eq!(
+            encode_link(Path::new("coverage/my src/a#b.rs.html")),
+            "coverage/my%20src/a%23b.rs.html"
+        );
+    }
+}
@@ -1,10 +1,13 @@
 use nom::IResult;
 use rustc_hash::FxHashMap;
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::path::PathBuf;
 
 pub mod coverage_mapping;
+pub mod html;
 pub mod reporting;
+pub mod segments;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CoverageMappingInfo {
@@ -12,11 +15,46 @@ pub struct CoverageMappingInfo {
     pub cov_fun: Vec<FunctionRecordV3>,
     pub prof_counts: Option<Vec<u64>>,
     pub prof_data: Option<Vec<ProfileData>>,
+    /// Function names from the profile names section
+    pub prof_names: Vec<String>,
+    /// The build id of the object file if it has one, profiles from the binary list it in their
+    /// binary ids
+    pub binary_id: Option<Vec<u8>>,
+    /// The compilation directory at the start of the filename lists in `cov_map` which have one,
+    /// relative paths in these lists are already joined onto it
+    pub compilation_dirs: FxHashMap<u64, PathBuf>,
 }
 
 impl CoverageMappingInfo {
-    /// Gets the files for a given ID converted to their absolute representation
+    /// Gets the counters for a function from the counters section of the object file
+    pub fn counters(&self, data: &ProfileData) -> Option<&[u64]> {
+        let start = (data.counters_offset / 8) as usize;
+        let end = start.checked_add(data.counters_len as usize)?;
+        self.prof_counts.as_ref()?.get(start..end)
+    }
+
+    /// Every source file in the filename lists of the coverage map, converted to their absolute
+    /// representation like `get_files_from_id`, sorted and without duplicates
+    pub fn all_files(&self) -> Vec<PathBuf> {
+        self.cov_map
+            .keys()
+            .flat_map(|id| {
+                // The compilation directory isn't a source file
+                let skip = usize::from(self.compilation_dirs.contains_key(id));
+                self.get_files_from_id(*id).into_iter().skip(skip)
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Gets the files for a given ID converted to their absolute representation. Lists starting
+    /// with a compilation directory were resolved against it when parsed, for older lists without
+    /// one the first absolute path is assumed to be the directory.
     pub fn get_files_from_id(&self, id: u64) -> Vec<PathBuf> {
+        if self.compilation_dirs.contains_key(&id) {
+            return self.cov_map.get(&id).cloned().unwrap_or_default();
+        }
         let mut paths = vec![];
         if let Some(v) = self.cov_map.get(&id) {
             let mut last_absolute = None;
@@ -49,6 +87,8 @@ pub struct ProfileData {
     pub name_md5: u64,
     pub structural_hash: u64,
     pub counters_len: u32,
+    /// Offset in bytes of the function's first counter from the start of the counters section
+    pub counters_offset: u64,
 }
 
 /// This is the type of a counter expression. The equivalent type in llvm would be
@@ -97,19 +137,14 @@ impl TryFrom<u64> for RegionKind {
 }
 
 /// Represents the type of a counter. The equivalent type in llvm would be `Counter::CounterKind`.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum CounterType {
+    #[default]
     Zero,
     ProfileInstrumentation,
     Expression(ExprKind),
 }
 
-impl Default for CounterType {
-    fn default() -> Self {
-        Self::Zero
-    }
-}
-
 /// A `Counter` is an abstract value that describes how to compute the execution count for a region
 /// of code using the collected profile count data. The equivalent type in llvm would be `Counter`.
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -198,11 +233,18 @@ pub struct CounterMappingRegion {
     pub count: Counter,
     /// Secondary counter that is also used for false branches
     pub false_count: Counter,
+    /// Index of the file the region is in within the filenames of the coverage mapping
     pub file_id: usize,
+    /// For expansion regions the index of the expanded file within the function's filename list
     pub expanded_file_id: usize,
     pub loc: SourceLocation,
 }
 
+/// The end column of regions covering whole lines, these are encoded in the mapping with a start and
+/// end column of 0. llvm stores columns as 32 bit integers and uses the largest one so exported
+/// regions and segments match llvm-cov.
+pub const WHOLE_LINE_COLUMN: usize = u32::MAX as usize;
+
 /// Refers to a location in the source code
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SourceLocation {
@@ -216,6 +258,14 @@ pub struct SourceLocation {
     pub column_end: usize,
 }
 
+impl SourceLocation {
+    /// Whether the position is within the region, the start and end are both inclusive
+    pub fn contains(&self, line: usize, column: usize) -> bool {
+        (self.line_start, self.column_start) <= (line, column)
+            && (line, column) <= (self.line_end, self.column_end)
+    }
+}
+
 /// The execution count information starting at a point in a file. A sequence of execution counters
 /// for a file in a format hat's simple to iterate over for processing. The equivalent llvm type is
 /// `CoverageSegment`.
@@ -230,9 +280,9 @@ pub struct CoverageSegment {
     /// When false the segment is not instrumented or skipped
     pub has_count: bool,
     /// whether this enters a new region or returns to a previous count
-    pub is_region_entry: usize,
+    pub is_region_entry: bool,
     /// Whether this enters a gap region
-    pub is_gap_region: usize,
+    pub is_gap_region: bool,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -269,15 +319,19 @@ pub struct CoverageMappingRecord {
 }
 
 /// Associates a source range with a specific counter. The equivalent llvm type is `CountedRegion`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct CountedRegion {
     pub execution_count: usize,
     pub false_execution_count: usize,
     pub folded: bool,
+    /// The counts come from a single byte coverage profile so only zero and non-zero matter
+    pub has_single_byte_coverage: bool,
     pub region: CounterMappingRegion,
 }
 
 /// This is the code coverage information for a single function. It is equivalent to
 /// `FunctionRecord` but has been renamed to avoid confusion with `FunctionRecordV3` etc
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FunctionCoverageRecord {
     /// Raw function name
     pub name: String,
@@ -290,4 +344,6 @@ pub struct FunctionCoverageRecord {
     pub counted_branch_regions: Vec<CountedRegion>,
     /// Number of times the function was executed
     pub execution_count: usize,
+    /// Indexes of the expressions which couldn't be evaluated, regions using them have a count of 0
+    pub unresolved_expressions: Vec<usize>,
 }
@@ -1,25 +1,561 @@
+use crate::coverage::segments::build_segments;
 use crate::coverage::*;
-use std::collections::BTreeMap;
+use rustc_hash::FxHashSet;
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::Path;
 use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Clone, Debug, Default)]
 pub struct CoverageReport {
     pub files: BTreeMap<PathBuf, CoverageResult>,
+    /// The functions in the report with their region counts
+    pub functions: Vec<FunctionCoverageRecord>,
+    /// Functions left out of the report because the hash in the profile doesn't match the
+    /// coverage mapping, this happens when the profile and binary are from different builds
+    pub hash_mismatches: Vec<HashMismatch>,
+    /// Functions with expressions that couldn't be evaluated, the report is still generated but
+    /// the regions using these expressions have a count of 0
+    pub unresolved_expressions: Vec<UnresolvedExpressions>,
+}
+
+/// A function where the profile record has a different function hash to the coverage mapping
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HashMismatch {
+    pub name: String,
+    pub profile_hash: u64,
+    pub mapping_hash: u64,
+}
+
+/// A function with expressions that are cyclic or refer to expressions that don't exist
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UnresolvedExpressions {
+    pub name: String,
+    /// Indexes of the expressions in the function's coverage mapping
+    pub expressions: Vec<usize>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct CoverageResult {
     pub hits: BTreeMap<SourceLocation, usize>,
+    /// Counts of the gap regions, these are kept apart from the hits as they only set the count of
+    /// a line when no other region covers it
+    pub gaps: BTreeMap<SourceLocation, usize>,
+    pub branches: BTreeMap<SourceLocation, BranchCount>,
+    /// Kind of each region added with `CoverageResult::insert_region`, this includes skipped
+    /// regions which have no count. Regions in `hits` without a kind are treated as code regions.
+    pub kinds: BTreeMap<SourceLocation, RegionKind>,
+}
+
+/// The execution counts for both sides of a branch region. A side of the branch is folded when its
+/// counter is always zero (for example a constant condition), folded sides aren't counted as
+/// branches. The equivalent llvm information is stored in `CountedRegion`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct BranchCount {
+    /// Number of times the condition evaluated to true
+    pub true_count: usize,
+    /// Number of times the condition evaluated to false
+    pub false_count: usize,
+    /// The true counter is always zero
+    pub true_folded: bool,
+    /// The false counter is always zero
+    pub false_folded: bool,
+}
+
+impl BranchCount {
+    /// Number of branches which aren't folded
+    pub fn num_branches(&self) -> usize {
+        usize::from(!self.true_folded) + usize::from(!self.false_folded)
+    }
+
+    /// Number of branches which aren't folded and were taken at least once
+    pub fn covered_branches(&self) -> usize {
+        usize::from(!self.true_folded && self.true_count > 0)
+            + usize::from(!self.false_folded && self.false_count > 0)
+    }
+}
+
+/// The number of covered items out of the total for a type of coverage
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct CoverageCount {
+    pub covered: usize,
+    pub total: usize,
+}
+
+impl CoverageCount {
+    pub fn missed(&self) -> usize {
+        self.total - self.covered
+    }
+
+    /// Percentage covered, if there's nothing to cover this returns `None`
+    pub fn percent(&self) -> Option<f64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some(self.covered as f64 / self.total as f64 * 100.0)
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.covered += other.covered;
+        self.total += other.total;
+    }
+}
+
+/// Summary of the coverage of a file or a collection of files. The equivalent llvm type is
+/// `FileCoverageSummary`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct CoverageSummary {
+    /// Lines with at least one region and lines with a count over zero
+    pub lines: CoverageCount,
+    /// Regions excluding skipped regions
+    pub regions: CoverageCount,
+    /// Functions where instantiations of the same function are counted once
+    pub functions: CoverageCount,
+    /// Every instantiation of a function
+    pub instantiations: CoverageCount,
+    /// Branches which aren't folded
+    pub branches: CoverageCount,
+}
+
+impl CoverageSummary {
+    fn add(&mut self, other: &Self) {
+        self.lines.add(&other.lines);
+        self.regions.add(&other.regions);
+        self.functions.add(&other.functions);
+        self.instantiations.add(&other.instantiations);
+        self.branches.add(&other.branches);
+    }
+}
+
+/// A summary of the coverage for each file in a report plus the totals of all the files.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReportSummary {
+    pub files: BTreeMap<PathBuf, CoverageSummary>,
+    pub total: CoverageSummary,
 }
 
 impl CoverageReport {
+    /// Summarises the line, region, function and branch coverage for each file in the report
+    pub fn summary(&self) -> ReportSummary {
+        let mut files = BTreeMap::new();
+        for (path, result) in &self.files {
+            let line_hits = result.line_hits();
+            let summary = CoverageSummary {
+                lines: CoverageCount {
+                    covered: line_hits.values().filter(|x| **x > 0).count(),
+                    total: line_hits.len(),
+                },
+                regions: CoverageCount {
+                    covered: result.code_regions().filter(|(_, x)| *x > 0).count(),
+                    total: result.code_regions().count(),
+                },
+                functions: CoverageCount::default(),
+                instantiations: CoverageCount::default(),
+                branches: CoverageCount {
+                    covered: result.covered_branches(),
+                    total: result.total_branches(),
+                },
+            };
+            files.insert(path.clone(), summary);
+        }
+
+        // Instantiations of a function (such as generics) share the location of their first region
+        // so a function is covered if any instantiation is executed
+        let mut functions = BTreeMap::new();
+        for function in &self.functions {
+            let region = match function
+                .counted_regions
+                .iter()
+                .find(|x| x.region.kind != RegionKind::Skipped)
+            {
+                Some(region) => region,
+                None => continue,
+            };
+            if let Some(path) = function.filenames.get(region.region.file_id) {
+                let path = PathBuf::from(path);
+                // The function is defined in a file that's been removed from the report
+                if !self.files.contains_key(&path) {
+                    continue;
+                }
+                let summary: &mut CoverageSummary = files.entry(path.clone()).or_default();
+                summary.instantiations.total += 1;
+                if function.execution_count > 0 {
+                    summary.instantiations.covered += 1;
+                }
+                let executed = functions
+                    .entry((path, region.region.loc.clone()))
+                    .or_insert(false);
+                *executed |= function.execution_count > 0;
+            }
+        }
+        for ((path, _), executed) in functions {
+            let summary: &mut CoverageSummary = files.entry(path).or_default();
+            summary.functions.total += 1;
+            if executed {
+                summary.functions.covered += 1;
+            }
+        }
+
+        let mut total = CoverageSummary::default();
+        for summary in files.values() {
+            total.add(summary);
+        }
+        ReportSummary { files, total }
+    }
+
+    /// The number of covered lines and instrumented lines in a file, lines in skipped regions
+    /// aren't instrumented. Returns `None` if the file isn't in the report.
+    pub fn file_line_coverage(&self, path: &Path) -> Option<(usize, usize)> {
+        let line_hits = self.files.get(path)?.line_hits();
+        let covered = line_hits.values().filter(|x| **x > 0).count();
+        Some((covered, line_hits.len()))
+    }
+
+    /// The count of each line in a file from the first line to the last line in a region, lines
+    /// without a count such as blank lines and skipped regions are `None`. The lines come from the
+    /// regions so the source file doesn't need to be present. Files not in the report have no
+    /// lines.
+    pub fn line_counts<'a>(
+        &'a self,
+        path: &Path,
+    ) -> impl Iterator<Item = (usize, Option<usize>)> + 'a {
+        self.files
+            .get(path)
+            .into_iter()
+            .flat_map(|result| result.line_counts())
+    }
+
+    /// Writes the report as an LCOV tracefile. Line counts are taken from
+    /// `CoverageResult::hits_for_line` and the output aims to match `llvm-cov export
+    /// -format=lcov`.
+    pub fn to_lcov(&self, writer: &mut impl Write) -> io::Result<()> {
+        // The functions that start in each file, with the line they start on
+        let mut functions = BTreeMap::<PathBuf, Vec<(usize, &FunctionCoverageRecord)>>::new();
+        for function in &self.functions {
+            let region = function
+                .counted_regions
+                .iter()
+                .find(|x| x.region.kind != RegionKind::Skipped);
+            if let Some(region) = region {
+                if let Some(path) = function.filenames.get(region.region.file_id) {
+                    functions
+                        .entry(PathBuf::from(path))
+                        .or_default()
+                        .push((region.region.loc.line_start, function));
+                }
+            }
+        }
+
+        for (path, result) in &self.files {
+            writeln!(writer, "SF:{}", path.display())?;
+            let functions = functions
+                .get(path)
+                .map(|x| x.as_slice())
+                .unwrap_or_default();
+            for (line, function) in functions {
+                writeln!(writer, "FN:{},{}", line, function.name)?;
+            }
+            for (_, function) in functions {
+                writeln!(
+                    writer,
+                    "FNDA:{},{}",
+                    function.execution_count, function.name
+                )?;
+            }
+            let functions_hit = functions
+                .iter()
+                .filter(|(_, x)| x.execution_count > 0)
+                .count();
+            writeln!(writer, "FNF:{}", functions.len())?;
+            writeln!(writer, "FNH:{}", functions_hit)?;
+
+            let line_hits = result.line_hits();
+            for (line, count) in &line_hits {
+                writeln!(writer, "DA:{},{}", line, count)?;
+            }
+
+            // Each branch region has two branches, the block ID is the index of the region on the
+            // line and the branch IDs count up on each line. Branches that are never evaluated are
+            // written as "-"
+            let mut current_line = 0;
+            let mut block_id = 0;
+            let mut branch_id = 0;
+            for (loc, branch) in &result.branches {
+                if loc.line_start != current_line {
+                    current_line = loc.line_start;
+                    block_id = 0;
+                    branch_id = 0;
+                }
+                let evaluated = branch.true_count > 0 || branch.false_count > 0;
+                for (folded, count) in [
+                    (branch.true_folded, branch.true_count),
+                    (branch.false_folded, branch.false_count),
+                ] {
+                    if folded {
+                        continue;
+                    }
+                    if evaluated {
+                        writeln!(
+                            writer,
+                            "BRDA:{},{},{},{}",
+                            current_line, block_id, branch_id, count
+                        )?;
+                    } else {
+                        writeln!(writer, "BRDA:{},{},{},-", current_line, block_id, branch_id)?;
+                    }
+                    branch_id += 1;
+                }
+                block_id += 1;
+            }
+            writeln!(writer, "BRF:{}", result.total_branches())?;
+            writeln!(writer, "BRH:{}", result.covered_branches())?;
+
+            writeln!(writer, "LF:{}", line_hits.len())?;
+            writeln!(
+                writer,
+                "LH:{}",
+                line_hits.values().filter(|x| **x > 0).count()
+            )?;
+            writeln!(writer, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Builds the coverage segments for a file in the report from the regions of the functions
+    /// in that file
+    pub fn segments_for_file(&self, path: &Path) -> Vec<CoverageSegment> {
+        let filename = path.display().to_string();
+        let filename = &filename;
+        let regions = self
+            .functions
+            .iter()
+            .flat_map(|function| {
+                function
+                    .counted_regions
+                    .iter()
+                    .filter(move |x| function.filenames.get(x.region.file_id) == Some(filename))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        build_segments(&regions)
+    }
+
+    /// Writes the report in the JSON format output by `llvm-cov export` (version 2.0.1). This
+    /// contains the coverage segments, branches, expansions and summary for each file, the regions
+    /// for each function and the totals for the whole report.
+    pub fn to_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        let summary = self.summary();
+        let mut files = vec![];
+        for path in self.files.keys() {
+            let filename = path.display().to_string();
+            let mut regions = vec![];
+            let mut branches = vec![];
+            let mut expansions = vec![];
+            for function in &self.functions {
+                let in_file = |region: &CountedRegion| {
+                    function.filenames.get(region.region.file_id) == Some(&filename)
+                };
+                let file_ids = function_file_ids(function);
+                for region in function.counted_regions.iter().filter(|x| in_file(x)) {
+                    regions.push(region.clone());
+                    if region.region.kind == RegionKind::Expansion {
+                        let expanded = |x: &&CountedRegion| {
+                            file_ids.get(region.region.expanded_file_id) == Some(&x.region.file_id)
+                        };
+                        expansions.push(json!({
+                            "filenames": json_filenames(function, &file_ids),
+                            "source_region": json_region(region, &file_ids),
+                            "target_regions": function.counted_regions.iter().filter(expanded).map(|x| json_region(x, &file_ids)).collect::<Vec<_>>(),
+                            "branches": function.counted_branch_regions.iter().filter(expanded).map(|x| json_branch(x, &file_ids)).collect::<Vec<_>>(),
+                        }));
+                    }
+                }
+                branches.extend(
+                    function
+                        .counted_branch_regions
+                        .iter()
+                        .filter(|x| in_file(x))
+                        .map(|x| json_branch(x, &file_ids)),
+                );
+            }
+            let segments = build_segments(&regions)
+                .iter()
+                .map(|x| {
+                    json!([
+                        x.line,
+                        json_column(x.col),
+                        x.count,
+                        x.has_count,
+                        x.is_region_entry,
+                        x.is_gap_region
+                    ])
+                })
+                .collect::<Vec<_>>();
+            let file_summary = summary.files.get(path).copied().unwrap_or_default();
+            files.push(json!({
+                "filename": filename,
+                "segments": segments,
+                "branches": branches,
+                "expansions": expansions,
+                "summary": json_summary(&file_summary),
+            }));
+        }
+
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                let file_ids = function_file_ids(function);
+                json!({
+                    "name": function.name,
+                    "count": function.execution_count,
+                    "regions": function.counted_regions.iter().map(|x| json_region(x, &file_ids)).collect::<Vec<_>>(),
+                    "branches": function.counted_branch_regions.iter().map(|x| json_branch(x, &file_ids)).collect::<Vec<_>>(),
+                    "filenames": json_filenames(function, &file_ids),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let export = json!({
+            "version": "2.0.1",
+            "type": "llvm.coverage.json.export",
+            "data": [{
+                "files": files,
+                "functions": functions,
+                "totals": json_summary(&summary.total),
+            }],
+        });
+        serde_json::to_writer(&mut *writer, &export)?;
+        writeln!(writer)
+    }
+
+    /// Merges the report for other object files into this one. Functions with the same name and
+    /// files as one already in the report (such as from a library linked into more than one
+    /// binary) are skipped like the `RecordProvenance` check in llvm-cov, so their counts aren't
+    /// added twice. The file results of `other` are rebuilt from the regions of the functions which
+    /// are added when they overlap with the report, so only files in `other` are kept.
+    ///
+    /// Merging is associative and, apart from the order of the functions, commutative so partial
+    /// reports can be merged in any grouping and give the same report as merging them in order.
+    pub fn merge(&mut self, other: CoverageReport) {
+        let existing = self
+            .functions
+            .iter()
+            .map(|x| (x.name.as_str(), x.filenames.as_slice()))
+            .collect::<FxHashSet<_>>();
+        let len = other.functions.len();
+        let added = other
+            .functions
+            .into_iter()
+            .filter(|x| !existing.contains(&(x.name.as_str(), x.filenames.as_slice())))
+            .collect::<Vec<_>>();
+        // Without any skipped functions the results for files not already in the report can be
+        // moved over as they are, the rest are rebuilt from the regions of the added functions
+        let mut rebuild = FxHashSet::default();
+        for (path, result) in other.files {
+            if added.len() == len && !self.files.contains_key(&path) {
+                self.files.insert(path, result);
+            } else {
+                rebuild.insert(path);
+            }
+        }
+        if !rebuild.is_empty() {
+            for function in &added {
+                self.insert_function_counts(function, |path| rebuild.contains(path));
+            }
+        }
+        self.functions.extend(added);
+
+        let existing = self
+            .hash_mismatches
+            .iter()
+            .cloned()
+            .collect::<FxHashSet<_>>();
+        self.hash_mismatches.extend(
+            other
+                .hash_mismatches
+                .into_iter()
+                .filter(|x| !existing.contains(x)),
+        );
+        let existing = self
+            .unresolved_expressions
+            .iter()
+            .cloned()
+            .collect::<FxHashSet<_>>();
+        self.unresolved_expressions.extend(
+            other
+                .unresolved_expressions
+                .into_iter()
+                .filter(|x| !existing.contains(x)),
+        );
+    }
+
+    /// Adds the counts of the regions and branches of a function to the results of the files
+    /// they're in, regions in files where `keep` returns false are left out.
+    pub(crate) fn insert_function_counts(
+        &mut self,
+        function: &FunctionCoverageRecord,
+        mut keep: impl FnMut(&Path) -> bool,
+    ) {
+        let region_path =
+            |region: &CountedRegion| PathBuf::from(&function.filenames[region.region.file_id]);
+        for region in &function.counted_branch_regions {
+            let path = region_path(region);
+            if !keep(&path) {
+                continue;
+            }
+            self.files.entry(path).or_default().insert_branch(
+                region.region.loc.clone(),
+                BranchCount {
+                    true_count: region.execution_count,
+                    false_count: region.false_execution_count,
+                    true_folded: region.region.count.is_zero(),
+                    false_folded: region.region.false_count.is_zero(),
+                },
+            );
+        }
+        for region in &function.counted_regions {
+            let path = region_path(region);
+            if !keep(&path) {
+                continue;
+            }
+            self.files.entry(path).or_default().insert_region(
+                region.region.loc.clone(),
+                region.region.kind,
+                region.execution_count,
+                region.has_single_byte_coverage,
+            );
+        }
+    }
+
     pub fn apply_remapping(&mut self, remapping: &PathRemapping) {
+        self.apply_remappings(std::slice::from_ref(remapping));
+    }
+
+    /// Keeps only the files where the predicate returns true. Functions without any regions in
+    /// the remaining files are removed as well.
+    pub fn retain_files(&mut self, mut predicate: impl FnMut(&Path) -> bool) {
+        self.files.retain(|path, _| predicate(path));
+        let files = &self.files;
+        self.functions.retain(|function| {
+            function
+                .filenames
+                .iter()
+                .any(|x| files.contains_key(Path::new(x)))
+        });
+    }
+
+    /// Applies a list of remappings to the paths in the report. Each path is only remapped once,
+    /// if more than one remapping matches the one with the longest source path is used and if
+    /// they're the same length the first one in the list wins.
+    pub fn apply_remappings(&mut self, remappings: &[PathRemapping]) {
         let inputs = self.files.keys().cloned().collect::<Vec<_>>();
         for path in &inputs {
-            if path.starts_with(&remapping.source) {
-                let end = path.strip_prefix(&remapping.source).unwrap();
-                let new_path = remapping.dest.join(end);
+            if let Some(new_path) = remap_path(path, remappings) {
                 if let Some(values) = self.files.remove(path) {
                     self.files.insert(new_path, values);
                 } else {
@@ -27,28 +563,316 @@ impl CoverageReport {
                 }
             }
         }
+        for function in self.functions.iter_mut() {
+            for filename in function.filenames.iter_mut() {
+                if let Some(new_path) = remap_path(Path::new(filename.as_str()), remappings) {
+                    *filename = new_path.display().to_string();
+                }
+            }
+        }
     }
 }
 
+fn remap_path(path: &Path, remappings: &[PathRemapping]) -> Option<PathBuf> {
+    remappings
+        .iter()
+        .rev()
+        .filter_map(|x| path.strip_prefix(&x.source).ok().map(|end| (x, end)))
+        .max_by_key(|(x, _)| x.source.components().count())
+        .map(|(x, end)| x.dest.join(end))
+}
+
 impl CoverageResult {
     pub fn max_hits(&self) -> usize {
         self.hits.values().max().copied().unwrap_or_default()
     }
 
     pub fn insert(&mut self, loc: SourceLocation, count: usize) {
+        add_count(&mut self.hits, loc, count, false);
+    }
+
+    /// Adds the count for a region from a single byte coverage profile, as the counts only show
+    /// whether a region was executed they're combined with a logical or
+    pub fn insert_single_byte(&mut self, loc: SourceLocation, count: usize) {
+        add_count(&mut self.hits, loc, count, true);
+    }
+
+    /// Adds the count for a gap region, `single_byte` combines the counts the same as
+    /// `CoverageResult::insert_single_byte`
+    pub fn insert_gap(&mut self, loc: SourceLocation, count: usize, single_byte: bool) {
+        add_count(&mut self.gaps, loc, count, single_byte);
+    }
+
+    /// Adds the count for a region keeping its kind. Skipped regions aren't instrumented so have
+    /// no count and gap regions only set the count of lines without any other region, see
+    /// `CoverageResult::hits_for_line`.
+    pub fn insert_region(
+        &mut self,
+        loc: SourceLocation,
+        kind: RegionKind,
+        count: usize,
+        single_byte: bool,
+    ) {
+        self.kinds.insert(loc.clone(), kind);
+        match kind {
+            RegionKind::Skipped => {}
+            RegionKind::Gap => self.insert_gap(loc, count, single_byte),
+            _ => add_count(&mut self.hits, loc, count, single_byte),
+        }
+    }
+
+    /// The kind of the region at a location, regions in `hits` added without a kind are code
+    /// regions
+    pub fn region_kind(&self, loc: &SourceLocation) -> Option<RegionKind> {
+        match self.kinds.get(loc) {
+            Some(kind) => Some(*kind),
+            None if self.gaps.contains_key(loc) => Some(RegionKind::Gap),
+            None if self.hits.contains_key(loc) => Some(RegionKind::Code),
+            None => None,
+        }
+    }
+
+    /// The code regions and their counts, like `llvm-cov report` only these are counted in the
+    /// region coverage. Expansion, gap and skipped regions are left out.
+    pub fn code_regions(&self) -> impl Iterator<Item = (&SourceLocation, usize)> {
         self.hits
+            .iter()
+            .filter(move |(k, _)| self.region_kind(k) == Some(RegionKind::Code))
+            .map(|(k, v)| (k, *v))
+    }
+
+    /// Locations of the skipped regions, such as code removed by the preprocessor
+    pub fn skipped_regions(&self) -> impl Iterator<Item = &SourceLocation> {
+        self.kinds
+            .iter()
+            .filter(|(_, kind)| **kind == RegionKind::Skipped)
+            .map(|(k, _)| k)
+    }
+
+    /// Adds the counts for a branch, if the branch is already present (such as from another
+    /// instantiation of a generic function) the counts are summed
+    pub fn insert_branch(&mut self, loc: SourceLocation, count: BranchCount) {
+        self.branches
             .entry(loc)
-            .and_modify(|x| *x = x.saturating_add(count))
+            .and_modify(|x| {
+                x.true_count = x.true_count.saturating_add(count.true_count);
+                x.false_count = x.false_count.saturating_add(count.false_count);
+                x.true_folded &= count.true_folded;
+                x.false_folded &= count.false_folded;
+            })
             .or_insert(count);
     }
 
-    /// For line coverage just finds first region that mentions this line
-    pub fn hits_for_line(&self, line: usize) -> Option<usize> {
+    /// Total number of branches which aren't folded
+    pub fn total_branches(&self) -> usize {
+        self.branches.values().map(|x| x.num_branches()).sum()
+    }
+
+    /// Number of branches which were taken at least once
+    pub fn covered_branches(&self) -> usize {
+        self.branches.values().map(|x| x.covered_branches()).sum()
+    }
+
+    /// The hits for every line with a region, the count for each line matches
+    /// `CoverageResult::hits_for_line`
+    pub fn line_hits(&self) -> BTreeMap<usize, usize> {
+        let lines = self
+            .hits
+            .keys()
+            .chain(self.gaps.keys())
+            .flat_map(|x| x.line_start..=x.line_end)
+            .collect::<BTreeSet<_>>();
+        lines
+            .into_iter()
+            .filter_map(|line| self.hits_for_line(line).map(|count| (line, count)))
+            .collect()
+    }
+
+    /// The last line covered by any region including skipped regions, `0` if there are no regions
+    pub fn max_line(&self) -> usize {
         self.hits
+            .keys()
+            .chain(self.gaps.keys())
+            .chain(self.kinds.keys())
+            .map(|x| x.line_end)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The count of every line from the first line of the file to `CoverageResult::max_line`
+    /// using `CoverageResult::hits_for_line`, lines without a count are `None`
+    pub fn line_counts(&self) -> impl Iterator<Item = (usize, Option<usize>)> + '_ {
+        (1..=self.max_line()).map(move |line| (line, self.hits_for_line(line)))
+    }
+
+    /// The count for a line following llvm's `LineCoverageStats`, this is the largest count of the
+    /// innermost region still active at the start of the line and the regions starting on the
+    /// line. Gap regions starting on the line don't contribute their count. Lines in a skipped
+    /// region, or starting with one, have no count.
+    pub fn hits_for_line(&self, line: usize) -> Option<usize> {
+        if let Some(column) = self
+            .skipped_regions()
+            .filter(|k| k.line_start == line)
+            .map(|k| k.column_start)
+            .min()
+        {
+            let counted_before = self.hits.keys().chain(self.gaps.keys()).any(|k| {
+                (k.line_start == line && k.column_start < column)
+                    || (k.line_end == line && k.column_end < column)
+            });
+            if !counted_before {
+                return None;
+            }
+        }
+        let wrapped = self.innermost_region(|k| k.line_start < line && k.line_end >= line);
+        let starts = self
+            .hits
             .iter()
-            .find(|(k, _)| k.line_start <= line && k.line_end >= line)
+            .filter(|(k, _)| k.line_start == line)
             .map(|(_, v)| *v)
+            .max();
+        match wrapped.max(starts) {
+            None if self.gaps.keys().any(|k| k.line_start == line) => Some(0),
+            count => count,
+        }
+    }
+
+    /// The count of the innermost region containing the position, this is the region llvm's
+    /// coverage segments would give for the position. Positions in a skipped region or outside
+    /// of every region have no count.
+    pub fn hits_for_position(&self, line: usize, column: usize) -> Option<usize> {
+        self.innermost_region(|k| k.contains(line, column))
+    }
+
+    /// Finds the count of the innermost region matching the filter. Regions nest so this is the
+    /// one which starts last, or ends first if they start in the same place.
+    fn innermost_region(&self, filter: impl Fn(&SourceLocation) -> bool) -> Option<usize> {
+        self.hits
+            .iter()
+            .chain(self.gaps.iter())
+            .map(|(k, v)| (k, Some(*v)))
+            .chain(self.skipped_regions().map(|k| (k, None)))
+            .filter(|(k, _)| filter(k))
+            .max_by_key(|(k, _)| {
+                (
+                    k.line_start,
+                    k.column_start,
+                    Reverse((k.line_end, k.column_end)),
+                )
+            })
+            .and_then(|(_, v)| v)
+    }
+}
+
+fn add_count(
+    counts: &mut BTreeMap<SourceLocation, usize>,
+    loc: SourceLocation,
+    count: usize,
+    single_byte: bool,
+) {
+    if single_byte {
+        let count = (count > 0) as usize;
+        counts
+            .entry(loc)
+            .and_modify(|x| *x = (*x > 0 || count > 0) as usize)
+            .or_insert(count);
+    } else {
+        counts
+            .entry(loc)
+            .and_modify(|x| *x = x.saturating_add(count))
+            .or_insert(count);
+    }
+}
+
+/// llvm stores columns as 32 bit integers so any larger column is exported as the end of the line
+fn json_column(column: usize) -> usize {
+    column.min(WHOLE_LINE_COLUMN)
+}
+
+/// The ids of the files a function's regions are in, ordered by first use which matches the
+/// function's virtual file ids. Region file ids index the filenames of the whole coverage mapping
+/// whereas llvm-cov exports them as an index into the files used by the function.
+fn function_file_ids(function: &FunctionCoverageRecord) -> Vec<usize> {
+    let mut file_ids = vec![];
+    for region in function
+        .counted_regions
+        .iter()
+        .chain(function.counted_branch_regions.iter())
+    {
+        if !file_ids.contains(&region.region.file_id) {
+            file_ids.push(region.region.file_id);
+        }
     }
+    file_ids
+}
+
+fn json_filenames(function: &FunctionCoverageRecord, file_ids: &[usize]) -> Vec<String> {
+    file_ids
+        .iter()
+        .filter_map(|x| function.filenames.get(*x).cloned())
+        .collect()
+}
+
+fn json_file_id(file_ids: &[usize], file_id: usize) -> usize {
+    file_ids
+        .iter()
+        .position(|x| *x == file_id)
+        .unwrap_or(file_id)
+}
+
+/// Regions are `[line_start, column_start, line_end, column_end, count, file_id,
+/// expanded_file_id, kind]`
+fn json_region(region: &CountedRegion, file_ids: &[usize]) -> Value {
+    let loc = &region.region.loc;
+    json!([
+        loc.line_start,
+        json_column(loc.column_start),
+        loc.line_end,
+        json_column(loc.column_end),
+        region.execution_count,
+        json_file_id(file_ids, region.region.file_id),
+        region.region.expanded_file_id,
+        region.region.kind as usize
+    ])
+}
+
+/// Branches are `[line_start, column_start, line_end, column_end, true_count, false_count,
+/// file_id, expanded_file_id, kind]`
+fn json_branch(region: &CountedRegion, file_ids: &[usize]) -> Value {
+    let loc = &region.region.loc;
+    json!([
+        loc.line_start,
+        json_column(loc.column_start),
+        loc.line_end,
+        json_column(loc.column_end),
+        region.execution_count,
+        region.false_execution_count,
+        json_file_id(file_ids, region.region.file_id),
+        region.region.expanded_file_id,
+        region.region.kind as usize
+    ])
+}
+
+fn json_summary(summary: &CoverageSummary) -> Value {
+    let count = |count: &CoverageCount| {
+        json!({
+            "count": count.total,
+            "covered": count.covered,
+            "percent": count.percent().unwrap_or_default(),
+        })
+    };
+    let count_with_missed = |x: &CoverageCount| {
+        let mut value = count(x);
+        value["notcovered"] = json!(x.missed());
+        value
+    };
+    json!({
+        "lines": count(&summary.lines),
+        "functions": count(&summary.functions),
+        "instantiations": count(&summary.instantiations),
+        "regions": count_with_missed(&summary.regions),
+        "branches": count_with_missed(&summary.branches),
+    })
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Error)]
@@ -127,4 +951,214 @@ mod tests {
             .files
             .contains_key(&PathBuf::from("/home/root/src/lib.rs")));
     }
+
+    #[test]
+    fn multiple_remappings() {
+        let mut report = CoverageReport::default();
+        for path in &[
+            "/build/vendor/foo/src/lib.rs",
+            "/build/src/main.rs",
+            "/other/lib.rs",
+        ] {
+            report
+                .files
+                .insert(PathBuf::from(path), CoverageResult::default());
+        }
+        report.functions.push(FunctionCoverageRecord {
+            name: "foo".to_string(),
+            filenames: vec!["/build/vendor/foo/src/lib.rs".to_string()],
+            counted_regions: vec![],
+            counted_branch_regions: vec![],
+            execution_count: 0,
+            unresolved_expressions: vec![],
+        });
+
+        let remappings = ["/build,/home/me/project", "/build/vendor/foo,/home/me/foo"]
+            .iter()
+            .map(|x| x.parse::<PathRemapping>().unwrap())
+            .collect::<Vec<_>>();
+        report.apply_remappings(&remappings);
+
+        let paths = report.files.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/home/me/foo/src/lib.rs"),
+                PathBuf::from("/home/me/project/src/main.rs"),
+                PathBuf::from("/other/lib.rs"),
+            ]
+        );
+        assert_eq!(
+            report.functions[0].filenames,
+            vec!["/home/me/foo/src/lib.rs".to_string()]
+        );
+    }
+
+    #[test]
+    fn gap_regions() {
+        let loc = |line_start, column_start, line_end, column_end| SourceLocation {
+            line_start,
+            column_start,
+            line_end,
+            column_end,
+        };
+        // fn check(x: u32) -> u32 {
+        //     if x > 5 {
+        //         return 1;
+        //     }
+        //     x
+        // }
+        let mut result = CoverageResult::default();
+        result.insert(loc(1, 1, 2, 13), 8);
+        result.insert(loc(3, 9, 3, 17), 2);
+        result.insert(loc(5, 5, 6, 2), 6);
+        // The gap after the return isn't executed but the rest of the function is
+        result.insert_gap(loc(3, 17, 5, 5), 0, false);
+
+        assert_eq!(result.hits_for_line(3), Some(2));
+        assert_eq!(result.hits_for_line(4), Some(0));
+        assert_eq!(result.hits_for_line(5), Some(6));
+        assert_eq!(result.hits_for_line(7), None);
+        assert_eq!(result.hits_for_position(2, 5), Some(8));
+        assert_eq!(result.hits_for_position(3, 5), None);
+        assert_eq!(result.hits_for_position(3, 9), Some(2));
+        assert_eq!(result.hits_for_position(3, 16), Some(2));
+        // The gap starts where the return ends
+        assert_eq!(result.hits_for_position(3, 17), Some(0));
+        assert_eq!(result.hits_for_position(4, 1), Some(0));
+        assert_eq!(result.hits_for_position(5, 5), Some(6));
+        assert_eq!(result.hits_for_position(6, 3), None);
+        let expected = [(1, 8), (2, 8), (3, 2), (4, 0), (5, 6), (6, 6)];
+        assert_eq!(
+            result.line_hits(),
+            expected.iter().copied().collect::<BTreeMap<_, _>>()
+        );
+
+        // Gap regions aren't counted as regions
+        let mut report = CoverageReport::default();
+        report.files.insert(PathBuf::from("lib.rs"), result);
+        assert_eq!(report.summary().total.regions.total, 3);
+    }
+
+    #[test]
+    fn skipped_and_expansion_regions() {
+        let loc = |line_start, column_start, line_end, column_end| SourceLocation {
+            line_start,
+            column_start,
+            line_end,
+            column_end,
+        };
+        // int main() {
+        //   int x = 0; // #if 0
+        // #if 0
+        //   x = 1;
+        // #endif
+        //   FOO(x);
+        //   return x;
+        // }
+        let mut result = CoverageResult::default();
+        result.insert_region(loc(1, 12, 8, 2), RegionKind::Code, 3, false);
+        result.insert_region(loc(2, 3, 2, 13), RegionKind::Code, 3, false);
+        result.insert_region(loc(2, 14, 2, 23), RegionKind::Skipped, 0, false);
+        result.insert_region(loc(3, 1, 5, 7), RegionKind::Skipped, 0, false);
+        result.insert_region(loc(6, 3, 6, 6), RegionKind::Expansion, 3, false);
+
+        assert_eq!(result.skipped_regions().count(), 2);
+        assert_eq!(
+            result.region_kind(&loc(6, 3, 6, 6)),
+            Some(RegionKind::Expansion)
+        );
+        // A skipped region after a counted region on the same line doesn't stop it being counted
+        assert_eq!(result.hits_for_line(2), Some(3));
+        for line in 3..=5 {
+            assert_eq!(result.hits_for_line(line), None);
+        }
+        assert_eq!(result.hits_for_position(2, 5), Some(3));
+        assert_eq!(result.hits_for_position(2, 20), None);
+        assert_eq!(result.hits_for_position(4, 3), None);
+        assert_eq!(result.hits_for_position(7, 3), Some(3));
+        assert_eq!(result.hits_for_line(6), Some(3));
+        let expected = [(1, 3), (2, 3), (6, 3), (7, 3), (8, 3)];
+        assert_eq!(
+            result.line_hits(),
+            expected.iter().copied().collect::<BTreeMap<_, _>>()
+        );
+
+        // Only code regions are counted as regions
+        let mut report = CoverageReport::default();
+        report.files.insert(PathBuf::from("main.c"), result);
+        let summary = report.summary().total;
+        assert_eq!(summary.regions.total, 2);
+        assert_eq!(summary.lines.total, 5);
+    }
+
+    #[test]
+    fn overlapping_line_regions() {
+        let loc = |line_start, column_start, line_end, column_end| SourceLocation {
+            line_start,
+            column_start,
+            line_end,
+            column_end,
+        };
+        // fn from_days(days: u64) -> Duration {
+        //     if days == 0 {
+        //         return Duration::from_secs(
+        //             0) } Duration::from_secs(
+        //         days * 24 * 60 * 60)
+        // }
+        let mut result = CoverageResult::default();
+        result.insert(loc(1, 1, 2, 17), 1);
+        result.insert(loc(3, 16, 4, 15), 0);
+        result.insert(loc(4, 18, 5, 29), 1);
+        result.insert(loc(6, 1, 6, 2), 1);
+
+        // The return is never executed but the trailing expression starting on the same line is
+        assert_eq!(result.hits_for_line(3), Some(0));
+        assert_eq!(result.hits_for_line(4), Some(1));
+        assert_eq!(result.hits_for_line(5), Some(1));
+
+        // Inside an unexecuted block the innermost region is used over the function body
+        let mut result = CoverageResult::default();
+        result.insert(loc(1, 1, 6, 2), 4);
+        result.insert(loc(2, 20, 4, 6), 0);
+        assert_eq!(result.hits_for_line(3), Some(0));
+        assert_eq!(result.hits_for_line(4), Some(0));
+        assert_eq!(result.hits_for_line(5), Some(4));
+    }
+
+    #[test]
+    fn folded_branches() {
+        let mut result = CoverageResult::default();
+        let loc = SourceLocation {
+            line_start: 2,
+            column_start: 8,
+            line_end: 2,
+            column_end: 13,
+        };
+        result.insert_branch(
+            loc.clone(),
+            BranchCount {
+                true_count: 4,
+                false_count: 0,
+                true_folded: false,
+                false_folded: true,
+            },
+        );
+        assert_eq!(result.total_branches(), 1);
+        assert_eq!(result.covered_branches(), 1);
+
+        // Another instantiation where the false branch isn't folded
+        result.insert_branch(
+            loc.clone(),
+            BranchCount {
+                true_count: 1,
+                false_count: 0,
+                true_folded: false,
+                false_folded: false,
+            },
+        );
+        assert_eq!(result.branches[&loc].true_count, 5);
+        assert_eq!(result.total_branches(), 2);
+        assert_eq!(result.covered_branches(), 1);
+    }
 }
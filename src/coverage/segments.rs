@@ -0,0 +1,334 @@
+use crate::coverage::*;
+use std::cmp::Reverse;
+
+type LineCol = (usize, usize);
+
+fn start_loc(region: &CountedRegion) -> LineCol {
+    (region.region.loc.line_start, region.region.loc.column_start)
+}
+
+fn end_loc(region: &CountedRegion) -> LineCol {
+    (region.region.loc.line_end, region.region.loc.column_end)
+}
+
+/// Builds the sorted list of `CoverageSegment`s for the regions in a single file. Each segment
+/// starts where a region is entered or exited and has the count of the innermost region active at
+/// that point. Branch regions should not be included. This follows llvm's `SegmentBuilder`.
+pub fn build_segments(regions: &[CountedRegion]) -> Vec<CoverageSegment> {
+    let mut regions = regions.to_vec();
+    sort_nested_regions(&mut regions);
+    let regions = combine_regions(regions);
+
+    let mut builder = SegmentBuilder::default();
+    builder.build(&regions);
+    builder.segments
+}
+
+/// Gets the segments entering a region on `line` with a different count to the line, these are
+/// the regions marked by `llvm-cov show --show-regions`. The last segment on the line is left out
+/// as it continues onto the following lines. `segments` must be sorted like the output of
+/// `build_segments`.
+pub fn region_markers(
+    segments: &[CoverageSegment],
+    line: usize,
+    line_count: usize,
+) -> Vec<&CoverageSegment> {
+    let start = segments.partition_point(|x| x.line < line);
+    let end = segments.partition_point(|x| x.line <= line);
+    let line_segments = &segments[start..end];
+    match line_segments.split_last() {
+        Some((_, segments)) => segments
+            .iter()
+            .filter(|x| x.is_region_entry && x.has_count && x.count != line_count)
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Sorts regions by their start location. When a region contains another the outer region comes
+/// first and regions covering the same area are ordered by kind so code regions take precedence
+/// over expansions and expansions over skipped regions.
+fn sort_nested_regions(regions: &mut [CountedRegion]) {
+    regions.sort_by_key(|x| (start_loc(x), Reverse(end_loc(x)), x.region.kind));
+}
+
+/// Merges regions which cover the same area. Only the counts of regions of the same kind as the
+/// first region for the area are summed, this avoids counting macros which fully expand to another
+/// macro twice while still accumulating nested expansions used multiple times. Single byte
+/// coverage counts only record whether a region ran so they're combined with a logical or.
+fn combine_regions(regions: Vec<CountedRegion>) -> Vec<CountedRegion> {
+    let mut result: Vec<CountedRegion> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match result.last_mut() {
+            Some(active)
+                if start_loc(active) == start_loc(&region)
+                    && end_loc(active) == end_loc(&region) =>
+            {
+                if active.region.kind == region.region.kind {
+                    active.execution_count = if region.has_single_byte_coverage {
+                        (active.execution_count > 0 || region.execution_count > 0) as usize
+                    } else {
+                        active
+                            .execution_count
+                            .saturating_add(region.execution_count)
+                    };
+                }
+            }
+            _ => result.push(region),
+        }
+    }
+    result
+}
+
+#[derive(Default)]
+struct SegmentBuilder {
+    segments: Vec<CoverageSegment>,
+}
+
+impl SegmentBuilder {
+    /// Emit a segment with the count from `region` starting at `loc`
+    fn start_segment(
+        &mut self,
+        region: &CountedRegion,
+        loc: LineCol,
+        is_region_entry: bool,
+        emit_skipped_region: bool,
+    ) {
+        let has_count = !emit_skipped_region && region.region.kind != RegionKind::Skipped;
+
+        // If the new segment wouldn't affect coverage rendering skip it
+        if !is_region_entry && !emit_skipped_region {
+            if let Some(last) = self.segments.last() {
+                if last.has_count == has_count
+                    && last.count == region.execution_count
+                    && !last.is_region_entry
+                {
+                    return;
+                }
+            }
+        }
+
+        self.segments.push(CoverageSegment {
+            line: loc.0,
+            col: loc.1,
+            count: if has_count { region.execution_count } else { 0 },
+            has_count,
+            is_region_entry,
+            is_gap_region: has_count && region.region.kind == RegionKind::Gap,
+        });
+    }
+
+    /// Emit segments for the active regions from `first_completed` onwards which end before `loc`.
+    /// If `loc` is `None` all the active regions are completed. The completed regions are then
+    /// removed from the active regions.
+    fn complete_regions_until(
+        &mut self,
+        loc: Option<LineCol>,
+        active: &mut Vec<&CountedRegion>,
+        first_completed: usize,
+    ) {
+        // Sorting the completed regions by end location makes it simple to emit the closing
+        // segments in order
+        active[first_completed..].sort_by_key(|x| end_loc(x));
+
+        for i in (first_completed + 1)..active.len() {
+            let mut completed = active[i];
+            let segment_loc = end_loc(active[i - 1]);
+
+            // Don't emit any more segments if they start where the new region begins
+            if loc == Some(segment_loc) {
+                break;
+            }
+            // Don't emit a segment if the next completed region ends at the same location
+            if segment_loc == end_loc(completed) {
+                continue;
+            }
+            // Use the count from the last completed region which ends at this location
+            for next in &active[(i + 1)..] {
+                if end_loc(completed) == end_loc(next) {
+                    completed = next;
+                }
+            }
+            self.start_segment(completed, segment_loc, false, false);
+        }
+
+        let last = active[active.len() - 1];
+        if first_completed > 0 && loc != Some(end_loc(last)) {
+            // If there's a gap between the end of the last completed region and the start of the
+            // new region the next active region fills the gap
+            self.start_segment(active[first_completed - 1], end_loc(last), false, false);
+        } else if first_completed == 0 && loc != Some(end_loc(last)) {
+            // With no more active regions emit a skipped segment so gaps between functions are
+            // marked correctly
+            self.start_segment(last, end_loc(last), false, true);
+        }
+
+        active.truncate(first_completed);
+    }
+
+    fn build(&mut self, regions: &[CountedRegion]) {
+        let mut active: Vec<&CountedRegion> = vec![];
+        for (index, region) in regions.iter().enumerate() {
+            let current_start = start_loc(region);
+
+            // Active regions which end before the current region starts are completed. The
+            // partition is stable so the remaining active regions keep their order
+            let (still_active, completed): (Vec<_>, Vec<_>) =
+                active.iter().partition(|x| end_loc(x) > current_start);
+            if !completed.is_empty() {
+                let first_completed = still_active.len();
+                active = still_active.into_iter().chain(completed).collect();
+                self.complete_regions_until(Some(current_start), &mut active, first_completed);
+            }
+
+            let is_gap = region.region.kind == RegionKind::Gap;
+
+            if current_start == end_loc(region) {
+                // Avoid making zero-length regions active. If it's the last region emit a skipped
+                // segment otherwise use the count of the enclosing region
+                let skipped =
+                    index + 1 == regions.len() || region.region.kind == RegionKind::Skipped;
+                let enclosing = active.last().copied().unwrap_or(region);
+                self.start_segment(enclosing, current_start, !is_gap, skipped);
+                if skipped {
+                    if let Some(enclosing) = active.last() {
+                        self.start_segment(enclosing, current_start, false, false);
+                    }
+                }
+                continue;
+            }
+
+            // Emit a segment if the next region doesn't start at the same location as this one
+            if index + 1 == regions.len() || current_start != start_loc(&regions[index + 1]) {
+                self.start_segment(region, current_start, !is_gap, false);
+            }
+
+            active.push(region);
+        }
+
+        if !active.is_empty() {
+            self.complete_regions_until(None, &mut active, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(
+        kind: RegionKind,
+        start: LineCol,
+        end: LineCol,
+        execution_count: usize,
+    ) -> CountedRegion {
+        CountedRegion {
+            execution_count,
+            false_execution_count: 0,
+            folded: false,
+            has_single_byte_coverage: false,
+            region: CounterMappingRegion {
+                kind,
+                count: Counter::default(),
+                false_count: Counter::default(),
+                file_id: 0,
+                expanded_file_id: 0,
+                loc: SourceLocation {
+                    line_start: start.0,
+                    column_start: start.1,
+                    line_end: end.0,
+                    column_end: end.1,
+                },
+            },
+        }
+    }
+
+    fn segment(
+        loc: LineCol,
+        count: usize,
+        has_count: bool,
+        is_region_entry: bool,
+        is_gap_region: bool,
+    ) -> CoverageSegment {
+        CoverageSegment {
+            line: loc.0,
+            col: loc.1,
+            count,
+            has_count,
+            is_region_entry,
+            is_gap_region,
+        }
+    }
+
+    #[test]
+    fn markers_for_line() {
+        let segments = vec![
+            segment((1, 1), 5, true, true, false),
+            segment((2, 5), 2, true, true, false),
+            segment((2, 9), 5, true, false, false),
+            segment((2, 12), 0, false, true, false),
+            segment((2, 15), 5, true, true, false),
+            segment((2, 20), 0, true, true, false),
+            segment((4, 2), 0, false, false, false),
+        ];
+        // Regions with the line count, exits and skipped regions aren't marked and neither is the
+        // last segment on the line
+        let markers = region_markers(&segments, 2, 5);
+        assert_eq!(markers, vec![&segments[1]]);
+        assert!(region_markers(&segments, 1, 0).is_empty());
+        assert!(region_markers(&segments, 3, 5).is_empty());
+    }
+
+    #[test]
+    fn nested_and_gap_regions() {
+        // Regions are deliberately unsorted to check they're ordered before building segments
+        let regions = vec![
+            region(RegionKind::Code, (6, 9), (8, 6), 3),
+            region(RegionKind::Code, (1, 1), (10, 2), 5),
+            region(RegionKind::Gap, (4, 6), (6, 9), 3),
+            region(RegionKind::Code, (2, 5), (4, 6), 2),
+            region(RegionKind::Skipped, (9, 1), (9, 20), 0),
+        ];
+
+        let expected = vec![
+            segment((1, 1), 5, true, true, false),
+            segment((2, 5), 2, true, true, false),
+            // The gap region starts where the nested region ends so the count goes straight to it
+            segment((4, 6), 3, true, false, true),
+            segment((6, 9), 3, true, true, false),
+            // Exiting back to the outer region
+            segment((8, 6), 5, true, false, false),
+            segment((9, 1), 0, false, true, false),
+            segment((9, 20), 5, true, false, false),
+            // No active regions after the function
+            segment((10, 2), 0, false, false, false),
+        ];
+
+        assert_eq!(build_segments(&regions), expected);
+    }
+
+    #[test]
+    fn duplicate_regions_combined() {
+        let regions = vec![
+            region(RegionKind::Code, (1, 1), (3, 2), 2),
+            region(RegionKind::Code, (1, 1), (3, 2), 4),
+        ];
+
+        let expected = vec![
+            segment((1, 1), 6, true, true, false),
+            segment((3, 2), 0, false, false, false),
+        ];
+
+        assert_eq!(build_segments(&regions), expected);
+
+        let single_byte = regions
+            .into_iter()
+            .map(|mut x| {
+                x.execution_count = 1;
+                x.has_single_byte_coverage = true;
+                x
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(build_segments(&single_byte)[0].count, 1);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::instrumentation_profile::{types::*, ParseResult};
+use crate::instrumentation_profile::{get_num_padding_bytes, types::*, ParseResult};
 use indexmap::IndexMap;
 use nom::{
+    bytes::complete::take,
     error::{ErrorKind, ParseError, VerboseError, VerboseErrorKind},
     number::complete::*,
 };
@@ -16,14 +17,14 @@ struct KeyDataLen {
 #[derive(Clone, Debug)]
 pub(crate) struct HashTable(pub IndexMap<(u64, String), InstrProfRecord>);
 
-fn read_key_data_len(input: &[u8]) -> ParseResult<KeyDataLen> {
+fn read_key_data_len(input: &[u8]) -> ParseResult<'_, KeyDataLen> {
     let (bytes, key_len) = le_u64(input)?;
     let (bytes, data_len) = le_u64(bytes)?;
     let res = KeyDataLen { key_len, data_len };
     Ok((bytes, res))
 }
 
-fn read_key(input: &[u8], key_len: usize) -> ParseResult<Cow<'_, str>> {
+fn read_key(input: &[u8], key_len: usize) -> ParseResult<'_, Cow<'_, str>> {
     if key_len > input.len() {
         Err(nom::Err::Failure(VerboseError::from_error_kind(
             &input[input.len()..],
@@ -35,11 +36,62 @@ fn read_key(input: &[u8], key_len: usize) -> ParseResult<Cow<'_, str>> {
     }
 }
 
+/// Reads the `ValueProfData` for a record, this is a total size and number of kinds followed by a
+/// `ValueProfRecord` for each kind. Unlike raw profiles indirect call targets are already stored
+/// as the MD5 hash of the target's name.
+fn read_value_data(input: &[u8]) -> ParseResult<'_, Option<Box<ValueProfDataRecord>>> {
+    let (bytes, total_size) = le_u32(input)?;
+    let (mut bytes, num_value_kinds) = le_u32(bytes)?;
+    if (total_size as usize) < 8 || total_size as usize > input.len() {
+        let errors = vec![(
+            input,
+            VerboseErrorKind::Context("invalid value profile data size"),
+        )];
+        return Err(nom::Err::Failure(VerboseError { errors }));
+    }
+    if num_value_kinds == 0 {
+        return Ok((&input[(total_size as usize)..], None));
+    }
+    let mut value_data = Box::<ValueProfDataRecord>::default();
+    for _ in 0..num_value_kinds {
+        let (b, kind) = le_u32(bytes)?;
+        let (b, num_value_sites) = le_u32(b)?;
+        let (b, site_counts) = take(num_value_sites as usize)(b)?;
+        // The site count array is padded so the value data is 8 byte aligned
+        let padding = get_num_padding_bytes(8 + num_value_sites as u64) as usize;
+        let (mut b, _) = take(padding)(b)?;
+        let mut sites = Vec::with_capacity(site_counts.len());
+        for num_values in site_counts {
+            let mut site = Vec::with_capacity(*num_values as usize);
+            for _ in 0..*num_values {
+                let (rest, value) = le_u64(b)?;
+                let (rest, count) = le_u64(rest)?;
+                b = rest;
+                site.push(InstrProfValueData { value, count });
+            }
+            site.sort_by_key(|x| std::cmp::Reverse(x.count));
+            sites.push(site);
+        }
+        match kind {
+            0 => value_data.indirect_callsites = sites,
+            1 => value_data.mem_op_sizes = sites,
+            // Kinds from newer versions of llvm aren't modelled
+            _ => debug!("Skipped value data for unknown value kind {}", kind),
+        }
+        bytes = b;
+    }
+    Ok((&input[(total_size as usize)..], Some(value_data)))
+}
+
+/// Reads the records for a key. Indexed profiles always store 8 byte counters, even byte coverage
+/// profiles are written with the single byte counters converted to counts of 0 or 1.
+// `is_multiple_of` isn't available on our MSRV
+#[allow(clippy::manual_is_multiple_of)]
 fn read_value(
     version: u64,
     mut input: &[u8],
     data_len: usize,
-) -> ParseResult<(u64, InstrProfRecord)> {
+) -> ParseResult<'_, (u64, InstrProfRecord)> {
     if data_len % 8 != 0 {
         // Element is corrupted, it should be aligned
         let errors = vec![(
@@ -83,28 +135,25 @@ fn read_value(
         if input.len() <= end_len {
             break;
         }
+        // Version 11 added the MC/DC bitmap bytes, each one stored as a u64. These aren't used so
+        // are skipped
+        if version >= 11 {
+            let (bytes, bitmap_len) = le_u64(input)?;
+            let (bytes, _) = take((bitmap_len as usize).saturating_mul(8))(bytes)?;
+            input = bytes;
+        }
 
         // If the version is > v2 then there can also be value profiling data so lets try and parse
         // that now
-        let (bytes, total_size) = le_u32(input)?;
-        if bytes.len() <= end_len {
-            break;
-        }
-        let (bytes, num_value_kinds) = le_u32(bytes)?;
-        // Here it's just less than because we don't need to read anything else so if it's equal to
-        // we're good
-        if bytes.len() < end_len {
-            break;
-        }
-        input = bytes;
-        let value_prof_data = ValueProfData {
-            total_size,
-            num_value_kinds,
-        };
-        if value_prof_data.num_value_kinds > 0 && version > 2 {
-            // If we actually want to change data in future get result.last_mut() and change it
-            // there
-            break;
+        if version > 2 {
+            let (bytes, data) = read_value_data(input)?;
+            if let Some((_, record)) = result.last_mut() {
+                record.data = data;
+            }
+            input = bytes;
+            if input.len() <= end_len {
+                break;
+            }
         }
     }
     if result.is_empty() {
@@ -129,7 +178,6 @@ impl HashTable {
         _offset: usize,
         bucket_start: usize,
     ) -> ParseResult<'a, Self> {
-        assert!(bucket_start > 0);
         let (bytes, num_buckets) = le_u64(&input[bucket_start..])?;
         debug!("Number of hashtable buckets: {}", num_buckets);
         let (_bytes, mut num_entries) = le_u64(bytes)?;
@@ -0,0 +1,314 @@
+use crate::instrumentation_profile::types::*;
+use crate::instrumentation_profile::{InstrProfReader, ParseResult};
+use nom::error::{ContextError, ErrorKind, ParseError, VerboseError};
+use nom::number::{complete::u32 as nom_u32, Endianness};
+use rustc_hash::FxHashMap;
+use std::io::Read;
+use tracing::debug;
+
+/// "gcda" read as a 32 bit integer in the endianness of the file
+const GCDA_MAGIC: u32 = 0x6763_6461;
+/// "gcno" read as a 32 bit integer in the endianness of the file
+const GCNO_MAGIC: u32 = 0x6763_6e6f;
+const TAG_FUNCTION: u32 = 0x0100_0000;
+const TAG_ARC_COUNTS: u32 = 0x01a1_0000;
+const TAG_OBJECT_SUMMARY: u32 = 0xa100_0000;
+
+/// Reads the gcov data files (gcda) written by gcc's `--coverage` instrumentation. The arc
+/// counters of each function become the counts of a record. Data files only identify functions by
+/// an id unique within the object file so records are named `<stamp>_<id>` in hex, where the stamp
+/// identifies the object file. [`GccProfReader::parse_with_notes`] can be used to name the records
+/// from the notes file (gcno) generated at compile time. Gcc 4.7 onwards is supported.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct GccProfReader;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Header {
+    endianness: Endianness,
+    /// The version as it's stored in the file, this is the gcc version as 4 characters
+    pub version: u32,
+    /// Stamp of the object file the data is for, this matches the stamp in the notes file
+    pub stamp: u32,
+    /// Checksum of the object file, present from gcc 12
+    pub checksum: Option<u32>,
+}
+
+impl Header {
+    /// The major version of gcc that wrote the file
+    pub fn major_version(&self) -> u32 {
+        let [major, minor, _, _] = self.version.to_be_bytes();
+        if major >= b'A' {
+            u32::from(major - b'A') * 10 + u32::from(minor.wrapping_sub(b'0'))
+        } else {
+            u32::from(major.wrapping_sub(b'0'))
+        }
+    }
+
+    /// From gcc 12 record lengths are in bytes rather than 32 bit words
+    fn lengths_in_bytes(&self) -> bool {
+        self.major_version() >= 12
+    }
+
+    fn read_u32<'a>(&self, input: &'a [u8]) -> ParseResult<'a, u32> {
+        nom_u32(self.endianness)(input)
+    }
+}
+
+fn failure<'a>(input: &'a [u8], context: &'static str) -> nom::Err<VerboseError<&'a [u8]>> {
+    let error = VerboseError::from_error_kind(input, ErrorKind::Satisfy);
+    nom::Err::Failure(VerboseError::add_context(input, context, error))
+}
+
+fn take_bytes(input: &[u8], len: usize) -> ParseResult<'_, &[u8]> {
+    if len > input.len() {
+        Err(nom::Err::Failure(VerboseError::from_error_kind(
+            &input[input.len()..],
+            ErrorKind::Eof,
+        )))
+    } else {
+        Ok((&input[len..], &input[..len]))
+    }
+}
+
+fn file_endianness(input: &[u8], magic: u32) -> Option<Endianness> {
+    if input.len() < 4 {
+        None
+    } else if input[..4] == magic.to_le_bytes() {
+        Some(Endianness::Little)
+    } else if input[..4] == magic.to_be_bytes() {
+        Some(Endianness::Big)
+    } else {
+        None
+    }
+}
+
+/// Parses the header shared by the notes and data files
+fn parse_gcov_header(input: &[u8], magic: u32) -> ParseResult<'_, Header> {
+    let endianness = match file_endianness(input, magic) {
+        Some(e) => e,
+        None => return Err(failure(input, "invalid gcov magic")),
+    };
+    let (bytes, _) = take_bytes(input, 4)?;
+    let (bytes, version) = nom_u32(endianness)(bytes)?;
+    let (bytes, stamp) = nom_u32(endianness)(bytes)?;
+    let mut header = Header {
+        endianness,
+        version,
+        stamp,
+        checksum: None,
+    };
+    let bytes = if header.lengths_in_bytes() {
+        let (bytes, checksum) = nom_u32(endianness)(bytes)?;
+        header.checksum = Some(checksum);
+        bytes
+    } else {
+        bytes
+    };
+    Ok((bytes, header))
+}
+
+/// Parses the records in a gcov file calling `parse_record` for each tag and its data
+fn parse_gcov_records<'a, F>(
+    header: &Header,
+    mut input: &'a [u8],
+    mut parse_record: F,
+) -> ParseResult<'a, ()>
+where
+    F: FnMut(u32, i32, &'a [u8]) -> ParseResult<'a, ()>,
+{
+    while !input.is_empty() {
+        let (bytes, tag) = header.read_u32(input)?;
+        if tag == 0 {
+            // Data files end with a zero tag
+            return Ok((bytes, ()));
+        }
+        let (bytes, length) = header.read_u32(bytes)?;
+        let length = length as i32;
+        // Negative lengths are used for counters which are all zero, there's no data after them
+        let (bytes, data) = if length < 0 {
+            (bytes, &bytes[..0])
+        } else if header.lengths_in_bytes() {
+            take_bytes(bytes, length as usize)?
+        } else {
+            take_bytes(bytes, length as usize * 4)?
+        };
+        parse_record(tag, length, data)?;
+        input = bytes;
+    }
+    Ok((input, ()))
+}
+
+/// Reads a string from a notes file, this is the length followed by the null terminated string
+fn parse_gcov_string<'a>(header: &Header, input: &'a [u8]) -> ParseResult<'a, String> {
+    let (bytes, len) = header.read_u32(input)?;
+    let len = if header.lengths_in_bytes() {
+        len as usize
+    } else {
+        len as usize * 4
+    };
+    let (bytes, string) = take_bytes(bytes, len)?;
+    let string = string.split(|x| *x == 0).next().unwrap_or_default();
+    Ok((bytes, String::from_utf8_lossy(string).to_string()))
+}
+
+impl GccProfReader {
+    /// Parses a data file naming the records with the function names in the notes file. Functions
+    /// not in the notes file keep the name they would have from `parse_bytes`.
+    pub fn parse_with_notes<'a>(
+        notes: &[u8],
+        data: &'a [u8],
+    ) -> ParseResult<'a, InstrumentationProfile> {
+        let names = parse_notes(notes).map_err(|e| match e {
+            nom::Err::Incomplete(n) => nom::Err::Incomplete(n),
+            _ => failure(data, "invalid gcov notes file"),
+        })?;
+        let (_, header) = Self::parse_header(data)?;
+        if header.stamp != names.stamp {
+            return Err(failure(data, "gcov notes and data stamps don't match"));
+        }
+        parse_data(data, &names.functions)
+    }
+}
+
+struct NotesNames {
+    stamp: u32,
+    functions: FxHashMap<u32, String>,
+}
+
+fn parse_notes(input: &[u8]) -> Result<NotesNames, nom::Err<VerboseError<&[u8]>>> {
+    let (bytes, header) = parse_gcov_header(input, GCNO_MAGIC)?;
+    let major = header.major_version();
+    // Gcc 9 adds the working directory and gcc 8 a flag for unexecuted blocks
+    let bytes = if major >= 9 {
+        parse_gcov_string(&header, bytes)?.0
+    } else {
+        bytes
+    };
+    let bytes = if major >= 8 {
+        header.read_u32(bytes)?.0
+    } else {
+        bytes
+    };
+    let mut functions = FxHashMap::default();
+    parse_gcov_records(&header, bytes, |tag, _, data| {
+        if tag == TAG_FUNCTION {
+            let (bytes, ident) = header.read_u32(data)?;
+            let (bytes, _lineno_checksum) = header.read_u32(bytes)?;
+            let (bytes, _cfg_checksum) = header.read_u32(bytes)?;
+            let (bytes, name) = parse_gcov_string(&header, bytes)?;
+            functions.insert(ident, name);
+            Ok((bytes, ()))
+        } else {
+            Ok((data, ()))
+        }
+    })?;
+    Ok(NotesNames {
+        stamp: header.stamp,
+        functions,
+    })
+}
+
+fn parse_data<'a>(
+    input: &'a [u8],
+    names: &FxHashMap<u32, String>,
+) -> ParseResult<'a, InstrumentationProfile> {
+    let (bytes, header) = GccProfReader::parse_header(input)?;
+    debug!("Parsed gcda header: {:?}", header);
+    let mut profile = InstrumentationProfile::default();
+    profile.version = Some(header.version as u64);
+    let mut function = None;
+    let (bytes, _) = parse_gcov_records(&header, bytes, |tag, length, data| {
+        match tag {
+            TAG_FUNCTION if data.is_empty() => {
+                // A function with no data isn't in this object file
+                function = None;
+            }
+            TAG_FUNCTION => {
+                let (bytes, ident) = header.read_u32(data)?;
+                let (bytes, lineno_checksum) = header.read_u32(bytes)?;
+                let (_, cfg_checksum) = header.read_u32(bytes)?;
+                function = Some((ident, lineno_checksum, cfg_checksum));
+            }
+            TAG_ARC_COUNTS => {
+                let (ident, lineno_checksum, cfg_checksum) = match function.take() {
+                    Some(function) => function,
+                    None => return Err(failure(data, "gcov counters without a function")),
+                };
+                let counts = if length < 0 {
+                    vec![0; length.unsigned_abs() as usize / 8]
+                } else {
+                    let mut counts = vec![];
+                    let mut bytes = data;
+                    // Counters are stored as the low and then high 32 bits
+                    while !bytes.is_empty() {
+                        let (b, low) = header.read_u32(bytes)?;
+                        let (b, high) = header.read_u32(b)?;
+                        counts.push((u64::from(high) << 32) | u64::from(low));
+                        bytes = b;
+                    }
+                    counts
+                };
+                let name = names
+                    .get(&ident)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:08x}_{:08x}", header.stamp, ident));
+                profile.symtab.add_func_name(name.clone(), None);
+                let record = NamedInstrProfRecord {
+                    name_hash: Some(compute_hash(&name)),
+                    name: Some(name),
+                    hash: Some((u64::from(lineno_checksum) << 32) | u64::from(cfg_checksum)),
+                    record: InstrProfRecord { counts, data: None },
+                };
+                debug!("Parsed record {:?}", record);
+                profile.push_record(record);
+            }
+            TAG_OBJECT_SUMMARY => {
+                let (bytes, runs) = header.read_u32(data)?;
+                let (_, sum_max) = header.read_u32(bytes)?;
+                debug!("Object summary: runs {} max sum {}", runs, sum_max);
+            }
+            tag => {
+                // Other counter kinds are value profiling which isn't supported
+                debug!("Skipping gcov record with tag {:x}", tag);
+            }
+        }
+        Ok((data, ()))
+    })?;
+    Ok((bytes, profile))
+}
+
+impl InstrProfReader for GccProfReader {
+    type Header = Header;
+
+    fn parse_bytes(input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        parse_data(input, &FxHashMap::default())
+    }
+
+    fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
+        parse_gcov_header(input, GCDA_MAGIC)
+    }
+
+    fn has_format(mut input: impl Read) -> bool {
+        let mut buffer = [0u8; 4];
+        input.read_exact(&mut buffer).is_ok() && file_endianness(&buffer, GCDA_MAGIC).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_numbers() {
+        let header = |version: &[u8; 4]| Header {
+            endianness: Endianness::Little,
+            version: u32::from_be_bytes(*version),
+            stamp: 0,
+            checksum: None,
+        };
+        assert_eq!(header(b"407*").major_version(), 4);
+        assert_eq!(header(b"A93*").major_version(), 9);
+        assert_eq!(header(b"B22*").major_version(), 12);
+        assert_eq!(header(b"B33*").major_version(), 13);
+    }
+}
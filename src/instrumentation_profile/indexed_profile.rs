@@ -1,4 +1,5 @@
 use crate::hash_table::*;
+use crate::instrumentation_profile::memprof::MemProfData;
 use crate::instrumentation_profile::*;
 use crate::summary::*;
 use anyhow::bail;
@@ -69,16 +70,65 @@ impl TryFrom<u64> for SummaryFieldKind {
 }
 
 impl Header {
+    /// The version number and variant flags of the profile
+    pub fn variant(&self) -> ProfileVariant {
+        ProfileVariant::from_version_word(self.version)
+    }
+
     pub fn version(&self) -> u64 {
-        self.version & !VARIANT_MASKS_ALL
+        self.variant().version
     }
 
     pub fn is_csir_prof(&self) -> bool {
-        (self.version & VARIANT_MASK_CSIR_PROF) > 0
+        self.variant().is_csir
     }
 
     pub fn is_ir_prof(&self) -> bool {
-        (self.version & VARIANT_MASK_IR_PROF) > 0
+        self.variant().is_ir
+    }
+
+    pub fn is_entry_first(&self) -> bool {
+        self.variant().entry_first
+    }
+
+    pub fn has_byte_coverage(&self) -> bool {
+        self.variant().byte_coverage
+    }
+
+    pub fn function_entry_only(&self) -> bool {
+        self.variant().entry_only
+    }
+
+    pub fn memory_profile(&self) -> bool {
+        self.variant().memory_profile
+    }
+
+    /// The header fields read from the file
+    pub fn info(&self) -> ProfileHeaderInfo {
+        let mut fields = vec![
+            ("hash_type", self.hash_type as u64),
+            ("hash_offset", self.hash_offset),
+        ];
+        let offsets = [
+            ("mem_prof_offset", self.mem_prof_offset),
+            ("binary_id_offset", self.binary_id_offset),
+            (
+                "temporary_prof_traces_offset",
+                self.temporary_prof_traces_offset,
+            ),
+            ("vtable_offset", self.vtable_offset),
+        ];
+        for (name, offset) in offsets {
+            if let Some(offset) = offset {
+                fields.push((name, offset));
+            }
+        }
+        ProfileHeaderInfo {
+            format: HeaderFormat::Indexed,
+            endianness: Endianness::Little,
+            version: self.version,
+            fields,
+        }
     }
 }
 
@@ -164,7 +214,7 @@ fn parse_summary<'a>(
 impl InstrProfReader for IndexedInstrProf {
     type Header = Header;
 
-    fn parse_bytes(mut input: &[u8]) -> ParseResult<InstrumentationProfile> {
+    fn parse_bytes(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
         let (bytes, header) = Self::parse_header(input)?;
         debug!("Parsed header: {:?}", header);
         let (bytes, summary) = parse_summary(bytes, &header, false)?;
@@ -179,12 +229,42 @@ impl InstrProfReader for IndexedInstrProf {
             Some(header.version),
             header.is_csir_prof(),
             header.is_ir_prof(),
-            false,
+            header.is_entry_first(),
         );
+        profile.llvm_version = LlvmVersion::from_indexed_version(header.version());
+        profile.header = Some(header.info());
+        profile.is_byte_coverage = header.has_byte_coverage();
+        profile.fn_entry_only = header.function_entry_only();
+        if let Some(offset) = header.mem_prof_offset.filter(|_| header.memory_profile()) {
+            let (_, memprof) = MemProfData::parse(input, offset)?;
+            debug!("Memprof: {:?}", memprof);
+            profile.memory_profiling = true;
+            profile.memprof = Some(memprof);
+        }
+        if let Some(offset) = header.binary_id_offset.filter(|x| *x > 0) {
+            // The section is the size in bytes followed by the binary ids
+            let offset = offset as usize;
+            if offset > input.len() {
+                return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                    &input[input.len()..],
+                    ErrorKind::Eof,
+                )));
+            }
+            let (section, size) = le_u64(&input[offset..])?;
+            if size as usize > section.len() {
+                return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                    section,
+                    ErrorKind::Eof,
+                )));
+            }
+            let (_, binary_ids) =
+                parse_binary_ids(&section[..(size as usize)], Endianness::Little)?;
+            profile.binary_ids = binary_ids;
+        }
 
         let table_start = input.len() - bytes.len();
         let (bytes, table) = HashTable::parse(
-            header.version,
+            header.version(),
             bytes,
             table_start,
             header.hash_offset as usize - table_start,
@@ -193,9 +273,7 @@ impl InstrProfReader for IndexedInstrProf {
         input = bytes;
         for ((hash, name), v) in &table.0 {
             let name = name.to_string();
-            profile
-                .symtab
-                .add_func_name(name.clone(), Some(Endianness::Little));
+            profile.symtab.add_func_name(name.clone(), None);
 
             let name_hash = compute_hash(&name);
             let record = NamedInstrProfRecord {
@@ -210,7 +288,7 @@ impl InstrProfReader for IndexedInstrProf {
         Ok((input, profile))
     }
 
-    fn parse_header(input: &[u8]) -> ParseResult<Self::Header> {
+    fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
         if Self::has_format(input) {
             let (bytes, version) = le_u64(&input[8..])?;
             let (bytes, _) = le_u64(bytes)?;
@@ -224,27 +302,26 @@ impl InstrProfReader for IndexedInstrProf {
                 ))
             })?;
             let (bytes, hash_offset) = le_u64(bytes)?;
-            let (bytes, mem_prof_offset) = if version >= 8 {
+            let version_num = ProfileVariant::from_version_word(version).version;
+            let (bytes, mem_prof_offset) = if version_num >= 8 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
                 (bytes, None)
             };
-            let (bytes, binary_id_offset) = if version >= 9 {
+            let (bytes, binary_id_offset) = if version_num >= 9 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
                 (bytes, None)
             };
-
-            let (bytes, vtable_offset) = if version >= 12 {
+            let (bytes, temporary_prof_traces_offset) = if version_num >= 10 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
                 (bytes, None)
             };
-
-            let (bytes, temporary_prof_traces_offset) = if version >= 10 {
+            let (bytes, vtable_offset) = if version_num >= 12 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
@@ -262,8 +339,20 @@ impl InstrProfReader for IndexedInstrProf {
                     temporary_prof_traces_offset,
                 },
             ))
+        } else if input.is_empty() {
+            let error = VerboseError::from_error_kind(input, ErrorKind::Eof);
+            Err(nom::Err::Failure(VerboseError::add_context(
+                input,
+                "empty indexed profile",
+                error,
+            )))
         } else {
-            todo!();
+            let error = VerboseError::from_error_kind(input, ErrorKind::IsNot);
+            Err(nom::Err::Failure(VerboseError::add_context(
+                input,
+                "invalid indexed profile magic",
+                error,
+            )))
         }
     }
 
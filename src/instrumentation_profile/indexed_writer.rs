@@ -0,0 +1,275 @@
+use crate::instrumentation_profile::summary::ProfileSummary;
+use crate::instrumentation_profile::types::*;
+use crate::instrumentation_profile::*;
+use crate::summary::DEFAULT_CUTOFFS;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// The indexed profile version written out. Version 7 is the newest version which doesn't
+/// require the memprof, binary id or trace sections
+const INDEXED_VERSION: u64 = 7;
+/// The indexed profile magic number "\xfflprofi\x81"
+const INDEXED_MAGIC: u64 = u64::from_le_bytes([0xff, 0x6c, 0x70, 0x72, 0x6f, 0x66, 0x69, 0x81]);
+/// Number of fields in the profile summary, matches `SummaryFieldKind`
+const NUM_SUMMARY_FIELDS: u64 = 6;
+/// Value sites can have at most 255 values as the count is stored in a byte
+const MAX_VALUES_PER_SITE: usize = u8::MAX as usize;
+
+/// Writes profiles in the indexed format (profdata) read by
+/// [`IndexedInstrProf`](crate::instrumentation_profile::indexed_profile::IndexedInstrProf).
+/// Records are stored in an on disk chained hash table keyed by function name.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct IndexedProfWriter {
+    /// Omit records where all the counts are zero
+    pub sparse: bool,
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn value_sites(
+    data: &ValueProfDataRecord,
+) -> impl Iterator<Item = (ValueKind, &[Vec<InstrProfValueData>])> {
+    IntoIterator::into_iter([
+        (
+            ValueKind::IndirectCallTarget,
+            data.indirect_callsites.as_slice(),
+        ),
+        (ValueKind::MemOpSize, data.mem_op_sizes.as_slice()),
+    ])
+    .filter(|(_, sites)| !sites.is_empty())
+}
+
+/// Writes the `ValueProfData` for a record, this is a total size and number of kinds followed by a
+/// `ValueProfRecord` for each kind with value sites.
+fn write_value_data(buffer: &mut Vec<u8>, record: &InstrProfRecord) {
+    let data = match record.data.as_ref() {
+        Some(data) => data,
+        None => {
+            write_u32(buffer, 8);
+            write_u32(buffer, 0);
+            return;
+        }
+    };
+    let start = buffer.len();
+    // Total size is filled in once the records are written
+    write_u32(buffer, 0);
+    write_u32(buffer, value_sites(data).count() as u32);
+    for (kind, sites) in value_sites(data) {
+        write_u32(buffer, kind as u32);
+        write_u32(buffer, sites.len() as u32);
+        for site in sites {
+            buffer.push(site.len().min(MAX_VALUES_PER_SITE) as u8);
+        }
+        let padding = get_num_padding_bytes(8 + sites.len() as u64);
+        buffer.resize(buffer.len() + padding as usize, 0);
+        for site in sites {
+            for value in sort_by_count(site).iter().take(MAX_VALUES_PER_SITE) {
+                write_u64(buffer, value.value);
+                write_u64(buffer, value.count);
+            }
+        }
+    }
+    let total_size = (buffer.len() - start) as u32;
+    buffer[start..(start + 4)].copy_from_slice(&total_size.to_le_bytes());
+}
+
+fn write_summary(buffer: &mut Vec<u8>, summary: &ProfileSummary) {
+    let detailed_summary = summary.detailed_summary(&DEFAULT_CUTOFFS);
+    write_u64(buffer, NUM_SUMMARY_FIELDS);
+    write_u64(buffer, detailed_summary.len() as u64);
+    // Fields are in the order of `SummaryFieldKind`
+    write_u64(buffer, summary.num_functions() as u64);
+    write_u64(buffer, summary.num_counts() as u64);
+    write_u64(buffer, summary.max_function_count());
+    write_u64(buffer, summary.max_count());
+    write_u64(buffer, summary.max_internal_block_count());
+    write_u64(buffer, summary.total_count());
+    for entry in &detailed_summary {
+        write_u64(buffer, entry.cutoff);
+        write_u64(buffer, entry.min_count);
+        write_u64(buffer, entry.num_counts);
+    }
+}
+
+impl IndexedProfWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InstrProfWriter for IndexedProfWriter {
+    fn write(&self, profile: &InstrumentationProfile, writer: &mut dyn Write) -> io::Result<()> {
+        // Group the records by name, each name is a key in the hash table containing all the
+        // records with that name
+        let mut functions = BTreeMap::<&str, Vec<&NamedInstrProfRecord>>::new();
+        let mut summary = ProfileSummary::new();
+        let mut cs_summary = ProfileSummary::new();
+        for record in profile.records() {
+            if !should_encode_record(record, self.sparse) {
+                continue;
+            }
+            let name = match record.name.as_ref() {
+                Some(name) => Some(name),
+                None => record.name_hash.and_then(|x| profile.symtab.get(x)),
+            };
+            if let (Some(name), Some(_)) = (name, record.hash) {
+                if record.has_cs_flag() {
+                    cs_summary.add_record(&record.record);
+                } else {
+                    summary.add_record(&record.record);
+                }
+                functions.entry(name.as_str()).or_default().push(record);
+            }
+        }
+
+        let version = ProfileVariant {
+            version: INDEXED_VERSION,
+            is_ir: profile.is_ir_level_profile(),
+            is_csir: profile.has_csir_level_profile(),
+            entry_first: profile.is_entry_first(),
+            byte_coverage: profile.is_byte_coverage(),
+            entry_only: profile.fn_entry_only(),
+            memory_profile: false,
+        }
+        .version_word();
+
+        let mut buffer = vec![];
+        write_u64(&mut buffer, INDEXED_MAGIC);
+        write_u64(&mut buffer, version);
+        // Unused field
+        write_u64(&mut buffer, 0);
+        // Hash type, only MD5 exists
+        write_u64(&mut buffer, 0);
+        let hash_offset_pos = buffer.len();
+        write_u64(&mut buffer, 0);
+        write_summary(&mut buffer, &summary);
+        if profile.has_csir_level_profile() {
+            write_summary(&mut buffer, &cs_summary);
+        }
+
+        // The table grows like llvm's `OnDiskChainedHashTableGenerator` keeping the load factor
+        // below 3/4
+        let mut num_buckets = 64;
+        while functions.len() * 4 >= num_buckets * 3 {
+            num_buckets *= 2;
+        }
+        let mut buckets = vec![vec![]; num_buckets];
+        for (name, records) in functions.iter() {
+            let key_hash = compute_hash(name);
+            buckets[(key_hash & (num_buckets as u64 - 1)) as usize]
+                .push((key_hash, *name, records));
+        }
+
+        let mut bucket_offsets = vec![0u64; num_buckets];
+        for (offset, bucket) in bucket_offsets.iter_mut().zip(buckets.iter()) {
+            if bucket.is_empty() {
+                continue;
+            }
+            *offset = buffer.len() as u64;
+            buffer.extend_from_slice(&(bucket.len() as u16).to_le_bytes());
+            for (key_hash, name, records) in bucket {
+                let mut data = vec![];
+                for record in records.iter() {
+                    write_u64(&mut data, record.hash_unchecked());
+                    write_u64(&mut data, record.counts().len() as u64);
+                    for count in record.counts() {
+                        write_u64(&mut data, *count);
+                    }
+                    write_value_data(&mut data, &record.record);
+                }
+                write_u64(&mut buffer, *key_hash);
+                write_u64(&mut buffer, name.len() as u64);
+                write_u64(&mut buffer, data.len() as u64);
+                buffer.extend_from_slice(name.as_bytes());
+                buffer.extend_from_slice(&data);
+            }
+        }
+        let padding = get_num_padding_bytes(buffer.len() as u64);
+        buffer.resize(buffer.len() + padding as usize, 0);
+
+        let hash_offset = buffer.len() as u64;
+        write_u64(&mut buffer, num_buckets as u64);
+        write_u64(&mut buffer, functions.len() as u64);
+        for offset in &bucket_offsets {
+            write_u64(&mut buffer, *offset);
+        }
+        buffer[hash_offset_pos..(hash_offset_pos + 8)].copy_from_slice(&hash_offset.to_le_bytes());
+
+        writer.write_all(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::indexed_profile::IndexedInstrProf;
+    use crate::instrumentation_profile::text_profile::TextInstrProf;
+
+    const PROFILE: &str = ":ir
+bar
+# Func Hash:
+10
+# Num Counters:
+2
+# Counter Values:
+7
+0
+
+foo
+# Func Hash:
+72057649435042473
+# Num Counters:
+2
+# Counter Values:
+40000
+60000
+
+zero
+# Func Hash:
+12
+# Num Counters:
+1
+# Counter Values:
+0
+";
+
+    #[test]
+    fn round_trip() {
+        let (_, profile) = TextInstrProf::parse_bytes(PROFILE.as_bytes()).unwrap();
+
+        let mut output = vec![];
+        IndexedProfWriter::new()
+            .write(&profile, &mut output)
+            .unwrap();
+        assert!(IndexedInstrProf::has_format(output.as_slice()));
+        let (_, reparsed) = IndexedInstrProf::parse_bytes(&output).unwrap();
+
+        assert!(reparsed.is_ir_level_profile());
+        assert!(!reparsed.has_csir_level_profile());
+        assert_eq!(profile.records().len(), reparsed.records().len());
+        for record in profile.records() {
+            let other = reparsed.get_record(&record.name_unchecked()).unwrap();
+            assert_eq!(record, other);
+        }
+    }
+
+    #[test]
+    fn sparse_output() {
+        let (_, profile) = TextInstrProf::parse_bytes(PROFILE.as_bytes()).unwrap();
+
+        let mut output = vec![];
+        IndexedProfWriter { sparse: true }
+            .write(&profile, &mut output)
+            .unwrap();
+        let (_, reparsed) = IndexedInstrProf::parse_bytes(&output).unwrap();
+
+        assert_eq!(reparsed.records().len(), 2);
+        assert!(reparsed.get_record("zero").is_none());
+    }
+}
@@ -0,0 +1,533 @@
+use crate::instrumentation_profile::ParseResult;
+use nom::{
+    error::{ContextError, ErrorKind, ParseError, VerboseError},
+    number::complete::*,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use tracing::debug;
+
+/// Size of a serialized `Frame`, a 64 bit GUID, two 32 bit integers and a bool
+const FRAME_SIZE: usize = 17;
+
+/// A frame in a call stack of a memory profile. The equivalent llvm type is `memprof::Frame`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Frame {
+    /// GUID of the function the frame is in
+    pub function: u64,
+    /// Line offset from the start of the function
+    pub line_offset: u32,
+    pub column: u32,
+    /// Whether the frame was inlined into the next frame in the call stack
+    pub is_inline_frame: bool,
+}
+
+/// The fields which can be stored for a `MemInfoBlock`, the schema of a memory profile lists the
+/// fields present in each block in the order they're stored. The values match llvm's
+/// `memprof::Meta`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[repr(u64)]
+pub enum MemInfoField {
+    AllocCount = 1,
+    TotalAccessCount,
+    MinAccessCount,
+    MaxAccessCount,
+    TotalSize,
+    MinSize,
+    MaxSize,
+    AllocTimestamp,
+    DeallocTimestamp,
+    TotalLifetime,
+    MinLifetime,
+    MaxLifetime,
+    AllocCpuId,
+    DeallocCpuId,
+    NumMigratedCpu,
+    NumLifetimeOverlaps,
+    NumSameAllocCpu,
+    NumSameDeallocCpu,
+    DataTypeId,
+    TotalAccessDensity,
+    MinAccessDensity,
+    MaxAccessDensity,
+    TotalLifetimeAccessDensity,
+    MinLifetimeAccessDensity,
+    MaxLifetimeAccessDensity,
+    AccessHistogramSize,
+    AccessHistogram,
+}
+
+impl TryFrom<u64> for MemInfoField {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        use MemInfoField::*;
+        const FIELDS: [MemInfoField; 27] = [
+            AllocCount,
+            TotalAccessCount,
+            MinAccessCount,
+            MaxAccessCount,
+            TotalSize,
+            MinSize,
+            MaxSize,
+            AllocTimestamp,
+            DeallocTimestamp,
+            TotalLifetime,
+            MinLifetime,
+            MaxLifetime,
+            AllocCpuId,
+            DeallocCpuId,
+            NumMigratedCpu,
+            NumLifetimeOverlaps,
+            NumSameAllocCpu,
+            NumSameDeallocCpu,
+            DataTypeId,
+            TotalAccessDensity,
+            MinAccessDensity,
+            MaxAccessDensity,
+            TotalLifetimeAccessDensity,
+            MinLifetimeAccessDensity,
+            MaxLifetimeAccessDensity,
+            AccessHistogramSize,
+            AccessHistogram,
+        ];
+        FIELDS
+            .iter()
+            .find(|x| **x as u64 == value)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("no variant matching {} found in `MemInfoField`", value))
+    }
+}
+
+impl MemInfoField {
+    /// Whether the field is stored as a 64 bit integer, otherwise it's 32 bits
+    fn is_u64(&self) -> bool {
+        matches!(
+            self,
+            Self::TotalAccessCount
+                | Self::MinAccessCount
+                | Self::MaxAccessCount
+                | Self::TotalSize
+                | Self::TotalLifetime
+                | Self::DataTypeId
+                | Self::TotalAccessDensity
+                | Self::TotalLifetimeAccessDensity
+                | Self::AccessHistogram
+        )
+    }
+}
+
+/// Statistics for the allocations made from a call stack. Fields not in the schema of the profile
+/// are zero. The equivalent llvm type is `PortableMemInfoBlock`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct MemInfoBlock {
+    pub alloc_count: u32,
+    pub total_access_count: u64,
+    pub min_access_count: u64,
+    pub max_access_count: u64,
+    pub total_size: u64,
+    pub min_size: u32,
+    pub max_size: u32,
+    pub alloc_timestamp: u32,
+    pub dealloc_timestamp: u32,
+    pub total_lifetime: u64,
+    pub min_lifetime: u32,
+    pub max_lifetime: u32,
+    pub alloc_cpu_id: u32,
+    pub dealloc_cpu_id: u32,
+    pub num_migrated_cpu: u32,
+    pub num_lifetime_overlaps: u32,
+    pub num_same_alloc_cpu: u32,
+    pub num_same_dealloc_cpu: u32,
+    pub data_type_id: u64,
+    pub total_access_density: u64,
+    pub min_access_density: u32,
+    pub max_access_density: u32,
+    pub total_lifetime_access_density: u64,
+    pub min_lifetime_access_density: u32,
+    pub max_lifetime_access_density: u32,
+    pub access_histogram_size: u32,
+    /// This is a pointer in the runtime so the value isn't meaningful in a profile
+    pub access_histogram: u64,
+}
+
+impl MemInfoBlock {
+    fn set(&mut self, field: MemInfoField, value: u64) {
+        // 32 bit fields are only ever read as 32 bit integers
+        let small = value as u32;
+        match field {
+            MemInfoField::AllocCount => self.alloc_count = small,
+            MemInfoField::TotalAccessCount => self.total_access_count = value,
+            MemInfoField::MinAccessCount => self.min_access_count = value,
+            MemInfoField::MaxAccessCount => self.max_access_count = value,
+            MemInfoField::TotalSize => self.total_size = value,
+            MemInfoField::MinSize => self.min_size = small,
+            MemInfoField::MaxSize => self.max_size = small,
+            MemInfoField::AllocTimestamp => self.alloc_timestamp = small,
+            MemInfoField::DeallocTimestamp => self.dealloc_timestamp = small,
+            MemInfoField::TotalLifetime => self.total_lifetime = value,
+            MemInfoField::MinLifetime => self.min_lifetime = small,
+            MemInfoField::MaxLifetime => self.max_lifetime = small,
+            MemInfoField::AllocCpuId => self.alloc_cpu_id = small,
+            MemInfoField::DeallocCpuId => self.dealloc_cpu_id = small,
+            MemInfoField::NumMigratedCpu => self.num_migrated_cpu = small,
+            MemInfoField::NumLifetimeOverlaps => self.num_lifetime_overlaps = small,
+            MemInfoField::NumSameAllocCpu => self.num_same_alloc_cpu = small,
+            MemInfoField::NumSameDeallocCpu => self.num_same_dealloc_cpu = small,
+            MemInfoField::DataTypeId => self.data_type_id = value,
+            MemInfoField::TotalAccessDensity => self.total_access_density = value,
+            MemInfoField::MinAccessDensity => self.min_access_density = small,
+            MemInfoField::MaxAccessDensity => self.max_access_density = small,
+            MemInfoField::TotalLifetimeAccessDensity => self.total_lifetime_access_density = value,
+            MemInfoField::MinLifetimeAccessDensity => self.min_lifetime_access_density = small,
+            MemInfoField::MaxLifetimeAccessDensity => self.max_lifetime_access_density = small,
+            MemInfoField::AccessHistogramSize => self.access_histogram_size = small,
+            MemInfoField::AccessHistogram => self.access_histogram = value,
+        }
+    }
+
+    fn parse<'a>(input: &'a [u8], schema: &[MemInfoField]) -> ParseResult<'a, Self> {
+        let mut result = Self::default();
+        let mut input = input;
+        for field in schema {
+            let (bytes, value) = if field.is_u64() {
+                le_u64(input)?
+            } else {
+                let (bytes, value) = le_u32(input)?;
+                (bytes, value as u64)
+            };
+            result.set(*field, value);
+            input = bytes;
+        }
+        Ok((input, result))
+    }
+}
+
+/// An allocation site, the call stack starts with the frame the allocation happens in
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct AllocationInfo {
+    pub call_stack: Vec<Frame>,
+    pub info: MemInfoBlock,
+}
+
+/// A call site which leads to an allocation
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct CallSiteInfo {
+    pub frames: Vec<Frame>,
+    /// GUIDs of the functions called from the call site, only present in version 4 onwards
+    pub callee_guids: Vec<u64>,
+}
+
+/// The memory profile for a single function. The equivalent llvm type is `MemProfRecord`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct MemProfRecord {
+    pub alloc_sites: Vec<AllocationInfo>,
+    pub call_sites: Vec<CallSiteInfo>,
+}
+
+/// The memory profiling section of an indexed profile. Versions 2 to 4 of the format are
+/// supported.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MemProfData {
+    pub version: u64,
+    /// Fields present in each `MemInfoBlock`
+    pub schema: Vec<MemInfoField>,
+    /// Frames keyed by their id, for version 3 onwards this is their index in the frame array
+    pub frames: BTreeMap<u64, Frame>,
+    /// Records keyed by the GUID of the function
+    pub records: BTreeMap<u64, MemProfRecord>,
+}
+
+fn failure<'a>(input: &'a [u8], context: &'static str) -> nom::Err<VerboseError<&'a [u8]>> {
+    let error = VerboseError::from_error_kind(input, ErrorKind::Satisfy);
+    nom::Err::Failure(VerboseError::add_context(input, context, error))
+}
+
+fn slice_from(input: &[u8], offset: u64) -> ParseResult<'_, &[u8]> {
+    let offset = offset as usize;
+    if offset > input.len() {
+        Err(nom::Err::Failure(VerboseError::from_error_kind(
+            &input[input.len()..],
+            ErrorKind::Eof,
+        )))
+    } else {
+        Ok((&input[offset..], &input[offset..]))
+    }
+}
+
+fn parse_frame(input: &[u8]) -> ParseResult<'_, Frame> {
+    let (bytes, function) = le_u64(input)?;
+    let (bytes, line_offset) = le_u32(bytes)?;
+    let (bytes, column) = le_u32(bytes)?;
+    let (bytes, is_inline_frame) = le_u8(bytes)?;
+    let frame = Frame {
+        function,
+        line_offset,
+        column,
+        is_inline_frame: is_inline_frame != 0,
+    };
+    Ok((bytes, frame))
+}
+
+fn parse_schema(input: &[u8]) -> ParseResult<'_, Vec<MemInfoField>> {
+    let (mut input, len) = le_u64(input)?;
+    let mut schema = vec![];
+    for _ in 0..len {
+        let (bytes, id) = le_u64(input)?;
+        let field = MemInfoField::try_from(id)
+            .map_err(|_| failure(input, "unknown field in the memprof schema"))?;
+        schema.push(field);
+        input = bytes;
+    }
+    Ok((input, schema))
+}
+
+/// Parses the entries of an on disk chained hash table where the keys are 64 bit integers. The
+/// buckets are stored consecutively from the start of the payload and `table` is the start of the
+/// table header containing the number of buckets and entries. Each entry is passed to `parse_data`
+/// with the data for the entry. Tables with a constant key size may not store the key length
+/// which is indicated by `has_key_len`.
+fn parse_table<'a, F>(
+    payload: &'a [u8],
+    table: &'a [u8],
+    has_key_len: bool,
+    mut parse_data: F,
+) -> ParseResult<'a, ()>
+where
+    F: FnMut(u64, &'a [u8]) -> ParseResult<'a, ()>,
+{
+    let (bytes, _num_buckets) = le_u64(table)?;
+    let (_, mut num_entries) = le_u64(bytes)?;
+    let mut input = payload;
+    while num_entries > 0 {
+        let (bytes, num_items) = le_u16(input)?;
+        input = bytes;
+        for _ in 0..num_items {
+            let (bytes, _hash) = le_u64(input)?;
+            let (bytes, key_len) = if has_key_len {
+                le_u64(bytes)?
+            } else {
+                (bytes, 8)
+            };
+            let (bytes, data_len) = le_u64(bytes)?;
+            if key_len != 8 {
+                return Err(failure(bytes, "memprof table keys should be 64 bits"));
+            }
+            let (bytes, key) = le_u64(bytes)?;
+            if data_len as usize > bytes.len() {
+                return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                    &bytes[bytes.len()..],
+                    ErrorKind::Eof,
+                )));
+            }
+            let (data, rest) = bytes.split_at(data_len as usize);
+            parse_data(key, data)?;
+            input = rest;
+            num_entries = num_entries.saturating_sub(1);
+        }
+    }
+    Ok((input, ()))
+}
+
+/// Reads a call stack from the radix tree array used from version 3 onwards. The array is 32 bit
+/// integers, a call stack starts with the number of frames then the frame ids. Negative values are
+/// a relative jump forward to where the rest of the call stack is shared with other call stacks.
+fn read_radix_call_stack<'a>(
+    call_stacks: &'a [u8],
+    frames: &BTreeMap<u64, Frame>,
+    index: u32,
+) -> Result<Vec<Frame>, nom::Err<VerboseError<&'a [u8]>>> {
+    let read = |position: usize| -> Result<u32, nom::Err<VerboseError<&'a [u8]>>> {
+        let (_, value) =
+            slice_from(call_stacks, position as u64 * 4).and_then(|(x, _)| le_u32(x))?;
+        Ok(value)
+    };
+    let mut position = index as usize;
+    let num_frames = read(position)?;
+    position += 1;
+    let mut result = vec![];
+    for _ in 0..num_frames {
+        let mut id = read(position)?;
+        if (id as i32) < 0 {
+            position += (id as i32).unsigned_abs() as usize;
+            id = read(position)?;
+        }
+        let frame = frames
+            .get(&(id as u64))
+            .copied()
+            .ok_or_else(|| failure(call_stacks, "memprof call stack references a missing frame"))?;
+        result.push(frame);
+        position += 1;
+    }
+    Ok(result)
+}
+
+impl MemProfData {
+    /// Parses the memory profile starting at `offset`, offsets within the section are relative to
+    /// the start of `input` so this should be the whole profile
+    pub(crate) fn parse(input: &[u8], offset: u64) -> ParseResult<'_, Self> {
+        let (section, _) = slice_from(input, offset)?;
+        let (bytes, version) = le_u64(section)?;
+        debug!("Memprof version: {}", version);
+        match version {
+            2 => Self::parse_v2(input, bytes),
+            3 | 4 => Self::parse_v3(input, bytes, version),
+            _ => Err(failure(section, "unsupported memprof version")),
+        }
+    }
+
+    fn parse_v2<'a>(input: &'a [u8], header: &'a [u8]) -> ParseResult<'a, Self> {
+        let (bytes, record_table_offset) = le_u64(header)?;
+        let (bytes, frame_payload_offset) = le_u64(bytes)?;
+        let (bytes, frame_table_offset) = le_u64(bytes)?;
+        let (bytes, call_stack_payload_offset) = le_u64(bytes)?;
+        let (bytes, call_stack_table_offset) = le_u64(bytes)?;
+        let (record_payload, schema) = parse_schema(bytes)?;
+
+        let mut frames = BTreeMap::new();
+        let (frame_payload, _) = slice_from(input, frame_payload_offset)?;
+        let (frame_table, _) = slice_from(input, frame_table_offset)?;
+        parse_table(frame_payload, frame_table, true, |id, data| {
+            let (bytes, frame) = parse_frame(data)?;
+            frames.insert(id, frame);
+            Ok((bytes, ()))
+        })?;
+
+        let mut call_stacks = BTreeMap::new();
+        let (call_stack_payload, _) = slice_from(input, call_stack_payload_offset)?;
+        let (call_stack_table, _) = slice_from(input, call_stack_table_offset)?;
+        parse_table(call_stack_payload, call_stack_table, false, |id, data| {
+            // The call stack is just the frame ids so the length comes from the data size
+            let mut bytes = data;
+            let mut call_stack = vec![];
+            for _ in 0..(data.len() / 8) {
+                let (b, frame_id) = le_u64(bytes)?;
+                let frame = frames.get(&frame_id).copied().ok_or_else(|| {
+                    failure(bytes, "memprof call stack references a missing frame")
+                })?;
+                call_stack.push(frame);
+                bytes = b;
+            }
+            call_stacks.insert(id, call_stack);
+            Ok((bytes, ()))
+        })?;
+        let get_call_stack = |id: u64, bytes| {
+            call_stacks
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| failure(bytes, "memprof record references a missing call stack"))
+        };
+
+        let mut records = BTreeMap::new();
+        let (record_table, _) = slice_from(input, record_table_offset)?;
+        parse_table(record_payload, record_table, true, |guid, data| {
+            let mut record = MemProfRecord::default();
+            let (mut bytes, num_alloc_sites) = le_u64(data)?;
+            for _ in 0..num_alloc_sites {
+                let (b, id) = le_u64(bytes)?;
+                let (b, info) = MemInfoBlock::parse(b, &schema)?;
+                record.alloc_sites.push(AllocationInfo {
+                    call_stack: get_call_stack(id, b)?,
+                    info,
+                });
+                bytes = b;
+            }
+            let (b, num_call_sites) = le_u64(bytes)?;
+            bytes = b;
+            for _ in 0..num_call_sites {
+                let (b, id) = le_u64(bytes)?;
+                record.call_sites.push(CallSiteInfo {
+                    frames: get_call_stack(id, b)?,
+                    callee_guids: vec![],
+                });
+                bytes = b;
+            }
+            records.insert(guid, record);
+            Ok((bytes, ()))
+        })?;
+
+        let result = Self {
+            version: 2,
+            schema,
+            frames,
+            records,
+        };
+        Ok((input, result))
+    }
+
+    fn parse_v3<'a>(input: &'a [u8], header: &'a [u8], version: u64) -> ParseResult<'a, Self> {
+        let (bytes, call_stack_payload_offset) = le_u64(header)?;
+        let (bytes, record_payload_offset) = le_u64(bytes)?;
+        let (mut bytes, record_table_offset) = le_u64(bytes)?;
+        if version >= 4 {
+            // Offset of the data access profile which isn't parsed followed by the summary which
+            // starts with the number of fields
+            let (b, _data_access_offset) = le_u64(bytes)?;
+            let (b, num_summary_fields) = le_u32(b)?;
+            let summary_len = num_summary_fields as u64 * 8;
+            let (b, _) = slice_from(b, summary_len)?;
+            bytes = b;
+        }
+        let (frame_array, schema) = parse_schema(bytes)?;
+
+        // The frame array runs up until the call stack array
+        let frame_array_start = input.len() - frame_array.len();
+        let frame_array_len =
+            (call_stack_payload_offset as usize).saturating_sub(frame_array_start) / FRAME_SIZE;
+        let mut frames = BTreeMap::new();
+        let mut bytes = frame_array;
+        for id in 0..frame_array_len {
+            let (b, frame) = parse_frame(bytes)?;
+            frames.insert(id as u64, frame);
+            bytes = b;
+        }
+
+        let (call_stacks, _) = slice_from(input, call_stack_payload_offset)?;
+        let (record_payload, _) = slice_from(input, record_payload_offset)?;
+        let (record_table, _) = slice_from(input, record_table_offset)?;
+        let mut records = BTreeMap::new();
+        parse_table(record_payload, record_table, true, |guid, data| {
+            let mut record = MemProfRecord::default();
+            let (mut bytes, num_alloc_sites) = le_u64(data)?;
+            for _ in 0..num_alloc_sites {
+                let (b, id) = le_u32(bytes)?;
+                let (b, info) = MemInfoBlock::parse(b, &schema)?;
+                record.alloc_sites.push(AllocationInfo {
+                    call_stack: read_radix_call_stack(call_stacks, &frames, id)?,
+                    info,
+                });
+                bytes = b;
+            }
+            let (b, num_call_sites) = le_u64(bytes)?;
+            bytes = b;
+            for _ in 0..num_call_sites {
+                let (b, id) = le_u32(bytes)?;
+                bytes = b;
+                let mut callee_guids = vec![];
+                if version >= 4 {
+                    let (b, num_guids) = le_u64(bytes)?;
+                    bytes = b;
+                    for _ in 0..num_guids {
+                        let (b, guid) = le_u64(bytes)?;
+                        callee_guids.push(guid);
+                        bytes = b;
+                    }
+                }
+                record.call_sites.push(CallSiteInfo {
+                    frames: read_radix_call_stack(call_stacks, &frames, id)?,
+                    callee_guids,
+                });
+            }
+            records.insert(guid, record);
+            Ok((bytes, ()))
+        })?;
+
+        let result = Self {
+            version,
+            schema,
+            frames,
+            records,
+        };
+        Ok((input, result))
+    }
+}
@@ -1,17 +1,35 @@
+use crate::instrumentation_profile::gcc_profile::*;
 use crate::instrumentation_profile::indexed_profile::*;
+use crate::instrumentation_profile::indexed_writer::IndexedProfWriter;
 use crate::instrumentation_profile::raw_profile::*;
 use crate::instrumentation_profile::text_profile::*;
+use crate::instrumentation_profile::text_writer::TextProfWriter;
 use crate::instrumentation_profile::types::*;
-use nom::{error::VerboseError, IResult};
+use crate::ProfileFormat;
+use memmap2::Mmap;
+use nom::bytes::complete::take;
+use nom::number::{complete::u64 as nom_u64, Endianness};
+use nom::{
+    error::{ErrorKind, VerboseError, VerboseErrorKind},
+    IResult,
+};
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
+use thiserror::Error;
 
+pub mod gcc_profile;
 pub mod indexed_profile;
+pub mod indexed_writer;
+pub mod memprof;
+pub mod overlap;
 pub mod raw_profile;
+pub mod show;
+pub mod stats;
 pub mod summary;
 pub mod text_profile;
+pub mod text_writer;
 pub mod types;
 
 pub type ParseResult<'a, T> = IResult<&'a [u8], T, VerboseError<&'a [u8]>>;
@@ -20,46 +38,218 @@ pub const fn get_num_padding_bytes(len: u64) -> u8 {
     7 & (8 - (len % 8) as u8)
 }
 
+/// Files at least this size are memory mapped by `parse` instead of being read into memory
+const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
 pub fn parse(filename: impl AsRef<Path>) -> io::Result<InstrumentationProfile> {
-    let mut buffer = Vec::new();
     let mut f = File::open(filename)?;
+    if f.metadata()?.len() >= MMAP_THRESHOLD {
+        return parse_file_mmap(&f);
+    }
+    let mut buffer = Vec::new();
     f.read_to_end(&mut buffer)?;
     parse_bytes(buffer.as_slice())
 }
 
+/// Parses a profile by memory mapping the file. This avoids holding a copy of the whole file in
+/// memory which can be significant for large profiles. The returned profile doesn't borrow from
+/// the mapping. The file mustn't be truncated while it's being parsed as that raises `SIGBUS` on
+/// unix, `parse` also maps files of 64MiB or more.
+pub fn parse_mmap(filename: impl AsRef<Path>) -> io::Result<InstrumentationProfile> {
+    let f = File::open(filename)?;
+    parse_file_mmap(&f)
+}
+
+/// Parses a profile from a reader such as a pipe or socket that can't be seeked. The whole stream
+/// is read into memory before parsing.
+pub fn parse_reader(mut reader: impl Read) -> io::Result<InstrumentationProfile> {
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
+    parse_bytes(buffer.as_slice())
+}
+
+fn parse_file_mmap(file: &File) -> io::Result<InstrumentationProfile> {
+    // SAFETY: the mapping is only read while parsing and the profile doesn't borrow from it. The
+    // file must not be modified or truncated while it's mapped, truncating it raises SIGBUS.
+    let map = unsafe { Mmap::map(file)? };
+    parse_bytes(&map)
+}
+
+/// Errors from parsing a profile, for a profile in a recognised format the offset is the position
+/// in the input where parsing failed
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Error)]
+pub enum ProfileParseError {
+    #[error("Unsupported instrumentation profile format")]
+    UnsupportedFormat,
+    #[error("Empty profile")]
+    Empty,
+    #[error("{message} at byte {offset} ({kind:?})")]
+    Invalid {
+        kind: RawProfileError,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl ProfileParseError {
+    /// The kind of error to distinguish between failures programmatically
+    pub fn kind(&self) -> RawProfileError {
+        match self {
+            Self::UnsupportedFormat => RawProfileError::UnrecognizedFormat,
+            Self::Empty => RawProfileError::EmptyRawProfile,
+            Self::Invalid { kind, .. } => *kind,
+        }
+    }
+
+    pub(crate) fn from_nom(data: &[u8], err: nom::Err<VerboseError<&[u8]>>) -> Self {
+        if data.is_empty() {
+            return Self::Empty;
+        }
+        let error = match err {
+            nom::Err::Incomplete(_) => {
+                return Self::Invalid {
+                    kind: RawProfileError::Truncated,
+                    offset: data.len(),
+                    message: ErrorKind::Eof.description().to_string(),
+                }
+            }
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        };
+        // Some errors come from decompressed data so only inputs within the profile give a
+        // meaningful offset
+        let start = data.as_ptr() as usize;
+        let offset = error
+            .errors
+            .iter()
+            .map(|(input, _)| input.as_ptr() as usize)
+            .find(|x| (start..=(start + data.len())).contains(x))
+            .map(|x| x - start)
+            .unwrap_or_default();
+        let is_eof = matches!(
+            error.errors.first(),
+            Some((_, VerboseErrorKind::Nom(ErrorKind::Eof)))
+        );
+        // Contexts are pushed as the error propagates so the outermost is last
+        let mut message = error
+            .errors
+            .iter()
+            .rev()
+            .filter_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(context) => Some(*context),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join(": ");
+        if message.is_empty() {
+            message = match error.errors.first() {
+                Some((_, VerboseErrorKind::Nom(kind))) => kind.description().to_string(),
+                Some((_, VerboseErrorKind::Char(c))) => format!("expected '{}'", c),
+                _ => "parsing failed".to_string(),
+            };
+        }
+        Self::Invalid {
+            kind: if is_eof {
+                RawProfileError::Truncated
+            } else {
+                RawProfileError::Malformed
+            },
+            offset,
+            message,
+        }
+    }
+}
+
+impl From<ProfileParseError> for io::Error {
+    fn from(e: ProfileParseError) -> Self {
+        io::Error::other(e)
+    }
+}
+
 pub fn parse_bytes(data: &[u8]) -> io::Result<InstrumentationProfile> {
-    let nom_res = if IndexedInstrProf::has_format(data) {
+    try_parse_bytes(data).map_err(io::Error::from)
+}
+
+/// Parses a profile returning a `ProfileParseError` describing the failure if it can't be parsed
+pub fn try_parse_bytes(data: &[u8]) -> Result<InstrumentationProfile, ProfileParseError> {
+    // Like llvm-profdata an empty input is an error rather than a profile with no records
+    if data.is_empty() {
+        return Err(ProfileParseError::Empty);
+    }
+    // The binary formats are detected from the magic number so only the first 8 bytes are needed
+    let magic = &data[..data.len().min(8)];
+    let nom_res = if IndexedInstrProf::has_format(magic) {
         IndexedInstrProf::parse_bytes(data)
-    } else if RawInstrProf64::has_format(data) {
+    } else if RawInstrProf64::has_format(magic) {
         RawInstrProf64::parse_bytes(data)
-    } else if RawInstrProf32::has_format(data) {
+    } else if RawInstrProf32::has_format(magic) {
         RawInstrProf32::parse_bytes(data)
+    } else if GccProfReader::has_format(magic) {
+        GccProfReader::parse_bytes(data)
     } else if TextInstrProf::has_format(data) {
         TextInstrProf::parse_bytes(data)
     } else {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unsupported instrumentation profile format",
-        ));
+        return Err(ProfileParseError::UnsupportedFormat);
     };
-    nom_res.map(|(_bytes, res)| res).map_err(|_e| {
-        #[cfg(test)]
-        println!("{}", _e);
-        io::Error::new(io::ErrorKind::Other, "Parsing failed")
-    })
+    nom_res
+        .map(|(_bytes, res)| res)
+        .map_err(|e| ProfileParseError::from_nom(data, e))
+}
+
+/// Parses a binary ids section, this is a list of ids each one prefixed by its length as a 64 bit
+/// integer and padded to an 8 byte boundary. The whole input is expected to be the section.
+pub(crate) fn parse_binary_ids(
+    mut input: &[u8],
+    endianness: Endianness,
+) -> ParseResult<'_, Vec<Vec<u8>>> {
+    let mut binary_ids = vec![];
+    while !input.is_empty() {
+        let (bytes, len) = nom_u64(endianness)(input)?;
+        let (bytes, id) = take(len)(bytes)?;
+        binary_ids.push(id.to_vec());
+        // The padding may be missing after the last id
+        let padding = (get_num_padding_bytes(len) as usize).min(bytes.len());
+        input = &bytes[padding..];
+    }
+    Ok((input, binary_ids))
 }
 
 pub trait InstrProfReader {
     type Header;
     /// Parse the profile no lazy parsing here!
-    fn parse_bytes(input: &[u8]) -> ParseResult<InstrumentationProfile>;
+    fn parse_bytes(input: &[u8]) -> ParseResult<'_, InstrumentationProfile>;
     /// Parses a header
-    fn parse_header(input: &[u8]) -> ParseResult<Self::Header>;
+    fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header>;
     /// Detects that the bytes match the current reader format if it can't read the format it will
     /// return false
     fn has_format(input: impl Read) -> bool;
 }
 
+/// Sparse profiles only contain records with non-zero counts
+pub(crate) fn should_encode_record(record: &NamedInstrProfRecord, sparse: bool) -> bool {
+    !sparse || record.counts().iter().any(|x| *x > 0)
+}
+
+/// Values are written in descending order of count matching llvm's `sortValueData`. The sort is
+/// stable so values with the same count keep their order
+pub(crate) fn sort_by_count(site: &[InstrProfValueData]) -> Vec<&InstrProfValueData> {
+    let mut values = site.iter().collect::<Vec<_>>();
+    values.sort_by_key(|x| std::cmp::Reverse(x.count));
+    values
+}
+
 pub trait InstrProfWriter {
-    fn write(&self, profile: &InstrumentationProfile, writer: &mut impl Write) -> io::Result<()>;
+    fn write(&self, profile: &InstrumentationProfile, writer: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Gets a writer for the given format, returning an `Unsupported` error for formats which can't be
+/// written yet. Sparse writers omit records where all the counts are zero.
+pub fn writer_for(format: ProfileFormat, sparse: bool) -> io::Result<Box<dyn InstrProfWriter>> {
+    match format {
+        ProfileFormat::Binary => Ok(Box::new(IndexedProfWriter { sparse })),
+        ProfileFormat::Text => Ok(Box::new(TextProfWriter { sparse })),
+        format => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("writing {:?} profiles isn't supported", format),
+        )),
+    }
 }
@@ -0,0 +1,433 @@
+use crate::instrumentation_profile::types::*;
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, Write};
+
+/// Either the sums of the counts in a profile or function, or the percentage of those sums which
+/// are overlapping, mismatched or unique.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CountSumOrPercent {
+    pub num_entries: u64,
+    pub count_sum: f64,
+    pub value_counts: [f64; ValueKind::len()],
+}
+
+impl CountSumOrPercent {
+    fn accumulate(&mut self, record: &InstrProfRecord) {
+        self.num_entries += record.counts.len() as u64;
+        self.count_sum += record.counts.iter().map(|x| *x as f64).sum::<f64>();
+        for kind in [ValueKind::IndirectCallTarget, ValueKind::MemOpSize] {
+            self.value_counts[kind as usize] += value_sites(record, kind)
+                .iter()
+                .flatten()
+                .map(|x| x.count as f64)
+                .sum::<f64>();
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "num_entries": self.num_entries,
+            "count_sum": self.count_sum,
+            "indirect_call_sum": self.value_counts[ValueKind::IndirectCallTarget as usize],
+            "memop_sum": self.value_counts[ValueKind::MemOpSize as usize],
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OverlapLevel {
+    Program,
+    Function { name: String, hash: u64 },
+}
+
+/// The overlap between a base and test profile. All the sums are taken from the base and test
+/// profile, the overlap, mismatch and unique fields are ratios of the test profile sums.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlapStats {
+    pub level: OverlapLevel,
+    pub base: CountSumOrPercent,
+    pub test: CountSumOrPercent,
+    pub overlap: CountSumOrPercent,
+    pub mismatch: CountSumOrPercent,
+    pub unique: CountSumOrPercent,
+}
+
+/// Filters to decide which functions have function level overlap information generated.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OverlapFuncFilters {
+    /// Only functions with a max count greater than or equal to this are reported. If this is
+    /// `None` then only functions matching the name filter are reported
+    pub value_cutoff: Option<u64>,
+    /// Functions whose name contains this string are always reported
+    pub name_filter: Option<String>,
+}
+
+/// The result of overlapping two profiles, contains the program level overlap and the function
+/// level overlap for every function that passed the filters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileOverlap {
+    pub program: OverlapStats,
+    pub functions: Vec<OverlapStats>,
+}
+
+impl ProfileOverlap {
+    /// The program level edge profile overlap as a ratio, multiply by 100 for the percentage
+    /// `llvm-profdata overlap` prints
+    pub fn program_overlap(&self) -> f64 {
+        self.program.overlap.count_sum
+    }
+
+    /// Writes the overlap as JSON with the program level stats under `program` and the function
+    /// level stats under `functions`. The overlap, mismatch and unique sums are ratios of the test
+    /// profile sums like in [`OverlapStats`].
+    pub fn to_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        let export = json!({
+            "program": self.program.to_json(),
+            "functions": self.functions.iter().map(|x| x.to_json()).collect::<Vec<_>>(),
+        });
+        serde_json::to_writer(&mut *writer, &export)?;
+        Ok(())
+    }
+}
+
+fn value_sites(record: &InstrProfRecord, kind: ValueKind) -> &[Vec<InstrProfValueData>] {
+    match (record.data.as_ref(), kind) {
+        (Some(data), ValueKind::IndirectCallTarget) => &data.indirect_callsites,
+        (Some(data), ValueKind::MemOpSize) => &data.mem_op_sizes,
+        (None, _) => &[],
+    }
+}
+
+/// Get the overlap score of two counts, this is the minimum of the counts as a proportion of their
+/// respective sums.
+fn score(base: u64, test: u64, base_sum: f64, test_sum: f64) -> f64 {
+    if base_sum < 1.0 || test_sum < 1.0 {
+        0.0
+    } else {
+        (base as f64 / base_sum).min(test as f64 / test_sum)
+    }
+}
+
+impl OverlapStats {
+    fn to_json(&self) -> Value {
+        let mut value = json!({
+            "base": self.base.to_json(),
+            "test": self.test.to_json(),
+            "overlap": self.overlap.to_json(),
+            "mismatch": self.mismatch.to_json(),
+            "unique": self.unique.to_json(),
+        });
+        if let OverlapLevel::Function { name, hash } = &self.level {
+            value["name"] = json!(name);
+            value["hash"] = json!(hash);
+        }
+        value
+    }
+
+    fn new(level: OverlapLevel) -> Self {
+        Self {
+            level,
+            base: Default::default(),
+            test: Default::default(),
+            overlap: Default::default(),
+            mismatch: Default::default(),
+            unique: Default::default(),
+        }
+    }
+
+    fn add_one_mismatch(&mut self, func: &CountSumOrPercent) {
+        self.mismatch.num_entries += 1;
+        self.mismatch.count_sum += func.count_sum / self.test.count_sum;
+        for i in 0..ValueKind::len() {
+            if self.test.value_counts[i] >= 1.0 {
+                self.mismatch.value_counts[i] += func.value_counts[i] / self.test.value_counts[i];
+            }
+        }
+    }
+
+    fn add_one_unique(&mut self, func: &CountSumOrPercent) {
+        self.unique.num_entries += 1;
+        self.unique.count_sum += func.count_sum / self.test.count_sum;
+        for i in 0..ValueKind::len() {
+            if self.test.value_counts[i] >= 1.0 {
+                self.unique.value_counts[i] += func.value_counts[i] / self.test.value_counts[i];
+            }
+        }
+    }
+
+    /// Computes the overlap of the value sites of a kind. Values in each site are matched by the
+    /// value and scored against both the program and function level sums.
+    fn overlap_value_sites(
+        &mut self,
+        func: &mut OverlapStats,
+        base: &InstrProfRecord,
+        test: &InstrProfRecord,
+        kind: ValueKind,
+    ) {
+        let k = kind as usize;
+        for (base_site, test_site) in value_sites(base, kind)
+            .iter()
+            .zip(value_sites(test, kind).iter())
+        {
+            let mut base_site = base_site.clone();
+            let mut test_site = test_site.clone();
+            base_site.sort_by_key(|x| x.value);
+            test_site.sort_by_key(|x| x.value);
+
+            let mut score_sum = 0.0;
+            let mut func_score_sum = 0.0;
+            let mut base_iter = base_site.iter().peekable();
+            let mut test_iter = test_site.iter().peekable();
+            while let (Some(b), Some(t)) = (base_iter.peek(), test_iter.peek()) {
+                match b.value.cmp(&t.value) {
+                    std::cmp::Ordering::Equal => {
+                        score_sum += score(
+                            b.count,
+                            t.count,
+                            self.base.value_counts[k],
+                            self.test.value_counts[k],
+                        );
+                        func_score_sum += score(
+                            b.count,
+                            t.count,
+                            func.base.value_counts[k],
+                            func.test.value_counts[k],
+                        );
+                        base_iter.next();
+                        test_iter.next();
+                    }
+                    std::cmp::Ordering::Less => {
+                        base_iter.next();
+                    }
+                    std::cmp::Ordering::Greater => {
+                        test_iter.next();
+                    }
+                }
+            }
+            self.overlap.value_counts[k] += score_sum;
+            func.overlap.value_counts[k] += func_score_sum;
+        }
+    }
+
+    /// Overlaps two records with the same name and hash. Returns true if the function level
+    /// overlap passes the value cutoff.
+    fn overlap_record(
+        &mut self,
+        func: &mut OverlapStats,
+        base: &InstrProfRecord,
+        test: &InstrProfRecord,
+        value_cutoff: Option<u64>,
+    ) -> bool {
+        func.base.accumulate(base);
+        let mismatch = base.counts.len() != test.counts.len()
+            || [ValueKind::IndirectCallTarget, ValueKind::MemOpSize]
+                .iter()
+                .any(|kind| value_sites(base, *kind).len() != value_sites(test, *kind).len());
+        if mismatch {
+            self.add_one_mismatch(&func.test);
+            return false;
+        }
+        for kind in [ValueKind::IndirectCallTarget, ValueKind::MemOpSize] {
+            self.overlap_value_sites(func, base, test, kind);
+        }
+
+        let mut max_count = 0;
+        for (b, t) in base.counts.iter().zip(test.counts.iter()) {
+            self.overlap.count_sum += score(*b, *t, self.base.count_sum, self.test.count_sum);
+            max_count = max_count.max(*t);
+        }
+        self.overlap.num_entries += 1;
+
+        match value_cutoff {
+            Some(cutoff) if max_count >= cutoff => {
+                func.overlap.count_sum = base
+                    .counts
+                    .iter()
+                    .zip(test.counts.iter())
+                    .map(|(b, t)| score(*b, *t, func.base.count_sum, func.test.count_sum))
+                    .sum();
+                func.overlap.num_entries = test.counts.len() as u64;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Computes the overlap of the test profile against the base profile matching functions by name
+/// and hash. This follows the `llvm-profdata overlap` implementation, with `is_cs` only context
+/// sensitive records from IR level profiles are considered otherwise only non-CS records are.
+pub fn overlap_profiles(
+    base: &InstrumentationProfile,
+    test: &InstrumentationProfile,
+    filter: &OverlapFuncFilters,
+    is_cs: bool,
+) -> ProfileOverlap {
+    let keep = |profile: &InstrumentationProfile, record: &NamedInstrProfRecord| {
+        !profile.is_ir_level_profile() || record.has_cs_flag() == is_cs
+    };
+    let mut program = OverlapStats::new(OverlapLevel::Program);
+    let mut base_records = FxHashMap::<&str, FxHashMap<u64, &InstrProfRecord>>::default();
+    for record in base.records() {
+        if keep(base, record) {
+            program.base.accumulate(&record.record);
+        }
+        if let (Some(name), Some(hash)) = (record.name.as_deref(), record.hash) {
+            base_records
+                .entry(name)
+                .or_default()
+                .insert(hash, &record.record);
+        }
+    }
+    for record in test.records().iter().filter(|x| keep(test, x)) {
+        program.test.accumulate(&record.record);
+    }
+
+    let mut functions = vec![];
+    for record in test.records().iter().filter(|x| keep(test, x)) {
+        let (name, hash) = match (record.name.as_ref(), record.hash) {
+            (Some(name), Some(hash)) => (name, hash),
+            _ => continue,
+        };
+        let mut func = OverlapStats::new(OverlapLevel::Function {
+            name: name.clone(),
+            hash,
+        });
+        func.test.accumulate(&record.record);
+        let base_record = match base_records.get(name.as_str()) {
+            Some(records) => records.get(&hash),
+            None => {
+                program.add_one_unique(&func.test);
+                continue;
+            }
+        };
+        if func.test.count_sum < 1.0 {
+            program.overlap.num_entries += 1;
+            continue;
+        }
+        let base_record = match base_record {
+            Some(base_record) => base_record,
+            None => {
+                program.add_one_mismatch(&func.test);
+                continue;
+            }
+        };
+        let value_cutoff = match filter.name_filter.as_ref() {
+            Some(filter) if name.contains(filter) => Some(0),
+            _ => filter.value_cutoff,
+        };
+        if program.overlap_record(&mut func, base_record, &record.record, value_cutoff) {
+            functions.push(func);
+        }
+    }
+    ProfileOverlap { program, functions }
+}
+
+impl fmt::Display for OverlapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry_name = match &self.level {
+            OverlapLevel::Program => {
+                writeln!(f, "Program level:")?;
+                "functions"
+            }
+            OverlapLevel::Function { name, hash } => {
+                writeln!(f, "Function level:")?;
+                writeln!(f, "  Function: {} (Hash={})", name, hash)?;
+                "edge counters"
+            }
+        };
+        writeln!(
+            f,
+            "  # of {} overlap: {}",
+            entry_name, self.overlap.num_entries
+        )?;
+        if self.mismatch.num_entries > 0 {
+            writeln!(
+                f,
+                "  # of {} mismatch: {}",
+                entry_name, self.mismatch.num_entries
+            )?;
+        }
+        if self.unique.num_entries > 0 {
+            writeln!(
+                f,
+                "  # of {} only in test_profile: {}",
+                entry_name, self.unique.num_entries
+            )?;
+        }
+        writeln!(
+            f,
+            "  Edge profile overlap: {:.3}%",
+            self.overlap.count_sum * 100.0
+        )?;
+        if self.mismatch.num_entries > 0 {
+            writeln!(
+                f,
+                "  Mismatched count percentage (Edge): {:.3}%",
+                self.mismatch.count_sum * 100.0
+            )?;
+        }
+        if self.unique.num_entries > 0 {
+            writeln!(
+                f,
+                "  Percentage of Edge profile only in test_profile: {:.3}%",
+                self.unique.count_sum * 100.0
+            )?;
+        }
+        writeln!(
+            f,
+            "  Edge profile base count sum: {:.0}",
+            self.base.count_sum
+        )?;
+        writeln!(
+            f,
+            "  Edge profile test count sum: {:.0}",
+            self.test.count_sum
+        )?;
+        for kind in [ValueKind::IndirectCallTarget, ValueKind::MemOpSize] {
+            let i = kind as usize;
+            if self.base.value_counts[i] < 1.0 && self.test.value_counts[i] < 1.0 {
+                continue;
+            }
+            let kind_name = match kind {
+                ValueKind::IndirectCallTarget => "IndirectCall",
+                ValueKind::MemOpSize => "MemOP",
+            };
+            writeln!(
+                f,
+                "  {} profile overlap: {:.3}%",
+                kind_name,
+                self.overlap.value_counts[i] * 100.0
+            )?;
+            if self.mismatch.num_entries > 0 {
+                writeln!(
+                    f,
+                    "  Mismatched count percentage ({}): {:.3}%",
+                    kind_name,
+                    self.mismatch.value_counts[i] * 100.0
+                )?;
+            }
+            if self.unique.num_entries > 0 {
+                writeln!(
+                    f,
+                    "  Percentage of {} profile only in test_profile: {:.3}%",
+                    kind_name,
+                    self.unique.value_counts[i] * 100.0
+                )?;
+            }
+            writeln!(
+                f,
+                "  {} profile base count sum: {:.0}",
+                kind_name, self.base.value_counts[i]
+            )?;
+            writeln!(
+                f,
+                "  {} profile test count sum: {:.0}",
+                kind_name, self.test.value_counts[i]
+            )?;
+        }
+        Ok(())
+    }
+}
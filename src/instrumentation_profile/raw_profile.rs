@@ -1,17 +1,17 @@
-use crate::instrumentation_profile::types::*;
 use crate::instrumentation_profile::*;
 use crate::util::parse_string_ref;
 use core::hash::Hash;
 use nom::bytes::complete::take;
 use nom::error::ParseError;
 use nom::lib::std::ops::RangeFrom;
-use nom::number::streaming::{u16 as nom_u16, u32 as nom_u32, u64 as nom_u64};
+use nom::number::streaming::{u16 as nom_u16, u32 as nom_u32, u64 as nom_u64, u8 as nom_u8};
 use nom::number::Endianness;
 use nom::{
     error::{ContextError, ErrorKind},
     Err,
 };
 use nom::{InputIter, InputLength, Slice};
+use rustc_hash::FxHashMap;
 use std::convert::TryInto;
 use std::fmt::{Debug, Display};
 use std::mem::size_of;
@@ -73,34 +73,39 @@ pub struct Header {
 }
 
 impl Header {
+    /// The version number and variant flags of the profile
+    pub fn variant(&self) -> ProfileVariant {
+        ProfileVariant::from_version_word(self.version)
+    }
+
     #[inline(always)]
     fn version(&self) -> u64 {
-        self.version & !VARIANT_MASKS_ALL
+        self.variant().version
     }
 
     #[inline(always)]
     fn has_byte_coverage(&self) -> bool {
-        (self.version & VARIANT_MASK_BYTE_COVERAGE) != 0
+        self.variant().byte_coverage
     }
 
     #[inline(always)]
     fn ir_profile(&self) -> bool {
-        (self.version & VARIANT_MASK_IR_PROF) != 0
+        self.variant().is_ir
     }
 
     #[inline(always)]
     fn csir_profile(&self) -> bool {
-        (self.version & VARIANT_MASK_CSIR_PROF) != 0
+        self.variant().is_csir
     }
 
     #[inline(always)]
     fn function_entry_only(&self) -> bool {
-        (self.version & VARIANT_MASK_FUNCTION_ENTRY_ONLY) != 0
+        self.variant().entry_only
     }
 
     #[inline(always)]
     fn memory_profile(&self) -> bool {
-        (self.version & VARIANT_MASK_MEMORY_PROFILE) != 0
+        self.variant().memory_profile
     }
 
     #[inline(always)]
@@ -122,18 +127,93 @@ pub struct ProfileData<T> {
     function_addr: T,
     values_ptr_expr: T,
     num_counters: u32,
-    /// This might just be two values?
-    num_value_sites: [u16; ValueKind::MemOpSize as usize + 1],
+    /// Number of value sites for every value kind up to the header's `value_kind_last`, this
+    /// includes kinds newer than the ones in `ValueKind`
+    num_value_sites: Vec<u16>,
     num_bitmap_bytes: u32,
 }
 
 impl<T> ProfileData<T> {
+    /// Size of the record in the data section including any padding
     fn len(&self) -> usize {
-        16 + 4 + (2 * (ValueKind::MemOpSize as usize + 1)) + 3 * size_of::<T>()
+        let len = 16 + 4 + 2 * self.num_value_sites.len() + 3 * size_of::<T>();
+        if self.bitmap_ptr.is_some() {
+            // The number of bitmap bytes is aligned to 4 bytes after the value sites and the
+            // record is padded to 8 bytes
+            let len = len + size_of::<T>();
+            let len = ((len + 3) & !3) + 4;
+            len + get_num_padding_bytes(len as u64) as usize
+        } else {
+            len
+        }
     }
 }
 
+/// Gets the offset of a counter pointer from the counters section. Pointers are only as wide as
+/// the target's memory width so the difference is sign extended from that width.
+fn relative_offset<T: MemoryWidthExt>(counter_ptr: T, counters_delta: u64) -> i64 {
+    let shift = 64 - 8 * size_of::<T>() as u32;
+    ((counter_ptr.into().wrapping_sub(counters_delta) << shift) as i64) >> shift
+}
+
 impl Header {
+    /// The header fields read from the file, the format is `Raw32` or `Raw64` depending on the
+    /// pointer width
+    pub fn info(&self, pointer_width: usize) -> ProfileHeaderInfo {
+        let format = if pointer_width == 4 {
+            HeaderFormat::Raw32
+        } else {
+            HeaderFormat::Raw64
+        };
+        let version = self.version();
+        let mut fields = vec![];
+        if version >= 7 {
+            fields.push(("binary_ids_len", self.binary_ids_len));
+        }
+        fields.extend([
+            ("data_len", self.data_len),
+            (
+                "padding_bytes_before_counters",
+                self.padding_bytes_before_counters,
+            ),
+            ("counters_len", self.counters_len),
+            (
+                "padding_bytes_after_counters",
+                self.padding_bytes_after_counters,
+            ),
+        ]);
+        if version >= 9 {
+            fields.extend([
+                ("num_bitmap_bytes", self.num_bitmap_bytes),
+                (
+                    "padding_bytes_after_bitmap_bytes",
+                    self.padding_bytes_after_bitmap_bytes,
+                ),
+            ]);
+        }
+        fields.extend([
+            ("names_len", self.names_len),
+            ("counters_delta", self.counters_delta),
+        ]);
+        if version >= 9 {
+            fields.push(("bitmap_delta", self.bitmap_delta));
+        }
+        fields.push(("names_delta", self.names_delta));
+        if version >= 10 {
+            fields.extend([
+                ("num_vtables", self.num_vtables),
+                ("vnames_size", self.vnames_size),
+            ]);
+        }
+        fields.push(("value_kind_last", self.value_kind_last));
+        ProfileHeaderInfo {
+            format,
+            endianness: self.endianness,
+            version: self.version,
+            fields,
+        }
+    }
+
     pub fn max_counters_len(&self) -> i64 {
         ((8 * self.counters_len) + self.padding_bytes_after_counters) as i64
     }
@@ -206,83 +286,276 @@ impl<T> RawInstrProf<T>
 where
     T: MemoryWidthExt,
 {
+    /// Parses a single profile, a file can contain several profiles one after another
+    fn parse_profile(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        let mut result = InstrumentationProfile::default();
+        let (bytes, header) = Self::parse_header(input)?;
+        let version_num = header.version();
+        result.header = Some(header.info(size_of::<T>()));
+        result.version = Some(version_num);
+        result.llvm_version = LlvmVersion::from_raw_version(version_num);
+        result.is_ir = header.ir_profile();
+        result.has_csir = header.csir_profile();
+        if version_num > 7 {
+            result.is_byte_coverage = header.has_byte_coverage();
+            result.fn_entry_only = header.function_entry_only();
+            result.memory_profiling = header.memory_profile();
+        }
+        if bytes.len() < header.binary_ids_len as usize {
+            return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                &bytes[bytes.len()..],
+                ErrorKind::Eof,
+            )));
+        }
+        let (binary_ids, _) = bytes.split_at(header.binary_ids_len as usize);
+        let (_, binary_ids) = parse_binary_ids(binary_ids, header.endianness)?;
+        result.binary_ids = binary_ids;
+        input = &bytes[(header.binary_ids_len as usize)..];
+        let mut data_section = vec![];
+        for _ in 0..header.data_len {
+            let (bytes, data) = ProfileData::<T>::parse(input, &header)?;
+            debug!("Parsed data section {:?}", data);
+            data_section.push(data);
+            input = bytes;
+        }
+        let bytes = match take(header.padding_bytes_before_counters as usize)(input) {
+            Ok((b, _)) => b,
+            Err(e) => {
+                error!("Failed to skip padding bytes");
+                return Err(e);
+            }
+        };
+        input = bytes;
+        let counters_len = (header.counters_len as usize).saturating_mul(header.counter_size());
+        let (bytes, counters_section) = take(counters_len)(input)?;
+        let (bytes, _) = take(header.padding_bytes_after_counters as usize)(bytes)?;
+        let mut counters = vec![];
+        // From version 8 the counter pointer in a data record is relative to that record, so
+        // the delta from the counters section moves back by a record for every record read.
+        // Older versions store the counters contiguously in the same order as the records.
+        let mut counters_delta = header.counters_delta;
+        let mut next_offset = 0;
+        for data in &data_section {
+            let counters_offset = if version_num >= 8 {
+                relative_offset(data.counter_ptr, counters_delta)
+            } else {
+                next_offset
+            };
+            let (_, record) =
+                Self::read_raw_counts(&header, data, counters_offset, counters_section)?;
+            debug!("Read counter record {:?}", record);
+            next_offset = counters_offset + (record.counts.len() * header.counter_size()) as i64;
+            counters_delta = counters_delta.wrapping_sub(data.len() as u64);
+            counters.push(record);
+        }
+        input = bytes;
+        let bitmap_len =
+            header.num_bitmap_bytes as usize + header.padding_bytes_after_bitmap_bytes as usize;
+        let (bytes, _) = take(bitmap_len)(input)?;
+        input = bytes;
+        let end_length = input.len() - header.names_len as usize;
+        let mut symtab = Symtab::default();
+        while input.len() > end_length {
+            let (new_bytes, names) = parse_string_ref(input)?;
+            debug!(
+                "Complete names string: '{}'. Read {} bytes",
+                names,
+                input.len() - new_bytes.len()
+            );
+            input = new_bytes;
+            for name in names.split(INSTR_PROF_NAME_SEP) {
+                debug!("Symbol name parsed: '{}'", name);
+                // The name refs were read in the file's endianness so they match the
+                // little endian hash whatever the endianness of the file
+                symtab.add_func_name(name.to_string(), None);
+            }
+        }
+        let padding = get_num_padding_bytes(header.names_len);
+        let (bytes, _) = take(padding)(input)?;
+        input = bytes;
+        if version_num >= 10 {
+            // Skip the vtable profile data and names, the value data is after these
+            // VTableProfileData is a u64 name hash, pointer and u32 size aligned to 8 bytes
+            let vtable_entry_len = 8 + size_of::<T>() + 4;
+            let vtable_entry_len =
+                vtable_entry_len + get_num_padding_bytes(vtable_entry_len as u64) as usize;
+            let vtable_data_len = header.num_vtables as usize * vtable_entry_len;
+            let vtables_len = vtable_data_len
+                + get_num_padding_bytes(vtable_data_len as u64) as usize
+                + header.vnames_size as usize
+                + get_num_padding_bytes(header.vnames_size) as usize;
+            let (bytes, _) = take(vtables_len)(input)?;
+            input = bytes;
+        }
+        let address_map = data_section
+            .iter()
+            .filter(|x| x.function_addr.into() != 0)
+            .map(|x| (x.function_addr.into(), x.name_ref))
+            .collect::<FxHashMap<u64, u64>>();
+        for (data, mut record) in data_section.iter().zip(counters.drain(..)) {
+            let (bytes, _) =
+                Self::read_value_profiling_data(&header, data, &address_map, input, &mut record)?;
+            input = bytes;
+            let name = symtab.names.get(&data.name_ref).cloned();
+            let (hash, name_hash) = if symtab.contains(data.name_ref) {
+                // Previously this function calculated the function hash itself to be
+                // ultra-defensive against the profraw format changing hash calculation method
+                // so we try not to rely on reimplementing it. However, md5::compute was more
+                // expensive than initially assumed and using the precomputed one reduces
+                // runtime by 25% on benchmarks
+                (Some(data.func_hash), Some(data.name_ref))
+            } else {
+                (None, None)
+            };
+            debug!("Parsed record: {:?} {:?} {:?}", name, hash, record);
+
+            result.push_record(NamedInstrProfRecord {
+                name,
+                name_hash,
+                hash,
+                record,
+            });
+        }
+        result.symtab = symtab;
+        Ok((input, result))
+    }
+
+    /// Reads the counters for `data` starting `counter_offset` bytes into `counters`, the
+    /// counters section of the profile
     fn read_raw_counts<'a>(
         header: &Header,
         data: &ProfileData<T>,
         counter_offset: i64,
-        mut bytes: &'a [u8],
+        counters: &'a [u8],
     ) -> ParseResult<'a, InstrProfRecord> {
-        let max_counters = header.max_counters_len();
-        // From LLVM coverage mapping version 8 relative counter offsets are allowed which can be
-        // signed
-        // num 2 max 24 offset 7 counters len 3
         trace!(
-            "Reading raw counts offset: {} max: {}. data {:?}",
+            "Reading raw counts offset: {} counters section: {}. data {:?}",
             counter_offset,
-            max_counters,
+            counters.len(),
             data
         );
+        let counters_size = data.num_counters as u64 * header.counter_size() as u64;
         if data.num_counters == 0
-            || max_counters < 0
             || counter_offset < 0
-            || counter_offset as u64 >= (header.counters_len * header.counter_size() as u64)
-            || data.num_counters as i64 > max_counters
-            || (header.version < 8 && counter_offset < 0)
-            || counter_offset > max_counters
-            || counter_offset + data.num_counters as i64 > max_counters
+            || counter_offset as u64 + counters_size > counters.len() as u64
         {
             error!("consistency check for reading counts failed");
-            //Err(Err::Failure(Error::new(bytes, ErrorKind::Satisfy))) TODO
-            Err(Err::Failure(VerboseError::from_error_kind(
-                bytes,
-                ErrorKind::Satisfy,
-            )))
-        } else if counter_offset as usize > bytes.len() {
-            let pos = &bytes[bytes.len()..];
-            let inner = VerboseError::from_error_kind(pos, ErrorKind::Eof);
-            Err(Err::Failure(VerboseError::add_context(
-                pos,
-                "end of file reached before counters offset",
+            let inner = VerboseError::from_error_kind(counters, ErrorKind::Satisfy);
+            return Err(Err::Failure(VerboseError::add_context(
+                counters,
+                "counters for record outside of counters section",
                 inner,
-            )))
-        } else {
-            let mut counts = Vec::<u64>::with_capacity(data.num_counters as usize);
-            bytes = &bytes[(counter_offset as usize)..];
-            for _ in 0..(data.num_counters as usize) {
-                let counter = if header.has_byte_coverage() {
-                    let counter = bytes[0];
-                    bytes = &bytes[1..];
-                    (counter == 0) as u64
-                } else {
-                    let (b, counter) = nom_u64(header.endianness)(bytes)?;
-                    bytes = b;
-                    counter
-                };
-                counts.push(counter);
-            }
-            let record = InstrProfRecord {
-                counts,
-                ..Default::default()
+            )));
+        }
+        let mut bytes = &counters[(counter_offset as usize)..];
+        let mut counts = Vec::<u64>::with_capacity(data.num_counters as usize);
+        for _ in 0..(data.num_counters as usize) {
+            let counter = if header.has_byte_coverage() {
+                let (b, counter) = nom_u8(bytes)?;
+                bytes = b;
+                (counter == 0) as u64
+            } else {
+                let (b, counter) = nom_u64(header.endianness)(bytes)?;
+                bytes = b;
+                counter
             };
-            Ok((bytes, record))
+            counts.push(counter);
         }
+        let record = InstrProfRecord {
+            counts,
+            ..Default::default()
+        };
+        Ok((bytes, record))
     }
 
+    /// Reads the `ValueProfData` block for a record. This is made up of a total size and number
+    /// of value kinds followed by a `ValueProfRecord` for each kind. Indirect call targets are
+    /// stored as function addresses so these are remapped to the MD5 hash of the function name
+    /// using the address map, any targets not in the map (i.e. external functions) become 0.
     fn read_value_profiling_data<'a>(
         header: &Header,
         data: &ProfileData<T>,
+        address_map: &FxHashMap<u64, u64>,
         bytes: &'a [u8],
-        _record: &mut InstrProfRecord,
+        record: &mut InstrProfRecord,
     ) -> ParseResult<'a, ()> {
-        // record clear value data
+        record.data = None;
         if data.num_value_sites.iter().all(|x| *x == 0) {
             // Okay so there's no value profiling data. So the next byte is actually a header
             // wewww
-            Ok((bytes, ()))
-        } else {
-            let (_bytes, _total_size) = nom_u32(header.endianness)(bytes)?;
-            todo!()
+            return Ok((bytes, ()));
+        }
+        let endianness = header.endianness;
+        let (input, total_size) = nom_u32(endianness)(bytes)?;
+        let (mut input, num_value_kinds) = nom_u32(endianness)(input)?;
+        if (total_size as usize) < 2 * size_of::<u32>()
+            || total_size as usize > bytes.len()
+            || num_value_kinds as u64 > header.value_kind_last.saturating_add(1)
+        {
+            error!("malformed value profile data");
+            return Err(Err::Failure(VerboseError::from_error_kind(
+                bytes,
+                ErrorKind::Verify,
+            )));
         }
+        let mut value_data = Box::<ValueProfDataRecord>::default();
+        for _ in 0..num_value_kinds {
+            let (bytes, kind) = nom_u32(endianness)(input)?;
+            let (bytes, num_value_sites) = nom_u32(endianness)(bytes)?;
+            if kind as u64 > header.value_kind_last {
+                return Err(Err::Failure(VerboseError::from_error_kind(
+                    input,
+                    ErrorKind::OneOf,
+                )));
+            }
+            let (bytes, site_counts) = take(num_value_sites as usize)(bytes)?;
+            // The site count array is padded so the value data is 8 byte aligned
+            let padding =
+                get_num_padding_bytes(2 * size_of::<u32>() as u64 + num_value_sites as u64)
+                    as usize;
+            let (mut bytes, _) = take(padding)(bytes)?;
+            let kind = match kind {
+                0 => ValueKind::IndirectCallTarget,
+                1 => ValueKind::MemOpSize,
+                _ => {
+                    // Kinds from newer versions of llvm aren't modelled so their values are
+                    // skipped, each value is a 64 bit value and count
+                    let num_values = site_counts.iter().map(|x| *x as usize).sum::<usize>();
+                    let (bytes, _) = take(num_values * 2 * size_of::<u64>())(bytes)?;
+                    debug!("Skipped value data for unknown value kind {}", kind);
+                    input = bytes;
+                    continue;
+                }
+            };
+            let mut sites = Vec::with_capacity(site_counts.len());
+            for num_values in site_counts {
+                let mut site = Vec::with_capacity(*num_values as usize);
+                for _ in 0..*num_values {
+                    let (b, value) = nom_u64(endianness)(bytes)?;
+                    let (b, count) = nom_u64(endianness)(b)?;
+                    bytes = b;
+                    let value = match kind {
+                        ValueKind::IndirectCallTarget => {
+                            address_map.get(&value).copied().unwrap_or_default()
+                        }
+                        ValueKind::MemOpSize => value,
+                    };
+                    site.push(InstrProfValueData { value, count });
+                }
+                // Match the order the text and indexed readers give, llvm writes the values in
+                // descending order of count keeping the runtime order for equal counts
+                site.sort_by_key(|x| std::cmp::Reverse(x.count));
+                sites.push(site);
+            }
+            match kind {
+                ValueKind::IndirectCallTarget => value_data.indirect_callsites = sites,
+                ValueKind::MemOpSize => value_data.mem_op_sizes = sites,
+            }
+            input = bytes;
+        }
+        record.data = Some(value_data);
+        // Use the total size to move onto the next record in case there's any trailing padding
+        Ok((&bytes[(total_size as usize)..], ()))
     }
 }
 
@@ -292,137 +565,38 @@ where
 {
     type Header = Header;
 
-    fn parse_bytes(mut input: &[u8]) -> ParseResult<InstrumentationProfile> {
-        if !input.is_empty() {
-            let mut result = InstrumentationProfile::default();
-            let (bytes, header) = Self::parse_header(input)?;
-            // LLVM 11 and 12 are version 5. LLVM 13 is version 7
-            let version_num = header.version();
-            result.version = Some(version_num);
-            result.is_ir = header.ir_profile();
-            result.has_csir = header.csir_profile();
-            if version_num > 7 {
-                result.is_byte_coverage = header.has_byte_coverage();
-                result.fn_entry_only = header.function_entry_only();
-                result.memory_profiling = header.memory_profile();
+    fn parse_bytes(input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        let (mut input, mut result) = Self::parse_profile(input)?;
+        // Processes writing to the same file without `%p` in the filename append their profiles
+        // one after another, like llvm-profdata these are merged
+        loop {
+            // Each profile is padded with zeroes to 8 bytes
+            let padding = input.iter().take_while(|x| **x == 0).count();
+            let next = &input[padding..];
+            if next.is_empty() || !Self::has_format(next) {
+                break;
             }
-            if bytes.len() < header.binary_ids_len as usize {
-                return Err(nom::Err::Failure(VerboseError::from_error_kind(
-                    &bytes[bytes.len()..],
-                    ErrorKind::Eof,
+            let (bytes, profile) = Self::parse_profile(next)?;
+            if result.merge(&profile).is_err() {
+                let error = VerboseError::from_error_kind(next, ErrorKind::Verify);
+                return Err(Err::Failure(VerboseError::add_context(
+                    next,
+                    "concatenated profiles can't be merged",
+                    error,
                 )));
             }
-            input = &bytes[(header.binary_ids_len as usize)..];
-            let mut data_section = vec![];
-            for _ in 0..header.data_len {
-                let (bytes, data) = ProfileData::<T>::parse(input, &header)?;
-                debug!("Parsed data section {:?}", data);
-                data_section.push(data);
-                if version_num > 8 {
-                    let (bytes, v) = take(4usize)(bytes)?; // TODO WHAT AM I MISSING HERE?
-                    debug!("Got those padding? bytes {:?}", v);
-                    input = bytes;
-                } else {
-                    input = bytes;
-                }
-            }
-            let bytes = match take(header.padding_bytes_before_counters as usize)(input) {
-                Ok((b, _)) => b,
-                Err(e) => {
-                    error!("Failed to skip padding bytes");
-                    return Err(e);
-                }
-            };
-            input = bytes;
-            let mut counters = vec![];
-            let mut counters_delta = header.counters_delta;
-
-            // Okay so the counters section looks a bit hairy. So as a brief explanation.
-            // 1. The base offset is from CountersStart pointer to entry of the record. Meaning
-            //    doing a nom type parsing we need to keep track of the total offset as counter
-            //    records can be offset in the middle of the counter list.
-            // 2. Also there may be some padding bytes before the last counter and end of counters
-            //    section. This needs to be applied as well as padding_bytes_after_counters for
-            //    total padding
-            let mut total_offset = 0;
-            let remaining_before_counters = input.len();
-            for data in &data_section {
-                let counters_offset = if header.version() == 8 {
-                    // Why oh why!?
-                    (data.counter_ptr.into() as i64 - counters_delta as i64) - total_offset
-                } else {
-                    0
-                };
-                let (bytes, record) = Self::read_raw_counts(&header, data, counters_offset, input)?;
-                debug!("Read counter record {:?}", record);
-                total_offset +=
-                    counters_offset + (record.counts.len() * header.counter_size()) as i64;
-                counters_delta -= data.len() as u64;
-                counters.push(record);
-                input = bytes;
-            }
-            let counters_end = header.padding_bytes_after_counters as usize
-                + (header.counters_len as usize * header.counter_size())
-                - (remaining_before_counters - input.len());
-            debug!("Applying padding bytes after counters");
-            let (bytes, _) = take(counters_end)(input)?;
             input = bytes;
-            let end_length = input.len() - header.names_len as usize;
-            let mut symtab = Symtab::default();
-            while input.len() > end_length {
-                let (new_bytes, names) = parse_string_ref(input)?;
-                debug!(
-                    "Complete names string: '{}'. Read {} bytes",
-                    names,
-                    input.len() - new_bytes.len()
-                );
-                input = new_bytes;
-                for name in names.split(INSTR_PROF_NAME_SEP) {
-                    debug!("Symbol name parsed: '{}'", name);
-                    symtab.add_func_name(name.to_string(), Some(header.endianness));
-                }
-            }
-            let padding = get_num_padding_bytes(header.names_len);
-            let (bytes, _) = take(padding)(input)?;
-            input = bytes;
-            for (data, mut record) in data_section.iter().zip(counters.drain(..)) {
-                let (bytes, _) =
-                    Self::read_value_profiling_data(&header, data, input, &mut record)?;
-                input = bytes;
-                let name = symtab.names.get(&data.name_ref).cloned();
-                let (hash, name_hash) = if symtab.contains(data.name_ref) {
-                    // Previously this function calculated the function hash itself to be
-                    // ultra-defensive against the profraw format changing hash calculation method
-                    // so we try not to rely on reimplementing it. However, md5::compute was more
-                    // expensive than initially assumed and using the precomputed one reduces
-                    // runtime by 25% on benchmarks
-                    (Some(data.func_hash), Some(data.name_ref))
-                } else {
-                    (None, None)
-                };
-                debug!("Parsed record: {:?} {:?} {:?}", name, hash, record);
-
-                result.push_record(NamedInstrProfRecord {
-                    name,
-                    name_hash,
-                    hash,
-                    record,
-                });
-            }
-            result.symtab = symtab;
-            Ok((input, result))
-        } else {
-            // Okay return an error here
-            todo!()
         }
+        Ok((input, result))
     }
 
-    fn parse_header(input: &[u8]) -> ParseResult<Self::Header> {
+    fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
         if Self::has_format(input) {
             let endianness = file_endianness::<T>(&input[..8].try_into().unwrap());
             let (bytes, version) = nom_u64(endianness)(&input[8..])?;
-            debug!("Profraw version: {}", version & !VARIANT_MASKS_ALL);
-            let (bytes, binary_ids_len) = if (version & !VARIANT_MASKS_ALL) >= 7 {
+            let version_num = ProfileVariant::from_version_word(version).version;
+            debug!("Profraw version: {}", version_num);
+            let (bytes, binary_ids_len) = if version_num >= 7 {
                 nom_u64(endianness)(bytes)?
             } else {
                 (bytes, 0)
@@ -432,19 +606,18 @@ where
             let (bytes, counters_len) = nom_u64(endianness)(bytes)?;
             let (bytes, padding_bytes_after_counters) = nom_u64(endianness)(bytes)?;
 
-            let (bytes, num_bitmap_bytes, padding_bytes_after_bitmap_bytes) =
-                if (version & !VARIANT_MASKS_ALL) >= 9 {
-                    let (bytes, num_bitmap_bytes) = nom_u64(endianness)(bytes)?;
-                    let (bytes, num_bitmap_padding_bytes) = nom_u64(endianness)(bytes)?;
-                    (bytes, num_bitmap_bytes, num_bitmap_padding_bytes)
-                } else {
-                    (bytes, 0, 0)
-                };
+            let (bytes, num_bitmap_bytes, padding_bytes_after_bitmap_bytes) = if version_num >= 9 {
+                let (bytes, num_bitmap_bytes) = nom_u64(endianness)(bytes)?;
+                let (bytes, num_bitmap_padding_bytes) = nom_u64(endianness)(bytes)?;
+                (bytes, num_bitmap_bytes, num_bitmap_padding_bytes)
+            } else {
+                (bytes, 0, 0)
+            };
 
             let (bytes, names_len) = nom_u64(endianness)(bytes)?;
             let (bytes, counters_delta) = nom_u64(endianness)(bytes)?;
 
-            let (bytes, bitmap_delta) = if (version & !VARIANT_MASKS_ALL) >= 9 {
+            let (bytes, bitmap_delta) = if version_num >= 9 {
                 let (bytes, bitmap_delta) = nom_u64(endianness)(bytes)?;
                 (bytes, bitmap_delta)
             } else {
@@ -453,7 +626,7 @@ where
 
             let (bytes, names_delta) = nom_u64(endianness)(bytes)?;
 
-            let (bytes, num_vtables, vnames_size) = if (version & !VARIANT_MASKS_ALL) >= 10 {
+            let (bytes, num_vtables, vnames_size) = if version_num >= 10 {
                 let (bytes, num_vtables) = nom_u64(endianness)(bytes)?;
                 let (bytes, vnames_len) = nom_u64(endianness)(bytes)?;
                 (bytes, num_vtables, vnames_len)
@@ -483,11 +656,19 @@ where
             };
             debug!("Read header {:?}", result);
             Ok((bytes, result))
+        } else if input.is_empty() {
+            let error = VerboseError::from_error_kind(input, ErrorKind::Eof);
+            Err(Err::Failure(VerboseError::add_context(
+                input,
+                "empty raw profile",
+                error,
+            )))
         } else {
-            //Err(Err::Failure(Error::new(input, ErrorKind::IsNot)))
-            Err(Err::Failure(VerboseError::from_error_kind(
+            let error = VerboseError::from_error_kind(input, ErrorKind::IsNot);
+            Err(Err::Failure(VerboseError::add_context(
                 input,
-                ErrorKind::IsNot,
+                "invalid raw profile magic",
+                error,
             )))
         }
     }
@@ -508,13 +689,13 @@ where
     T: MemoryWidthExt,
 {
     fn parse<'a>(
-        bytes: &'a [u8],
+        input: &'a [u8],
         header: &Header,
     ) -> IResult<&'a [u8], Self, VerboseError<&'a [u8]>> {
         let endianness = header.endianness;
         let parse = T::nom_parse_fn(endianness);
 
-        let (bytes, name_ref) = nom_u64(endianness)(bytes)?;
+        let (bytes, name_ref) = nom_u64(endianness)(input)?;
         let (bytes, func_hash) = nom_u64(endianness)(bytes)?;
         let (bytes, counter_ptr) = parse(bytes)?;
         let (bytes, bitmap_ptr) = if header.version() > 8 {
@@ -525,28 +706,34 @@ where
         };
         let (bytes, function_addr) = parse(bytes)?;
         let (bytes, values_ptr_expr) = parse(bytes)?;
-        let (bytes, num_counters) = nom_u32(endianness)(bytes)?;
-        let (bytes, value_0) = nom_u16(endianness)(bytes)?;
-        let (bytes, value_1) = nom_u16(endianness)(bytes)?;
+        let (mut bytes, num_counters) = nom_u32(endianness)(bytes)?;
+        // Newer versions of llvm can add value kinds so the header says how many there are
+        let mut num_value_sites = vec![];
+        for _ in 0..=header.value_kind_last {
+            let (b, sites) = nom_u16(endianness)(bytes)?;
+            num_value_sites.push(sites);
+            bytes = b;
+        }
         let (bytes, num_bitmap_bytes) = if header.version() > 8 {
+            let (bytes, _) = take(num_value_sites.len() % 2 * 2)(bytes)?;
             nom_u32(endianness)(bytes)?
         } else {
             (bytes, 0)
         };
-
-        Ok((
-            bytes,
-            Self {
-                name_ref,
-                func_hash,
-                counter_ptr,
-                bitmap_ptr,
-                function_addr,
-                values_ptr_expr,
-                num_counters,
-                num_value_sites: [value_0, value_1],
-                num_bitmap_bytes,
-            },
-        ))
+        let data = Self {
+            name_ref,
+            func_hash,
+            counter_ptr,
+            bitmap_ptr,
+            function_addr,
+            values_ptr_expr,
+            num_counters,
+            num_value_sites,
+            num_bitmap_bytes,
+        };
+        // Skip the padding at the end of the record
+        let read = input.len() - bytes.len();
+        let (bytes, _) = take(data.len().saturating_sub(read))(bytes)?;
+        Ok((bytes, data))
     }
 }
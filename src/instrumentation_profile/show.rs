@@ -0,0 +1,184 @@
+use crate::instrumentation_profile::summary::ProfileSummary;
+use crate::instrumentation_profile::types::*;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+/// Filters to decide which functions are shown, these match the `profparser show` options.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ShowFilters {
+    /// Show every function
+    pub all_functions: bool,
+    /// Show functions whose name contains this string
+    pub function: Option<String>,
+    /// Show the context sensitive records of IR profiles instead of the other records
+    pub context_sensitive: bool,
+    /// Functions with a max count below this aren't shown
+    pub value_cutoff: u64,
+    /// Demangle the function names
+    pub demangle: bool,
+    /// Sort the functions by name then hash instead of using the order in the profile
+    pub sort_by_name: bool,
+}
+
+impl ShowFilters {
+    /// Whether a function with this name is shown, the name is the one in the profile before any
+    /// demangling
+    pub fn shows(&self, name: &str) -> bool {
+        self.all_functions
+            || self
+                .function
+                .as_ref()
+                .map(|x| name.contains(x.as_str()))
+                .unwrap_or(false)
+    }
+}
+
+/// The information shown for a function
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShowFunction {
+    pub name: String,
+    pub hash: u64,
+    /// All the counters, for front-end profiles the first one is the function count
+    pub counts: Vec<u64>,
+    pub max_count: u64,
+    pub total_count: u64,
+    /// The targets of each indirect call site with their counts. Targets are resolved from the
+    /// name hashes in the profile's symbol table, targets outside the profile are `None`
+    pub indirect_call_sites: Vec<Vec<(Option<String>, u64)>>,
+    /// The sizes of each memory intrinsic call site with their counts
+    pub memop_sizes: Vec<Vec<(u64, u64)>>,
+}
+
+/// The functions and summary `profparser show` outputs for an instrumentation profile.
+#[derive(Clone, Debug)]
+pub struct ShowReport {
+    pub level: InstrumentationLevel,
+    /// The functions passing the filters
+    pub functions: Vec<ShowFunction>,
+    /// Summary of every function considered, this includes functions which aren't shown
+    pub summary: ProfileSummary,
+    /// Number of functions with a max count below the value cutoff
+    pub below_cutoff: usize,
+}
+
+impl ShowReport {
+    pub fn new(profile: &InstrumentationProfile, filters: &ShowFilters) -> Self {
+        let level = profile.get_level();
+        let is_ir_instr = level == InstrumentationLevel::Ir;
+        let mut summary = ProfileSummary::new();
+        let mut below_cutoff = 0;
+        let mut functions = vec![];
+        let mut records = profile.iter_named().collect::<Vec<_>>();
+        if filters.sort_by_name {
+            records.sort_by(|a, b| a.0.cmp(b.0).then_with(|| a.1.hash.cmp(&b.1.hash)));
+        }
+        for (name, func) in records {
+            let hash = match func.hash {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if is_ir_instr && func.has_cs_flag() != filters.context_sensitive {
+                continue;
+            }
+            summary.add_record(&func.record);
+            let max_count = func.max_count();
+            if max_count < filters.value_cutoff {
+                below_cutoff += 1;
+                continue;
+            }
+            if !filters.shows(name) {
+                continue;
+            }
+            let name = if filters.demangle {
+                demangle(name).unwrap_or_else(|| name.to_string())
+            } else {
+                name.to_string()
+            };
+            let data = func.record.data.as_ref();
+            let indirect_call_sites = data
+                .map(|x| x.indirect_callsites.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|site| {
+                    site.iter()
+                        .map(|x| (profile.symtab.get(x.value).cloned(), x.count))
+                        .collect()
+                })
+                .collect();
+            let memop_sizes = data
+                .map(|x| x.mem_op_sizes.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|site| site.iter().map(|x| (x.value, x.count)).collect())
+                .collect();
+            functions.push(ShowFunction {
+                name,
+                hash,
+                counts: func.counts().to_vec(),
+                max_count,
+                total_count: func.total_count(),
+                indirect_call_sites,
+                memop_sizes,
+            });
+        }
+        Self {
+            level,
+            functions,
+            summary,
+            below_cutoff,
+        }
+    }
+
+    /// Writes the report as JSON. Each function has its name, hash, number of counters, block
+    /// counts, max and total count and value sites under `functions`, front-end profiles also
+    /// have the function count. The summary of all the functions is under `summary`.
+    pub fn to_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        let is_ir_instr = self.level == InstrumentationLevel::Ir;
+        let functions = self
+            .functions
+            .iter()
+            .map(|func| {
+                // Front-end profiles store the function count as the first counter
+                let start = if is_ir_instr { 0 } else { 1 };
+                let mut value = json!({
+                    "name": func.name,
+                    "hash": func.hash,
+                    "counters": func.counts.len(),
+                    "block_counts": func.counts.iter().skip(start).collect::<Vec<_>>(),
+                    "max_count": func.max_count,
+                    "total_count": func.total_count,
+                    "indirect_call_sites": func.indirect_call_sites.iter().map(|site| {
+                        site.iter().map(|(target, count)| json!({
+                            "target": target,
+                            "count": count,
+                        })).collect::<Vec<_>>()
+                    }).collect::<Vec<_>>(),
+                    "memop_sizes": func.memop_sizes.iter().map(|site| {
+                        site.iter().map(|(size, count)| json!({
+                            "size": size,
+                            "count": count,
+                        })).collect::<Vec<_>>()
+                    }).collect::<Vec<_>>(),
+                });
+                if !is_ir_instr {
+                    value["function_count"] = json!(func.counts.first().copied().unwrap_or(0));
+                }
+                value
+            })
+            .collect::<Vec<Value>>();
+        let export = json!({
+            "instrumentation_level": self.level.to_string(),
+            "functions": functions,
+            "summary": {
+                "total_functions": self.summary.num_functions(),
+                "below_cutoff": self.below_cutoff,
+                "max_function_count": self.summary.max_function_count(),
+                "max_internal_block_count": self.summary.max_internal_block_count(),
+                "total_blocks": self.summary.num_counts(),
+                "total_count": self.summary.total_count(),
+            },
+        });
+        serde_json::to_writer(&mut *writer, &export)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use crate::instrumentation_profile::types::*;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Statistics on the value profiling sites for a single value kind. These are gathered by
+/// traversing the value sites of each record with `ValueSiteStats::traverse_sites`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ValueSiteStats {
+    /// Total number of value sites
+    pub total_num_value_sites: u64,
+    /// Number of value sites with at least one profiled value
+    pub total_value_sites_with_value_profile: u64,
+    /// Total number of profiled values across all sites
+    pub total_num_values: u64,
+    /// Number of sites for each number of targets, index `i` is the count of sites with `i + 1`
+    /// values
+    pub histogram: Vec<u64>,
+}
+
+impl ValueSiteStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Walks the value sites of the given kind in a record updating the statistics and writing the
+    /// values at each site out. If a symtab is provided values are written as the function names
+    /// they resolve to (this is only meaningful for indirect call targets).
+    pub fn traverse_sites(
+        &mut self,
+        record: &InstrProfRecord,
+        kind: ValueKind,
+        symtab: Option<&Symtab>,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        let sites = match record.data.as_ref() {
+            Some(data) => match kind {
+                ValueKind::IndirectCallTarget => &data.indirect_callsites,
+                ValueKind::MemOpSize => &data.mem_op_sizes,
+            },
+            None => return Ok(()),
+        };
+        self.total_num_value_sites += sites.len() as u64;
+        for (i, site) in sites.iter().enumerate() {
+            if site.is_empty() {
+                continue;
+            }
+            self.total_num_values += site.len() as u64;
+            self.total_value_sites_with_value_profile += 1;
+            if site.len() > self.histogram.len() {
+                self.histogram.resize(site.len(), 0);
+            }
+            self.histogram[site.len() - 1] += 1;
+
+            let site_sum = site
+                .iter()
+                .fold(0u64, |acc, x| acc.saturating_add(x.count))
+                .max(1);
+            for value in site.iter() {
+                let percentage = (value.count as f64 * 100.0) / site_sum as f64;
+                match symtab {
+                    Some(symtab) => {
+                        let name = symtab
+                            .get(value.value)
+                            .map(|x| x.as_str())
+                            .unwrap_or_default();
+                        writeln!(
+                            writer,
+                            "\t[ {:2}, {}, {:10} ] ({:.2}%)",
+                            i, name, value.count, percentage
+                        )?;
+                    }
+                    None => {
+                        writeln!(
+                            writer,
+                            "\t[ {:2}, {:4}, {:10} ] ({:.2}%)",
+                            i, value.value, value.count, percentage
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ValueSiteStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  Total number of sites: {}", self.total_num_value_sites)?;
+        if self.total_num_value_sites == 0 {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "  Total number of sites with values: {}",
+            self.total_value_sites_with_value_profile
+        )?;
+        writeln!(
+            f,
+            "  Total number of profiled values: {}",
+            self.total_num_values
+        )?;
+        writeln!(f, "  Value sites histogram:")?;
+        writeln!(f, "\tNumTargets, SiteCount")?;
+        for (i, count) in self.histogram.iter().enumerate().filter(|(_, x)| **x > 0) {
+            writeln!(f, "\t{}, {}", i + 1, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value_data(values: &[(u64, u64)]) -> Vec<InstrProfValueData> {
+        values
+            .iter()
+            .map(|(value, count)| InstrProfValueData {
+                value: *value,
+                count: *count,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn site_statistics() {
+        let mut symtab = Symtab::default();
+        symtab.add_func_name("foo".to_string(), None);
+        let foo = compute_hash("foo");
+
+        let record = InstrProfRecord {
+            counts: vec![100],
+            data: Some(Box::new(ValueProfDataRecord {
+                indirect_callsites: vec![
+                    value_data(&[(foo, 30), (0, 10)]),
+                    vec![],
+                    value_data(&[(foo, 5)]),
+                ],
+                mem_op_sizes: vec![value_data(&[(8, 1), (16, 3)])],
+            })),
+        };
+
+        let mut ic_stats = ValueSiteStats::new();
+        let mut output = vec![];
+        ic_stats
+            .traverse_sites(
+                &record,
+                ValueKind::IndirectCallTarget,
+                Some(&symtab),
+                &mut output,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\t[  0, foo,         30 ] (75.00%)\n\t[  0, ,         10 ] (25.00%)\n\t[  2, foo,          5 ] (100.00%)\n"
+        );
+        assert_eq!(ic_stats.total_num_value_sites, 3);
+        assert_eq!(ic_stats.total_value_sites_with_value_profile, 2);
+        assert_eq!(ic_stats.total_num_values, 3);
+        assert_eq!(ic_stats.histogram, vec![1, 1]);
+
+        let mut memop_stats = ValueSiteStats::new();
+        let mut output = vec![];
+        memop_stats
+            .traverse_sites(&record, ValueKind::MemOpSize, None, &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\t[  0,    8,          1 ] (25.00%)\n\t[  0,   16,          3 ] (75.00%)\n"
+        );
+        assert_eq!(
+            memop_stats.to_string(),
+            "  Total number of sites: 1
+  Total number of sites with values: 1
+  Total number of profiled values: 2
+  Value sites histogram:
+\tNumTargets, SiteCount
+\t2, 1
+"
+        );
+    }
+}
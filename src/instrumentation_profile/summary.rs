@@ -1,4 +1,5 @@
 use crate::instrumentation_profile::types::*;
+use crate::summary::{ProfileSummaryEntry, CUTOFF_SCALE, DEFAULT_CUTOFFS};
 use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Default)]
@@ -47,6 +48,18 @@ impl ProfileSummary {
         }
     }
 
+    pub fn num_counts(&self) -> usize {
+        self.count_frequencies.values().sum()
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.total_count
+    }
+
+    pub fn max_count(&self) -> u64 {
+        self.max_count
+    }
+
     pub fn num_functions(&self) -> usize {
         self.num_functions
     }
@@ -58,4 +71,133 @@ impl ProfileSummary {
     pub fn max_internal_block_count(&self) -> u64 {
         self.max_internal_block_count
     }
+
+    /// The number of counters seen for each count value
+    pub fn count_frequencies(&self) -> &BTreeMap<u64, usize> {
+        &self.count_frequencies
+    }
+
+    /// The count at the percentile `p` (between 0 and 100) of all the counters, this is the
+    /// smallest count where at least `p` percent of the counters are less than or equal to it.
+    /// Returns 0 if there are no counters.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let num_counts = self.num_counts();
+        let p = if p.is_nan() { 0.0 } else { p.clamp(0.0, 100.0) };
+        let target = ((p / 100.0) * num_counts as f64).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (count, freq) in &self.count_frequencies {
+            seen += *freq;
+            if seen >= target {
+                return *count;
+            }
+        }
+        0
+    }
+
+    /// Computes the detailed summary for the given cutoffs, if no cutoffs are provided the
+    /// `DEFAULT_CUTOFFS` are used. Each cutoff is a percentile of the total count scaled by
+    /// `CUTOFF_SCALE` and the entry contains the minimum count and number of counts needed to
+    /// reach it when taking the largest counts first.
+    pub fn detailed_summary(&self, cutoffs: &[u64]) -> Vec<ProfileSummaryEntry> {
+        let mut cutoffs = if cutoffs.is_empty() {
+            DEFAULT_CUTOFFS.to_vec()
+        } else {
+            cutoffs.to_vec()
+        };
+        cutoffs.sort_unstable();
+
+        let mut result = Vec::with_capacity(cutoffs.len());
+        let mut frequencies = self.count_frequencies.iter().rev();
+        let mut current_sum = 0u64;
+        let mut min_count = 0;
+        let mut num_counts = 0;
+        for cutoff in cutoffs {
+            let desired_count =
+                ((self.total_count as u128 * cutoff as u128) / CUTOFF_SCALE as u128) as u64;
+            while current_sum < desired_count {
+                match frequencies.next() {
+                    Some((count, freq)) => {
+                        min_count = *count;
+                        current_sum =
+                            current_sum.saturating_add(count.saturating_mul(*freq as u64));
+                        num_counts += *freq as u64;
+                    }
+                    None => break,
+                }
+            }
+            result.push(ProfileSummaryEntry {
+                cutoff,
+                min_count,
+                num_counts,
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detailed_summary_cutoffs() {
+        let mut summary = ProfileSummary::new();
+        summary.add_record(&InstrProfRecord {
+            counts: vec![7, 13, 11],
+            data: None,
+        });
+        summary.add_record(&InstrProfRecord {
+            counts: vec![3, 5, 2],
+            data: None,
+        });
+        assert_eq!(summary.num_counts(), 6);
+        assert_eq!(summary.total_count(), 41);
+
+        let entries = summary.detailed_summary(&[500000, 10000, 999999]);
+        let entries = entries
+            .iter()
+            .map(|x| (x.cutoff, x.min_count, x.num_counts))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![(10000, 0, 0), (500000, 11, 2), (999999, 2, 6)]
+        );
+
+        assert_eq!(summary.detailed_summary(&[]).len(), DEFAULT_CUTOFFS.len());
+    }
+
+    #[test]
+    fn count_percentiles() {
+        let mut summary = ProfileSummary::new();
+        assert!(summary.count_frequencies().is_empty());
+        assert_eq!(summary.percentile(50.0), 0);
+
+        summary.add_record(&InstrProfRecord {
+            counts: vec![7, 13, 11, 0],
+            data: None,
+        });
+        summary.add_record(&InstrProfRecord {
+            counts: vec![7, 5, 2, 0],
+            data: None,
+        });
+        let frequencies = summary
+            .count_frequencies()
+            .iter()
+            .map(|(count, freq)| (*count, *freq))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frequencies,
+            vec![(0, 2), (2, 1), (5, 1), (7, 2), (11, 1), (13, 1)]
+        );
+
+        assert_eq!(summary.percentile(0.0), 0);
+        assert_eq!(summary.percentile(25.0), 0);
+        assert_eq!(summary.percentile(30.0), 2);
+        assert_eq!(summary.percentile(50.0), 5);
+        assert_eq!(summary.percentile(75.0), 7);
+        assert_eq!(summary.percentile(90.0), 13);
+        assert_eq!(summary.percentile(100.0), 13);
+        assert_eq!(summary.percentile(150.0), 13);
+        assert_eq!(summary.percentile(-1.0), 0);
+    }
 }
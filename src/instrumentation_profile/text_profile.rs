@@ -18,6 +18,7 @@ const FE_TAG: &[u8] = b"fe";
 const CSIR_TAG: &[u8] = b"csir";
 const ENTRY_TAG: &[u8] = b"entry_first";
 const NOT_ENTRY_TAG: &[u8] = b"not_entry_first";
+const BYTE_COVERAGE_TAG: &[u8] = b"single_byte_coverage";
 const EXTERNAL_SYMBOL: &[u8] = b"** External Symbol **";
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -28,6 +29,7 @@ pub struct Header {
     is_ir_level: bool,
     has_csir: bool,
     entry_first: bool,
+    byte_coverage: bool,
 }
 
 fn check_tag(data: &[u8], tag: &[u8]) -> bool {
@@ -47,7 +49,7 @@ fn str_to_digit(bytes: &[u8]) -> u64 {
         .unwrap_or_default()
 }
 
-fn read_hexadecimal(input: &[u8]) -> ParseResult<u64> {
+fn read_hexadecimal(input: &[u8]) -> ParseResult<'_, u64> {
     preceded(alt((tag(b"0x"), tag(b"0X"))), take_while1(is_hex_digit))(input).map(|(b, v)| unsafe {
         // We know this is okay because it's just the bytes that pass `is_hex_digit`
         (
@@ -63,11 +65,11 @@ fn valid_name_char(character: u8) -> bool {
     c.is_ascii() && c != '\n' && c != '\r'
 }
 
-fn strip_whitespace(s: &[u8]) -> ParseResult<()> {
+fn strip_whitespace(s: &[u8]) -> ParseResult<'_, ()> {
     one_of(&b" \n\r\t"[..])(s).map(|(b, _)| (b, ()))
 }
 
-fn strip_comments(s: &[u8]) -> ParseResult<()> {
+fn strip_comments(s: &[u8]) -> ParseResult<'_, ()> {
     delimited(
         tag(b"#"),
         alt((take_until("\r"), take_until("\n"))),
@@ -76,48 +78,52 @@ fn strip_comments(s: &[u8]) -> ParseResult<()> {
     .map(|(b, _)| (b, ()))
 }
 
-fn skip_to_content(s: &[u8]) -> ParseResult<()> {
+fn skip_to_content(s: &[u8]) -> ParseResult<'_, ()> {
     many0(alt((strip_whitespace, strip_comments)))(s).map(|(b, _)| (b, ()))
 }
 
-fn match_header_tags(s: &[u8]) -> ParseResult<&[u8]> {
+fn match_header_tags(s: &[u8]) -> ParseResult<'_, &[u8]> {
     alt((
         tag_no_case(IR_TAG),
         tag_no_case(FE_TAG),
         tag_no_case(CSIR_TAG),
         tag_no_case(ENTRY_TAG),
+        tag_no_case(BYTE_COVERAGE_TAG),
         take_until("\r"),
         take_until("\n"),
     ))(s)
 }
 
-fn parse_header_tags(s: &[u8]) -> ParseResult<Vec<&[u8]>> {
+fn parse_header_tags(s: &[u8]) -> ParseResult<'_, Vec<&[u8]>> {
     many0(delimited(tag(b":"), match_header_tags, line_ending))(s)
 }
 
-fn read_line(s: &[u8]) -> ParseResult<&[u8]> {
+fn read_line(s: &[u8]) -> ParseResult<'_, &[u8]> {
     tuple((take_while1(valid_name_char), line_ending))(s).map(|(b, (v, _))| (b, v))
 }
 
-fn read_decimal(s: &[u8]) -> ParseResult<u64> {
+fn read_decimal(s: &[u8]) -> ParseResult<'_, u64> {
     tuple((take_while1(is_digit), alt((line_ending, eof))))(s).map(|(b, v)| (b, str_to_digit(v.0)))
 }
 
-fn read_digit(s: &[u8]) -> ParseResult<u64> {
+fn read_digit(s: &[u8]) -> ParseResult<'_, u64> {
     alt((read_decimal, read_hexadecimal))(s)
 }
 
-fn indirect_value_site(s: &[u8]) -> ParseResult<(&[u8], u64)> {
+fn indirect_value_site(s: &[u8]) -> ParseResult<'_, (&[u8], u64)> {
     tuple((take_until(":"), tag(":"), take_while1(is_digit)))(s)
         .map(|(b, v)| (b, (v.0, str_to_digit(v.2))))
 }
 
-fn memop_value_site(s: &[u8]) -> ParseResult<(u64, u64)> {
+fn memop_value_site(s: &[u8]) -> ParseResult<'_, (u64, u64)> {
     tuple((take_while1(is_digit), tag(":"), take_while1(is_digit)))(s)
         .map(|(b, v)| (b, (str_to_digit(v.0), str_to_digit(v.2))))
 }
 
-fn read_value_profile_data(mut input: &[u8]) -> ParseResult<Option<Box<ValueProfDataRecord>>> {
+fn read_value_profile_data<'a>(
+    mut input: &'a [u8],
+    symtab: &mut Symtab,
+) -> ParseResult<'a, Option<Box<ValueProfDataRecord>>> {
     if let Ok((bytes, n_kinds)) = read_digit(input) {
         let mut record = Box::<ValueProfDataRecord>::default();
         // We have value profiling data!
@@ -164,7 +170,11 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<Option<Box<ValueProf
                             let value = if sym == EXTERNAL_SYMBOL {
                                 0
                             } else {
-                                compute_hash(sym)
+                                // Add the target so it can be resolved back to a name later
+                                let sym = String::from_utf8_lossy(sym).to_string();
+                                let value = compute_hash(&sym);
+                                symtab.names.insert(value, sym);
+                                value
                             };
                             site_records.push(InstrProfValueData { value, count });
                             bytes
@@ -190,7 +200,7 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<Option<Box<ValueProf
 
 impl InstrProfReader for TextInstrProf {
     type Header = Header;
-    fn parse_bytes(mut input: &[u8]) -> ParseResult<InstrumentationProfile> {
+    fn parse_bytes(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
         let (bytes, header) = Self::parse_header(input)?;
         let (bytes, _) = skip_to_content(bytes)?;
         input = bytes;
@@ -200,6 +210,7 @@ impl InstrProfReader for TextInstrProf {
             header.is_ir_level,
             header.entry_first,
         );
+        result.is_byte_coverage = header.byte_coverage;
         while !input.is_empty() {
             // function name (demangled)
             let (bytes, name) = read_line(input)?;
@@ -229,7 +240,7 @@ impl InstrProfReader for TextInstrProf {
                     }
                 }
             }
-            let (bytes, data) = read_value_profile_data(input)?;
+            let (bytes, data) = read_value_profile_data(input, &mut result.symtab)?;
             let record = InstrProfRecord {
                 counts: counters,
                 data,
@@ -242,7 +253,7 @@ impl InstrProfReader for TextInstrProf {
                 record,
             });
             if let Some(name) = name {
-                result.symtab.names.insert(hash, name);
+                result.symtab.add_func_name(name, None);
             }
             input = match skip_to_content(bytes) {
                 Ok((bytes, _)) => bytes,
@@ -252,12 +263,13 @@ impl InstrProfReader for TextInstrProf {
         Ok((bytes, result))
     }
 
-    fn parse_header(input: &[u8]) -> ParseResult<Self::Header> {
+    fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
         let (input, _) = skip_to_content(input)?;
         let (bytes, names) = parse_header_tags(input)?;
         let mut is_ir_level = false;
         let mut has_csir = false;
         let mut entry_first = false;
+        let mut byte_coverage = false;
         for name in &names {
             if check_tag(name, IR_TAG) | check_tag(name, NOT_ENTRY_TAG) {
                 is_ir_level = true;
@@ -266,6 +278,8 @@ impl InstrProfReader for TextInstrProf {
                 is_ir_level = true;
             } else if check_tag(name, ENTRY_TAG) {
                 entry_first = true;
+            } else if check_tag(name, BYTE_COVERAGE_TAG) {
+                byte_coverage = true;
             } else if !check_tag(name, FE_TAG) {
                 // return Err(Err::Failure(Error::new(bytes, ErrorKind::Tag)));
                 return Err(Err::Failure(VerboseError::from_error_kind(
@@ -280,18 +294,24 @@ impl InstrProfReader for TextInstrProf {
                 is_ir_level,
                 has_csir,
                 entry_first,
+                byte_coverage,
             },
         ))
     }
 
     fn has_format(mut input: impl Read) -> bool {
         // looking at the code it looks like with file memory buffers in llvm it sets the buffer
-        // size to the size of the file meaning it checks all the characters
-        let mut s = String::new();
-        if input.read_to_string(&mut s).is_ok() {
-            s.is_ascii()
-        } else {
-            false
+        // size to the size of the file meaning it checks all the characters. This is done in
+        // chunks to avoid copying the whole input
+        let mut buffer = [0u8; 8192];
+        loop {
+            match input.read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(n) if buffer[..n].is_ascii() => {}
+                Ok(_) => return false,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
         }
     }
 }
@@ -344,6 +364,11 @@ mod tests {
         let (_, header) = TextInstrProf::parse_header(&header[..]).unwrap();
         assert!(header.is_ir_level);
         assert!(header.entry_first);
+
+        let header = b":ir\n:single_byte_coverage\n#content";
+        let (_, header) = TextInstrProf::parse_header(&header[..]).unwrap();
+        assert!(header.is_ir_level);
+        assert!(header.byte_coverage);
     }
 
     #[test]
@@ -375,7 +400,7 @@ mod tests {
         assert_eq!(report.get_level(), InstrumentationLevel::FrontEnd);
         assert_eq!(report.records().len(), 1);
         assert_eq!(report.symtab.len(), 1);
-        assert_eq!(report.symtab.names.get(&0).unwrap(), "main");
+        assert_eq!(report.symtab.get(compute_hash("main")).unwrap(), "main");
 
         let rec = &report.records()[0];
 
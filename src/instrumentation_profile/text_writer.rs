@@ -0,0 +1,204 @@
+use crate::instrumentation_profile::types::*;
+use crate::instrumentation_profile::{should_encode_record, sort_by_count, InstrProfWriter};
+use std::io::{self, Write};
+
+const EXTERNAL_SYMBOL: &str = "** External Symbol **";
+
+/// Writes profiles out in the human readable text format which is parsed by
+/// [`TextInstrProf`](crate::instrumentation_profile::text_profile::TextInstrProf). This aims to
+/// match the output of `llvm-profdata merge --text`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct TextProfWriter {
+    /// Omit records where all the counts are zero
+    pub sparse: bool,
+}
+
+impl TextProfWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the flags at the start of the text profile which describe the instrumentation level
+    /// and variant.
+    pub fn write_header(
+        &self,
+        profile: &InstrumentationProfile,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        // CS implies an IR level profile so it's checked first
+        if profile.has_csir_level_profile() {
+            writeln!(writer, "# CSIR level Instrumentation Flag\n:csir")?;
+        } else if profile.is_ir_level_profile() {
+            writeln!(writer, "# IR level Instrumentation Flag\n:ir")?;
+        }
+        if profile.is_entry_first() {
+            writeln!(
+                writer,
+                "# Always instrument the function entry block\n:entry_first"
+            )?;
+        }
+        if profile.is_byte_coverage() {
+            writeln!(writer, "# Instrument block coverage\n:single_byte_coverage")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a single function record. The symtab is used to resolve the names of indirect call
+    /// targets, any that can't be resolved are written as external symbols. The equivalent llvm
+    /// function is `InstrProfWriter::writeRecordInText`.
+    pub fn write_record(
+        &self,
+        name: &str,
+        record: &NamedInstrProfRecord,
+        symtab: &Symtab,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        writeln!(writer, "{}", name)?;
+        writeln!(writer, "# Func Hash:\n{}", record.hash_unchecked())?;
+        writeln!(writer, "# Num Counters:\n{}", record.counts().len())?;
+        writeln!(writer, "# Counter Values:")?;
+        for count in record.counts() {
+            writeln!(writer, "{}", count)?;
+        }
+
+        if let Some(data) = record.record.data.as_ref() {
+            let kinds = [
+                (ValueKind::IndirectCallTarget, &data.indirect_callsites),
+                (ValueKind::MemOpSize, &data.mem_op_sizes),
+            ];
+            let num_value_kinds = kinds.iter().filter(|(_, x)| !x.is_empty()).count();
+            if num_value_kinds > 0 {
+                writeln!(writer, "# Num Value Kinds:\n{}", num_value_kinds)?;
+                for (kind, sites) in kinds.iter().filter(|(_, x)| !x.is_empty()) {
+                    let kind_name = match kind {
+                        ValueKind::IndirectCallTarget => "IPVK_IndirectCallTarget",
+                        ValueKind::MemOpSize => "IPVK_MemOPSize",
+                    };
+                    writeln!(writer, "# ValueKind = {}:\n{}", kind_name, *kind as usize)?;
+                    writeln!(writer, "# NumValueSites:\n{}", sites.len())?;
+                    for site in sites.iter() {
+                        writeln!(writer, "{}", site.len())?;
+                        for value in sort_by_count(site) {
+                            match kind {
+                                ValueKind::IndirectCallTarget => {
+                                    let target = symtab
+                                        .get(value.value)
+                                        .map(|x| x.as_str())
+                                        .unwrap_or(EXTERNAL_SYMBOL);
+                                    writeln!(writer, "{}:{}", target, value.count)?;
+                                }
+                                ValueKind::MemOpSize => {
+                                    writeln!(writer, "{}:{}", value.value, value.count)?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(writer)
+    }
+}
+
+impl InstrProfWriter for TextProfWriter {
+    fn write(
+        &self,
+        profile: &InstrumentationProfile,
+        mut writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.write_header(profile, &mut writer)?;
+        // Sorted by name and then hash so the output is stable regardless of the input ordering
+        let mut records = profile
+            .records()
+            .iter()
+            .filter(|record| should_encode_record(record, self.sparse))
+            .filter_map(|record| {
+                let name = match record.name.as_ref() {
+                    Some(name) => Some(name),
+                    None => record.name_hash.and_then(|x| profile.symtab.get(x)),
+                };
+                name.map(|x| (x.as_str(), record))
+            })
+            .collect::<Vec<_>>();
+        records.sort_by_key(|(name, record)| (*name, record.hash_unchecked()));
+        for (name, record) in &records {
+            self.write_record(name, record, &profile.symtab, &mut writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::text_profile::TextInstrProf;
+    use crate::instrumentation_profile::InstrProfReader;
+
+    const VALUE_PROFILE: &str = ":ir
+bar
+# Func Hash:
+10
+# Num Counters:
+1
+# Counter Values:
+7
+
+foo
+# Func Hash:
+72057649435042473
+# Num Counters:
+2
+# Counter Values:
+40000
+60000
+# Num Value Kinds:
+2
+# ValueKind = IPVK_IndirectCallTarget:
+0
+# NumValueSites:
+1
+2
+** External Symbol **:60000
+bar1:40000
+# ValueKind = IPVK_MemOPSize:
+1
+# NumValueSites:
+1
+2
+4:60000
+1:40000
+
+";
+
+    #[test]
+    fn round_trip() {
+        let (_, profile) = TextInstrProf::parse_bytes(VALUE_PROFILE.as_bytes()).unwrap();
+
+        let mut output = vec![];
+        TextProfWriter::new().write(&profile, &mut output).unwrap();
+        let (_, reparsed) = TextInstrProf::parse_bytes(&output).unwrap();
+
+        assert_eq!(
+            profile.is_ir_level_profile(),
+            reparsed.is_ir_level_profile()
+        );
+        assert_eq!(profile.records().len(), reparsed.records().len());
+        for record in profile.records() {
+            let other = reparsed.get_record(&record.name_unchecked()).unwrap();
+            assert_eq!(record, other);
+        }
+    }
+
+    #[test]
+    fn write_output_format() {
+        let (_, profile) = TextInstrProf::parse_bytes(VALUE_PROFILE.as_bytes()).unwrap();
+
+        let mut output = vec![];
+        TextProfWriter::new().write(&profile, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        // Records are already sorted in the input so the output should match bar the comment
+        let expected = format!("# IR level Instrumentation Flag\n{}", VALUE_PROFILE);
+        assert_eq!(output, expected);
+    }
+}
@@ -1,22 +1,146 @@
+use crate::instrumentation_profile::memprof::MemProfData;
+use crate::instrumentation_profile::should_encode_record;
 use nom::number::Endianness;
-use rustc_hash::FxHashMap;
+use regex::Regex;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use thiserror::Error;
+use tracing::warn;
 
 /// ~VARIANT_MASKS_ALL & Header.version is the version number
-pub(crate) const VARIANT_MASKS_ALL: u64 = 0xff00_0000_0000_0000;
+pub const VARIANT_MASKS_ALL: u64 = 0xff00_0000_0000_0000;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
-pub(crate) const VARIANT_MASK_IR_PROF: u64 = 1u64 << 56;
+pub const VARIANT_MASK_IR_PROF: u64 = 1u64 << 56;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
-pub(crate) const VARIANT_MASK_CSIR_PROF: u64 = 1u64 << 57;
+pub const VARIANT_MASK_CSIR_PROF: u64 = 1u64 << 57;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
-pub(crate) const VARIANT_MASK_BYTE_COVERAGE: u64 = 1u64 << 60;
+pub const VARIANT_MASK_INSTR_ENTRY: u64 = 1u64 << 58;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
-pub(crate) const VARIANT_MASK_FUNCTION_ENTRY_ONLY: u64 = 1u64 << 61;
+pub const VARIANT_MASK_BYTE_COVERAGE: u64 = 1u64 << 60;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
-pub(crate) const VARIANT_MASK_MEMORY_PROFILE: u64 = 1u64 << 62;
+pub const VARIANT_MASK_FUNCTION_ENTRY_ONLY: u64 = 1u64 << 61;
+/// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
+pub const VARIANT_MASK_MEMORY_PROFILE: u64 = 1u64 << 62;
+
+/// The version number and variant flags packed into the version word of raw and indexed profile
+/// headers
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProfileVariant {
+    /// Format version with the variant flags removed
+    pub version: u64,
+    /// Profile generated by IR level instrumentation
+    pub is_ir: bool,
+    /// Profile contains context sensitive IR level counts
+    pub is_csir: bool,
+    /// Function entry counts are the first counter instead of the last
+    pub entry_first: bool,
+    /// Counters are single bytes where 0 means covered
+    pub byte_coverage: bool,
+    /// Only the function entry counters are instrumented
+    pub entry_only: bool,
+    /// Profile contains memory profiling data
+    pub memory_profile: bool,
+}
+
+impl ProfileVariant {
+    /// Decodes the version number and flags from a header version word
+    pub const fn from_version_word(word: u64) -> Self {
+        Self {
+            version: word & !VARIANT_MASKS_ALL,
+            is_ir: (word & VARIANT_MASK_IR_PROF) != 0,
+            is_csir: (word & VARIANT_MASK_CSIR_PROF) != 0,
+            entry_first: (word & VARIANT_MASK_INSTR_ENTRY) != 0,
+            byte_coverage: (word & VARIANT_MASK_BYTE_COVERAGE) != 0,
+            entry_only: (word & VARIANT_MASK_FUNCTION_ENTRY_ONLY) != 0,
+            memory_profile: (word & VARIANT_MASK_MEMORY_PROFILE) != 0,
+        }
+    }
+
+    /// Encodes the version number and flags into a header version word
+    pub const fn version_word(&self) -> u64 {
+        let mut word = self.version & !VARIANT_MASKS_ALL;
+        if self.is_ir {
+            word |= VARIANT_MASK_IR_PROF;
+        }
+        if self.is_csir {
+            word |= VARIANT_MASK_CSIR_PROF;
+        }
+        if self.entry_first {
+            word |= VARIANT_MASK_INSTR_ENTRY;
+        }
+        if self.byte_coverage {
+            word |= VARIANT_MASK_BYTE_COVERAGE;
+        }
+        if self.entry_only {
+            word |= VARIANT_MASK_FUNCTION_ENTRY_ONLY;
+        }
+        if self.memory_profile {
+            word |= VARIANT_MASK_MEMORY_PROFILE;
+        }
+        word
+    }
+}
+
+impl From<u64> for ProfileVariant {
+    fn from(word: u64) -> Self {
+        Self::from_version_word(word)
+    }
+}
+
+/// The kind of file a profile header was read from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HeaderFormat {
+    /// Raw profile from a 32 bit target
+    Raw32,
+    /// Raw profile from a 64 bit target
+    Raw64,
+    Indexed,
+}
+
+/// The header of a raw or indexed profile as it was read by the parser. The fields differ
+/// between formats and versions so they're listed by name in the order they appear in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileHeaderInfo {
+    pub format: HeaderFormat,
+    /// Indexed profiles are always little endian
+    pub endianness: Endianness,
+    /// The version word including the variant flags
+    pub version: u64,
+    /// The rest of the header fields after the version, offsets not present in the version of
+    /// the profile are left out
+    pub fields: Vec<(&'static str, u64)>,
+}
+
+impl ProfileHeaderInfo {
+    /// The version number and variant flags of the profile
+    pub fn variant(&self) -> ProfileVariant {
+        ProfileVariant::from_version_word(self.version)
+    }
+
+    /// Gets a header field by name, e.g. `counters_delta` or `hash_offset`
+    pub fn get(&self, field: &str) -> Option<u64> {
+        self.fields
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, value)| *value)
+    }
+}
+
+impl fmt::Display for ProfileHeaderInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "format: {:?}", self.format)?;
+        writeln!(f, "endianness: {:?}", self.endianness)?;
+        writeln!(f, "version: {:#x}", self.version)?;
+        for (name, value) in &self.fields {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
 pub enum ValueKind {
@@ -40,6 +164,25 @@ pub fn compute_hash(data: impl AsRef<[u8]>) -> u64 {
     u64::from_le_bytes(hash)
 }
 
+/// Demangles a Rust or C++ symbol name returning `None` if it isn't a mangled name. Functions with
+/// local linkage have their name prefixed by the source file and a `;` (or `:` in older profiles),
+/// the prefix is kept and only the symbol after it is demangled.
+pub fn demangle(name: &str) -> Option<String> {
+    let (prefix, symbol) = match name.rfind([';', ':']) {
+        Some(index) => name.split_at(index + 1),
+        None => ("", name),
+    };
+    let demangled = if let Ok(symbol) = rustc_demangle::try_demangle(symbol) {
+        format!("{:#}", symbol)
+    } else {
+        cpp_demangle::Symbol::new(symbol)
+            .ok()?
+            .demangle(&Default::default())
+            .ok()?
+    };
+    Some(format!("{}{}", prefix, demangled))
+}
+
 fn compute_be_hash(data: impl AsRef<[u8]>) -> u64 {
     let hash = md5::compute(data).0[..8].try_into().unwrap_or_default();
     u64::from_be_bytes(hash)
@@ -56,10 +199,11 @@ impl Symtab {
         self.len() == 0
     }
 
-    /// Some formats such as the Raw profiles have configurable endianness. I think these may
-    /// require a matching endian hash. However, this doesn't seem to be represented in any of the
-    /// llvm test files so is largely a mystery. Computes a little endian hash unless specified
-    /// otherwise.
+    /// Adds a name to the table keyed by its hash. llvm computes the hash as an integer from the
+    /// first 8 bytes of the MD5 read as little endian, profiles store that integer in their own
+    /// endianness so once it's been read the hash is the same for little and big endian profiles.
+    /// This means readers should pass `None` to use `compute_hash`, a big endian hash is only
+    /// computed if specified.
     pub fn add_func_name(&mut self, name: String, endianness: Option<Endianness>) {
         let hash = match endianness {
             Some(Endianness::Big) => compute_be_hash(&name),
@@ -79,6 +223,13 @@ impl Symtab {
     pub fn iter(&self) -> impl Iterator<Item = (&u64, &String)> {
         self.names.iter()
     }
+
+    /// Gets the demangled name for the hash, if the name can't be demangled it's returned
+    /// unchanged. See [`demangle`]
+    pub fn demangled(&self, hash: u64) -> Option<String> {
+        self.get(hash)
+            .map(|name| demangle(name).unwrap_or_else(|| name.clone()))
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
@@ -96,17 +247,86 @@ impl fmt::Display for InstrumentationLevel {
     }
 }
 
+/// The range of llvm major versions which write a profile format version, as llvm releases don't
+/// always change the format a version can be written by several releases.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct LlvmVersion {
+    /// The first llvm major version to write the format version
+    pub min: u32,
+    /// The last llvm major version to write the format version, `None` if it's still written by
+    /// the newest llvm release known to this crate
+    pub max: Option<u32>,
+}
+
+impl LlvmVersion {
+    const fn new(min: u32, max: Option<u32>) -> Self {
+        Self { min, max }
+    }
+
+    /// The llvm versions writing a raw profile (profraw) version, older versions than the crate
+    /// can parse return `None`
+    pub fn from_raw_version(version: u64) -> Option<Self> {
+        match version {
+            5 => Some(Self::new(11, Some(12))),
+            6 | 7 => Some(Self::new(13, Some(13))),
+            8 => Some(Self::new(14, Some(17))),
+            9 => Some(Self::new(18, Some(18))),
+            10 => Some(Self::new(19, None)),
+            _ => None,
+        }
+    }
+
+    /// The llvm versions writing an indexed profile (profdata) version, versions before 7 are
+    /// written by a wide range of llvm releases so return `None`
+    pub fn from_indexed_version(version: u64) -> Option<Self> {
+        match version {
+            7 => Some(Self::new(13, Some(13))),
+            8 => Some(Self::new(14, Some(15))),
+            9 => Some(Self::new(16, Some(16))),
+            10 => Some(Self::new(17, Some(17))),
+            11 => Some(Self::new(18, Some(18))),
+            12 => Some(Self::new(19, Some(20))),
+            13 => Some(Self::new(21, None)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the llvm major version could have written the profile
+    pub fn contains(&self, major: u32) -> bool {
+        major >= self.min && !matches!(self.max, Some(max) if major > max)
+    }
+}
+
+impl fmt::Display for LlvmVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "LLVM {}", self.min),
+            Some(max) => write!(f, "LLVM {}-{}", self.min, max),
+            None => write!(f, "LLVM {}+", self.min),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct InstrumentationProfile {
     pub(crate) version: Option<u64>,
+    pub(crate) llvm_version: Option<LlvmVersion>,
     pub(crate) has_csir: bool,
     pub(crate) is_ir: bool,
     pub(crate) is_entry_first: bool,
     pub(crate) is_byte_coverage: bool,
     pub(crate) fn_entry_only: bool,
     pub(crate) memory_profiling: bool,
+    pub(crate) binary_ids: Vec<Vec<u8>>,
+    pub(crate) memprof: Option<MemProfData>,
+    pub(crate) header: Option<ProfileHeaderInfo>,
     records: Vec<NamedInstrProfRecord>,
-    record_name_lookup: FxHashMap<String, usize>,
+    /// Index of the regular and context sensitive record for each name
+    record_name_lookup: FxHashMap<String, [Option<usize>; 2]>,
+    /// Index of the first record for each name hash and function hash pair
+    record_hash_lookup: FxHashMap<(u64, u64), usize>,
+    /// Index of the first record with a function hash for each name hash
+    record_name_hash_lookup: FxHashMap<u64, usize>,
     pub symtab: Symtab,
 }
 
@@ -121,6 +341,38 @@ impl InstrumentationProfile {
         }
     }
 
+    /// Creates an empty profile with every flag set from `flags`
+    pub fn with_flags(version: Option<u64>, flags: ProfileFlags) -> Self {
+        let mut profile = Self {
+            version,
+            ..Default::default()
+        };
+        profile.set_flags(flags);
+        profile
+    }
+
+    /// The flags describing the kind of profile, these are the variant bits written to the header
+    /// of indexed profiles and the `:` lines at the start of text profiles
+    pub fn flags(&self) -> ProfileFlags {
+        ProfileFlags {
+            is_ir: self.is_ir,
+            has_csir: self.has_csir,
+            is_entry_first: self.is_entry_first,
+            is_byte_coverage: self.is_byte_coverage,
+            fn_entry_only: self.fn_entry_only,
+            memory_profiling: self.memory_profiling,
+        }
+    }
+
+    pub fn set_flags(&mut self, flags: ProfileFlags) {
+        self.is_ir = flags.is_ir;
+        self.has_csir = flags.has_csir;
+        self.is_entry_first = flags.is_entry_first;
+        self.is_byte_coverage = flags.is_byte_coverage;
+        self.fn_entry_only = flags.fn_entry_only;
+        self.memory_profiling = flags.memory_profiling;
+    }
+
     pub fn version(&self) -> Option<u64> {
         self.version
     }
@@ -129,6 +381,13 @@ impl InstrumentationProfile {
         *self.version.as_ref().unwrap()
     }
 
+    /// The llvm versions which write the format version of the profile this was parsed from, this
+    /// is only known for raw and indexed profiles. It can be compared against the llvm version of
+    /// the toolchain used for a binary to spot profiles from a different toolchain.
+    pub fn llvm_version_hint(&self) -> Option<LlvmVersion> {
+        self.llvm_version
+    }
+
     pub fn is_ir_level_profile(&self) -> bool {
         self.is_ir
     }
@@ -137,6 +396,23 @@ impl InstrumentationProfile {
         self.has_csir
     }
 
+    /// Compares the instrumentation level and records of two profiles ignoring the order of the
+    /// records. Different readers and writers store records in a different order and the version
+    /// and symbol table (which may have hash collisions) can also differ for the same profile.
+    pub fn semantically_eq(&self, other: &Self) -> bool {
+        if self.is_ir != other.is_ir
+            || self.has_csir != other.has_csir
+            || self.records.len() != other.records.len()
+        {
+            return false;
+        }
+        let mut records = self.records.iter().collect::<Vec<_>>();
+        let mut other_records = other.records.iter().collect::<Vec<_>>();
+        records.sort_unstable();
+        other_records.sort_unstable();
+        records == other_records
+    }
+
     pub fn is_entry_first(&self) -> bool {
         self.is_entry_first
     }
@@ -145,6 +421,24 @@ impl InstrumentationProfile {
         self.memory_profiling
     }
 
+    /// The memory profile, only indexed profiles with a memprof section have one
+    pub fn memprof(&self) -> Option<&MemProfData> {
+        self.memprof.as_ref()
+    }
+
+    /// The header of the raw or indexed profile this was parsed from, for a raw file with several
+    /// profiles appended this is the header of the first one. Other formats and profiles created
+    /// in memory don't have a header.
+    pub fn raw_header(&self) -> Option<&ProfileHeaderInfo> {
+        self.header.as_ref()
+    }
+
+    /// The build ids of the binaries which produced the profile, used to match a profile to the
+    /// exact binary it came from
+    pub fn binary_ids(&self) -> &[Vec<u8>] {
+        &self.binary_ids
+    }
+
     pub fn get_level(&self) -> InstrumentationLevel {
         if self.is_ir_level_profile() {
             InstrumentationLevel::Ir
@@ -157,22 +451,222 @@ impl InstrumentationProfile {
         &self.records
     }
 
+    /// Iterates over the records with their names, records without a name have it looked up in
+    /// the symbol table from the name hash. Records with no name in either are skipped.
+    pub fn iter_named(&self) -> impl Iterator<Item = (&str, &NamedInstrProfRecord)> {
+        self.records
+            .iter()
+            .filter_map(move |record| self.record_name(record).map(|x| (x, record)))
+    }
+
+    /// The records with a maximum count of at least `cutoff`, these are the functions `profparser
+    /// show --value-cutoff` counts as hot
+    pub fn functions_above(&self, cutoff: u64) -> impl Iterator<Item = &NamedInstrProfRecord> {
+        self.records.iter().filter(move |x| x.max_count() >= cutoff)
+    }
+
+    /// The records with a maximum count less than `cutoff`, like `profparser show --value-cutoff
+    /// --list-below-cutoff`
+    pub fn functions_below(&self, cutoff: u64) -> impl Iterator<Item = &NamedInstrProfRecord> {
+        self.records.iter().filter(move |x| x.max_count() < cutoff)
+    }
+
+    /// The `n` hottest named functions by their largest counter, hottest first. Functions with the
+    /// same count are ordered by name so the list is stable across runs
+    pub fn top_functions(&self, n: usize) -> Vec<(&str, u64)> {
+        self.top_functions_by(n, |_| true)
+    }
+
+    /// Like [`InstrumentationProfile::top_functions`] only considering records for which `filter`
+    /// returns true
+    pub fn top_functions_by(
+        &self,
+        n: usize,
+        mut filter: impl FnMut(&NamedInstrProfRecord) -> bool,
+    ) -> Vec<(&str, u64)> {
+        let mut hot = self
+            .iter_named()
+            .filter(|(_, record)| filter(record))
+            .map(|(name, record)| (name, record.max_count()))
+            .collect::<Vec<_>>();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        hot.truncate(n);
+        hot
+    }
+
+    fn record_name<'a>(&'a self, record: &'a NamedInstrProfRecord) -> Option<&'a str> {
+        match record.name.as_ref() {
+            Some(name) => Some(name.as_str()),
+            None => record
+                .name_hash
+                .and_then(|x| self.symtab.get(x))
+                .map(|x| x.as_str()),
+        }
+    }
+
+    /// Creates a profile with only the records matching `pred`. The symbol table only keeps the
+    /// names of the retained records and their indirect call targets, and the memory profile
+    /// records of any removed functions are dropped.
+    pub fn filter<F>(&self, pred: F) -> InstrumentationProfile
+    where
+        F: Fn(&NamedInstrProfRecord) -> bool,
+    {
+        let mut profile = Self {
+            version: self.version,
+            llvm_version: self.llvm_version,
+            has_csir: self.has_csir,
+            is_ir: self.is_ir,
+            is_entry_first: self.is_entry_first,
+            is_byte_coverage: self.is_byte_coverage,
+            fn_entry_only: self.fn_entry_only,
+            memory_profiling: self.memory_profiling,
+            binary_ids: self.binary_ids.clone(),
+            memprof: self.memprof.clone(),
+            header: self.header.clone(),
+            ..Default::default()
+        };
+        let mut keep_symbol = |hash: u64| {
+            if let Some(name) = self.symtab.get(hash) {
+                profile.symtab.names.insert(hash, name.clone());
+            }
+        };
+        let mut removed = FxHashSet::default();
+        let mut retained = vec![];
+        for record in &self.records {
+            if !pred(record) {
+                if let Some(name) = self.record_name(record) {
+                    removed.insert(compute_hash(name));
+                }
+                continue;
+            }
+            if let Some(hash) = record.name_hash {
+                keep_symbol(hash);
+            }
+            if let Some(data) = record.record.data.as_ref() {
+                for value in data.indirect_callsites.iter().flatten() {
+                    keep_symbol(value.value);
+                }
+            }
+            retained.push(record);
+        }
+        // A function's regular and context sensitive records share a GUID so its memory profile is
+        // kept as long as either of them is
+        let kept = retained
+            .iter()
+            .filter_map(|record| self.record_name(record))
+            .map(compute_hash)
+            .collect::<FxHashSet<_>>();
+        for record in retained {
+            profile.push_record(record.clone());
+        }
+        if let Some(memprof) = profile.memprof.as_mut() {
+            memprof
+                .records
+                .retain(|guid, _| !removed.contains(guid) || kept.contains(guid));
+        }
+        profile
+    }
+
+    /// Creates a profile with only the records whose name matches the regex, see
+    /// [`InstrumentationProfile::filter`]
+    pub fn filter_by_name(&self, re: &Regex) -> InstrumentationProfile {
+        self.filter(|record| {
+            self.record_name(record)
+                .map(|x| re.is_match(x))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Removes records where all the counts are zero, matching `llvm-profdata merge -sparse`
+    pub fn to_sparse(&self) -> InstrumentationProfile {
+        self.filter(|record| should_encode_record(record, true))
+    }
+
+    /// Removes the value profiling data from every record leaving just the counters. Useful when
+    /// only the counts are needed as the value data can make up a lot of a profile.
+    pub fn drop_value_profiling(&mut self) {
+        for record in self.records.iter_mut() {
+            record.record.data = None;
+        }
+    }
+
     pub fn push_record(&mut self, record: NamedInstrProfRecord) {
         if let Some(name) = record.name.clone() {
-            self.record_name_lookup.insert(name, self.records.len());
+            let indexes = self.record_name_lookup.entry(name).or_default();
+            indexes[record.has_cs_flag() as usize] = Some(self.records.len());
+        }
+        if let (Some(name_hash), Some(hash)) = (record.name_hash, record.hash) {
+            self.record_hash_lookup
+                .entry((name_hash, hash))
+                .or_insert(self.records.len());
+            self.record_name_hash_lookup
+                .entry(name_hash)
+                .or_insert(self.records.len());
         }
         self.records.push(record);
     }
 
+    /// Finds the record with the given name hash and function hash, these are the hashes used to
+    /// identify a function in the coverage mapping
+    pub fn find_by_hashes(&self, name_hash: u64, fn_hash: u64) -> Option<&NamedInstrProfRecord> {
+        self.record_hash_lookup
+            .get(&(name_hash, fn_hash))
+            .map(|x| &self.records[*x])
+    }
+
+    /// Finds the first record with the given name hash regardless of its function hash
+    pub fn find_by_name_hash(&self, name_hash: u64) -> Option<&NamedInstrProfRecord> {
+        self.record_name_hash_lookup
+            .get(&name_hash)
+            .map(|x| &self.records[*x])
+    }
+
+    /// Finds the record with the given name, if there's a context sensitive and a regular record
+    /// with this name the regular record is returned.
     pub fn find_record_by_name(&self, name: &str) -> Option<&NamedInstrProfRecord> {
-        self.record_name_lookup.get(name).map(|x| &self.records[*x])
+        self.record_name_lookup
+            .get(name)
+            .and_then(|x| x[0].or(x[1]))
+            .map(|x| &self.records[x])
     }
 
+    /// Finds the record with the given name, if there's a context sensitive and a regular record
+    /// with this name the regular record is returned.
     pub fn find_record_by_name_mut(&mut self, name: &str) -> Option<&mut NamedInstrProfRecord> {
-        if let Some(index) = self.record_name_lookup.get(name) {
-            Some(&mut self.records[*index])
-        } else {
-            None
+        match self
+            .record_name_lookup
+            .get(name)
+            .and_then(|x| x[0].or(x[1]))
+        {
+            Some(index) => Some(&mut self.records[index]),
+            None => None,
+        }
+    }
+
+    /// Finds the context sensitive or regular record with the given name
+    pub fn find_record(
+        &self,
+        name: &str,
+        context_sensitive: bool,
+    ) -> Option<&NamedInstrProfRecord> {
+        self.record_name_lookup
+            .get(name)
+            .and_then(|x| x[context_sensitive as usize])
+            .map(|x| &self.records[x])
+    }
+
+    fn find_record_mut(
+        &mut self,
+        name: &str,
+        context_sensitive: bool,
+    ) -> Option<&mut NamedInstrProfRecord> {
+        match self
+            .record_name_lookup
+            .get(name)
+            .and_then(|x| x[context_sensitive as usize])
+        {
+            Some(index) => Some(&mut self.records[index]),
+            None => None,
         }
     }
 
@@ -187,16 +681,138 @@ impl InstrumentationProfile {
         self.fn_entry_only
     }
 
-    pub fn merge(&mut self, other: &Self) {
+    /// Checks the invariants of the profile: every record's name is in the symbol table, IR level
+    /// records have counters and different names don't share a name hash. All the problems found
+    /// are returned.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+        let names = self
+            .symtab
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect::<FxHashSet<_>>();
+        let mut hashes = FxHashMap::<u64, &str>::default();
+        for record in &self.records {
+            let name = match (record.name.as_deref(), record.name_hash) {
+                (Some(name), _) => {
+                    if !names.contains(name) {
+                        errors.push(ValidationError::MissingName {
+                            name: name.to_string(),
+                        });
+                    }
+                    let name_hash = record.name_hash.unwrap_or_else(|| compute_hash(name));
+                    match hashes.get(&name_hash) {
+                        Some(other) if *other != name => {
+                            errors.push(ValidationError::HashCollision {
+                                name_hash,
+                                name: name.to_string(),
+                                other: other.to_string(),
+                            });
+                        }
+                        Some(_) => {}
+                        None => {
+                            hashes.insert(name_hash, name);
+                        }
+                    }
+                    name.to_string()
+                }
+                (None, Some(name_hash)) => match self.symtab.get(name_hash) {
+                    Some(name) => name.clone(),
+                    None => {
+                        errors.push(ValidationError::UnresolvedNameHash { name_hash });
+                        format!("{:#x}", name_hash)
+                    }
+                },
+                (None, None) => String::new(),
+            };
+            if self.is_ir && record.counts().is_empty() {
+                errors.push(ValidationError::NoCounters { name });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Checks the profiles can be merged, like `llvm-profdata merge` IR and front-end profiles
+    /// can't be merged and neither can function entry only and basic block profiles. Byte
+    /// coverage counters record whether a block ran rather than a count so they can't be merged
+    /// with other profiles either. Context sensitive and regular IR profiles can be merged.
+    pub fn check_compatible(&self, other: &Self) -> Result<(), MergeError> {
+        if self.is_ir != other.is_ir {
+            Err(MergeError::InstrumentationLevel)
+        } else if self.fn_entry_only != other.fn_entry_only {
+            Err(MergeError::FunctionEntryOnly)
+        } else if self.is_byte_coverage != other.is_byte_coverage {
+            Err(MergeError::ByteCoverage)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Merges another profile into this one, returning an error without merging anything if the
+    /// profiles are incompatible, see [`InstrumentationProfile::check_compatible`]. An empty
+    /// profile takes the flags of the other profile.
+    pub fn merge(&mut self, other: &Self) -> Result<MergeStats, MergeError> {
+        if self.version.is_none() && self.records.is_empty() {
+            self.is_ir = other.is_ir;
+            self.fn_entry_only = other.fn_entry_only;
+            self.is_byte_coverage = other.is_byte_coverage;
+            self.is_entry_first = other.is_entry_first;
+        }
+        self.check_compatible(other)?;
         if self.version.is_none() && other.version.is_some() {
             self.version = other.version;
+            self.llvm_version = other.llvm_version;
+        }
+        self.has_csir |= other.has_csir;
+        for id in &other.binary_ids {
+            if !self.binary_ids.contains(id) {
+                self.binary_ids.push(id.clone());
+            }
         }
+        let mut stats = MergeStats::default();
         for func in &other.records {
-            self.merge_record(func);
+            stats.add(&self.merge_record(func));
+        }
+        Ok(stats)
+    }
+
+    /// Merges all the profiles into one, the first profile is used as the base and the rest are
+    /// merged into it in order. Returns the merged profile and the combined stats, or an error if
+    /// any of the profiles are incompatible.
+    pub fn merged(
+        profiles: impl IntoIterator<Item = Self>,
+    ) -> Result<(Self, MergeStats), MergeError> {
+        let mut profiles = profiles.into_iter();
+        let mut stats = MergeStats::default();
+        let mut base = profiles.next().unwrap_or_default();
+        for profile in profiles {
+            stats.add(&base.merge(&profile)?);
+        }
+        Ok((base, stats))
+    }
+
+    /// Multiplies every counter and value count in the profile by `weight`, saturating at
+    /// `u64::MAX` on overflow. Used to apply input weights when merging profiles, the number of
+    /// counters which overflowed is returned.
+    pub(crate) fn scale(&mut self, weight: u64) -> usize {
+        let mut overflows = 0;
+        if weight == 1 {
+            return overflows;
+        }
+        for record in self.records.iter_mut() {
+            overflows += record.record.scale(weight);
         }
+        overflows
     }
 
-    pub fn merge_record(&mut self, record: &NamedInstrProfRecord) {
+    /// Merges a record into the profile, adding it if the profile doesn't have a record for the
+    /// function
+    pub fn merge_record(&mut self, record: &NamedInstrProfRecord) -> MergeStats {
+        let mut stats = MergeStats::default();
         if let Some(hash) = record.name_hash.as_ref() {
             let added = if self.symtab.contains(*hash) {
                 // Find the record and merge things. 0 hashed records should have no counters in the
@@ -204,9 +820,9 @@ impl InstrumentationProfile {
                 if let Some(rec) = record
                     .name
                     .as_ref()
-                    .and_then(|x| self.find_record_by_name_mut(x))
+                    .and_then(|x| self.find_record_mut(x, record.has_cs_flag()))
                 {
-                    rec.record.merge(&record.record);
+                    stats = rec.record.merge(&record.record);
                     true
                 } else {
                     false
@@ -216,9 +832,9 @@ impl InstrumentationProfile {
                     if let Some(rec) = record
                         .name
                         .as_ref()
-                        .and_then(|x| self.find_record_by_name_mut(x))
+                        .and_then(|x| self.find_record_mut(x, record.has_cs_flag()))
                     {
-                        rec.record.merge(&record.record);
+                        stats = rec.record.merge(&record.record);
                         true
                     } else {
                         false
@@ -234,6 +850,47 @@ impl InstrumentationProfile {
                 self.push_record(record.clone());
             }
         }
+        stats
+    }
+
+    /// Compares the counts in this profile to `other` returning the change in each counter going
+    /// from this profile to `other`. Records are matched by name and whether they're context
+    /// sensitive, a record in only one of the profiles is compared against zero counts. Entries
+    /// for records in this profile come first followed by the records only in `other`.
+    pub fn diff(&self, other: &Self) -> Vec<ProfileDiffEntry> {
+        let mut other_records = FxHashMap::default();
+        let mut other_order = vec![];
+        for (name, record) in other.iter_named() {
+            let key = (name, record.has_cs_flag());
+            if other_records.insert(key, record).is_none() {
+                other_order.push(key);
+            }
+        }
+        let mut result = vec![];
+        for (name, record) in self.iter_named() {
+            let key = (name, record.has_cs_flag());
+            let other_counts = other_records
+                .remove(&key)
+                .map(|x| x.counts())
+                .unwrap_or_default();
+            result.push(ProfileDiffEntry::new(
+                name,
+                record.hash_unchecked(),
+                record.counts(),
+                other_counts,
+            ));
+        }
+        for key in &other_order {
+            if let Some(record) = other_records.remove(key) {
+                result.push(ProfileDiffEntry::new(
+                    key.0,
+                    record.hash_unchecked(),
+                    &[],
+                    record.counts(),
+                ));
+            }
+        }
+        result
     }
 
     /// Gets the instrumentation record for the give function
@@ -249,6 +906,223 @@ impl InstrumentationProfile {
     }
 }
 
+/// The flags of an `InstrumentationProfile`, the defaults are those of a frontend profile
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ProfileFlags {
+    /// IR level instrumentation
+    pub is_ir: bool,
+    /// Has context sensitive IR level records
+    pub has_csir: bool,
+    /// The entry count is the first counter
+    pub is_entry_first: bool,
+    /// Counters are single bytes
+    pub is_byte_coverage: bool,
+    /// Only the function entry is instrumented
+    pub fn_entry_only: bool,
+    /// Has a memory profile
+    pub memory_profiling: bool,
+}
+
+/// Builds an `InstrumentationProfile` in code, the symbol table and record lookup are kept up to
+/// date as records are added.
+#[derive(Clone, Debug, Default)]
+pub struct InstrumentationProfileBuilder {
+    profile: InstrumentationProfile,
+}
+
+impl InstrumentationProfileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the version number without the variant flags
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.profile.version = Some(version);
+        self
+    }
+
+    pub fn with_ir(mut self, is_ir: bool) -> Self {
+        self.profile.is_ir = is_ir;
+        self
+    }
+
+    pub fn with_csir(mut self, has_csir: bool) -> Self {
+        self.profile.has_csir = has_csir;
+        self
+    }
+
+    pub fn with_entry_first(mut self, is_entry_first: bool) -> Self {
+        self.profile.is_entry_first = is_entry_first;
+        self
+    }
+
+    pub fn with_byte_coverage(mut self, is_byte_coverage: bool) -> Self {
+        self.profile.is_byte_coverage = is_byte_coverage;
+        self
+    }
+
+    pub fn with_fn_entry_only(mut self, fn_entry_only: bool) -> Self {
+        self.profile.fn_entry_only = fn_entry_only;
+        self
+    }
+
+    pub fn with_memory_profiling(mut self, memory_profiling: bool) -> Self {
+        self.profile.memory_profiling = memory_profiling;
+        self
+    }
+
+    /// Sets every flag at once, see [`InstrumentationProfile::flags`]
+    pub fn with_flags(mut self, flags: ProfileFlags) -> Self {
+        self.profile.set_flags(flags);
+        self
+    }
+
+    pub fn with_binary_id(mut self, id: Vec<u8>) -> Self {
+        self.profile.binary_ids.push(id);
+        self
+    }
+
+    /// Adds a record to the profile. If the record has a name its name hash is computed when
+    /// missing and the name is added to the symbol table, then it's merged in the same way as
+    /// [`InstrumentationProfile::merge_record`]. Records without a name are added as they are.
+    pub fn add_record(mut self, mut record: NamedInstrProfRecord) -> Self {
+        match record.name.as_ref() {
+            Some(name) => {
+                record.name_hash.get_or_insert_with(|| compute_hash(name));
+                self.profile.merge_record(&record);
+            }
+            None => self.profile.push_record(record),
+        }
+        self
+    }
+
+    /// Adds the records in order, see [`InstrumentationProfileBuilder::add_record`]
+    pub fn add_records(self, records: impl IntoIterator<Item = NamedInstrProfRecord>) -> Self {
+        records
+            .into_iter()
+            .fold(self, |builder, x| builder.add_record(x))
+    }
+
+    pub fn build(self) -> InstrumentationProfile {
+        self.profile
+    }
+}
+
+/// Problems found while merging profiles, these are the same issues `llvm-profdata merge` warns
+/// about. Nothing is merged for a record with a different number of counters and counters which
+/// overflow saturate at `u64::MAX`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct MergeStats {
+    /// Records that weren't merged as the number of counters didn't match
+    pub count_mismatches: usize,
+    /// Counters that overflowed when added or multiplied by a weight
+    pub counter_overflows: usize,
+}
+
+impl MergeStats {
+    pub fn add(&mut self, other: &Self) {
+        self.count_mismatches += other.count_mismatches;
+        self.counter_overflows += other.counter_overflows;
+    }
+
+    /// True if the merge had no mismatched records or overflowing counters
+    pub fn is_clean(&self) -> bool {
+        self.count_mismatches == 0 && self.counter_overflows == 0
+    }
+}
+
+/// Reasons two profiles can't be merged, see [`InstrumentationProfile::check_compatible`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Error)]
+pub enum MergeError {
+    #[error("can't merge IR level and front-end profiles")]
+    InstrumentationLevel,
+    #[error("can't merge function entry only and basic block profiles")]
+    FunctionEntryOnly,
+    #[error("can't merge byte coverage and counter profiles")]
+    ByteCoverage,
+}
+
+/// Problems found in a profile by [`InstrumentationProfile::validate`]. Records without a name are
+/// identified by their name hash in hex.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Error)]
+pub enum ValidationError {
+    #[error("{name} isn't in the symbol table")]
+    MissingName { name: String },
+    #[error("name hash {name_hash:#x} isn't in the symbol table")]
+    UnresolvedNameHash { name_hash: u64 },
+    #[error("{name} has no counters")]
+    NoCounters { name: String },
+    #[error("{name} and {other} have the same name hash {name_hash:#x}")]
+    HashCollision {
+        name_hash: u64,
+        name: String,
+        other: String,
+    },
+    #[error("{name} has {counters} counters but the binary has {expected}")]
+    CounterMismatch {
+        name: String,
+        counters: usize,
+        expected: usize,
+    },
+}
+
+/// The change in a function's counts between two profiles, see [`InstrumentationProfile::diff`]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ProfileDiffEntry {
+    pub name: String,
+    /// Function hash of the record, taken from the first profile if it's present in both
+    pub hash: u64,
+    /// Change in each counter, if the number of counters differs the missing counters are zero
+    pub deltas: Vec<i64>,
+    /// The delta with the largest magnitude, positive if the function got hotter
+    pub max_delta: i64,
+    /// Sum of all the deltas
+    pub total_delta: i64,
+}
+
+impl ProfileDiffEntry {
+    fn new(name: &str, hash: u64, base: &[u64], test: &[u64]) -> Self {
+        let len = base.len().max(test.len());
+        let deltas = (0..len)
+            .map(|i| {
+                let base = base.get(i).copied().unwrap_or_default();
+                let test = test.get(i).copied().unwrap_or_default();
+                count_delta(base, test)
+            })
+            .collect::<Vec<_>>();
+        let max_delta = deltas
+            .iter()
+            .copied()
+            .max_by_key(|x| x.unsigned_abs())
+            .unwrap_or_default();
+        let total_delta = deltas.iter().fold(0i64, |acc, x| acc.saturating_add(*x));
+        Self {
+            name: name.to_string(),
+            hash,
+            deltas,
+            max_delta,
+            total_delta,
+        }
+    }
+
+    /// Returns true if none of the counts changed
+    pub fn is_unchanged(&self) -> bool {
+        self.deltas.iter().all(|x| *x == 0)
+    }
+}
+
+/// Gets `test - base` saturating at the bounds of an `i64`
+fn count_delta(base: u64, test: u64) -> i64 {
+    if test >= base {
+        (test - base).try_into().unwrap_or(i64::MAX)
+    } else {
+        (base - test)
+            .try_into()
+            .map(|x: i64| -x)
+            .unwrap_or(i64::MIN)
+    }
+}
+
 #[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct NamedInstrProfRecord {
     pub name: Option<String>,
@@ -271,6 +1145,28 @@ impl NamedInstrProfRecord {
         .unwrap_or_default()
     }
 
+    /// The targets of every indirect call site in site order paired with their counts. Target
+    /// values are function name hashes which are resolved with `symtab`, targets outside the
+    /// profile are `None`.
+    pub fn indirect_call_targets(&self, symtab: &Symtab) -> Vec<(Option<String>, u64)> {
+        self.record
+            .data
+            .iter()
+            .flat_map(|x| x.indirect_callsites.iter().flatten())
+            .map(|x| (symtab.get(x.value).cloned(), x.count))
+            .collect()
+    }
+
+    /// The sizes of every memory intrinsic call site in site order paired with their counts
+    pub fn memop_sizes(&self) -> Vec<(u64, u64)> {
+        self.record
+            .data
+            .iter()
+            .flat_map(|x| x.mem_op_sizes.iter().flatten())
+            .map(|x| (x.value, x.count))
+            .collect()
+    }
+
     pub fn has_cs_flag(&self) -> bool {
         let hash = self.hash.unwrap_or_default();
         ((hash >> Self::CS_FLAG_IN_FUNC_HASH) & 1) != 0
@@ -278,13 +1174,25 @@ impl NamedInstrProfRecord {
 
     pub fn set_cs_flag(&mut self) {
         let x = self.hash.get_or_insert(0);
-        *x &= Self::CS_FLAG_IN_FUNC_HASH;
+        *x |= 1 << Self::CS_FLAG_IN_FUNC_HASH;
     }
 
     pub fn counts(&self) -> &[u64] {
         &self.record.counts
     }
 
+    /// The largest counter, or 0 if there are no counters
+    pub fn max_count(&self) -> u64 {
+        self.counts().iter().copied().max().unwrap_or_default()
+    }
+
+    /// The sum of the counters, saturating at `u64::MAX`
+    pub fn total_count(&self) -> u64 {
+        self.counts()
+            .iter()
+            .fold(0u64, |acc, x| acc.saturating_add(*x))
+    }
+
     pub fn hash_unchecked(&self) -> u64 {
         self.hash.unwrap_or_default()
     }
@@ -301,16 +1209,24 @@ pub struct InstrProfRecord {
 }
 
 impl InstrProfRecord {
-    pub fn merge(&mut self, other: &Self) {
+    /// Adds the counts from `other` to this record, if the number of counters differ the records
+    /// are from different versions of the function and nothing is merged
+    pub fn merge(&mut self, other: &Self) -> MergeStats {
+        let mut stats = MergeStats::default();
         if self.counts.len() != other.counts.len() {
-            return;
+            warn!(
+                "Counter mismatch merging records: {} counters and {} counters",
+                self.counts.len(),
+                other.counts.len()
+            );
+            stats.count_mismatches += 1;
+            return stats;
         }
         for (own, other) in self.counts.iter_mut().zip(other.counts.iter()) {
-            let res = own.checked_add(*other);
-            *own = match res {
-                Some(s) => s,
-                None => u64::MAX, // TODO handle the warnings?
-            };
+            *own = own.checked_add(*other).unwrap_or_else(|| {
+                stats.counter_overflows += 1;
+                u64::MAX
+            });
         }
         // TODO merge the data
         if let Some((own, other)) = self.data.as_mut().zip(other.data.as_ref()) {
@@ -329,6 +1245,45 @@ impl InstrProfRecord {
                 }
             }
         }
+        stats
+    }
+
+    /// Multiplies every counter and value count in the record by `weight`, saturating at
+    /// `u64::MAX` on overflow. The number of counters and value counts which overflowed is
+    /// returned.
+    pub fn scale(&mut self, weight: u64) -> usize {
+        let mut overflows = 0;
+        if weight == 1 {
+            return overflows;
+        }
+        for count in self.counts.iter_mut() {
+            *count = count.checked_mul(weight).unwrap_or_else(|| {
+                overflows += 1;
+                u64::MAX
+            });
+        }
+        if let Some(data) = self.data.as_mut() {
+            for value in data
+                .indirect_callsites
+                .iter_mut()
+                .chain(data.mem_op_sizes.iter_mut())
+                .flat_map(|x| x.iter_mut())
+            {
+                value.count = value.count.checked_mul(weight).unwrap_or_else(|| {
+                    overflows += 1;
+                    u64::MAX
+                });
+            }
+        }
+        overflows
+    }
+
+    /// Returns a copy of the record with the counts multiplied by `weight`, see
+    /// [`InstrProfRecord::scale`]
+    pub fn scaled(&self, weight: u64) -> Self {
+        let mut record = self.clone();
+        record.scale(weight);
+        record
     }
 }
 
@@ -355,7 +1310,7 @@ fn merge_site_records(dst: &mut InstrProfValueSiteRecord, src: &InstrProfValueSi
 
             match current {
                 Some((index, element)) if element.value == j.value => {
-                    element.count = element.count.checked_add(j.count).unwrap_or(u64::MAX);
+                    element.count = element.count.saturating_add(j.count);
                     dst.insert(index + 1, j.clone());
                     i = index + 1;
                 }
@@ -372,7 +1327,7 @@ fn merge_site_records(dst: &mut InstrProfValueSiteRecord, src: &InstrProfValueSi
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash)]
+#[derive(Clone, Debug, Default, Eq)]
 pub struct InstrProfValueData {
     pub value: u64,
     pub count: u64,
@@ -411,3 +1366,70 @@ impl PartialEq for InstrProfValueData {
         self.value == other.value
     }
 }
+
+impl Hash for InstrProfValueData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Must be consistent with `PartialEq` which only considers the value
+        self.value.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::memprof::MemProfRecord;
+
+    #[test]
+    fn filter_keeps_memprof_of_retained_variant() {
+        let record = |counts: Vec<u64>, cs: bool| {
+            let mut record = NamedInstrProfRecord {
+                name: Some("foo".to_string()),
+                name_hash: Some(compute_hash("foo")),
+                hash: Some(0x1234),
+                record: InstrProfRecord { counts, data: None },
+            };
+            if cs {
+                record.set_cs_flag();
+            }
+            record
+        };
+        let mut memprof = MemProfData::default();
+        memprof
+            .records
+            .insert(compute_hash("foo"), MemProfRecord::default());
+        memprof
+            .records
+            .insert(compute_hash("bar"), MemProfRecord::default());
+        let mut profile = InstrumentationProfile {
+            memprof: Some(memprof),
+            ..Default::default()
+        };
+        profile.push_record(record(vec![3], false));
+        profile.push_record(record(vec![0], true));
+        profile.push_record(NamedInstrProfRecord {
+            name: Some("bar".to_string()),
+            name_hash: Some(compute_hash("bar")),
+            hash: Some(0x1234),
+            record: InstrProfRecord {
+                counts: vec![0],
+                data: None,
+            },
+        });
+
+        // The all zero context sensitive record is dropped but the regular one is kept
+        let sparse = profile.to_sparse();
+        assert_eq!(sparse.records().len(), 1);
+        assert!(!sparse.records()[0].has_cs_flag());
+        let guids = sparse
+            .memprof()
+            .unwrap()
+            .records
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(guids, vec![compute_hash("foo")]);
+
+        let none = profile.filter(|_| false);
+        assert!(none.memprof().unwrap().records.is_empty());
+    }
+}
@@ -1,13 +1,19 @@
-use crate::instrumentation_profile::types::InstrumentationProfile;
-use std::path::Path;
+use crate::instrumentation_profile::types::{InstrumentationProfile, MergeError, MergeStats};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, warn};
 
 pub mod coverage;
 mod hash_table;
 pub mod instrumentation_profile;
+pub mod sample_profile;
 pub mod summary;
 pub mod util;
 
-pub use crate::instrumentation_profile::{parse, parse_bytes};
+pub use crate::instrumentation_profile::{
+    parse, parse_bytes, parse_mmap, parse_reader, try_parse_bytes, ProfileParseError,
+};
 pub use coverage::coverage_mapping::CoverageMapping;
 pub use coverage::reporting::*;
 pub use coverage::*;
@@ -21,22 +27,149 @@ pub enum ProfileFormat {
     Gcc,
 }
 
-pub fn merge_profiles<T>(files: &[T]) -> std::io::Result<InstrumentationProfile>
+impl FromStr for ProfileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            "compbinary" => Ok(Self::CompactBinary),
+            "extbinary" => Ok(Self::ExtBinary),
+            "text" => Ok(Self::Text),
+            "gcc" => Ok(Self::Gcc),
+            s => Err(format!("Unknown profile format: {}", s)),
+        }
+    }
+}
+
+/// Replaces any directories in `paths` with the profraw and profdata files found by recursively
+/// searching them, other paths are kept as is. Returns the profiles and the files in the
+/// directories which were skipped as they aren't profiles. Files from a directory are sorted so
+/// the order is consistent between runs.
+pub fn expand_profile_dirs<T>(paths: &[T]) -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>)>
 where
     T: AsRef<Path>,
 {
-    if files.is_empty() {
-        Ok(InstrumentationProfile::default())
-    } else {
-        let mut profiles = vec![];
-        for input in files {
-            let profile = parse(input)?;
-            profiles.push(profile);
+    let mut profiles = vec![];
+    let mut skipped = vec![];
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let mut files = vec![];
+            find_files(path, &mut files)?;
+            files.sort();
+            for file in files {
+                let extension = file.extension().and_then(|x| x.to_str());
+                if matches!(extension, Some("profraw") | Some("profdata")) {
+                    profiles.push(file);
+                } else {
+                    warn!("Skipping {} as it isn't a profile", file.display());
+                    skipped.push(file);
+                }
+            }
+        } else {
+            profiles.push(path.to_path_buf());
         }
-        let mut base = profiles.remove(0);
-        for profile in &profiles {
-            base.merge(profile);
+    }
+    Ok((profiles, skipped))
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // The entry's file type doesn't follow symlinks, symlinked directories are skipped as
+        // they could link back to a parent and loop forever
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_files(&path, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            debug!("Not following symlinked directory {}", path.display());
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Merges the profiles in `files` with each profile having an equal weight.
+pub fn merge_profiles<T>(files: &[T]) -> std::io::Result<InstrumentationProfile>
+where
+    T: AsRef<Path>,
+{
+    let inputs = files.iter().map(|x| (1, x)).collect::<Vec<_>>();
+    merge_profiles_weighted(&inputs)
+}
+
+/// Merges the profiles in `files` with equal weight then drops any records where all the counts
+/// are zero, this matches `llvm-profdata merge -sparse`.
+pub fn merge_profiles_sparse<T>(files: &[T]) -> std::io::Result<InstrumentationProfile>
+where
+    T: AsRef<Path>,
+{
+    merge_profiles(files).map(|profile| profile.to_sparse())
+}
+
+/// Merges the profiles in `inputs` where each input is a `(weight, path)` pair. Every counter in a
+/// profile is multiplied by its weight before being accumulated, matching the behaviour of
+/// `llvm-profdata merge -weighted-input=<weight>,<file>`. Counts saturate at `u64::MAX`. Profiles
+/// which can't be merged, such as IR and front-end profiles, return an `InvalidData` error.
+pub fn merge_profiles_weighted<T>(inputs: &[(u64, T)]) -> std::io::Result<InstrumentationProfile>
+where
+    T: AsRef<Path>,
+{
+    merge_profiles_with_stats(inputs).map(|(profile, _)| profile)
+}
+
+/// Merges the profiles like `merge_profiles_weighted` also returning the number of records with
+/// mismatched counters and the number of counters that overflowed.
+pub fn merge_profiles_with_stats<T>(
+    inputs: &[(u64, T)],
+) -> std::io::Result<(InstrumentationProfile, MergeStats)>
+where
+    T: AsRef<Path>,
+{
+    merge_inputs(inputs, false)
+}
+
+/// Merges the profiles in `files` with equal weight ignoring any value profiling data, the merged
+/// profile only has counters. This skips merging the value data so uses less memory and time when
+/// only the counts are needed.
+pub fn merge_profiles_counts_only<T>(files: &[T]) -> std::io::Result<InstrumentationProfile>
+where
+    T: AsRef<Path>,
+{
+    let inputs = files.iter().map(|x| (1, x)).collect::<Vec<_>>();
+    merge_inputs(&inputs, true).map(|(profile, _)| profile)
+}
+
+fn merge_inputs<T>(
+    inputs: &[(u64, T)],
+    drop_value_profiling: bool,
+) -> std::io::Result<(InstrumentationProfile, MergeStats)>
+where
+    T: AsRef<Path>,
+{
+    let mut stats = MergeStats::default();
+    let mut profiles = vec![];
+    for (weight, input) in inputs {
+        let mut profile = parse(input)?;
+        if drop_value_profiling {
+            profile.drop_value_profiling();
         }
-        Ok(base)
+        stats.counter_overflows += profile.scale(*weight);
+        profiles.push(profile);
     }
+    let (profile, merged) = InstrumentationProfile::merged(profiles)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    stats.add(&merged);
+    Ok((profile, stats))
+}
+
+/// Merges profiles which have already been parsed with each profile having an equal weight. This
+/// avoids reparsing profiles which are already in memory.
+pub fn merge_in_memory(
+    profiles: Vec<InstrumentationProfile>,
+) -> Result<InstrumentationProfile, MergeError> {
+    InstrumentationProfile::merged(profiles).map(|(profile, _)| profile)
 }
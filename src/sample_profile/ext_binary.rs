@@ -0,0 +1,288 @@
+use crate::instrumentation_profile::ParseResult;
+use crate::sample_profile::*;
+use crate::util::parse_leb128;
+use flate2::read::ZlibDecoder;
+use nom::bytes::complete::{tag, take, take_until};
+use nom::error::{ErrorKind, ParseError, VerboseError};
+use nom::number::complete::le_u64;
+use std::io::Read;
+use tracing::debug;
+
+/// The only version of the binary sample profile formats
+const SAMPLE_PROF_VERSION: u64 = 103;
+/// Format byte for the extensible binary format in the magic number
+const SPF_EXT_BINARY: u64 = 0x4;
+const SAMPLE_PROF_MAGIC: u64 = u64::from_be_bytes(*b"SPROF42\0") | SPF_EXT_BINARY;
+
+/// Reader for the extensible binary sample profile format. This starts with a header listing the
+/// sections in the file and each section can be optionally compressed. Sections that aren't
+/// needed to read the function profiles, like the profile summary and function metadata, are
+/// listed in the section headers but not parsed.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ExtBinarySampleProf;
+
+fn leb(input: &[u8]) -> ParseResult<'_, u64> {
+    parse_leb128(input)
+}
+
+/// Errors from decompressed data can't point to it after it's dropped so they point to the start
+/// of the section instead
+fn rebase_error<'a>(
+    section: &'a [u8],
+    err: nom::Err<VerboseError<&[u8]>>,
+) -> nom::Err<VerboseError<&'a [u8]>> {
+    err.map(|e| VerboseError {
+        errors: e
+            .errors
+            .into_iter()
+            .map(|(_, kind)| (section, kind))
+            .collect(),
+    })
+}
+
+/// Runs `parser` on the contents of a section, decompressing it first if needed
+fn parse_section<'a, T, F>(
+    section: &'a [u8],
+    header: &SectionHeader,
+    mut parser: F,
+) -> Result<T, nom::Err<VerboseError<&'a [u8]>>>
+where
+    F: FnMut(&[u8]) -> ParseResult<'_, T>,
+{
+    if header.is_compressed() {
+        let (bytes, uncompressed_size) = leb(section)?;
+        let (bytes, compressed_size) = leb(bytes)?;
+        let (_, compressed) = take(compressed_size)(bytes)?;
+        let mut decoder = ZlibDecoder::new(compressed);
+        let mut output = vec![];
+        if decoder.read_to_end(&mut output).is_err() || output.len() as u64 != uncompressed_size {
+            return Err(failure(section, "invalid compressed section"));
+        }
+        parser(&output)
+            .map(|(_, res)| res)
+            .map_err(|e| rebase_error(section, e))
+    } else {
+        parser(section).map(|(_, res)| res)
+    }
+}
+
+fn parse_section_header(input: &[u8]) -> ParseResult<'_, SectionHeader> {
+    let (bytes, ty) = le_u64(input)?;
+    let (bytes, flags) = le_u64(bytes)?;
+    let (bytes, offset) = le_u64(bytes)?;
+    let (bytes, size) = le_u64(bytes)?;
+    let header = SectionHeader {
+        ty: ty.into(),
+        flags,
+        offset,
+        size,
+    };
+    Ok((bytes, header))
+}
+
+fn parse_name_table<'a>(input: &'a [u8], header: &SectionHeader) -> ParseResult<'a, Vec<String>> {
+    let (mut bytes, len) = leb(input)?;
+    let mut names = vec![];
+    for _ in 0..len {
+        let (b, name) = if header.has_flag(section_flags::NAME_TABLE_FIXED_LENGTH_MD5) {
+            let (b, hash) = le_u64(bytes)?;
+            (b, hash.to_string())
+        } else if header.has_flag(section_flags::NAME_TABLE_MD5) {
+            let (b, hash) = leb(bytes)?;
+            (b, hash.to_string())
+        } else {
+            let (b, name) = take_until(&b"\0"[..])(bytes)?;
+            let (b, _) = tag(b"\0")(b)?;
+            (b, String::from_utf8_lossy(name).to_string())
+        };
+        names.push(name);
+        bytes = b;
+    }
+    Ok((bytes, names))
+}
+
+fn parse_name<'a>(input: &'a [u8], names: &[String]) -> ParseResult<'a, String> {
+    let (bytes, index) = leb(input)?;
+    match names.get(index as usize) {
+        Some(name) => Ok((bytes, name.clone())),
+        None => Err(failure(input, "name index out of range")),
+    }
+}
+
+fn parse_line_location(input: &[u8]) -> ParseResult<'_, LineLocation> {
+    let (bytes, line_offset) = leb(input)?;
+    if line_offset > MAX_LINE_OFFSET {
+        return Err(failure(input, "illegal line offset"));
+    }
+    let (bytes, discriminator) = leb(bytes)?;
+    let location = LineLocation {
+        line_offset: line_offset as u32,
+        discriminator: discriminator as u32,
+    };
+    Ok((bytes, location))
+}
+
+/// Parses the samples for a function after the head samples and name, this is the whole profile
+/// for inlined functions
+fn parse_function_body<'a>(
+    input: &'a [u8],
+    names: &[String],
+    name: String,
+) -> ParseResult<'a, FunctionSamples> {
+    let mut function = FunctionSamples {
+        name,
+        ..Default::default()
+    };
+    let (mut bytes, total_samples) = leb(input)?;
+    function.total_samples = total_samples;
+    let (b, num_records) = leb(bytes)?;
+    bytes = b;
+    for _ in 0..num_records {
+        let (b, location) = parse_line_location(bytes)?;
+        let (b, samples) = leb(b)?;
+        let (mut b, num_calls) = leb(b)?;
+        let record = function.body.entry(location).or_default();
+        record.samples = record.samples.saturating_add(samples);
+        for _ in 0..num_calls {
+            let (b2, callee) = parse_name(b, names)?;
+            let (b2, count) = leb(b2)?;
+            let target = record.call_targets.entry(callee).or_default();
+            *target = target.saturating_add(count);
+            b = b2;
+        }
+        bytes = b;
+    }
+    let (b, num_callsites) = leb(bytes)?;
+    bytes = b;
+    for _ in 0..num_callsites {
+        let (b, location) = parse_line_location(bytes)?;
+        let (b, callee) = parse_name(b, names)?;
+        let (b, callee) = parse_function_body(b, names, callee)?;
+        function
+            .callsites
+            .entry(location)
+            .or_default()
+            .insert(callee.name.clone(), callee);
+        bytes = b;
+    }
+    Ok((bytes, function))
+}
+
+fn parse_functions<'a>(
+    mut input: &'a [u8],
+    names: &[String],
+) -> ParseResult<'a, Vec<FunctionSamples>> {
+    let mut functions = vec![];
+    while !input.is_empty() {
+        let (bytes, head_samples) = leb(input)?;
+        let (bytes, name) = parse_name(bytes, names)?;
+        let (bytes, mut function) = parse_function_body(bytes, names, name)?;
+        function.head_samples = head_samples;
+        debug!("Parsed function samples: {:?}", function);
+        functions.push(function);
+        input = bytes;
+    }
+    Ok((input, functions))
+}
+
+fn parse_func_offsets<'a>(
+    input: &'a [u8],
+    names: &[String],
+) -> ParseResult<'a, Vec<(String, u64)>> {
+    let (mut bytes, len) = leb(input)?;
+    let mut offsets = vec![];
+    for _ in 0..len {
+        let (b, name) = parse_name(bytes, names)?;
+        let (b, offset) = leb(b)?;
+        offsets.push((name, offset));
+        bytes = b;
+    }
+    Ok((bytes, offsets))
+}
+
+fn parse_symbol_list(input: &[u8]) -> ParseResult<'_, Vec<String>> {
+    let symbols = input
+        .split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).to_string())
+        .collect();
+    Ok((&input[input.len()..], symbols))
+}
+
+impl ExtBinarySampleProf {
+    pub fn parse_bytes(input: &[u8]) -> ParseResult<'_, SampleProfile> {
+        let (bytes, magic) = leb(input)?;
+        if magic != SAMPLE_PROF_MAGIC {
+            return Err(failure(input, "invalid sample profile magic"));
+        }
+        let (bytes, version) = leb(bytes)?;
+        if version != SAMPLE_PROF_VERSION {
+            return Err(failure(input, "unsupported sample profile version"));
+        }
+        let (mut bytes, num_sections) = le_u64(bytes)?;
+        let mut profile = SampleProfile {
+            version,
+            file_size: input.len() as u64,
+            ..Default::default()
+        };
+        for _ in 0..num_sections {
+            let (b, header) = parse_section_header(bytes)?;
+            debug!("Section header: {:?}", header);
+            profile.sections.push(header);
+            bytes = b;
+        }
+
+        let section_data = |header: &SectionHeader| {
+            let start = header.offset as usize;
+            match start.checked_add(header.size as usize) {
+                Some(end) if end <= input.len() => Ok(&input[start..end]),
+                _ => Err(nom::Err::Failure(VerboseError::from_error_kind(
+                    &input[input.len()..],
+                    ErrorKind::Eof,
+                ))),
+            }
+        };
+        // Function profiles refer to the name table so the sections are parsed in two passes
+        for header in &profile.sections {
+            let section = section_data(header)?;
+            match header.ty {
+                SectionType::ProfileSummary if header.has_flag(section_flags::SUMMARY_CONTEXT) => {
+                    return Err(failure(
+                        section,
+                        "context sensitive sample profiles aren't supported",
+                    ));
+                }
+                SectionType::NameTable => {
+                    let names = parse_section(section, header, |x| parse_name_table(x, header))?;
+                    profile.names.extend(names);
+                }
+                SectionType::ProfileSymbolList => {
+                    let symbols = parse_section(section, header, parse_symbol_list)?;
+                    profile.symbol_list.extend(symbols);
+                }
+                _ => {}
+            }
+        }
+        for header in &profile.sections {
+            let section = section_data(header)?;
+            match header.ty {
+                SectionType::LbrProfile => {
+                    let names = &profile.names;
+                    let functions = parse_section(section, header, |x| parse_functions(x, names))?;
+                    profile.functions.extend(functions);
+                }
+                SectionType::FuncOffsetTable => {
+                    let names = &profile.names;
+                    let offsets = parse_section(section, header, |x| parse_func_offsets(x, names))?;
+                    profile.func_offsets.extend(offsets);
+                }
+                _ => {}
+            }
+        }
+        Ok((&input[input.len()..], profile))
+    }
+
+    pub fn has_format(mut input: impl Read) -> bool {
+        matches!(leb128::read::unsigned(&mut input), Ok(SAMPLE_PROF_MAGIC))
+    }
+}
@@ -0,0 +1,371 @@
+//! Sample based profiles as generated by tools like `create_llvm_prof` from hardware sampling.
+//! Currently the extensible binary format (extbinary) and the text format are supported.
+use crate::instrumentation_profile::ProfileParseError;
+use crate::sample_profile::ext_binary::ExtBinarySampleProf;
+use crate::sample_profile::text::TextSampleProf;
+use nom::error::{ContextError, ErrorKind, ParseError, VerboseError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::Path;
+
+pub mod ext_binary;
+pub mod text;
+
+/// Line offsets are limited to 16 bits
+const MAX_LINE_OFFSET: u64 = 0xffff;
+
+/// Parses a sample profile from a file
+pub fn parse(filename: impl AsRef<Path>) -> io::Result<SampleProfile> {
+    let mut buffer = Vec::new();
+    File::open(filename)?.read_to_end(&mut buffer)?;
+    parse_bytes(&buffer)
+}
+
+/// Parses a sample profile, returning an `UnsupportedFormat` error for anything that isn't a
+/// sample profile in a supported format
+pub fn parse_bytes(data: &[u8]) -> io::Result<SampleProfile> {
+    if ExtBinarySampleProf::has_format(data) {
+        ExtBinarySampleProf::parse_bytes(data)
+            .map(|(_bytes, res)| res)
+            .map_err(|e| ProfileParseError::from_nom(data, e).into())
+    } else if TextSampleProf::has_format(data) {
+        TextSampleProf::parse_bytes(data)
+            .map(|(_bytes, res)| res)
+            .map_err(|e| ProfileParseError::from_nom(data, e).into())
+    } else {
+        Err(ProfileParseError::UnsupportedFormat.into())
+    }
+}
+
+/// Returns true if the input is a sample profile in a format that can be parsed
+pub fn has_format(mut input: impl Read) -> bool {
+    // The magic is a leb128 so only the first 10 bytes are needed to check for extbinary
+    let mut start = vec![];
+    if input.by_ref().take(10).read_to_end(&mut start).is_err() {
+        return false;
+    }
+    ExtBinarySampleProf::has_format(start.as_slice())
+        || TextSampleProf::has_format(start.as_slice().chain(input))
+}
+
+fn failure<'a>(input: &'a [u8], context: &'static str) -> nom::Err<VerboseError<&'a [u8]>> {
+    let error = VerboseError::from_error_kind(input, ErrorKind::Satisfy);
+    nom::Err::Failure(VerboseError::add_context(input, context, error))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleProfile {
+    /// Version of the binary format, text profiles don't have a version
+    pub version: u64,
+    /// Section headers in the order they appear in the section header table
+    pub sections: Vec<SectionHeader>,
+    /// Size of the file the profile was parsed from
+    pub file_size: u64,
+    /// Function names from the name table of binary profiles, MD5 names are stored as the hash
+    /// in decimal
+    pub names: Vec<String>,
+    /// Top level function profiles in the order they appear in the profile
+    pub functions: Vec<FunctionSamples>,
+    /// Offsets of each function profile from the start of the profile section
+    pub func_offsets: Vec<(String, u64)>,
+    /// List of the functions in the profiled binary, used to tell functions which weren't
+    /// sampled apart from ones that are new since profiling
+    pub symbol_list: Vec<String>,
+}
+
+impl SampleProfile {
+    /// Size of the header and section header table, this is everything before the first section
+    pub fn header_size(&self) -> u64 {
+        self.sections.first().map(|x| x.offset).unwrap_or_default()
+    }
+
+    /// Sum of the sizes of all the sections
+    pub fn total_sections_size(&self) -> u64 {
+        self.sections.iter().map(|x| x.size).sum()
+    }
+
+    /// Function profiles sorted by the total samples in descending order, which is the order
+    /// llvm-profdata shows them in
+    pub fn sorted_functions(&self) -> Vec<&FunctionSamples> {
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| {
+            b.total_samples
+                .cmp(&a.total_samples)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        functions
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub enum SectionType {
+    Invalid,
+    ProfileSummary,
+    NameTable,
+    ProfileSymbolList,
+    FuncOffsetTable,
+    FuncMetadata,
+    CsNameTable,
+    LbrProfile,
+    Unknown(u64),
+}
+
+impl From<u64> for SectionType {
+    fn from(ty: u64) -> Self {
+        match ty {
+            0 => Self::Invalid,
+            1 => Self::ProfileSummary,
+            2 => Self::NameTable,
+            3 => Self::ProfileSymbolList,
+            4 => Self::FuncOffsetTable,
+            5 => Self::FuncMetadata,
+            6 => Self::CsNameTable,
+            0x20 => Self::LbrProfile,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl fmt::Display for SectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Invalid => "InvalidSection",
+            Self::ProfileSummary => "ProfileSummarySection",
+            Self::NameTable => "NameTableSection",
+            Self::ProfileSymbolList => "ProfileSymbolListSection",
+            Self::FuncOffsetTable => "FuncOffsetTableSection",
+            Self::FuncMetadata => "FunctionMetadata",
+            Self::CsNameTable => "CSNameTableSection",
+            Self::LbrProfile => "LBRProfileSection",
+            Self::Unknown(_) => "UnknownSection",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Section header flags, the lower 32 bits are common to all sections and the upper 32 bits are
+/// specific to the section type
+pub mod section_flags {
+    pub const COMPRESS: u64 = 1;
+    pub const FLAT: u64 = 1 << 1;
+    pub const NAME_TABLE_MD5: u64 = 1 << 32;
+    pub const NAME_TABLE_FIXED_LENGTH_MD5: u64 = 1 << 33;
+    pub const NAME_TABLE_UNIQ_SUFFIX: u64 = 1 << 34;
+    pub const SUMMARY_PARTIAL: u64 = 1 << 32;
+    pub const SUMMARY_CONTEXT: u64 = 1 << 33;
+    pub const SUMMARY_FS_DISCRIMINATOR: u64 = 1 << 34;
+    pub const SUMMARY_PRE_INLINED: u64 = 1 << 35;
+    pub const FUNC_OFFSET_ORDERED: u64 = 1 << 32;
+    pub const FUNC_METADATA_PROBE: u64 = 1 << 32;
+    pub const FUNC_METADATA_ATTRIBUTE: u64 = 1 << 33;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SectionHeader {
+    pub ty: SectionType,
+    pub flags: u64,
+    /// Offset from the start of the file
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl SectionHeader {
+    pub fn has_flag(&self, flag: u64) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.has_flag(section_flags::COMPRESS)
+    }
+
+    /// The flags in the format used by `llvm-profdata show --show-sec-info-only`
+    pub fn flags_string(&self) -> String {
+        use section_flags::*;
+        let mut flags = vec![];
+        if self.is_compressed() {
+            flags.push("compressed");
+        }
+        if self.has_flag(FLAT) {
+            flags.push("flat");
+        }
+        match self.ty {
+            SectionType::NameTable => {
+                if self.has_flag(NAME_TABLE_FIXED_LENGTH_MD5) {
+                    flags.push("fixlenmd5");
+                } else if self.has_flag(NAME_TABLE_MD5) {
+                    flags.push("md5");
+                }
+                if self.has_flag(NAME_TABLE_UNIQ_SUFFIX) {
+                    flags.push("uniq");
+                }
+            }
+            SectionType::ProfileSummary => {
+                if self.has_flag(SUMMARY_PARTIAL) {
+                    flags.push("partial");
+                }
+                if self.has_flag(SUMMARY_CONTEXT) {
+                    flags.push("context");
+                }
+                if self.has_flag(SUMMARY_PRE_INLINED) {
+                    flags.push("preInlined");
+                }
+                if self.has_flag(SUMMARY_FS_DISCRIMINATOR) {
+                    flags.push("fs-discriminator");
+                }
+            }
+            SectionType::FuncOffsetTable if self.has_flag(FUNC_OFFSET_ORDERED) => {
+                flags.push("ordered");
+            }
+            SectionType::FuncMetadata => {
+                if self.has_flag(FUNC_METADATA_PROBE) {
+                    flags.push("probe");
+                }
+                if self.has_flag(FUNC_METADATA_ATTRIBUTE) {
+                    flags.push("attr");
+                }
+            }
+            _ => {}
+        }
+        format!("{{{}}}", flags.join(","))
+    }
+}
+
+impl fmt::Display for SectionHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} - Offset: {}, Size: {}, Flags: {}",
+            self.ty,
+            self.offset,
+            self.size,
+            self.flags_string()
+        )
+    }
+}
+
+/// A location in a function as the line offset from the start of the function and the
+/// discriminator to tell apart blocks on the same line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub struct LineLocation {
+    pub line_offset: u32,
+    pub discriminator: u32,
+}
+
+impl fmt::Display for LineLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.line_offset)?;
+        if self.discriminator > 0 {
+            write!(f, ".{}", self.discriminator)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleRecord {
+    pub samples: u64,
+    /// Functions called from this location and the number of samples for each call
+    pub call_targets: BTreeMap<String, u64>,
+}
+
+impl SampleRecord {
+    /// Call targets sorted by the number of samples in descending order
+    pub fn sorted_call_targets(&self) -> Vec<(&str, u64)> {
+        let mut targets = self
+            .call_targets
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect::<Vec<_>>();
+        targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        targets
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionSamples {
+    pub name: String,
+    pub total_samples: u64,
+    /// Samples at the function entry, this is always zero for inlined functions
+    pub head_samples: u64,
+    pub body: BTreeMap<LineLocation, SampleRecord>,
+    /// Profiles of functions inlined at each location keyed by the callee name
+    pub callsites: BTreeMap<LineLocation, BTreeMap<String, FunctionSamples>>,
+}
+
+impl FunctionSamples {
+    /// Writes the profile in the same format as `llvm-profdata show --sample`
+    pub fn write(&self, indent: usize, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            writer,
+            "{}, {}, {} sampled lines",
+            self.total_samples,
+            self.head_samples,
+            self.body.len()
+        )?;
+        if self.body.is_empty() {
+            writeln!(
+                writer,
+                "{:indent$}No samples collected in the function's body",
+                "",
+                indent = indent
+            )?;
+        } else {
+            writeln!(
+                writer,
+                "{:indent$}Samples collected in the function's body {{",
+                "",
+                indent = indent
+            )?;
+            for (location, record) in &self.body {
+                write!(
+                    writer,
+                    "{:indent$}{}: {}",
+                    "",
+                    location,
+                    record.samples,
+                    indent = indent + 2
+                )?;
+                if !record.call_targets.is_empty() {
+                    write!(writer, ", calls:")?;
+                    for (name, count) in record.sorted_call_targets() {
+                        write!(writer, " {}:{}", name, count)?;
+                    }
+                }
+                writeln!(writer)?;
+            }
+            writeln!(writer, "{:indent$}}}", "", indent = indent)?;
+        }
+        if self.callsites.is_empty() {
+            writeln!(
+                writer,
+                "{:indent$}No inlined callsites in this function",
+                "",
+                indent = indent
+            )?;
+        } else {
+            writeln!(
+                writer,
+                "{:indent$}Samples collected in inlined callsites {{",
+                "",
+                indent = indent
+            )?;
+            for (location, callees) in &self.callsites {
+                for (name, callee) in callees {
+                    write!(
+                        writer,
+                        "{:indent$}{}: inlined callee: {}: ",
+                        "",
+                        location,
+                        name,
+                        indent = indent + 2
+                    )?;
+                    callee.write(indent + 4, writer)?;
+                }
+            }
+            writeln!(writer, "{:indent$}}}", "", indent = indent)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use crate::instrumentation_profile::ParseResult;
+use crate::sample_profile::*;
+use rustc_hash::FxHashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+use tracing::debug;
+
+/// Reader for the text sample profile format. Each function starts with an unindented
+/// `name:total_samples:head_samples` line followed by a line per location indented by one space.
+/// A location either has its samples and the samples of any calls made there, e.g.
+/// `9: 2064 _Z3bari:1471 _Z3fooi:631`, or the total samples of a function inlined there, e.g.
+/// `10: inline1:1000`, with the inlined function's samples indented by one more space. Metadata
+/// lines like `!CFGChecksum: 123` are skipped.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct TextSampleProf;
+
+/// Parses the `name:total_samples:head_samples` line starting a function. Names of context
+/// sensitive profiles can contain colons so the counts are taken from the end of the line.
+fn parse_head(line: &str) -> Option<(&str, u64, u64)> {
+    let (rest, head_samples) = line.rsplit_once(':')?;
+    let (name, total_samples) = rest.rsplit_once(':')?;
+    Some((
+        name,
+        total_samples.parse().ok()?,
+        head_samples.parse().ok()?,
+    ))
+}
+
+fn parse_location(location: &str) -> Option<LineLocation> {
+    let (line_offset, discriminator) = match location.split_once('.') {
+        Some((offset, discriminator)) => (offset, discriminator.parse().ok()?),
+        None => (location, 0),
+    };
+    let line_offset = u32::from_str(line_offset).ok()?;
+    if u64::from(line_offset) > MAX_LINE_OFFSET {
+        return None;
+    }
+    Some(LineLocation {
+        line_offset,
+        discriminator,
+    })
+}
+
+fn parse_name_count(s: &str) -> Option<(&str, u64)> {
+    let (name, count) = s.rsplit_once(':')?;
+    Some((name, count.parse().ok()?))
+}
+
+enum Line<'a> {
+    Body {
+        location: LineLocation,
+        samples: u64,
+        call_targets: Vec<(&'a str, u64)>,
+    },
+    Callsite {
+        location: LineLocation,
+        name: &'a str,
+        total_samples: u64,
+    },
+    Metadata,
+}
+
+/// Parses a line of samples after the indentation
+fn parse_line(line: &str) -> Option<Line<'_>> {
+    if line.starts_with('!') {
+        return Some(Line::Metadata);
+    }
+    let (location, rest) = line.split_once(':')?;
+    let location = parse_location(location)?;
+    let rest = rest.trim_start();
+    if rest.starts_with(|c: char| c.is_ascii_digit()) {
+        let mut values = rest.split_whitespace();
+        let samples = values.next()?.parse().ok()?;
+        let call_targets = values.map(parse_name_count).collect::<Option<Vec<_>>>()?;
+        Some(Line::Body {
+            location,
+            samples,
+            call_targets,
+        })
+    } else {
+        let (name, total_samples) = parse_name_count(rest.trim_end())?;
+        Some(Line::Callsite {
+            location,
+            name,
+            total_samples,
+        })
+    }
+}
+
+/// Follows the inlined callsites in `stack` down from `function`
+fn inlined_function<'a>(
+    mut function: &'a mut FunctionSamples,
+    stack: &[(LineLocation, String)],
+) -> &'a mut FunctionSamples {
+    for (location, name) in stack {
+        function = function
+            .callsites
+            .get_mut(location)
+            .and_then(|x| x.get_mut(name))
+            .expect("inlined function is added before its samples");
+    }
+    function
+}
+
+impl TextSampleProf {
+    pub fn parse_bytes(input: &[u8]) -> ParseResult<'_, SampleProfile> {
+        let mut profile = SampleProfile {
+            file_size: input.len() as u64,
+            ..Default::default()
+        };
+        // Functions appearing more than once are merged like llvm-profdata does
+        let mut indexes = FxHashMap::<String, usize>::default();
+        let mut current = None;
+        // Location and name of the inlined functions the current line is nested in
+        let mut stack: Vec<(LineLocation, String)> = vec![];
+        // Metadata has to come after the samples of a function
+        let mut metadata_depth = None;
+        let mut offset = 0;
+        for raw_line in input.split(|x| *x == b'\n') {
+            let line_start = &input[offset..];
+            offset = (offset + raw_line.len() + 1).min(input.len());
+            let line = std::str::from_utf8(raw_line)
+                .map_err(|_| failure(line_start, "invalid utf-8 in text sample profile"))?
+                .trim_end();
+            if line.trim_start().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let depth = line.len() - line.trim_start_matches(' ').len();
+            if depth == 0 {
+                let (name, total_samples, head_samples) = parse_head(line)
+                    .ok_or_else(|| failure(line_start, "invalid function header"))?;
+                let index = *indexes.entry(name.to_string()).or_insert_with(|| {
+                    profile.functions.push(FunctionSamples {
+                        name: name.to_string(),
+                        ..Default::default()
+                    });
+                    profile.functions.len() - 1
+                });
+                let function = &mut profile.functions[index];
+                function.total_samples = function.total_samples.saturating_add(total_samples);
+                function.head_samples = function.head_samples.saturating_add(head_samples);
+                current = Some(index);
+                stack.clear();
+                metadata_depth = None;
+                continue;
+            }
+            let index =
+                current.ok_or_else(|| failure(line_start, "samples before a function header"))?;
+            let line = parse_line(&line[depth..])
+                .ok_or_else(|| failure(line_start, "invalid sample line"))?;
+            if metadata_depth == Some(depth) && !matches!(line, Line::Metadata) {
+                return Err(failure(line_start, "samples after metadata"));
+            }
+            // Top level samples have a depth of 1 so anything deeper is in an inlined function
+            stack.truncate(depth - 1);
+            let function = inlined_function(&mut profile.functions[index], &stack);
+            match line {
+                Line::Body {
+                    location,
+                    samples,
+                    call_targets,
+                } => {
+                    let record = function.body.entry(location).or_default();
+                    record.samples = record.samples.saturating_add(samples);
+                    for (name, count) in call_targets {
+                        let target = record.call_targets.entry(name.to_string()).or_default();
+                        *target = target.saturating_add(count);
+                    }
+                }
+                Line::Callsite {
+                    location,
+                    name,
+                    total_samples,
+                } => {
+                    let callee = function
+                        .callsites
+                        .entry(location)
+                        .or_default()
+                        .entry(name.to_string())
+                        .or_insert_with(|| FunctionSamples {
+                            name: name.to_string(),
+                            ..Default::default()
+                        });
+                    callee.total_samples = callee.total_samples.saturating_add(total_samples);
+                    stack.push((location, name.to_string()));
+                    metadata_depth = None;
+                }
+                Line::Metadata => metadata_depth = Some(depth),
+            }
+        }
+        debug!("Parsed {} function samples", profile.functions.len());
+        Ok((&input[input.len()..], profile))
+    }
+
+    /// Checks the first line which isn't blank or a comment is a function header, like llvm this
+    /// means instrumentation text profiles aren't mistaken for sample profiles
+    pub fn has_format(input: impl Read) -> bool {
+        for line in BufReader::new(input).lines() {
+            match line {
+                Ok(line) if line.trim().is_empty() || line.starts_with('#') => {}
+                Ok(line) => {
+                    return !line.starts_with(' ') && parse_head(line.trim_end()).is_some();
+                }
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_sample_lines() {
+        assert_eq!(parse_head("main:100:2"), Some(("main", 100, 2)));
+        assert_eq!(
+            parse_head("[main:3 @ foo]:20:0"),
+            Some(("[main:3 @ foo]", 20, 0))
+        );
+        assert_eq!(parse_head("main:100"), None);
+        assert_eq!(parse_head("main.c:foo"), None);
+
+        assert_eq!(
+            parse_location("4.2"),
+            Some(LineLocation {
+                line_offset: 4,
+                discriminator: 2
+            })
+        );
+        assert_eq!(parse_location("65536"), None);
+
+        match parse_line("9: 2064 _Z3bari:1471 _Z3fooi:631") {
+            Some(Line::Body {
+                samples,
+                call_targets,
+                ..
+            }) => {
+                assert_eq!(samples, 2064);
+                assert_eq!(call_targets, vec![("_Z3bari", 1471), ("_Z3fooi", 631)]);
+            }
+            _ => panic!("expected a body line"),
+        }
+        match parse_line("10: inline1:1000") {
+            Some(Line::Callsite {
+                name,
+                total_samples,
+                ..
+            }) => {
+                assert_eq!(name, "inline1");
+                assert_eq!(total_samples, 1000);
+            }
+            _ => panic!("expected a callsite line"),
+        }
+        assert!(matches!(
+            parse_line("!CFGChecksum: 1234"),
+            Some(Line::Metadata)
+        ));
+        assert!(parse_line("9: 10 foo").is_none());
+    }
+
+    #[test]
+    fn text_sample_format() {
+        assert!(TextSampleProf::has_format(
+            &b"# comment\nmain:10:1\n 1: 10\n"[..]
+        ));
+        assert!(!TextSampleProf::has_format(
+            &b"# IR level Instrumentation Flag\n:ir\nmain\n# Func Hash:\n1234\n"[..]
+        ));
+        assert!(!TextSampleProf::has_format(&b" 1: 10\n"[..]));
+        assert!(!TextSampleProf::has_format(&b""[..]));
+    }
+
+    #[test]
+    fn nested_text_samples() {
+        let input = b"main:30:1\n 1: 5\n 2: foo:20\n  1: 10\n  2: bar:5\n   1: 5\n  3: 5\n 3: 5\nmain:10:1\n 1: 10\n";
+        let (_, profile) = TextSampleProf::parse_bytes(&input[..]).unwrap();
+        assert_eq!(profile.functions.len(), 1);
+        let main = &profile.functions[0];
+        assert_eq!((main.total_samples, main.head_samples), (40, 2));
+        let location = |line_offset| LineLocation {
+            line_offset,
+            discriminator: 0,
+        };
+        assert_eq!(main.body[&location(1)].samples, 15);
+        assert_eq!(main.body[&location(3)].samples, 5);
+        let foo = &main.callsites[&location(2)]["foo"];
+        assert_eq!(foo.total_samples, 20);
+        assert_eq!(foo.body.len(), 2);
+        assert_eq!(
+            foo.callsites[&location(2)]["bar"].body[&location(1)].samples,
+            5
+        );
+
+        assert!(TextSampleProf::parse_bytes(b" 1: 5\n").is_err());
+        assert!(TextSampleProf::parse_bytes(b"main:1:0\n x: 5\n").is_err());
+
+        let input = b"main:10:0\n 1: 5\n 2: foo:5\n  1: 5\n  !CFGChecksum: 12\n !CFGChecksum: 34\n";
+        assert!(TextSampleProf::parse_bytes(&input[..]).is_ok());
+        let input = b"main:10:0\n !CFGChecksum: 12\n 1: 5\n";
+        assert!(TextSampleProf::parse_bytes(&input[..]).is_err());
+    }
+}
@@ -1,9 +1,9 @@
 /// The amount to scale cutoffs by to go back to a more readable percentile
-pub const CUTOFF_SCALE: u64 = 10_000_000;
+pub const CUTOFF_SCALE: u64 = 1_000_000;
 
 /// These are the default cutoffs for profile summary entries. Without cutoffs specified manually
 /// llvm-profdata and associated tools will use these. These numbers represent percentiles of
-/// counts in the profile data scaled by 10_000_000 (divide by these scales to go back to
+/// counts in the profile data scaled by 1_000_000 (divide by these scales to go back to
 /// percentiles)
 pub const DEFAULT_CUTOFFS: [u64; 16] = [
     10000, 100000, 200000, 300000, 400000, 500000, 600000, 700000, 800000, 900000, 950000, 990000,
@@ -24,6 +24,35 @@ where
     Ok((input, x))
 }
 
+/// The magic number at the start of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompresses a name or path list, llvm compresses these with zlib or zstd and the zstd frame
+/// magic is used to tell them apart. zstd is only supported with the `zstd` feature, on failure the
+/// error is a description to add as context to a parse error.
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if compressed.starts_with(&ZSTD_MAGIC) {
+        decompress_zstd(compressed)
+    } else {
+        let mut decoder = ZlibDecoder::new(compressed);
+        let mut output = vec![];
+        decoder
+            .read_to_end(&mut output)
+            .map(|_| output)
+            .map_err(|_| "invalid deflate stream")
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(compressed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    zstd::stream::decode_all(compressed).map_err(|_| "invalid zstd stream")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_compressed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    Err("zstd compressed data requires the zstd feature")
+}
+
 pub fn parse_string_ref<'a, E>(input: &'a [u8]) -> IResult<&'a [u8], String, E>
 where
     E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
@@ -36,23 +65,31 @@ where
             Err(nom::Err::Error(E::from_error_kind(input, ErrorKind::Eof)))
         } else {
             let compressed_size = compressed_size as usize;
-            let mut decoder = ZlibDecoder::new(&input[..compressed_size]);
-            let mut output = vec![];
-            if decoder.read_to_end(&mut output).is_ok() {
-                let name = String::from_utf8(output);
-                let name = name.unwrap();
-                Ok((&input[compressed_size..], name))
-            } else {
-                let inner = E::from_error_kind(input, ErrorKind::Satisfy);
-                Err(nom::Err::Failure(E::add_context(
-                    input,
-                    "invalid deflate stream",
-                    inner,
-                )))
+            match decompress(&input[..compressed_size]) {
+                Ok(output) => match String::from_utf8(output) {
+                    Ok(name) => Ok((&input[compressed_size..], name)),
+                    Err(_e) => {
+                        debug!("Invalid UTF-8 in compressed string");
+                        let inner = E::from_error_kind(input, ErrorKind::Satisfy);
+                        Err(nom::Err::Failure(E::add_context(
+                            input,
+                            "invalid utf-8 string",
+                            inner,
+                        )))
+                    }
+                },
+                Err(context) => {
+                    let inner = E::from_error_kind(input, ErrorKind::Satisfy);
+                    Err(nom::Err::Failure(E::add_context(input, context, inner)))
+                }
             }
         }
     } else {
         let uncompressed_size = uncompressed_size as usize;
+        if uncompressed_size > input.len() {
+            debug!("Unexpected EOF parsing a string ref");
+            return Err(nom::Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
+        }
         match String::from_utf8(input[..uncompressed_size].to_vec()) {
             Ok(name) => Ok((&input[uncompressed_size..], name)),
             Err(_e) => {
@@ -71,6 +108,19 @@ where
 /// Parses a list of paths - this is currently only used in parsing the sections in an instrumented
 /// object file, and due to CWD joining is different to the other string parsing implemented
 pub fn parse_path_list<'a, E>(input: &'a [u8], version: u64) -> IResult<&'a [u8], Vec<PathBuf>, E>
+where
+    E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
+{
+    parse_path_list_with_compilation_dir(input, version, None)
+}
+
+/// Parses a list of paths joining relative paths onto `compilation_dir` instead of the working
+/// directory recorded in the list, like `llvm-cov -compilation-dir`
+pub fn parse_path_list_with_compilation_dir<'a, E>(
+    input: &'a [u8],
+    version: u64,
+    compilation_dir: Option<&Path>,
+) -> IResult<&'a [u8], Vec<PathBuf>, E>
 where
     E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
 {
@@ -78,7 +128,8 @@ where
 
     if version < 3 {
         // read_uncompressed
-        let (input, values) = parse_uncompressed_file_list(input, list_length, version)?;
+        let (input, values) =
+            parse_uncompressed_file_list(input, list_length, version, compilation_dir)?;
         Ok((input, values))
     } else {
         let (input, uncompressed_size) = parse_leb128::<E>(input)?;
@@ -88,16 +139,28 @@ where
         let uncompressed_size = uncompressed_size as usize;
 
         if compressed_size == 0 {
-            let (input, values) = parse_uncompressed_file_list::<E>(input, list_length, version)?;
+            let (input, values) =
+                parse_uncompressed_file_list::<E>(input, list_length, version, compilation_dir)?;
             Ok((input, values))
         } else {
-            let mut decoder = ZlibDecoder::new(&input[..compressed_size]);
-            let mut output = Vec::with_capacity(uncompressed_size);
-            decoder.read_to_end(&mut output).unwrap();
-            // Use context error to
-            let values = parse_uncompressed_string_list::<()>(&output)
-                .map(|(_, v)| v.iter().map(PathBuf::from).collect())
-                .map_err(|_| nom::Err::Failure(E::from_error_kind(input, ErrorKind::Fail)))?;
+            if input.len() < compressed_size {
+                return Err(nom::Err::Failure(E::from_error_kind(input, ErrorKind::Eof)));
+            }
+            let output = match decompress(&input[..compressed_size]) {
+                Ok(output) if output.len() == uncompressed_size => output,
+                _ => {
+                    return Err(nom::Err::Failure(E::from_error_kind(
+                        input,
+                        ErrorKind::Fail,
+                    )))
+                }
+            };
+            // The decompressed list is read like an uncompressed one, errors refer to the local
+            // buffer so are replaced with one for the input
+            let values =
+                parse_uncompressed_file_list::<()>(&output, list_length, version, compilation_dir)
+                    .map(|(_, v)| v)
+                    .map_err(|_| nom::Err::Failure(E::from_error_kind(input, ErrorKind::Fail)))?;
             Ok((&input[compressed_size..], values))
         }
     }
@@ -109,20 +172,30 @@ where
 {
     let (bytes, len) = parse_leb128::<E>(bytes)?;
     let len = len as usize;
+    if len > bytes.len() {
+        return Err(nom::Err::Failure(E::from_error_kind(bytes, ErrorKind::Eof)));
+    }
     let string = String::from_utf8_lossy(&bytes[..len]).to_string();
     Ok((&bytes[len..], string))
 }
 
+/// Filename lists from version 5 start with the compilation directory, relative paths in the rest
+/// of the list are joined onto it
+pub(crate) fn has_compilation_dir(version: u64) -> bool {
+    version >= 5
+}
+
 fn parse_uncompressed_file_list<'a, E>(
     mut input: &'a [u8],
     list_length: u64,
     version: u64,
+    compilation_dir: Option<&Path>,
 ) -> IResult<&'a [u8], Vec<PathBuf>, E>
 where
     E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
 {
     let mut res = vec![];
-    if version < 5 {
+    if !has_compilation_dir(version) {
         for _ in 0..list_length {
             let (bytes, string) = read_string(input)?;
             res.push(PathBuf::from(string));
@@ -141,27 +214,83 @@ where
             if tmp.is_absolute() {
                 res.push(tmp.to_path_buf());
             } else {
-                // NB here the llvm code sometimes has the compilation dir available and joins
-                // paths onto that instead.
-                res.push(cwd.join(tmp));
+                res.push(compilation_dir.unwrap_or(cwd).join(tmp));
             }
         }
         Ok((input, res))
     }
 }
 
-fn parse_uncompressed_string_list<'a, E>(mut input: &'a [u8]) -> IResult<&'a [u8], Vec<String>, E>
-where
-    E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
-{
-    let mut res = vec![];
-    while !input.is_empty() {
-        let (bytes, len) = parse_leb128::<E>(input)?;
-        let len = len as usize;
-        let string = String::from_utf8_lossy(&bytes[..len]).to_string();
-        res.push(string);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use nom::error::VerboseError;
+    use std::io::Write;
 
-        input = &bytes[len..];
+    fn string_ref(uncompressed_size: u64, data: &[u8], compressed: bool) -> Vec<u8> {
+        let mut bytes = vec![];
+        leb128::write::unsigned(&mut bytes, uncompressed_size).unwrap();
+        let compressed_size = if compressed { data.len() as u64 } else { 0 };
+        leb128::write::unsigned(&mut bytes, compressed_size).unwrap();
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn malformed_string_refs() {
+        let name = b"foo\x01bar";
+        let (_, parsed) =
+            parse_string_ref::<VerboseError<_>>(&string_ref(7, &zlib(name), true)).unwrap();
+        assert_eq!(parsed, "foo\u{1}bar");
+
+        // Invalid utf-8 once decompressed
+        let bytes = string_ref(2, &zlib(&[0xc3, 0x28]), true);
+        assert!(matches!(
+            parse_string_ref::<VerboseError<_>>(&bytes),
+            Err(nom::Err::Failure(_))
+        ));
+        // A corrupt zlib stream
+        let bytes = string_ref(7, &[0x78, 0x9c, 0xff, 0xff, 0xff], true);
+        assert!(matches!(
+            parse_string_ref::<VerboseError<_>>(&bytes),
+            Err(nom::Err::Failure(_))
+        ));
+        // Shorter than the uncompressed length
+        let bytes = string_ref(20, b"foo", false);
+        assert!(parse_string_ref::<VerboseError<_>>(&bytes).is_err());
+    }
+
+    #[test]
+    fn malformed_path_lists() {
+        // A list of two paths with the first longer than the data
+        let mut bytes = vec![2, 0, 0, 10];
+        bytes.extend_from_slice(b"/src");
+        assert!(parse_path_list::<VerboseError<_>>(&bytes, 5).is_err());
+
+        // A compressed list with a corrupt zlib stream
+        let bytes = vec![1, 8, 5, 0x78, 0x9c, 0xff, 0xff, 0xff];
+        assert!(parse_path_list::<VerboseError<_>>(&bytes, 5).is_err());
+
+        let mut list = vec![];
+        for path in ["/src", "main.rs"] {
+            leb128::write::unsigned(&mut list, path.len() as u64).unwrap();
+            list.extend_from_slice(path.as_bytes());
+        }
+        let compressed = zlib(&list);
+        let mut bytes = vec![2, list.len() as u8, compressed.len() as u8];
+        bytes.extend_from_slice(&compressed);
+        let (_, paths) = parse_path_list::<VerboseError<_>>(&bytes, 5).unwrap();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("/src"), PathBuf::from("/src/main.rs")]
+        );
     }
-    Ok((input, res))
 }
@@ -1,11 +1,17 @@
 use cargo_metadata::Message;
-use llvm_profparser::{parse, CoverageMapping};
+use llvm_profparser::coverage_mapping::{
+    read_object_bytes, read_object_file, sections_report, sections_report_bytes, LlvmSection,
+    SectionAvailability,
+};
+use llvm_profparser::{
+    html, parse, segments, CoverageMapping, CoverageReport, CoverageResult, RegionKind,
+};
 use pretty_assertions::assert_eq;
 use regex::Regex;
 
 use std::fs::{self};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -76,6 +82,52 @@ fn get_project_dir(project: &str) -> PathBuf {
         .join(project)
 }
 
+/// Generates the report for the given profile and binaries of one of the test projects
+fn report_for(project: &str, profile: &str, binaries: &[&str]) -> CoverageReport {
+    let dir = get_project_dir(project);
+    let instr = parse(dir.join(profile)).unwrap();
+    let binaries = binaries.iter().map(|x| dir.join(x)).collect::<Vec<_>>();
+    CoverageMapping::new(&binaries, &instr, false)
+        .unwrap()
+        .generate_report()
+}
+
+/// The result for the file in the report whose path ends with `name`
+fn file_result<'a>(report: &'a CoverageReport, name: &str) -> &'a CoverageResult {
+    report
+        .files
+        .iter()
+        .find(|(path, _)| path.ends_with(name))
+        .map(|(_, result)| result)
+        .unwrap()
+}
+
+/// The test projects are built with `--remap-path-prefix` so their sources are recorded under this
+/// placeholder instead of wherever the repo happened to be checked out.
+fn recorded_project_dir(project: &str) -> PathBuf {
+    PathBuf::from("/llvm_profparser/tests/data").join(project)
+}
+
+/// A `--path-equivalence` argument mapping the recorded sources of a project onto the checkout.
+fn source_remapping(project: &str) -> String {
+    format!(
+        "{},{}",
+        recorded_project_dir(project).display(),
+        get_project_dir(project).display()
+    )
+}
+
+/// The directory the first object in the mapping was compiled in. Sources of the test projects
+/// are recorded relative to this, so tests should use it rather than the checkout location.
+fn recorded_compilation_dir(mapping: &CoverageMapping) -> PathBuf {
+    mapping.mapping_info[0]
+        .compilation_dirs
+        .values()
+        .next()
+        .cloned()
+        .unwrap()
+}
+
 fn get_printout(output: &[u8]) -> Vec<String> {
     String::from_utf8_lossy(output)
         .lines()
@@ -100,7 +152,7 @@ fn run_coverage(project: &str) -> io::Result<Option<Run>> {
     };
 
     let mut child = Command::new("cargo")
-        .args(&["test", "--no-run", "--message-format", "json"])
+        .args(["test", "--no-run", "--message-format", "json"])
         .env("RUSTFLAGS", rustflags)
         .stdout(Stdio::piped())
         .current_dir(&project)
@@ -139,8 +191,8 @@ fn compare_reports(run: &Run) {
     println!("Comparing run reports for: {:?}", run);
     let profdata = run.profraw.parent().unwrap().join("default.profdata");
     let merge = Command::new("cargo")
-        .args(&["profdata", "--", "merge", "-sparse", "-o"])
-        .args(&[&profdata, &run.profraw])
+        .args(["profdata", "--", "merge", "-sparse", "-o"])
+        .args([&profdata, &run.profraw])
         .output()
         .unwrap();
 
@@ -150,20 +202,20 @@ fn compare_reports(run: &Run) {
     }
 
     let llvm_report = Command::new("cargo")
-        .args(&[
+        .args([
             "cov",
             "--",
             "show",
             "--show-instantiations=false",
             "--instr-profile",
         ])
-        .args(&[&profdata, &run.binary])
+        .args([&profdata, &run.binary])
         .output()
         .unwrap();
 
     let profparser_report = assert_cmd::Command::cargo_bin("cov")
         .unwrap()
-        .args(&["show", "--instr-profile"])
+        .args(["show", "--instr-profile"])
         .arg(&run.profraw)
         .arg("--object")
         .arg(&run.binary)
@@ -194,7 +246,7 @@ fn check_matches() {
     let profparser_report = assert_cmd::Command::cargo_bin("cov")
         .unwrap()
         .current_dir(&dir)
-        .args(&[
+        .args([
             "show",
             "--instr-profile",
             "matches/merged.profdata",
@@ -264,3 +316,1141 @@ fn check_mapping_consistency() {
         assert_eq!(expected_len, counts);
     }
 }
+
+#[test]
+fn branch_coverage() {
+    let report = report_for("branches", "branches.profraw", &["branches_bin"]);
+
+    let result = file_result(&report, "main.rs");
+
+    // `if x > 5` is evaluated for 0..8 so is true twice and false six times
+    let branches = result
+        .branches
+        .iter()
+        .filter(|(loc, _)| loc.line_start == 2)
+        .collect::<Vec<_>>();
+    assert_eq!(branches.len(), 1);
+    let (loc, count) = branches[0];
+    assert_eq!(loc.column_start, 8);
+    assert_eq!(count.true_count, 2);
+    assert_eq!(count.false_count, 6);
+    assert!(!count.true_folded && !count.false_folded);
+
+    assert_eq!(result.total_branches(), 2);
+    assert_eq!(result.covered_branches(), 2);
+}
+
+#[test]
+fn trailing_expression_line_counts() {
+    // Expected counts are taken from `llvm-cov export -format=lcov`. The trailing
+    // `Duration::from_secs` expression in `from_days` used to be reported as not executed
+    let report = report_for("from_days", "from_days.profdata", &["from_days_bin"]);
+
+    let result = file_result(&report, "main.rs");
+
+    let expected = [
+        (3, 2),
+        (4, 2),
+        (5, 0),
+        (6, 2),
+        (7, 2),
+        (9, 2),
+        (11, 1),
+        (12, 1),
+        (13, 1),
+        (14, 1),
+        (16, 1),
+        (17, 1),
+        (18, 1),
+        (19, 1),
+    ];
+    assert_eq!(
+        result.line_hits().into_iter().collect::<Vec<_>>(),
+        expected.to_vec()
+    );
+    assert_eq!(result.hits_for_line(8), None);
+}
+
+#[test]
+fn region_markers() {
+    // Expected markers are taken from `llvm-cov show --show-regions`
+    let report = report_for("from_days", "from_days.profdata", &["from_days_bin"]);
+
+    let (path, result) = report
+        .files
+        .iter()
+        .find(|(path, _)| path.ends_with("main.rs"))
+        .unwrap();
+    let segments = report.segments_for_file(path);
+    let markers = (1..=19)
+        .flat_map(|line| {
+            let count = result.hits_for_line(line).unwrap_or_default();
+            segments::region_markers(&segments, line, count)
+                .into_iter()
+                .map(move |x| (line, x.col, x.count))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(markers, vec![(4, 39, 0), (12, 33, 0)]);
+}
+
+#[test]
+fn embedded_profile_data() {
+    // The newer records have a bitmap pointer and the older ones don't, the offsets are checked
+    // against the counter pointers in the object files
+    let expected = [
+        (
+            get_project_dir("from_days").join("from_days_bin"),
+            vec![(1, 0), (2, 8), (1, 24), (1, 32)],
+        ),
+        (
+            get_project_dir("cov").join("simple_project"),
+            vec![(2, 0), (2, 16), (3, 32), (2, 56), (2, 72)],
+        ),
+    ];
+    for (binary, expected) in &expected {
+        let info = read_object_file(binary, 8).unwrap();
+        let data = info.prof_data.as_ref().unwrap();
+        let layout = data
+            .iter()
+            .map(|x| (x.counters_len, x.counters_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(&layout, expected);
+        for record in data {
+            let counters = info.counters(record).unwrap();
+            assert_eq!(counters.len(), record.counters_len as usize);
+        }
+    }
+}
+
+#[test]
+fn validate_counters_against_binary() {
+    use llvm_profparser::instrumentation_profile::types::{
+        InstrumentationProfileBuilder, ValidationError,
+    };
+    let project = get_project_dir("from_days");
+    let binary = vec![project.join("from_days_bin")];
+    let profile = parse(project.join("from_days.profdata")).unwrap();
+    assert!(CoverageMapping::new(&binary, &profile, false)
+        .unwrap()
+        .validate()
+        .is_ok());
+
+    // Add a counter to one of the functions so it no longer matches the binary
+    let mut records = profile.records().to_vec();
+    records[0].record.counts.push(0);
+    let name = records[0].name.clone().unwrap();
+    let expected = records[0].counts().len();
+    let modified = InstrumentationProfileBuilder::new()
+        .with_version(profile.version().unwrap())
+        .with_ir(profile.is_ir_level_profile())
+        .add_records(records)
+        .build();
+    let errors = CoverageMapping::new(&binary, &modified, false)
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert_eq!(
+        errors,
+        vec![ValidationError::CounterMismatch {
+            name,
+            counters: expected,
+            expected: expected - 1,
+        }]
+    );
+}
+
+#[test]
+fn coverage_from_embedded_counters() {
+    use object::{Object, ObjectSection};
+
+    // Write the counts from the profile into the counters section to make a binary like one that
+    // has had its counters written back after a run
+    let dir = get_project_dir("from_days");
+    let binary = dir.join("from_days_bin");
+    let instr = parse(dir.join("from_days.profdata")).unwrap();
+    let info = read_object_file(&binary, 8).unwrap();
+    let mut data = fs::read(&binary).unwrap();
+    let (counters_start, _) = object::File::parse(&*data)
+        .unwrap()
+        .section_by_name("__llvm_prf_cnts")
+        .and_then(|x| x.file_range())
+        .unwrap();
+    for record in info.prof_data.as_ref().unwrap() {
+        let counts = instr
+            .find_by_hashes(record.name_md5, record.structural_hash)
+            .unwrap()
+            .counts();
+        for (i, count) in counts.iter().enumerate() {
+            let start = (counters_start + record.counters_offset) as usize + i * 8;
+            data[start..(start + 8)].copy_from_slice(&count.to_le_bytes());
+        }
+    }
+    let embedded = std::env::temp_dir().join("from_days_embedded_bin");
+    fs::write(&embedded, &data).unwrap();
+
+    let expected = CoverageMapping::new(&[binary], &instr, false)
+        .unwrap()
+        .generate_report();
+    let report = CoverageMapping::from_object_only(&[embedded])
+        .unwrap()
+        .generate_report();
+    assert_eq!(report.functions, expected.functions);
+    for (path, result) in &expected.files {
+        assert_eq!(report.files[path].line_hits(), result.line_hits());
+    }
+}
+
+#[test]
+fn single_function_coverage() {
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("branches.profdata")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+
+    let function = mapping
+        .function_coverage("_RNvCs3UIYSLOGnTh_4main5check")
+        .unwrap();
+    assert_eq!(function.execution_count, 8);
+    let file_id = function.counted_regions[0].region.file_id;
+    assert!(function.filenames[file_id].ends_with("main.rs"));
+    // Matches the regions from `llvm-cov export`
+    let regions = function
+        .counted_regions
+        .iter()
+        .map(|x| {
+            (
+                x.region.loc.line_start,
+                x.region.loc.column_start,
+                x.execution_count,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        regions,
+        vec![(1, 1, 8), (2, 8, 8), (3, 9, 2), (5, 9, 6), (7, 1, 8)]
+    );
+    assert_eq!(function.counted_branch_regions.len(), 1);
+    assert_eq!(function.counted_branch_regions[0].execution_count, 2);
+    assert_eq!(function.counted_branch_regions[0].false_execution_count, 6);
+
+    let report = mapping.generate_report();
+    assert!(report.functions.contains(&function));
+    assert!(mapping.function_coverage("missing").is_none());
+}
+
+#[test]
+fn shared_functions_across_objects() {
+    // `is_even` is from a library linked into both binaries, the expected counts are taken from
+    // `llvm-cov export -format=lcov bin_a -object bin_b`
+    let report = report_for("shared_lib", "shared.profdata", &["bin_a", "bin_b"]);
+
+    let result = file_result(&report, "lib.rs");
+    let expected = [(1, 5), (2, 5), (3, 2), (5, 3), (7, 5)];
+    assert_eq!(
+        result.line_hits().into_iter().collect::<Vec<_>>(),
+        expected.to_vec()
+    );
+    let functions = report
+        .functions
+        .iter()
+        .filter(|x| x.name.ends_with("is_even"))
+        .count();
+    assert_eq!(functions, 1);
+    assert_eq!(report.summary().total.functions.total, 3);
+}
+
+#[test]
+fn ignore_filenames() {
+    let dir = get_project_dir("shared_lib");
+    let mut report = report_for("shared_lib", "shared.profdata", &["bin_a", "bin_b"]);
+    assert_eq!(report.files.len(), 3);
+
+    report.retain_files(|path| !path.ends_with("bin_b.rs"));
+    let files = report
+        .files
+        .keys()
+        .map(|x| x.file_name().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(files, ["bin_a.rs", "lib.rs"]);
+    assert_eq!(report.functions.len(), 2);
+    let summary = report.summary();
+    assert_eq!(summary.files.len(), 2);
+    assert_eq!(summary.total.functions.total, 2);
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .current_dir(&dir)
+        .args([
+            "show",
+            "--instr-profile",
+            "shared.profdata",
+            "--object",
+            "bin_a",
+            "--object",
+            "bin_b",
+            "--ignore-filename-regex",
+            "bin_a",
+            "--ignore-filename-regex",
+            r"bin_b\.rs$",
+            "--path-equivalence",
+            &source_remapping("shared_lib"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let printout = get_printout(&output.stdout);
+    assert_eq!(printout.len(), 8);
+    assert!(printout[0].contains("pub fn is_even"));
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .current_dir(&dir)
+        .args([
+            "show",
+            "--instr-profile",
+            "shared.profdata",
+            "--object",
+            "bin_a",
+            "--ignore-filename-regex",
+            "(",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn single_byte_coverage() {
+    // Expected values are taken from `llvm-cov export` with the profile generated by
+    // `llvm-profdata merge` from a text profile with the `:single_byte_coverage` header
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("byte_coverage.profdata")).unwrap();
+    assert!(instr.is_byte_coverage());
+    let report = report_for("branches", "byte_coverage.profdata", &["branches_bin"]);
+
+    let result = file_result(&report, "main.rs");
+    assert!(result.hits.values().all(|x| *x <= 1));
+    let hits = |line, column| {
+        result
+            .hits
+            .iter()
+            .find(|(loc, _)| loc.line_start == line && loc.column_start == column)
+            .map(|(_, count)| *count)
+            .unwrap()
+    };
+    assert_eq!(hits(1, 1), 1);
+    assert_eq!(hits(3, 9), 1);
+    assert_eq!(hits(5, 9), 0);
+    assert_eq!(hits(10, 14), 1);
+    assert_eq!(hits(11, 9), 0);
+
+    let (_, count) = result
+        .branches
+        .iter()
+        .find(|(loc, _)| loc.line_start == 2)
+        .unwrap();
+    assert_eq!((count.true_count, count.false_count), (1, 0));
+
+    let summary = report.summary().total;
+    assert_eq!((summary.regions.total, summary.regions.covered), (11, 7));
+    assert_eq!((summary.branches.total, summary.branches.covered), (2, 1));
+}
+
+#[test]
+fn function_entry_only_coverage() {
+    // Only the entry counts are recorded so every region gets the count of its function entry
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("entry_only.profdata")).unwrap();
+    assert!(instr.fn_entry_only());
+    let report = report_for("branches", "entry_only.profdata", &["branches_bin"]);
+
+    let result = file_result(&report, "main.rs");
+    for (loc, count) in &result.hits {
+        let expected = if loc.line_start < 9 { 8 } else { 1 };
+        assert_eq!(*count, expected, "{:?}", loc);
+    }
+}
+
+#[test]
+fn hash_mismatches() {
+    // `check` has a different function hash in the profile so it's left out like in llvm-cov
+    let dir = get_project_dir("branches");
+    let report = report_for("branches", "hash_mismatch.profdata", &["branches_bin"]);
+
+    assert_eq!(report.hash_mismatches.len(), 1);
+    let mismatch = &report.hash_mismatches[0];
+    assert_eq!(mismatch.name, "_RNvCs3UIYSLOGnTh_4main5check");
+    assert_eq!(mismatch.profile_hash, 0x136c46d923863273);
+    assert_eq!(mismatch.mapping_hash, 0x136c46d923863272);
+    let names = report
+        .functions
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["_RNvCs3UIYSLOGnTh_4main4main"]);
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .arg("report")
+        .arg("--object")
+        .arg(dir.join("branches_bin"))
+        .arg("--instr-profile")
+        .arg(dir.join("hash_mismatch.profdata"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "warning: 1 functions have mismatched data\n"
+    );
+
+    let report = report_for("branches", "branches.profraw", &["branches_bin"]);
+    assert!(report.hash_mismatches.is_empty());
+}
+
+#[test]
+fn report_for_files() {
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("branches.profraw")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    let full = mapping.generate_report();
+    let files = full.files.keys().cloned().collect::<Vec<_>>();
+    assert_eq!(files.len(), 1);
+
+    let report = mapping.generate_report_for(&files);
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.files[&files[0]].hits, full.files[&files[0]].hits);
+    assert_eq!(
+        report.files[&files[0]].branches,
+        full.files[&files[0]].branches
+    );
+    assert_eq!(report.functions, full.functions);
+
+    let report = mapping.generate_report_for(&[dir.join("other.rs")]);
+    assert!(report.files.is_empty());
+    assert!(report.functions.is_empty());
+}
+
+#[test]
+fn report_summary() {
+    // Expected values are taken from `llvm-cov report`
+    let summary = report_for("matches", "merged.profdata", &["matches_bin"]).summary();
+
+    assert_eq!(summary.files.len(), 1);
+    let total = summary.total;
+    assert_eq!((total.regions.total, total.regions.covered), (25, 25));
+    assert_eq!((total.functions.total, total.functions.covered), (6, 6));
+    assert_eq!((total.lines.total, total.lines.covered), (36, 36));
+    assert_eq!(total.branches.total, 0);
+    assert_eq!(total.branches.percent(), None);
+
+    let report = report_for("branches", "branches.profraw", &["branches_bin"]);
+    let summary = report.summary();
+
+    let total = summary.total;
+    assert_eq!((total.regions.total, total.regions.covered), (11, 11));
+    assert_eq!((total.functions.total, total.functions.covered), (2, 2));
+    assert_eq!((total.lines.total, total.lines.covered), (10, 10));
+    assert_eq!((total.branches.total, total.branches.covered), (2, 2));
+
+    let check = report
+        .functions
+        .iter()
+        .find(|x| x.name.contains("check"))
+        .unwrap();
+    assert_eq!(check.execution_count, 8);
+    assert_eq!(check.counted_branch_regions.len(), 1);
+}
+
+#[test]
+fn file_line_coverage() {
+    // Expected values are the lines with a count in `llvm-cov show`
+    let report = report_for("from_days", "from_days.profdata", &["from_days_bin"]);
+    let summary = report.summary();
+
+    let path = report.files.keys().next().unwrap();
+    assert_eq!(report.file_line_coverage(path), Some((13, 14)));
+    let lines = summary.files[path].lines;
+    assert_eq!(
+        report.file_line_coverage(path),
+        Some((lines.covered, lines.total))
+    );
+    assert_eq!(
+        report.file_line_coverage(Path::new("/not/in/report.rs")),
+        None
+    );
+}
+
+#[test]
+fn line_counts() {
+    // Expected counts are from `llvm-cov show`
+    let report = report_for("from_days", "from_days.profdata", &["from_days_bin"]);
+
+    let path = report.files.keys().next().unwrap();
+    let counts = report.line_counts(path).collect::<Vec<_>>();
+    let expected = vec![
+        None,
+        None,
+        Some(2),
+        Some(2),
+        Some(0),
+        Some(2),
+        Some(2),
+        None,
+        Some(2),
+        None,
+        Some(1),
+        Some(1),
+        Some(1),
+        Some(1),
+        None,
+        Some(1),
+        Some(1),
+        Some(1),
+        Some(1),
+    ];
+    assert_eq!(
+        counts,
+        expected
+            .into_iter()
+            .enumerate()
+            .map(|(line, count)| (line + 1, count))
+            .collect::<Vec<_>>()
+    );
+    let line_hits = report.files[path].line_hits();
+    assert!(counts
+        .iter()
+        .all(|(line, count)| line_hits.get(line) == count.as_ref()));
+
+    assert_eq!(
+        report.line_counts(Path::new("/not/in/report.rs")).count(),
+        0
+    );
+}
+
+#[test]
+fn lcov_export() {
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("branches.profraw")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    let source = recorded_compilation_dir(&mapping).join("main.rs");
+    // Expected output is from `llvm-cov export -format=lcov`
+    let expected = "FN:9,_RNvCs3UIYSLOGnTh_4main4main
+FN:1,_RNvCs3UIYSLOGnTh_4main5check
+FNDA:1,_RNvCs3UIYSLOGnTh_4main4main
+FNDA:8,_RNvCs3UIYSLOGnTh_4main5check
+FNF:2
+FNH:2
+DA:1,8
+DA:2,8
+DA:3,2
+DA:5,6
+DA:7,8
+DA:9,1
+DA:10,8
+DA:11,8
+DA:12,8
+DA:13,1
+BRDA:2,0,0,2
+BRDA:2,0,1,6
+BRF:2
+BRH:2
+LF:10
+LH:10
+end_of_record
+";
+    let expected = format!("SF:{}\n{}", source.display(), expected);
+    let report = mapping.generate_report();
+
+    let mut output = vec![];
+    report.to_lcov(&mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[test]
+fn json_export() {
+    let report = report_for("branches", "branches.profraw", &["branches_bin"]);
+
+    let mut output = vec![];
+    report.to_json(&mut output).unwrap();
+    let export: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(export["version"], "2.0.1");
+    assert_eq!(export["type"], "llvm.coverage.json.export");
+
+    // Expected segments and branches are from `llvm-cov export`
+    let file = &export["data"][0]["files"][0];
+    let expected_segments = serde_json::json!([
+        [1, 1, 8, true, true, false],
+        [1, 33, 0, false, false, false],
+        [2, 8, 8, true, true, false],
+        [2, 13, 0, false, false, false],
+        [3, 9, 2, true, true, false],
+        [3, 14, 0, false, false, false],
+        [5, 9, 6, true, true, false],
+        [5, 16, 0, false, false, false],
+        [7, 1, 8, true, true, false],
+        [7, 2, 0, false, false, false],
+        [9, 1, 1, true, true, false],
+        [9, 10, 0, false, false, false],
+        [10, 9, 8, true, true, false],
+        [10, 10, 0, false, false, false],
+        [10, 14, 1, true, true, false],
+        [10, 18, 0, false, false, false],
+        [10, 19, 8, true, true, false],
+        [11, 9, 8, true, true, false],
+        [11, 17, 8, true, false, false],
+        [12, 6, 0, false, false, false],
+        [13, 1, 1, true, true, false],
+        [13, 2, 0, false, false, false]
+    ]);
+    assert_eq!(file["segments"], expected_segments);
+    assert_eq!(
+        file["branches"],
+        serde_json::json!([[2, 8, 2, 13, 2, 6, 0, 0, 4]])
+    );
+
+    let functions = export["data"][0]["functions"].as_array().unwrap();
+    assert_eq!(functions.len(), 2);
+    let totals = &export["data"][0]["totals"];
+    assert_eq!(totals["functions"]["covered"], 2);
+    assert_eq!(totals["regions"]["count"], 11);
+    assert_eq!(totals["regions"]["notcovered"], 0);
+    assert_eq!(totals["branches"]["count"], 2);
+    assert_eq!(totals["lines"]["count"], 10);
+}
+
+#[test]
+fn file_segments() {
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("branches.profraw")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    let recorded = recorded_project_dir("branches");
+    assert_eq!(recorded_compilation_dir(&mapping), recorded);
+
+    let segments = mapping.segments_for_file(&recorded.join("main.rs"));
+    assert_eq!(segments.len(), 22);
+    // `check` is entered with a count of 8 and the branch taken twice
+    assert_eq!(
+        (segments[0].line, segments[0].col, segments[0].count),
+        (1, 1, 8)
+    );
+    assert_eq!(
+        (segments[4].line, segments[4].col, segments[4].count),
+        (3, 9, 2)
+    );
+    assert!(segments.iter().all(|x| !x.is_gap_region));
+
+    assert!(mapping
+        .segments_for_file(&recorded.join("missing.rs"))
+        .is_empty());
+}
+
+#[test]
+fn colored_show() {
+    let dir = get_project_dir("from_days");
+    let show = |args: &[&str]| {
+        let output = assert_cmd::Command::cargo_bin("cov")
+            .unwrap()
+            .current_dir(&dir)
+            .args([
+                "show",
+                "--instr-profile",
+                "from_days.profdata",
+                "--object",
+                "from_days_bin",
+                "--path-equivalence",
+                &source_remapping("from_days"),
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        get_printout(&output.stdout)
+    };
+
+    let colored = show(&["--use-color"]);
+    assert_eq!(colored, show(&["--use-color=always"]));
+    assert_eq!(
+        colored[2],
+        "    3|\x1b[36m      2\x1b[0m|fn from_days(days: Option<u64>) -> Duration {"
+    );
+    assert!(colored[4].starts_with("    5|\x1b[31m      0|"));
+    assert!(colored[4].ends_with("\x1b[0m"));
+    assert!(colored[7].starts_with("\x1b[2m    8|       |"));
+
+    // Output isn't coloured when it's not written to a terminal
+    let plain = show(&[]);
+    assert_eq!(plain, show(&["--use-color=auto"]));
+    assert_eq!(plain, show(&["--use-color=never"]));
+    assert!(plain.iter().all(|x| !x.contains('\x1b')));
+    assert_eq!(plain[4].len() + 9, colored[4].len());
+}
+
+#[test]
+fn profile_from_stdin() {
+    let dir = get_project_dir("from_days");
+    let show = |profile: &str, stdin: Vec<u8>| {
+        let output = assert_cmd::Command::cargo_bin("cov")
+            .unwrap()
+            .current_dir(&dir)
+            .args([
+                "show",
+                "--instr-profile",
+                profile,
+                "--object",
+                "from_days_bin",
+                "--path-equivalence",
+                &source_remapping("from_days"),
+            ])
+            .write_stdin(stdin)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let contents = std::fs::read(dir.join("from_days.profdata")).unwrap();
+    let shown = show("from_days.profdata", vec![]);
+    assert!(!shown.is_empty());
+    assert_eq!(show("-", contents), shown);
+}
+
+#[test]
+fn mapping_from_object_bytes() {
+    let dir = get_project_dir("shared_lib");
+    let instr = parse(dir.join("shared.profdata")).unwrap();
+    let objects = [dir.join("bin_a"), dir.join("bin_b")];
+    let contents = objects
+        .iter()
+        .map(|x| fs::read(x).unwrap())
+        .collect::<Vec<_>>();
+    let in_memory = objects
+        .iter()
+        .cloned()
+        .zip(contents.iter().map(|x| x.as_slice()))
+        .collect::<Vec<_>>();
+
+    let version = instr.version().unwrap();
+    assert_eq!(
+        read_object_bytes(&contents[0], &objects[0], version).unwrap(),
+        read_object_file(&objects[0], version).unwrap()
+    );
+
+    let expected = CoverageMapping::new(&objects, &instr, false).unwrap();
+    let mapping = CoverageMapping::from_bytes(&in_memory, &instr, false, None).unwrap();
+    assert_eq!(mapping.mapping_info, expected.mapping_info);
+    let expected_info = expected.mapping_info.clone();
+    let report = mapping.generate_report();
+    let expected = expected.generate_report();
+    assert_eq!(report.summary(), expected.summary());
+    assert_eq!(report.functions, expected.functions);
+
+    let invalid = [(dir.join("lib.rs"), b"not an object".as_slice())];
+    assert!(CoverageMapping::from_bytes(&invalid, &instr, false, None).is_err());
+    let mut with_invalid = invalid.to_vec();
+    with_invalid.extend_from_slice(&in_memory);
+    let mapping = CoverageMapping::from_bytes(&with_invalid, &instr, true, None).unwrap();
+    assert_eq!(mapping.mapping_info, expected_info);
+}
+
+#[test]
+fn object_sections() {
+    let binary = get_project_dir("from_days").join("from_days_bin");
+    let sections = sections_report(&binary).unwrap();
+    assert_eq!(
+        sections,
+        SectionAvailability {
+            coverage_map: true,
+            coverage_functions: true,
+            profile_names: true,
+            profile_counts: true,
+            profile_data: true,
+        }
+    );
+    assert!(sections.has_coverage_mapping());
+    assert!(sections.missing().is_empty());
+
+    // Built with memory profiling instead of coverage instrumentation
+    let data = fs::read(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data/profdata/llvm-16/pic.memprofexe"),
+    )
+    .unwrap();
+    let sections = sections_report_bytes(&data).unwrap();
+    assert!(!sections.has_coverage_mapping());
+    assert_eq!(sections.missing(), LlvmSection::ALL.to_vec());
+
+    assert!(sections_report_bytes(b"not an object").is_err());
+}
+
+#[test]
+fn coff_sections() {
+    // The llvm sections of `from_days_bin` copied into a COFF object with the grouped section
+    // names used on Windows, `objcopy -O pe-x86-64 --rename-section __llvm_covmap=.lcovmap$M ...`
+    let dir = get_project_dir("from_days");
+    let coff = dir.join("from_days_coff.obj");
+    let sections = sections_report(&coff).unwrap();
+    assert!(sections.missing().is_empty());
+
+    let instr = parse(dir.join("from_days.profdata")).unwrap();
+    let version = instr.version().unwrap();
+    let elf_info = read_object_file(&dir.join("from_days_bin"), version).unwrap();
+    let coff_info = read_object_file(&coff, version).unwrap();
+    assert_eq!(coff_info.cov_map, elf_info.cov_map);
+    assert_eq!(coff_info.cov_fun, elf_info.cov_fun);
+    assert_eq!(coff_info.prof_names, elf_info.prof_names);
+
+    let expected = CoverageMapping::new(&[dir.join("from_days_bin")], &instr, false)
+        .unwrap()
+        .generate_report();
+    let report = CoverageMapping::new(&[coff], &instr, false)
+        .unwrap()
+        .generate_report();
+    assert_eq!(report.summary(), expected.summary());
+    assert_eq!(report.functions, expected.functions);
+}
+
+#[test]
+fn counted_regions() {
+    let dir = get_project_dir("branches");
+    let instr = parse(dir.join("branches.profraw")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    let report = mapping.generate_report();
+    let regions = mapping.counted_regions();
+
+    let expected = report
+        .functions
+        .iter()
+        .map(|x| x.counted_regions.len() + x.counted_branch_regions.len())
+        .sum::<usize>();
+    assert_eq!(regions.len(), expected);
+    assert!(regions.iter().all(|(path, _)| path.ends_with("main.rs")));
+
+    let branches = regions
+        .iter()
+        .filter(|(_, x)| x.region.kind == RegionKind::Branch)
+        .collect::<Vec<_>>();
+    assert_eq!(branches.len(), 1);
+    let (path, branch) = branches[0];
+    let expected = &report.files[path].branches[&branch.region.loc];
+    assert_eq!(branch.execution_count, expected.true_count);
+    assert_eq!(branch.false_execution_count, expected.false_count);
+    assert!(branch.execution_count > 0 && branch.false_execution_count > 0);
+
+    for (path, region) in regions
+        .iter()
+        .filter(|(_, x)| x.region.kind == RegionKind::Code)
+    {
+        assert!(report.files[path].hits.contains_key(&region.region.loc));
+    }
+}
+
+#[test]
+fn binary_id_mismatches() {
+    let dir = get_project_dir("branches");
+    let other = get_project_dir("from_days").join("from_days_bin");
+    let instr = parse(dir.join("branches.profraw")).unwrap();
+    assert!(!instr.binary_ids().is_empty());
+
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    assert!(mapping.binary_id_mismatches().is_empty());
+    assert_eq!(
+        mapping.mapping_info[0].binary_id.as_ref(),
+        instr.binary_ids().first()
+    );
+
+    // The binary of another project isn't the one the profile came from
+    let mapping = CoverageMapping::new(std::slice::from_ref(&other), &instr, false).unwrap();
+    let mismatches = mapping.binary_id_mismatches();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(
+        Some(mismatches[0]),
+        mapping.mapping_info[0].binary_id.as_deref()
+    );
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .arg("report")
+        .arg("--object")
+        .arg(&other)
+        .arg("--instr-profile")
+        .arg(dir.join("branches.profraw"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("warning: 1 objects have build ids that aren't in the profile"));
+
+    // Profiles without binary ids can't be checked
+    let instr = parse(get_project_dir("matches").join("merged.profdata")).unwrap();
+    assert!(instr.binary_ids().is_empty());
+    let mapping = CoverageMapping::new(&[dir.join("branches_bin")], &instr, false).unwrap();
+    assert!(mapping.binary_id_mismatches().is_empty());
+}
+
+#[test]
+fn show_missing_sources() {
+    let dir = get_project_dir("from_days");
+    let show = |args: &[&str]| {
+        let output = assert_cmd::Command::cargo_bin("cov")
+            .unwrap()
+            .current_dir(&dir)
+            .args([
+                "show",
+                "--instr-profile",
+                "from_days.profdata",
+                "--object",
+                "from_days_bin",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        get_printout(&output.stdout)
+    };
+
+    let instr = parse(dir.join("from_days.profdata")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("from_days_bin")], &instr, false).unwrap();
+    let recorded = recorded_compilation_dir(&mapping);
+
+    // Remap the source somewhere that doesn't exist
+    let remapping = format!("{},/llvm_profparser/missing", recorded.display());
+    let remap = ["--path-equivalence", remapping.as_str()];
+    assert!(show(&remap).is_empty());
+
+    let missing = show(&[&remap[..], &["--show-missing-sources"]].concat());
+    assert!(missing[0].starts_with("/llvm_profparser/missing/main.rs: "));
+    let found = format!("{},{}", recorded.display(), dir.display());
+    let counted = show(&["--path-equivalence", found.as_str()])
+        .into_iter()
+        .filter(|x| !x.is_empty() && !x[6..].starts_with("       |"))
+        .map(|x| x[..14].to_string())
+        .collect::<Vec<_>>();
+    assert!(!counted.is_empty());
+    assert_eq!(
+        missing[1..]
+            .iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>(),
+        counted.iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn profile_dir() {
+    let dir = get_project_dir("from_days");
+    let report = |profile: &str| {
+        assert_cmd::Command::cargo_bin("cov")
+            .unwrap()
+            .current_dir(&dir)
+            .args([
+                "report",
+                "--instr-profile",
+                profile,
+                "--object",
+                "from_days_bin",
+            ])
+            .output()
+            .unwrap()
+    };
+
+    // The directory only has one profile, the binary and sources are skipped
+    let from_dir = report(".");
+    assert!(from_dir.status.success());
+    let stderr = String::from_utf8_lossy(&from_dir.stderr);
+    assert!(stderr.contains("skipping ./main.rs as it isn't a profile"));
+    assert!(!stderr.contains("from_days.profdata"));
+    assert_eq!(from_dir.stdout, report("from_days.profdata").stdout);
+}
+
+#[test]
+fn empty_profile_dir() {
+    let dir = std::env::temp_dir().join("llvm_profparser_empty_profile_dir");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let object = get_project_dir("from_days").join("from_days_bin");
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .args(["report", "--instr-profile"])
+        .arg(&dir)
+        .arg("--object")
+        .arg(&object)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("no profiles found in {}", dir.display())));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn compilation_dir() {
+    let dir = get_project_dir("matches");
+    let instr = parse(dir.join("merged.profdata")).unwrap();
+    let objects = [dir.join("matches_bin")];
+
+    // The mapping has `src/lib.rs` relative to the directory it was compiled in
+    let report = CoverageMapping::new(&objects, &instr, false)
+        .unwrap()
+        .generate_report();
+    let files = report.files.keys().cloned().collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        vec![PathBuf::from(
+            "/home/daniel/personal/tarpaulin/tests/data/matches/src/lib.rs"
+        )]
+    );
+
+    let moved = std::env::temp_dir().join("llvm_profparser_compilation_dir");
+    let report = CoverageMapping::with_compilation_dir(&objects, &instr, false, &moved)
+        .unwrap()
+        .generate_report();
+    let files = report.files.keys().cloned().collect::<Vec<_>>();
+    assert_eq!(files, vec![moved.join("src/lib.rs")]);
+    let data = fs::read(&objects[0]).unwrap();
+    let in_memory = [(objects[0].clone(), data.as_slice())];
+    let from_bytes = CoverageMapping::from_bytes(&in_memory, &instr, false, Some(&moved))
+        .unwrap()
+        .generate_report();
+    assert_eq!(
+        from_bytes.files.keys().collect::<Vec<_>>(),
+        report.files.keys().collect::<Vec<_>>()
+    );
+    assert_eq!(from_bytes.summary(), report.summary());
+
+    // Recreate the source from the expected output so it can be shown from the new directory
+    let expected = fs::read_to_string(dir.join("matches.stdout")).unwrap();
+    let source = expected
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("{}\n", &x[14..]))
+        .collect::<String>();
+    fs::create_dir_all(moved.join("src")).unwrap();
+    fs::write(moved.join("src/lib.rs"), source).unwrap();
+
+    let output = assert_cmd::Command::cargo_bin("cov")
+        .unwrap()
+        .arg("show")
+        .arg("--instr-profile")
+        .arg(dir.join("merged.profdata"))
+        .arg("--object")
+        .arg(dir.join("matches_bin"))
+        .arg("--compilation-dir")
+        .arg(&moved)
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&moved);
+    assert!(output.status.success());
+    let shown = get_printout(&output.stdout);
+    let result = &report.files[&moved.join("src/lib.rs")];
+    let expected = expected.lines().collect::<Vec<_>>();
+    assert_eq!(shown.len(), expected.len());
+    for (line, (shown, expected)) in shown.iter().zip(&expected).enumerate() {
+        if expected.is_empty() {
+            assert!(shown.is_empty());
+            continue;
+        }
+        // Only the source is compared, the counts in matches.stdout are from an older llvm-cov
+        assert_eq!(shown[14..], expected[14..]);
+        let count = result
+            .hits_for_line(line + 1)
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        assert_eq!(shown[6..13].trim(), count);
+    }
+}
+
+#[test]
+fn referenced_files() {
+    let dir = get_project_dir("shared_lib");
+    let instr = parse(dir.join("shared.profdata")).unwrap();
+    let objects = [dir.join("bin_a"), dir.join("bin_b")];
+    let mapping = CoverageMapping::new(&objects, &instr, false).unwrap();
+
+    let recorded = recorded_compilation_dir(&mapping);
+
+    let files = mapping.referenced_files();
+    let mut sorted = files.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(files, sorted);
+    for name in ["bin_a.rs", "bin_b.rs", "lib.rs"] {
+        assert!(files.contains(&recorded.join(name)), "{}", name);
+    }
+    // The compilation directory at the start of the filename lists isn't a source file
+    assert!(!mapping.mapping_info[0].compilation_dirs.is_empty());
+    assert!(mapping.mapping_info[0]
+        .compilation_dirs
+        .values()
+        .all(|x| !files.contains(x)));
+    assert!(!files.contains(&recorded));
+    // Every file in the report is referenced
+    let report = mapping.generate_report();
+    assert!(report.files.keys().all(|x| files.contains(x)));
+
+    let bin_a = mapping.mapping_info[0].all_files();
+    assert!(bin_a.contains(&recorded.join("bin_a.rs")));
+    assert!(!bin_a.contains(&recorded.join("bin_b.rs")));
+    assert!(bin_a.iter().all(|x| files.contains(x)));
+}
+
+#[test]
+fn html_report() {
+    let dir = get_project_dir("from_days");
+    let write_html = |output_dir: &Path, args: &[&str]| {
+        let _ = fs::remove_dir_all(output_dir);
+        let output = assert_cmd::Command::cargo_bin("cov")
+            .unwrap()
+            .current_dir(&dir)
+            .args([
+                "show",
+                "--instr-profile",
+                "from_days.profdata",
+                "--object",
+                "from_days_bin",
+                "--format=html",
+                "--output-dir",
+            ])
+            .arg(output_dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    };
+
+    let instr = parse(dir.join("from_days.profdata")).unwrap();
+    let mapping = CoverageMapping::new(&[dir.join("from_days_bin")], &instr, false).unwrap();
+    let recorded = recorded_compilation_dir(&mapping);
+
+    let output_dir = std::env::temp_dir().join(format!(
+        "llvm_profparser_html_report_{}",
+        std::process::id()
+    ));
+    let found = format!("{},{}", recorded.display(), dir.display());
+    write_html(&output_dir, &["--path-equivalence", &found]);
+    let source = dir.join("main.rs");
+    let page = html::file_page_path(&source);
+    let index = fs::read_to_string(output_dir.join("index.html")).unwrap();
+    // Percentages match `llvm-cov report`
+    assert!(index.contains(&format!(
+        "<a href=\"{}\">{}</a>",
+        page.display(),
+        source.display()
+    )));
+    assert!(index.contains("<td class=\"low\">75.00% (3/4)</td>"));
+    assert!(index.contains("<td class=\"medium\">87.50% (21/24)</td>"));
+
+    let html = fs::read_to_string(output_dir.join(&page)).unwrap();
+    assert!(html.contains("<tr class=\"covered\"><td class=\"line-number\">3</td><td class=\"count\">2</td><td><pre>fn from_days(days: Option&lt;u64&gt;) -&gt; Duration {</pre></td></tr>"));
+    assert!(html.contains("<tr class=\"uncovered\"><td class=\"line-number\">5</td><td class=\"count\">0</td><td><pre>        panic!(&quot;no days&quot;) });</pre></td></tr>"));
+    assert_eq!(html.matches("<td class=\"line-number\">").count(), 19);
+
+    // Files without source still have their line counts
+    let remapping = format!("{},/llvm_profparser/missing", recorded.display());
+    write_html(&output_dir, &["--path-equivalence", &remapping]);
+    let page = html::file_page_path(Path::new("/llvm_profparser/missing/main.rs"));
+    let html = fs::read_to_string(output_dir.join(page)).unwrap();
+    assert!(html.contains("The source file couldn't be read"));
+    assert!(html.contains("<tr class=\"uncovered\"><td class=\"line-number\">5</td><td class=\"count\">0</td><td><pre></pre></td></tr>"));
+    assert_eq!(html.matches("<td class=\"line-number\">").count(), 19);
+    let _ = fs::remove_dir_all(output_dir);
+}
@@ -0,0 +1,13 @@
+fn check(x: u32) -> &'static str {
+    if x > 5 {
+        "big"
+    } else {
+        "small"
+    }
+}
+
+fn main() {
+    for i in 0..8 {
+        println!("{}", check(i));
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+fn from_days(days: Option<u64>) -> Duration {
+    let days = days.unwrap_or_else(|| {
+        panic!("no days") });
+    Duration::from_secs(
+        days * 24 * 60 * 60,
+    )
+}
+
+fn from_weeks(weeks: u64) -> Duration {
+    let days = if weeks > 100 { Some(0) } else {
+        Some(weeks * 7) }; from_days(days)
+}
+
+fn main() {
+    println!("{:?}", from_days(Some(2)));
+    println!("{:?}", from_weeks(2));
+}
@@ -0,0 +1,21 @@
+#include <stdio.h>
+
+static int check(int x) {
+    if (x % 3 == 0) {
+        return 1;
+    }
+    return 0;
+}
+
+int never(int x) {
+    return x * 2;
+}
+
+int main(void) {
+    int total = 0;
+    for (int i = 0; i < 10; i++) {
+        total += check(i);
+    }
+    printf("%d\n", total);
+    return 0;
+}
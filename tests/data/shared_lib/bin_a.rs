@@ -0,0 +1,5 @@
+fn main() {
+    for i in 0..4 {
+        println!("{}", lib::is_even(i));
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", lib::is_even(1));
+}
@@ -0,0 +1,7 @@
+pub fn is_even(x: u32) -> bool {
+    if x % 2 == 0 {
+        true
+    } else {
+        false
+    }
+}
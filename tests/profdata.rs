@@ -1,8 +1,31 @@
-use llvm_profparser::{merge_profiles, parse, parse_bytes};
+use llvm_profparser::instrumentation_profile::gcc_profile::GccProfReader;
+use llvm_profparser::instrumentation_profile::indexed_profile::IndexedInstrProf;
+use llvm_profparser::instrumentation_profile::memprof::Frame;
+use llvm_profparser::instrumentation_profile::overlap::*;
+use llvm_profparser::instrumentation_profile::raw_profile::{
+    RawInstrProf32, RawInstrProf64, RawProfileError,
+};
+use llvm_profparser::instrumentation_profile::show::{ShowFilters, ShowReport};
+use llvm_profparser::instrumentation_profile::types::{
+    compute_hash, demangle, HeaderFormat, InstrProfRecord, InstrProfValueData,
+    InstrumentationProfile, InstrumentationProfileBuilder, LlvmVersion, MergeError,
+    NamedInstrProfRecord, ProfileFlags, ProfileVariant, ValidationError, ValueProfDataRecord,
+};
+use llvm_profparser::instrumentation_profile::writer_for;
+use llvm_profparser::instrumentation_profile::InstrProfReader;
+use llvm_profparser::{
+    expand_profile_dirs, merge_in_memory, merge_profiles, merge_profiles_counts_only,
+    merge_profiles_sparse, merge_profiles_weighted, merge_profiles_with_stats, parse, parse_bytes,
+    parse_mmap, parse_reader, sample_profile, try_parse_bytes, ProfileFormat, ProfileParseError,
+};
+use nom::number::Endianness;
+use regex::Regex;
 use serde::Deserialize;
+use serde_json::json;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::ffi::OsStr;
-use std::fs::read_dir;
+use std::fs::{self, read_dir};
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -85,7 +108,7 @@ fn check_merge_command(files: &[PathBuf], id: &str) {
         .map(|x| x.display().to_string())
         .collect::<Vec<String>>();
     let llvm = Command::new("cargo")
-        .args(&["profdata", "--", "merge"])
+        .args(["profdata", "--", "merge"])
         .args(&names)
         .arg("-o")
         .arg(&llvm_output)
@@ -96,20 +119,8 @@ fn check_merge_command(files: &[PathBuf], id: &str) {
         let llvm_merged = parse(&llvm_output).unwrap();
         let rust_merged = merge_profiles(&names).unwrap();
 
-        // Okay so we don't care about versioning. We don't care about symtab as there might be
-        // hash collisions. And we don't care about the record ordering.
-        assert_eq!(
-            llvm_merged.is_ir_level_profile(),
-            rust_merged.is_ir_level_profile()
-        );
-        assert_eq!(
-            llvm_merged.has_csir_level_profile(),
-            rust_merged.has_csir_level_profile()
-        );
-        let llvm_records = llvm_merged.records().iter().collect::<HashSet<_>>();
-        let rust_records = rust_merged.records().iter().collect::<HashSet<_>>();
-        assert!(!llvm_records.is_empty());
-        std::assert_eq!(llvm_records, rust_records);
+        assert!(!llvm_merged.records().is_empty());
+        assert!(llvm_merged.semantically_eq(&rust_merged));
     } else {
         println!("Unsupported LLVM version");
     }
@@ -131,7 +142,7 @@ fn check_command(ext: &OsStr) {
         // llvm comes with by default. So first we check if it works and if so we test
         let llvm = Command::new("cargo")
             .current_dir(&data)
-            .args(&["profdata", "--", "show", "--all-functions", "--counts"])
+            .args(["profdata", "--", "show", "--all-functions", "--counts"])
             .arg(raw_file.file_name())
             .output()
             .expect("cargo binutils or llvm-profdata is not installed");
@@ -144,7 +155,7 @@ fn check_command(ext: &OsStr) {
             let rust = assert_cmd::Command::cargo_bin("profparser")
                 .unwrap()
                 .current_dir(&data)
-                .args(&["show", "--all-functions", "--counts", "-i"])
+                .args(["show", "--all-functions", "--counts", "-i"])
                 .arg(raw_file.file_name())
                 .output()
                 .expect("Failed to run profparser on file");
@@ -176,7 +187,7 @@ fn check_against_text(ext: &OsStr) {
         println!("{:?}", raw_file.file_name());
         let llvm = Command::new("cargo")
             .current_dir(&data)
-            .args(&[
+            .args([
                 "profdata",
                 "--",
                 "show",
@@ -198,20 +209,7 @@ fn check_against_text(ext: &OsStr) {
             let text_prof = parse_bytes(&llvm.stdout).unwrap();
             let parsed_prof = parse(data.join(raw_file.file_name())).unwrap();
 
-            // Okay so we don't care about versioning. We don't care about symtab as there might be
-            // hash collisions. And we don't care about the record ordering.
-
-            assert_eq!(
-                text_prof.is_ir_level_profile(),
-                parsed_prof.is_ir_level_profile()
-            );
-            assert_eq!(
-                text_prof.has_csir_level_profile(),
-                parsed_prof.has_csir_level_profile()
-            );
-            let text_records = text_prof.records().iter().collect::<HashSet<_>>();
-            let parse_records = parsed_prof.records().iter().collect::<HashSet<_>>();
-            assert_eq!(text_records, parse_records);
+            assert!(text_prof.semantically_eq(&parsed_prof));
         } else {
             println!("{} failed", raw_file.path().display());
         }
@@ -297,6 +295,69 @@ fn multi_app_profraw_merging() {
     }
 }
 
+#[test]
+fn merge_profile_dirs() {
+    let misc = data_root_dir().join("misc");
+    let dir = misc.join("multibin_merge");
+    let files = [
+        dir.join("bin_1.profraw"),
+        dir.join("bin_2.1.profraw"),
+        dir.join("bin_2.2.profraw"),
+        dir.join("bin_2.3.profraw"),
+    ];
+    let (profiles, skipped) = expand_profile_dirs(&[&dir]).unwrap();
+    assert_eq!(profiles, files);
+    assert!(skipped.is_empty());
+
+    // Directories are searched recursively and other paths are kept as they are
+    let (profiles, skipped) = expand_profile_dirs(&[misc.clone(), PathBuf::from("-")]).unwrap();
+    assert!(profiles.contains(&misc.join("stable.profraw")));
+    assert!(profiles.contains(&misc.join("value_prof.profdata")));
+    assert!(profiles.contains(&files[2]));
+    assert_eq!(profiles.last(), Some(&PathBuf::from("-")));
+    assert!(skipped.contains(&misc.join("value_prof.proftext")));
+
+    let expected = merge_profiles(&files).unwrap();
+    let expected_records = expected.records().iter().collect::<HashSet<_>>();
+    let output = std::env::temp_dir().join("profparser_merge_dir.proftext");
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["merge", "--text", "-o"])
+        .arg(&output)
+        .arg("-i")
+        .arg(&dir)
+        .assert()
+        .success();
+    let merged = parse(&output).unwrap();
+    assert_eq!(
+        merged.records().iter().collect::<HashSet<_>>(),
+        expected_records
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn profile_dir_symlink_loop() {
+    let profile = data_root_dir().join("misc").join("stable.profraw");
+    let dir = std::env::temp_dir().join("llvm_profparser_symlink_loop");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    fs::copy(&profile, dir.join("nested").join("stable.profraw")).unwrap();
+    // A link back to the parent directory would recurse forever if followed
+    std::os::unix::fs::symlink(&dir, dir.join("nested").join("parent")).unwrap();
+    std::os::unix::fs::symlink(&profile, dir.join("linked.profraw")).unwrap();
+
+    let (profiles, skipped) = expand_profile_dirs(&[&dir]).unwrap();
+    assert_eq!(
+        profiles,
+        vec![
+            dir.join("linked.profraw"),
+            dir.join("nested").join("stable.profraw")
+        ]
+    );
+    assert!(skipped.is_empty());
+}
+
 #[test]
 fn profraw_merging() {
     let premerge_1 = data_root_dir().join("misc").join("premerge_1.profraw");
@@ -310,6 +371,25 @@ fn profraw_merging() {
     assert_eq!(merged.records(), expected_merged.records());
 }
 
+#[test]
+fn big_endian_profraw() {
+    // premerge_1.profraw with every integer byte swapped
+    let little = data_root_dir().join("misc").join("premerge_1.profraw");
+    let big = data_root_dir().join("misc").join("premerge_1_be.profraw");
+    let premerge_2 = data_root_dir().join("misc").join("premerge_2.profraw");
+
+    let little_profile = parse(&little).unwrap();
+    let big_profile = parse(&big).unwrap();
+    assert_eq!(big_profile.symtab, little_profile.symtab);
+    assert_eq!(big_profile.records(), little_profile.records());
+    assert!(big_profile.records().iter().all(|x| x.name.is_some()));
+
+    let expected = merge_profiles(&[&little, &premerge_2]).unwrap();
+    let merged = merge_profiles(&[&big, &premerge_2]).unwrap();
+    assert_eq!(merged.symtab, expected.symtab);
+    assert_eq!(merged.records(), expected.records());
+}
+
 #[test]
 fn check_raw_data_consistency() {
     let raw = data_root_dir().join("misc").join("stable.profraw");
@@ -328,3 +408,1695 @@ fn check_raw_data_consistency() {
         std::assert_eq!(data_record, raw_record);
     }
 }
+
+#[test]
+fn raw_value_profiling() {
+    // Generated with llvm-profdata merge --text from the profraw
+    let raw = data_root_dir().join("misc").join("value_prof.profraw");
+    let text = data_root_dir().join("misc").join("value_prof.proftext");
+
+    let raw = parse(raw).unwrap();
+    let text = parse(text).unwrap();
+
+    let mut value_records = 0;
+    assert_eq!(raw.records().len(), text.records().len());
+    for record in text.records() {
+        let name = record.name.as_ref().unwrap();
+        let raw_record = raw.get_record(name).unwrap();
+        assert_eq!(raw_record.counts(), record.counts());
+
+        match (&raw_record.record.data, &record.record.data) {
+            (Some(raw_data), Some(text_data)) => {
+                value_records += 1;
+                assert_eq!(raw_data.indirect_callsites, text_data.indirect_callsites);
+                assert_eq!(raw_data.mem_op_sizes, text_data.mem_op_sizes);
+            }
+            (None, None) => {}
+            (raw_data, text_data) => panic!(
+                "{} value data mismatch\nraw: {:?}\ntext: {:?}",
+                name, raw_data, text_data
+            ),
+        }
+    }
+    assert_eq!(value_records, 3);
+
+    // Generated with llvm-profdata merge from the profraw
+    let indexed = parse(data_root_dir().join("misc").join("value_prof.profdata")).unwrap();
+    assert_eq!(
+        indexed.records().iter().collect::<HashSet<_>>(),
+        text.records().iter().collect::<HashSet<_>>()
+    );
+    for record in indexed.records() {
+        let text_record = text.get_record(record.name.as_ref().unwrap()).unwrap();
+        match (&record.record.data, &text_record.record.data) {
+            (Some(data), Some(text_data)) => {
+                assert_eq!(data.indirect_callsites, text_data.indirect_callsites);
+                assert_eq!(data.mem_op_sizes, text_data.mem_op_sizes);
+                // Value data equality only compares the values so check the counts too
+                let sites = data.indirect_callsites.iter().chain(&data.mem_op_sizes);
+                let text_sites = text_data
+                    .indirect_callsites
+                    .iter()
+                    .chain(&text_data.mem_op_sizes);
+                for (site, text_site) in sites.zip(text_sites) {
+                    let counts = site.iter().map(|x| x.count).collect::<Vec<_>>();
+                    let text_counts = text_site.iter().map(|x| x.count).collect::<Vec<_>>();
+                    assert_eq!(counts, text_counts);
+                }
+            }
+            (None, None) => {}
+            (data, text_data) => panic!(
+                "{:?} value data mismatch\nindexed: {:?}\ntext: {:?}",
+                record.name, data, text_data
+            ),
+        }
+    }
+}
+
+#[test]
+fn raw_unknown_value_kinds() {
+    // value_prof.profraw rewritten with two extra value kinds in every data record and value data
+    // for the first unknown kind in one record
+    let misc = data_root_dir().join("misc");
+    let expected = parse(misc.join("value_prof.profraw")).unwrap();
+    let profile = parse(misc.join("value_prof_extra_kinds.profraw")).unwrap();
+    assert_eq!(profile.records(), expected.records());
+    assert_eq!(profile.symtab, expected.symtab);
+}
+
+#[test]
+fn llvm_version_hints() {
+    for major in 11..=19 {
+        let dir = data_root_dir().join(format!("llvm-{}", major));
+        for file in read_dir(&dir)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.path().extension().unwrap_or_default() == "profraw")
+        {
+            let profile = parse(file.path()).unwrap();
+            let hint = profile.llvm_version_hint().unwrap();
+            assert!(hint.contains(major), "{} {}", file.path().display(), hint);
+        }
+    }
+    let text = parse(data_root_dir().join("llvm-19").join("foo3-1.proftext")).unwrap();
+    assert_eq!(text.llvm_version_hint(), None);
+
+    let indexed = LlvmVersion::from_indexed_version(7).unwrap();
+    assert_eq!(indexed.to_string(), "LLVM 13");
+    assert!(!indexed.contains(14));
+    let latest = LlvmVersion::from_raw_version(10).unwrap();
+    assert_eq!(latest.to_string(), "LLVM 19+");
+    assert!(latest.contains(22));
+    assert_eq!(
+        LlvmVersion::from_raw_version(8).unwrap().to_string(),
+        "LLVM 14-17"
+    );
+}
+
+#[test]
+fn validate_profile() {
+    let data = data_root_dir();
+    for file in [
+        "llvm-19/foo3bar3-1.proftext",
+        "llvm-19/overlap_1.proftext",
+        "misc/value_prof.profraw",
+        "misc/value_prof.proftext",
+    ] {
+        let profile = parse(data.join(file)).unwrap();
+        assert_eq!(profile.validate(), Ok(()), "{}", file);
+    }
+
+    let mut profile = parse(data.join("llvm-19/foo3bar3-1.proftext")).unwrap();
+    profile.symtab.names.clear();
+    assert_eq!(
+        profile.validate(),
+        Err(vec![
+            ValidationError::MissingName {
+                name: "foo".to_string()
+            },
+            ValidationError::MissingName {
+                name: "bar".to_string()
+            }
+        ])
+    );
+
+    let record = |name: Option<&str>, name_hash, counts| NamedInstrProfRecord {
+        name: name.map(|x| x.to_string()),
+        name_hash: Some(name_hash),
+        hash: Some(1),
+        record: InstrProfRecord { counts, data: None },
+    };
+    let profile = InstrumentationProfileBuilder::new()
+        .with_ir(true)
+        .add_records([
+            record(Some("foo"), 1, vec![1]),
+            record(Some("bar"), 1, vec![]),
+            record(None, 2, vec![3]),
+        ])
+        .build();
+    assert_eq!(
+        profile.validate(),
+        Err(vec![
+            // bar replaces foo in the symbol table as they have the same hash
+            ValidationError::MissingName {
+                name: "foo".to_string()
+            },
+            ValidationError::HashCollision {
+                name_hash: 1,
+                name: "bar".to_string(),
+                other: "foo".to_string(),
+            },
+            ValidationError::NoCounters {
+                name: "bar".to_string()
+            },
+            ValidationError::UnresolvedNameHash { name_hash: 2 },
+        ])
+    );
+
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["validate", "-i"])
+        .arg(data.join("misc/value_prof.profraw"))
+        .assert()
+        .success();
+}
+
+#[test]
+fn overlap_proftexts() {
+    let base = data_root_dir().join("llvm-19").join("overlap_1.proftext");
+    let test = data_root_dir().join("llvm-19").join("overlap_2.proftext");
+
+    let base = parse(base).unwrap();
+    let test = parse(test).unwrap();
+
+    let filter = OverlapFuncFilters {
+        value_cutoff: Some(1),
+        name_filter: None,
+    };
+    let overlap = overlap_profiles(&base, &test, &filter, false);
+
+    // Expected values from llvm-profdata overlap --value-cutoff=1
+    assert_eq!(overlap.program.overlap.num_entries, 2);
+    assert_eq!(overlap.program.mismatch.num_entries, 1);
+    assert_eq!(overlap.program.unique.num_entries, 1);
+    assert_eq!(overlap.program.base.count_sum, 500000.0);
+    assert_eq!(overlap.program.test.count_sum, 50000.0);
+    assert!((overlap.program.overlap.count_sum - 0.6).abs() < f64::EPSILON);
+    assert!((overlap.program.mismatch.count_sum - 0.2).abs() < f64::EPSILON);
+    assert!((overlap.program.unique.count_sum - 0.2).abs() < f64::EPSILON);
+
+    let functions = overlap
+        .functions
+        .iter()
+        .map(|x| x.level.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        functions,
+        vec![
+            OverlapLevel::Function {
+                name: "bar".to_string(),
+                hash: 12884901887
+            },
+            OverlapLevel::Function {
+                name: "main".to_string(),
+                hash: 29212902728
+            }
+        ]
+    );
+    for func in &overlap.functions {
+        assert!((func.overlap.count_sum - 1.0).abs() < f64::EPSILON);
+    }
+}
+
+#[test]
+fn overlap_json_output() {
+    let data = data_root_dir().join("llvm-19");
+    let base = data.join("overlap_1.proftext");
+    let test = data.join("overlap_2.proftext");
+    let filter = OverlapFuncFilters {
+        value_cutoff: Some(1),
+        name_filter: None,
+    };
+    let overlap = overlap_profiles(
+        &parse(&base).unwrap(),
+        &parse(&test).unwrap(),
+        &filter,
+        false,
+    );
+    assert!((overlap.program_overlap() - 0.6).abs() < f64::EPSILON);
+
+    let output = std::env::temp_dir().join("profparser_overlap_output.json");
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["overlap", "--json", "--value-cutoff=1", "-o"])
+        .arg(&output)
+        .arg(&base)
+        .arg(&test)
+        .assert()
+        .success()
+        .stdout("");
+
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&output).unwrap()).unwrap();
+    let mut expected = vec![];
+    overlap.to_json(&mut expected).unwrap();
+    assert_eq!(
+        json,
+        serde_json::from_slice::<serde_json::Value>(&expected).unwrap()
+    );
+
+    let program = &json["program"];
+    assert_eq!(program["overlap"]["num_entries"], 2);
+    assert_eq!(program["mismatch"]["num_entries"], 1);
+    assert_eq!(program["unique"]["num_entries"], 1);
+    assert_eq!(program["base"]["count_sum"], 500000.0);
+    let names = json["functions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["bar", "main"]);
+    assert_eq!(json["functions"][0]["hash"], 12884901887u64);
+}
+
+#[test]
+fn merge_command_output() {
+    let data = data_root_dir().join("llvm-19");
+    let files = [
+        data.join("foo3-1.proftext"),
+        data.join("foo3bar3-1.proftext"),
+    ];
+    let expected = merge_profiles(&files).unwrap();
+    let expected_records = expected.records().iter().collect::<HashSet<_>>();
+
+    for (format, ext) in [
+        ("--binary", "profdata"),
+        ("--text", "proftext"),
+        ("--format=binary", "profdata"),
+        ("--format=text", "proftext"),
+    ] {
+        let output = std::env::temp_dir().join(format!("profparser_merge_output.{}", ext));
+        assert_cmd::Command::cargo_bin("profparser")
+            .unwrap()
+            .args(["merge", format, "-o"])
+            .arg(&output)
+            .arg("-i")
+            .args(&files)
+            .assert()
+            .success();
+
+        let merged = parse(&output).unwrap();
+        assert_eq!(merged.is_ir_level_profile(), expected.is_ir_level_profile());
+        let records = merged.records().iter().collect::<HashSet<_>>();
+        assert_eq!(records, expected_records);
+    }
+
+    for format in [ProfileFormat::Binary, ProfileFormat::Text] {
+        let mut output = vec![];
+        writer_for(format, false)
+            .unwrap()
+            .write(&expected, &mut output)
+            .unwrap();
+        let written = parse_bytes(&output).unwrap();
+        assert_eq!(
+            written.records().iter().collect::<HashSet<_>>(),
+            expected_records
+        );
+    }
+    let err = writer_for(ProfileFormat::Gcc, false).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn weighted_merge() {
+    let data = data_root_dir().join("llvm-19");
+    let foo = data.join("foo3-1.proftext");
+    let foobar = data.join("foo3bar3-1.proftext");
+
+    let weighted = merge_profiles_weighted(&[(3, &foo), (1, &foobar)]).unwrap();
+    let expected = merge_profiles(&[&foo, &foo, &foo, &foobar]).unwrap();
+    assert_eq!(
+        weighted.records().iter().collect::<HashSet<_>>(),
+        expected.records().iter().collect::<HashSet<_>>()
+    );
+
+    let saturated = merge_profiles_weighted(&[(u64::MAX, &foo)]).unwrap();
+    for record in saturated.records() {
+        assert!(record.counts().iter().all(|x| *x == 0 || *x == u64::MAX));
+    }
+}
+
+#[test]
+fn merge_stats() {
+    let data = data_root_dir().join("llvm-11");
+    let inputs = [
+        (1, data.join("counter-mismatch-1.proftext")),
+        (1, data.join("counter-mismatch-2.proftext")),
+    ];
+    let (merged, stats) = merge_profiles_with_stats(&inputs).unwrap();
+    assert_eq!(stats.count_mismatches, 1);
+    assert_eq!(stats.counter_overflows, 0);
+    assert!(!stats.is_clean());
+    // The mismatched record is left as it was
+    let base = parse(&inputs[0].1).unwrap();
+    assert_eq!(merged.records(), base.records());
+
+    let foo = data_root_dir().join("llvm-19").join("foo3-1.proftext");
+    let (_, stats) = merge_profiles_with_stats(&[(1, &foo), (1, &foo)]).unwrap();
+    assert!(stats.is_clean());
+    // foo's counts are 1, 2 and 3, all but the first overflow when weighted and then all of them
+    // overflow when merged
+    let (saturated, stats) = merge_profiles_with_stats(&[(u64::MAX, &foo), (1, &foo)]).unwrap();
+    assert_eq!(
+        saturated.get_record("foo").unwrap().counts(),
+        &[u64::MAX; 3]
+    );
+    assert_eq!(stats.counter_overflows, 5);
+    assert_eq!(stats.count_mismatches, 0);
+
+    let mut record = InstrProfRecord {
+        counts: vec![u64::MAX - 1, 2],
+        data: None,
+    };
+    let stats = record.merge(&InstrProfRecord {
+        counts: vec![2, 2],
+        data: None,
+    });
+    assert_eq!(stats.counter_overflows, 1);
+    assert_eq!(record.counts, vec![u64::MAX, 4]);
+    let stats = record.merge(&InstrProfRecord {
+        counts: vec![1],
+        data: None,
+    });
+    assert_eq!(stats.count_mismatches, 1);
+    assert_eq!(record.counts, vec![u64::MAX, 4]);
+}
+
+#[test]
+fn sparse_merge() {
+    let data = data_root_dir().join("llvm-19");
+    let files = [
+        data.join("mix_instr.proftext"),
+        data.join("mix_instr_small.proftext"),
+    ];
+    let merged = merge_profiles(&files).unwrap();
+    assert!(merged.get_record("goo").is_some());
+
+    let sparse = merge_profiles_sparse(&files).unwrap();
+    assert_eq!(sparse.records().len(), 2);
+    assert!(sparse.get_record("goo").is_none());
+    assert!(sparse.symtab.iter().all(|(_, name)| name != "goo"));
+    for name in ["foo", "moo"] {
+        assert_eq!(sparse.get_record(name), merged.get_record(name));
+    }
+}
+
+#[test]
+fn in_memory_merge() {
+    let data = data_root_dir().join("llvm-19");
+    let files = [
+        data.join("mix_instr.proftext"),
+        data.join("mix_instr_small.proftext"),
+    ];
+    let profiles = files.iter().map(|x| parse(x).unwrap()).collect::<Vec<_>>();
+    let merged = merge_in_memory(profiles.clone()).unwrap();
+    assert_eq!(merged, merge_profiles(&files).unwrap());
+    let inputs = files.iter().map(|x| (1, x)).collect::<Vec<_>>();
+    let (expected, expected_stats) = merge_profiles_with_stats(&inputs).unwrap();
+    let (merged, stats) = InstrumentationProfile::merged(profiles).unwrap();
+    assert_eq!(merged, expected);
+    assert_eq!(stats, expected_stats);
+
+    assert_eq!(
+        merge_in_memory(vec![]).unwrap(),
+        InstrumentationProfile::default()
+    );
+    let ir = parse(data.join("ir-basic.proftext")).unwrap();
+    let fe = parse(data.join("fe-basic.proftext")).unwrap();
+    assert_eq!(
+        merge_in_memory(vec![ir, fe]),
+        Err(MergeError::InstrumentationLevel)
+    );
+}
+
+#[test]
+fn binary_ids() {
+    // Build id of the binary from `readelf -n` and `llvm-profdata show --binary-ids`
+    let expected = vec![vec![
+        0xac, 0xcd, 0xf1, 0x7f, 0xfd, 0x4e, 0xb4, 0x09, 0xa7, 0x4b, 0xce, 0x5c, 0x28, 0xa3, 0x15,
+        0x9f, 0x2b, 0xd0, 0xc0, 0xd3,
+    ]];
+    let data = data_root_dir().parent().unwrap().join("branches");
+
+    let raw = parse(data.join("branches.profraw")).unwrap();
+    assert_eq!(raw.binary_ids(), expected.as_slice());
+
+    let indexed = parse(data.join("branches.profdata")).unwrap();
+    assert_eq!(indexed.binary_ids(), expected.as_slice());
+
+    // Merging the same binary doesn't duplicate the id
+    let merged = merge_profiles(&[
+        data.join("branches.profraw"),
+        data.join("branches.profdata"),
+    ])
+    .unwrap();
+    assert_eq!(merged.binary_ids(), expected.as_slice());
+
+    let text = parse(data_root_dir().join("llvm-19").join("foo3-1.proftext")).unwrap();
+    assert!(text.binary_ids().is_empty());
+}
+
+#[test]
+fn memprof_section() {
+    // Profiles are generated from memprof.yaml with `llvm-profdata merge --memprof-version`
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/memprof");
+    let frame = |function, line_offset, column, is_inline_frame| Frame {
+        function,
+        line_offset,
+        column,
+        is_inline_frame,
+    };
+    let shared = frame(0x200, 22, 20, false);
+
+    for file in &[
+        "memprof_v2.profdata",
+        "memprof_v3.profdata",
+        "memprof_v4.profdata",
+        "memprof_v3_full_schema.profdata",
+    ] {
+        let profile = parse(dir.join(file)).unwrap();
+        assert!(profile.has_memory_profile());
+        let memprof = profile.memprof().unwrap();
+        assert_eq!(memprof.records.len(), 1);
+        let record = &memprof.records[&0xdeadbeef12345678];
+
+        assert_eq!(record.alloc_sites.len(), 2);
+        let alloc = &record.alloc_sites[0];
+        assert_eq!(
+            alloc.call_stack,
+            vec![frame(0x100, 11, 10, true), shared],
+            "{}",
+            file
+        );
+        assert_eq!(alloc.info.alloc_count, 777);
+        assert_eq!(alloc.info.total_size, 888);
+        assert_eq!(alloc.info.total_lifetime, 999);
+        assert_eq!(alloc.info.total_lifetime_access_density, 1000);
+        let alloc = &record.alloc_sites[1];
+        assert_eq!(alloc.call_stack, vec![frame(0x300, 33, 30, false), shared]);
+        assert_eq!(alloc.info.alloc_count, 666);
+        assert_eq!(alloc.info.total_size, 555);
+
+        // Only the full schema contains the access counts
+        if file.contains("full_schema") {
+            assert_eq!(memprof.schema.len(), 27);
+            assert_eq!(record.alloc_sites[0].info.total_access_count, 1111);
+            assert_eq!(record.alloc_sites[1].info.total_access_count, 222);
+        } else {
+            assert_eq!(memprof.schema.len(), 4);
+            assert_eq!(record.alloc_sites[0].info.total_access_count, 0);
+        }
+
+        let call_sites = record
+            .call_sites
+            .iter()
+            .map(|x| x.frames.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            call_sites,
+            vec![
+                vec![frame(0x500, 55, 50, true), frame(0x600, 66, 60, false)],
+                vec![frame(0x700, 77, 70, true), frame(0x800, 88, 80, false)],
+            ]
+        );
+        assert_eq!(memprof.frames.len(), 7);
+        assert!(memprof.frames.values().any(|x| *x == shared));
+    }
+
+    let profile = parse(data_root_dir().join("misc").join("stable.profdata")).unwrap();
+    assert!(profile.memprof().is_none());
+}
+
+#[test]
+fn mmap_parsing() {
+    let misc = data_root_dir().join("misc");
+    for file in &[
+        misc.join("stable.profraw"),
+        misc.join("stable.profdata"),
+        misc.join("value_prof.proftext"),
+        misc.join("value_prof.profraw"),
+    ] {
+        let expected = parse(file).unwrap();
+        let mapped = parse_mmap(file).unwrap();
+        assert_eq!(expected, mapped, "{}", file.display());
+    }
+    assert!(parse_mmap(misc.join("missing.profraw")).is_err());
+}
+
+#[test]
+fn parse_from_reader() {
+    let misc = data_root_dir().join("misc");
+    for file in &[
+        misc.join("stable.profraw"),
+        misc.join("stable.profdata"),
+        misc.join("value_prof.proftext"),
+    ] {
+        let expected = parse(file).unwrap();
+        let streamed = parse_reader(std::fs::File::open(file).unwrap()).unwrap();
+        assert_eq!(expected, streamed, "{}", file.display());
+    }
+    assert!(parse_reader(&[0xffu8, 0xfe, 0x00, 0x81][..]).is_err());
+}
+
+#[test]
+fn old_raw_profile_counters() {
+    // Version 5 profiles with several functions, the counters are laid out in record order
+    let expected = [
+        (
+            "llvm-11",
+            vec![
+                ("foo", vec![1, 2]),
+                ("bar", vec![3]),
+                ("baz", vec![4, 5, 6]),
+            ],
+        ),
+        (
+            "llvm-12",
+            vec![
+                ("main", vec![1]),
+                ("foo", vec![7, 0, 9]),
+                ("bar", vec![3, 3]),
+                ("_ZN4test5checkE", vec![100, 42, 0, 1]),
+            ],
+        ),
+    ];
+    for (dir, functions) in &expected {
+        let file = data_root_dir().join(dir).join("multi_function.profraw");
+        let profile = parse(&file).unwrap();
+        assert_eq!(profile.version(), Some(5));
+        assert_eq!(profile.records().len(), functions.len());
+        for (name, counts) in functions {
+            let record = profile.get_record(name).unwrap();
+            assert_eq!(record.counts(), counts.as_slice(), "{} {}", dir, name);
+        }
+    }
+
+    // The last record claims more counters than are in the counters section
+    let file = data_root_dir()
+        .join("llvm-11")
+        .join("multi_function.profraw");
+    let mut data = std::fs::read(file).unwrap();
+    data[216..220].copy_from_slice(&4u32.to_le_bytes());
+    let err = try_parse_bytes(&data).unwrap_err();
+    assert_eq!(err.kind(), RawProfileError::Malformed);
+}
+
+#[test]
+fn structured_parse_errors() {
+    let err = try_parse_bytes(&[0xff, 0xfe, 0x00, 0x81]).unwrap_err();
+    assert_eq!(err, ProfileParseError::UnsupportedFormat);
+    assert_eq!(err.kind(), RawProfileError::UnrecognizedFormat);
+
+    let err = try_parse_bytes(&[]).unwrap_err();
+    assert_eq!(err, ProfileParseError::Empty);
+    assert_eq!(err.kind(), RawProfileError::EmptyRawProfile);
+    assert!(RawInstrProf64::parse_bytes(&[]).is_err());
+    assert!(RawInstrProf64::parse_header(&[]).is_err());
+    // A zero byte file is an error rather than a panic
+    let empty = std::env::temp_dir().join("llvm_profparser_empty.profraw");
+    std::fs::write(&empty, []).unwrap();
+    let io_err = parse(&empty).unwrap_err();
+    assert!(io_err.to_string().contains("Empty profile"));
+    let _ = std::fs::remove_file(empty);
+    let raw = std::fs::read(data_root_dir().join("misc/stable.profraw")).unwrap();
+    assert!(RawInstrProf32::parse_bytes(&raw).is_err());
+
+    let misc = data_root_dir().join("misc");
+    for file in &["stable.profraw", "stable.profdata"] {
+        let data = std::fs::read(misc.join(file)).unwrap();
+        // Cut off part way through the header
+        let err = try_parse_bytes(&data[..20]).unwrap_err();
+        assert_eq!(err.kind(), RawProfileError::Truncated, "{}", file);
+        match err {
+            ProfileParseError::Invalid { offset, .. } => assert!(offset <= 20),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        // The io::Error keeps the description
+        let io_err = parse_bytes(&data[..20]).unwrap_err();
+        assert!(io_err.to_string().contains("Truncated"));
+    }
+}
+
+#[test]
+fn gcov_profile() {
+    let gcov = data_root_dir().join("gcov");
+    let data = std::fs::read(gcov.join("main.gcda")).unwrap();
+    let notes = std::fs::read(gcov.join("main.gcno")).unwrap();
+
+    let counts = |profile: &InstrumentationProfile| {
+        profile
+            .records()
+            .iter()
+            .map(|x| (x.name.clone().unwrap(), x.counts().to_vec()))
+            .collect::<Vec<_>>()
+    };
+
+    // Without the notes file the names are the object stamp and function id
+    let profile = parse(gcov.join("main.gcda")).unwrap();
+    assert_eq!(
+        counts(&profile),
+        vec![
+            ("3e40b8bf_067072eb".to_string(), vec![1, 10, 10, 1]),
+            ("3e40b8bf_41e25752".to_string(), vec![0]),
+            ("3e40b8bf_6edd0758".to_string(), vec![10, 4]),
+        ]
+    );
+    assert!(!profile.is_ir_level_profile());
+
+    let (_, profile) = GccProfReader::parse_with_notes(&notes, &data).unwrap();
+    assert_eq!(
+        counts(&profile),
+        vec![
+            ("main".to_string(), vec![1, 10, 10, 1]),
+            ("never".to_string(), vec![0]),
+            ("check".to_string(), vec![10, 4]),
+        ]
+    );
+
+    // Notes from a different compilation can't be used
+    let mut other_notes = notes.clone();
+    other_notes[8] ^= 0xff;
+    assert!(GccProfReader::parse_with_notes(&other_notes, &data).is_err());
+}
+
+#[test]
+fn ext_binary_sample_profiles() {
+    let data = data_root_dir().join("sample");
+    let plain = sample_profile::parse(data.join("plain.extbinary")).unwrap();
+    let compressed = sample_profile::parse(data.join("compressed.extbinary")).unwrap();
+    let md5 = sample_profile::parse(data.join("md5.extbinary")).unwrap();
+
+    assert_eq!(plain.sections.len(), 7);
+    assert!(compressed.sections.iter().all(|x| x.is_compressed()));
+    assert_eq!(plain.functions, compressed.functions);
+    assert_eq!(plain.func_offsets.len(), 3);
+    assert!(plain.symbol_list.is_empty());
+    assert_eq!(
+        compressed.symbol_list,
+        vec!["_Z3bari", "_Z3fooi", "main", "unused_fn"]
+    );
+
+    let names = plain
+        .sorted_functions()
+        .iter()
+        .map(|x| (x.name.as_str(), x.total_samples, x.head_samples))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            ("main", 184019, 0),
+            ("_Z3bari", 20301, 1437),
+            ("_Z3fooi", 7711, 610)
+        ]
+    );
+    let main = &plain.sorted_functions()[0];
+    assert_eq!(main.body.len(), 7);
+    let call = &main.body[&sample_profile::LineLocation {
+        line_offset: 9,
+        discriminator: 0,
+    }];
+    assert_eq!(
+        call.sorted_call_targets(),
+        vec![("_Z3bari", 1471), ("_Z3fooi", 631)]
+    );
+    assert_eq!(main.callsites.values().map(|x| x.len()).sum::<usize>(), 2);
+
+    // MD5 names are the hash of the name
+    assert_eq!(md5.functions.len(), 3);
+    assert!(md5.names.contains(&compute_hash("main").to_string()));
+
+    assert!(sample_profile::parse(data_root_dir().join("misc/value_prof.proftext")).is_err());
+    assert!(sample_profile::parse(data_root_dir().join("misc/stable.profdata")).is_err());
+}
+
+#[test]
+fn text_sample_profiles() {
+    // The extbinary profiles were generated from the text profile with llvm-profdata
+    let data = data_root_dir().join("sample");
+    let text = sample_profile::parse(data.join("sample.proftext")).unwrap();
+    let plain = sample_profile::parse(data.join("plain.extbinary")).unwrap();
+    assert!(text.sections.is_empty());
+    assert_eq!(text.sorted_functions(), plain.sorted_functions());
+
+    let bytes = std::fs::read(data.join("sample.proftext")).unwrap();
+    assert!(sample_profile::has_format(bytes.as_slice()));
+    assert_eq!(sample_profile::parse_bytes(&bytes).unwrap(), text);
+    assert!(!sample_profile::has_format(
+        std::fs::File::open(data_root_dir().join("misc/value_prof.proftext")).unwrap()
+    ));
+
+    // Output checked against llvm-profdata show --sample
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["show", "--function", "_Z3bari", "-i"])
+        .arg(data.join("sample.proftext"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Function: _Z3bari: 20301, 1437, 1 sampled lines
+Samples collected in the function's body {
+  1: 1437
+}
+No inlined callsites in this function
+"
+    );
+}
+
+#[test]
+fn show_sample_section_info() {
+    let data = data_root_dir().join("sample");
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["show", "--show_section_info_only", "-i"])
+        .arg(data.join("compressed.extbinary"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "ProfileSummarySection - Offset: 242, Size: 102, Flags: {compressed}
+NameTableSection - Offset: 344, Size: 41, Flags: {compressed}
+CSNameTableSection - Offset: 385, Size: 11, Flags: {compressed}
+FuncOffsetTableSection - Offset: 530, Size: 17, Flags: {compressed}
+LBRProfileSection - Offset: 396, Size: 93, Flags: {compressed}
+ProfileSymbolListSection - Offset: 489, Size: 41, Flags: {compressed}
+FunctionMetadata - Offset: 547, Size: 0, Flags: {compressed}
+Header Size: 242
+Total Sections Size: 305
+File Size: 547
+"
+    );
+}
+
+#[test]
+fn iter_named_records() {
+    let mut profile = InstrumentationProfile::default();
+    profile.symtab.add_func_name("resolved".to_string(), None);
+    let record = |name: Option<&str>, name_hash: u64| NamedInstrProfRecord {
+        name: name.map(|x| x.to_string()),
+        name_hash: Some(name_hash),
+        hash: Some(0),
+        record: InstrProfRecord {
+            counts: vec![1],
+            data: None,
+        },
+    };
+    profile.push_record(record(Some("named"), compute_hash("named")));
+    profile.push_record(record(None, compute_hash("resolved")));
+    profile.push_record(record(None, compute_hash("missing")));
+
+    let names = profile.iter_named().map(|(x, _)| x).collect::<Vec<_>>();
+    assert_eq!(names, vec!["named", "resolved"]);
+
+    // For parsed profiles every record has a name
+    let profile = parse(data_root_dir().join("misc/stable.profraw")).unwrap();
+    assert_eq!(profile.iter_named().count(), profile.records().len());
+}
+
+#[test]
+fn filter_profiles() {
+    let profile = parse(data_root_dir().join("misc/value_prof.profraw")).unwrap();
+
+    let filtered = profile.filter_by_name(&Regex::new("_ZN2vp4call").unwrap());
+    assert_eq!(filtered.records().len(), 1);
+    assert_eq!(
+        filtered.is_ir_level_profile(),
+        profile.is_ir_level_profile()
+    );
+    let name = filtered.records()[0].name.as_ref().unwrap();
+    assert_eq!(
+        filtered.find_record_by_name(name),
+        filtered.records().first()
+    );
+    // The function and both of its indirect call targets
+    let mut symbols = filtered
+        .symtab
+        .iter()
+        .map(|(_, x)| x.as_str())
+        .collect::<Vec<_>>();
+    symbols.sort_unstable();
+    assert_eq!(
+        symbols,
+        vec![
+            "vp.5701034a8e7e735c-cgu.0;_ZN2vp3add17h3650604ffc5645a3E",
+            "vp.5701034a8e7e735c-cgu.0;_ZN2vp4call17h2c256f80137f6e10E",
+            "vp.5701034a8e7e735c-cgu.0;_ZN2vp6double17h85444b17529389f9E",
+        ]
+    );
+    for record in profile.records() {
+        let name = record.name.as_ref().unwrap();
+        assert_eq!(
+            filtered.find_record_by_name(name).is_some(),
+            name.contains("_ZN2vp4call")
+        );
+    }
+
+    let all = profile.filter(|_| true);
+    assert_eq!(all, profile);
+    let none = profile.filter(|_| false);
+    assert!(none.records().is_empty());
+    assert!(none.symtab.is_empty());
+}
+
+#[test]
+fn merge_context_sensitive_records() {
+    let record = |counts: Vec<u64>, cs: bool| {
+        let mut record = NamedInstrProfRecord {
+            name: Some("foo".to_string()),
+            name_hash: Some(compute_hash("foo")),
+            hash: Some(0x1234),
+            record: InstrProfRecord { counts, data: None },
+        };
+        if cs {
+            record.set_cs_flag();
+            assert_eq!(record.hash, Some(0x1234 | (1 << 60)));
+        }
+        assert_eq!(record.has_cs_flag(), cs);
+        record
+    };
+
+    let mut profile = InstrumentationProfile::default();
+    profile.merge_record(&record(vec![1, 2], false));
+    profile.merge_record(&record(vec![10, 20], true));
+    assert_eq!(profile.records().len(), 2);
+    profile.merge_record(&record(vec![1, 1], false));
+    profile.merge_record(&record(vec![5, 5], true));
+    assert_eq!(profile.records().len(), 2);
+
+    assert_eq!(profile.find_record("foo", false).unwrap().counts(), &[2, 3]);
+    assert_eq!(
+        profile.find_record("foo", true).unwrap().counts(),
+        &[15, 25]
+    );
+    assert_eq!(
+        profile.find_record_by_name("foo").unwrap().counts(),
+        &[2, 3]
+    );
+}
+
+#[test]
+fn diff_profiles() {
+    let record = |name: &str, counts: Vec<u64>| NamedInstrProfRecord {
+        name: Some(name.to_string()),
+        name_hash: Some(compute_hash(name)),
+        hash: Some(0x1234),
+        record: InstrProfRecord { counts, data: None },
+    };
+
+    let mut base = InstrumentationProfile::default();
+    base.merge_record(&record("hotter", vec![1, 2]));
+    base.merge_record(&record("colder", vec![u64::MAX, 5]));
+    base.merge_record(&record("removed", vec![3]));
+    let mut test = InstrumentationProfile::default();
+    test.merge_record(&record("added", vec![4, 0]));
+    test.merge_record(&record("colder", vec![0, 5]));
+    test.merge_record(&record("hotter", vec![2, 7, 1]));
+
+    let diff = base.diff(&test);
+    let names = diff.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["hotter", "colder", "removed", "added"]);
+
+    assert_eq!(diff[0].deltas, vec![1, 5, 1]);
+    assert_eq!(diff[0].max_delta, 5);
+    assert_eq!(diff[0].total_delta, 7);
+
+    assert_eq!(diff[1].deltas, vec![i64::MIN, 0]);
+    assert_eq!(diff[1].max_delta, i64::MIN);
+    assert_eq!(diff[1].total_delta, i64::MIN);
+
+    assert_eq!(diff[2].deltas, vec![-3]);
+    assert_eq!(diff[3].deltas, vec![4, 0]);
+    assert_eq!(diff[3].total_delta, 4);
+
+    assert!(base.diff(&base).iter().all(|x| x.is_unchanged()));
+}
+
+#[test]
+fn demangle_names() {
+    assert_eq!(
+        demangle("_ZN2vp4call17h2c256f80137f6e10E").as_deref(),
+        Some("vp::call")
+    );
+    assert_eq!(
+        demangle("_RNvCs3UIYSLOGnTh_4main5check").as_deref(),
+        Some("main::check")
+    );
+    assert_eq!(demangle("_Z3fooi").as_deref(), Some("foo(int)"));
+    assert_eq!(
+        demangle("vp.5701034a8e7e735c-cgu.0;_ZN2vp3add17h3650604ffc5645a3E").as_deref(),
+        Some("vp.5701034a8e7e735c-cgu.0;vp::add")
+    );
+    assert_eq!(demangle("main"), None);
+
+    let profile = parse(data_root_dir().join("misc/value_prof.profraw")).unwrap();
+    for (hash, name) in profile.symtab.iter() {
+        let demangled = profile.symtab.demangled(*hash).unwrap();
+        assert_eq!(demangled, demangle(name).unwrap_or_else(|| name.clone()));
+    }
+    assert_eq!(profile.symtab.demangled(0), None);
+
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["show", "--all-functions", "--demangle", "-i"])
+        .arg(data_root_dir().join("misc/value_prof.profraw"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  vp.5701034a8e7e735c-cgu.0;vp::call:\n"));
+    assert!(stdout.contains("  vp::main:\n"));
+}
+
+#[test]
+fn build_profile() {
+    let record = |name: Option<&str>, counts: Vec<u64>| NamedInstrProfRecord {
+        name: name.map(|x| x.to_string()),
+        name_hash: None,
+        hash: Some(0x1234),
+        record: InstrProfRecord { counts, data: None },
+    };
+    let mut cs_record = record(Some("foo"), vec![5, 5]);
+    cs_record.set_cs_flag();
+
+    let profile = InstrumentationProfileBuilder::new()
+        .with_version(10)
+        .with_ir(true)
+        .with_csir(true)
+        .with_binary_id(vec![1, 2, 3])
+        .add_record(record(Some("foo"), vec![1, 2]))
+        .add_records(vec![
+            record(Some("bar"), vec![3]),
+            record(Some("foo"), vec![1, 1]),
+            cs_record,
+            record(None, vec![4]),
+        ])
+        .build();
+
+    assert_eq!(profile.version(), Some(10));
+    assert!(profile.is_ir_level_profile());
+    assert!(profile.has_csir_level_profile());
+    assert_eq!(profile.binary_ids(), &[vec![1, 2, 3]]);
+    assert_eq!(profile.records().len(), 4);
+    assert_eq!(profile.find_record("foo", false).unwrap().counts(), &[2, 3]);
+    assert_eq!(profile.find_record("foo", true).unwrap().counts(), &[5, 5]);
+    let bar = profile.find_record_by_name("bar").unwrap();
+    assert_eq!(bar.name_hash, Some(compute_hash("bar")));
+    assert_eq!(profile.symtab.len(), 2);
+    assert_eq!(profile.symtab.get(compute_hash("foo")).unwrap(), "foo");
+    assert_eq!(profile.symtab.get(compute_hash("bar")).unwrap(), "bar");
+
+    let named = profile
+        .iter_named()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(named, vec!["foo", "bar", "foo"]);
+}
+
+#[test]
+fn find_record_by_hashes() {
+    let profile = parse(data_root_dir().join("llvm-19").join("mix_instr.proftext")).unwrap();
+    for record in profile.records() {
+        let name_hash = record.name_hash.unwrap();
+        let fn_hash = record.hash.unwrap();
+        assert_eq!(profile.find_by_hashes(name_hash, fn_hash), Some(record));
+        assert!(profile.find_by_hashes(name_hash, fn_hash + 1).is_none());
+        let first = profile
+            .records()
+            .iter()
+            .find(|x| x.name_hash == Some(name_hash));
+        assert_eq!(profile.find_by_name_hash(name_hash), first);
+    }
+    assert!(profile.find_by_name_hash(compute_hash("missing")).is_none());
+    let merged =
+        merge_profiles_sparse(&[data_root_dir().join("llvm-19").join("mix_instr.proftext")])
+            .unwrap();
+    assert!(merged.find_by_hashes(compute_hash("goo"), 5).is_none());
+    assert!(merged.find_by_hashes(compute_hash("foo"), 7).is_some());
+}
+
+#[test]
+fn incompatible_merges() {
+    let data = data_root_dir().join("llvm-19");
+    let ir = parse(data.join("ir-basic.proftext")).unwrap();
+    let fe = parse(data.join("fe-basic.proftext")).unwrap();
+    assert_eq!(
+        ir.check_compatible(&fe),
+        Err(MergeError::InstrumentationLevel)
+    );
+
+    let mut merged = ir.clone();
+    assert_eq!(merged.merge(&fe), Err(MergeError::InstrumentationLevel));
+    assert_eq!(merged, ir);
+    let err = merge_profiles(&[
+        data.join("ir-basic.proftext"),
+        data.join("fe-basic.proftext"),
+    ])
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // Context sensitive and regular IR profiles can be merged
+    let cs = parse(data.join("cs.proftext")).unwrap();
+    let noncs = parse(data.join("noncs.proftext")).unwrap();
+    let mut merged = noncs.clone();
+    assert!(merged.merge(&cs).is_ok());
+    assert!(merged.has_csir_level_profile());
+    assert!(merged.is_ir_level_profile());
+
+    // An empty profile takes the flags of the profile merged into it
+    let mut empty = InstrumentationProfile::default();
+    assert!(empty.merge(&fe).is_ok());
+    assert!(empty.merge(&ir).is_err());
+}
+
+#[test]
+fn concatenated_raw_profiles() {
+    let data = data_root_dir().join("misc");
+    let files = [
+        data.join("premerge_1.profraw"),
+        data.join("premerge_2.profraw"),
+    ];
+    let mut bytes = vec![];
+    for file in &files {
+        bytes.extend(std::fs::read(file).unwrap());
+    }
+    let concatenated = parse_bytes(&bytes).unwrap();
+    let first = parse(&files[0]).unwrap();
+    let second = parse(&files[1]).unwrap();
+    for record in first.records().iter().chain(second.records()) {
+        assert!(concatenated
+            .records()
+            .iter()
+            .any(|x| x.name == record.name && x.hash == record.hash));
+    }
+    assert_eq!(concatenated, merge_profiles(&files).unwrap());
+}
+
+#[test]
+fn profile_flags() {
+    let data = data_root_dir().join("llvm-19");
+    let cs = parse(data.join("CSIR_profile.proftext")).unwrap();
+    let flags = cs.flags();
+    assert!(flags.is_ir && flags.has_csir);
+    assert_eq!(flags.is_ir, cs.is_ir_level_profile());
+    assert_eq!(flags.has_csir, cs.has_csir_level_profile());
+    assert_eq!(flags.is_entry_first, cs.is_entry_first());
+    assert_eq!(flags.is_byte_coverage, cs.is_byte_coverage());
+    assert_eq!(flags.fn_entry_only, cs.fn_entry_only());
+    assert_eq!(flags.memory_profiling, cs.has_memory_profile());
+
+    let rebuilt = InstrumentationProfileBuilder::new()
+        .with_flags(flags)
+        .add_records(cs.records().iter().cloned())
+        .build();
+    assert_eq!(rebuilt.flags(), flags);
+    for format in [ProfileFormat::Binary, ProfileFormat::Text] {
+        let mut output = vec![];
+        writer_for(format, false)
+            .unwrap()
+            .write(&rebuilt, &mut output)
+            .unwrap();
+        let written = parse_bytes(&output).unwrap();
+        assert_eq!(written.flags(), flags);
+        assert_eq!(written.records(), cs.records());
+    }
+
+    let flags = ProfileFlags {
+        is_ir: true,
+        is_entry_first: true,
+        fn_entry_only: true,
+        ..Default::default()
+    };
+    let mut profile = InstrumentationProfile::with_flags(Some(8), flags);
+    assert_eq!(profile.version(), Some(8));
+    assert_eq!(profile.flags(), flags);
+    assert!(profile.is_entry_first() && profile.fn_entry_only());
+    profile.set_flags(ProfileFlags::default());
+    assert_eq!(profile.flags(), ProfileFlags::default());
+    assert!(!profile.is_ir_level_profile());
+}
+
+#[test]
+fn value_profile_targets() {
+    let file = data_root_dir().join("llvm-19/overlap_1_vp.proftext");
+    let profile = parse(&file).unwrap();
+    let foo = profile.find_record_by_name("foo").unwrap();
+    assert_eq!(
+        foo.indirect_call_targets(&profile.symtab),
+        vec![
+            (Some("bar1".to_string()), 40000),
+            (Some("bar2".to_string()), 60000)
+        ]
+    );
+    assert_eq!(foo.memop_sizes(), vec![(1, 40000), (4, 60000)]);
+
+    let empty = InstrumentationProfile::default();
+    assert_eq!(
+        foo.indirect_call_targets(&empty.symtab),
+        vec![(None, 40000), (None, 60000)]
+    );
+
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args([
+            "show",
+            "--all-functions",
+            "--ic-targets",
+            "--memop-sizes",
+            "-i",
+        ])
+        .arg(&file)
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&output.get_output().stdout);
+    assert!(stdout.contains(
+        "    Indirect Target Results:
+\t[  0, bar1,      40000 ] (40.00%)
+\t[  0, bar2,      60000 ] (60.00%)
+    Memory Intrinsic Size Results:
+\t[  0,    1,      40000 ] (40.00%)
+\t[  0,    4,      60000 ] (60.00%)
+"
+    ));
+}
+
+#[test]
+fn profile_from_stdin() {
+    let data = data_root_dir().join("llvm-19");
+    for file in ["mix_instr.proftext", "weight-instr-foo.profdata"] {
+        let show = |input: &str, stdin: Vec<u8>| {
+            let output = assert_cmd::Command::cargo_bin("profparser")
+                .unwrap()
+                .current_dir(&data)
+                .args(["show", "--all-functions", "--counts", "-i", input])
+                .write_stdin(stdin)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{}", file);
+            output.stdout
+        };
+        let contents = std::fs::read(data.join(file)).unwrap();
+        assert_eq!(show("-", contents), show(file, vec![]), "{}", file);
+    }
+
+    let contents = std::fs::read(data.join("mix_instr.proftext")).unwrap();
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["validate", "-i", "-"])
+        .write_stdin(contents)
+        .assert()
+        .success();
+
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["show", "-i", "-"])
+        .write_stdin(vec![])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Empty profile"));
+}
+
+#[test]
+fn indexed_header_wrong_format() {
+    assert!(IndexedInstrProf::parse_header(&[]).is_err());
+    let raw = std::fs::read(data_root_dir().join("misc/stable.profraw")).unwrap();
+    assert!(IndexedInstrProf::parse_header(&raw).is_err());
+    // Pseudo-random inputs of different lengths
+    let mut state = 0x2545f4914f6cdd1du64;
+    for len in 1..128 {
+        let bytes = (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        assert!(IndexedInstrProf::parse_header(&bytes).is_err());
+    }
+}
+
+#[test]
+fn functions_by_cutoff() {
+    let profile = parse(data_root_dir().join("llvm-19/cutoff.proftext")).unwrap();
+    let main = profile
+        .iter_named()
+        .find(|(name, _)| *name == "main")
+        .unwrap()
+        .1;
+    assert_eq!(main.max_count(), 1000000);
+    assert_eq!(main.total_count(), 1 + 1000 + 1000000 + 499500);
+
+    let names = |records: Vec<&NamedInstrProfRecord>| {
+        let mut names = records
+            .iter()
+            .filter_map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(
+        names(profile.functions_above(999).collect()),
+        vec!["foo".to_string(), "main".to_string()]
+    );
+    assert_eq!(
+        names(profile.functions_below(999).collect()),
+        vec!["bar".to_string()]
+    );
+    assert_eq!(profile.functions_above(0).count(), 3);
+    assert_eq!(profile.functions_below(0).count(), 0);
+
+    let empty = NamedInstrProfRecord::default();
+    assert_eq!(empty.max_count(), 0);
+    assert_eq!(empty.total_count(), 0);
+}
+
+#[test]
+fn record_count_helpers_saturate() {
+    let record = NamedInstrProfRecord {
+        name: Some("foo".to_string()),
+        name_hash: Some(compute_hash("foo")),
+        hash: Some(0x1234),
+        record: InstrProfRecord {
+            counts: vec![u64::MAX - 1, 5, u64::MAX],
+            data: None,
+        },
+    };
+    assert_eq!(record.max_count(), u64::MAX);
+    assert_eq!(record.total_count(), u64::MAX);
+
+    let mut profile = InstrumentationProfile::default();
+    profile.merge_record(&record);
+    assert_eq!(profile.functions_above(u64::MAX).count(), 1);
+    assert_eq!(profile.functions_below(u64::MAX).count(), 0);
+}
+
+#[test]
+fn record_scale_saturates() {
+    let record = InstrProfRecord {
+        counts: vec![0, 3, u64::MAX / 2, u64::MAX],
+        data: Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![InstrProfValueData {
+                value: 0xdead,
+                count: u64::MAX - 1,
+            }]],
+            mem_op_sizes: vec![vec![InstrProfValueData { value: 8, count: 4 }]],
+        })),
+    };
+    assert_eq!(record.scaled(1), record);
+
+    let mut scaled = record.clone();
+    // Two counters and the indirect call count overflow
+    assert_eq!(scaled.scale(3), 3);
+    assert_eq!(scaled.counts, vec![0, 9, u64::MAX, u64::MAX]);
+    let data = scaled.data.as_ref().unwrap();
+    assert_eq!(data.indirect_callsites[0][0].count, u64::MAX);
+    assert_eq!(data.indirect_callsites[0][0].value, 0xdead);
+    assert_eq!(data.mem_op_sizes[0][0].count, 12);
+
+    let mut record = record;
+    assert_eq!(record.scale(u64::MAX), 5);
+    assert_eq!(record.counts, vec![0, u64::MAX, u64::MAX, u64::MAX]);
+    assert_eq!(record.scale(0), 0);
+    assert!(record.counts.iter().all(|x| *x == 0));
+}
+
+#[test]
+fn profile_variant_flags() {
+    let file = data_root_dir().join("llvm-14/function-entry-coverage.profdata");
+    let data = std::fs::read(file).unwrap();
+    let word = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    let variant = ProfileVariant::from_version_word(word);
+    assert_eq!(
+        variant,
+        ProfileVariant {
+            version: 7,
+            is_ir: true,
+            byte_coverage: true,
+            ..Default::default()
+        }
+    );
+    assert_eq!(variant.version_word(), word);
+
+    let variant = ProfileVariant {
+        version: 10,
+        is_csir: true,
+        entry_first: true,
+        entry_only: true,
+        memory_profile: true,
+        ..Default::default()
+    };
+    assert_eq!(ProfileVariant::from(variant.version_word()), variant);
+    assert_eq!(
+        ProfileVariant::from(10),
+        ProfileVariant::from_version_word(10)
+    );
+}
+
+#[test]
+fn byte_coverage_indexed_round_trip() {
+    // byte_coverage.profdata is `llvm-profdata merge` of byte_coverage.profraw, indexed profiles
+    // store 8 byte counters even when the raw profile used single byte counters
+    let data = data_root_dir().join("misc");
+    let raw = parse(data.join("byte_coverage.profraw")).unwrap();
+    let indexed = parse(data.join("byte_coverage.profdata")).unwrap();
+    for profile in [&raw, &indexed] {
+        assert!(profile.is_byte_coverage());
+        assert!(profile.fn_entry_only());
+        assert!(profile.is_ir_level_profile());
+    }
+    let expected = raw.records().iter().collect::<HashSet<_>>();
+    assert_eq!(indexed.records().iter().collect::<HashSet<_>>(), expected);
+    assert!(raw.records().iter().any(|x| x.record.counts == [0]));
+    assert!(raw
+        .records()
+        .iter()
+        .all(|x| x.record.counts == [0] || x.record.counts == [1]));
+
+    let mut output = vec![];
+    writer_for(ProfileFormat::Binary, false)
+        .unwrap()
+        .write(&raw, &mut output)
+        .unwrap();
+    let written = parse_bytes(&output).unwrap();
+    assert_eq!(written.flags(), indexed.flags());
+    assert_eq!(written.records().iter().collect::<HashSet<_>>(), expected);
+}
+
+#[test]
+fn show_report() {
+    let data = data_root_dir();
+    let filters = ShowFilters {
+        all_functions: true,
+        ..Default::default()
+    };
+    for file in ["llvm-19/foo3bar3-1.proftext", "misc/value_prof.profdata"] {
+        let profile = parse(data.join(file)).unwrap();
+        let report = ShowReport::new(&profile, &filters);
+
+        let text = assert_cmd::Command::cargo_bin("profparser")
+            .unwrap()
+            .args(["show", "--all-functions", "--counts", "-i"])
+            .arg(data.join(file))
+            .output()
+            .unwrap();
+        let text: Output = serde_yaml::from_slice(&text.stdout).unwrap();
+        assert_eq!(
+            text.functions_shown,
+            Some(report.functions.len()),
+            "{}",
+            file
+        );
+        assert_eq!(text.total_functions, Some(report.summary.num_functions()));
+        assert_eq!(
+            text.maximum_function_count,
+            Some(report.summary.max_function_count() as usize)
+        );
+
+        let json = assert_cmd::Command::cargo_bin("profparser")
+            .unwrap()
+            .args(["show", "--all-functions", "--json", "-i"])
+            .arg(data.join(file))
+            .output()
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json.stdout).unwrap();
+        assert_eq!(
+            json["instrumentation_level"],
+            text.instrumentation_level
+                .as_deref()
+                .and_then(|x| x.split_whitespace().next())
+                .unwrap()
+        );
+        assert_eq!(
+            json["summary"]["max_internal_block_count"],
+            text.maximum_internal_block_count.unwrap()
+        );
+        let functions = json["functions"].as_array().unwrap();
+        assert_eq!(functions.len(), report.functions.len());
+        for function in functions {
+            let entry = &text.counters[function["name"].as_str().unwrap()];
+            assert_eq!(function["hash"], entry.hash.unwrap());
+            assert_eq!(function["counters"], entry.counters.unwrap());
+            assert_eq!(function["block_counts"], json!(entry.block_counts));
+            match entry.function_count {
+                Some(count) => assert_eq!(function["function_count"], count),
+                None => assert!(function.get("function_count").is_none()),
+            }
+        }
+    }
+
+    let profile = parse(data.join("misc/value_prof.profdata")).unwrap();
+    let filters = ShowFilters {
+        function: Some("vp4call".to_string()),
+        ..Default::default()
+    };
+    let report = ShowReport::new(&profile, &filters);
+    assert_eq!(report.functions.len(), 1);
+    let targets = &report.functions[0].indirect_call_sites;
+    assert_eq!(targets.len(), 1);
+    assert_eq!(
+        targets[0].iter().map(|x| x.1).collect::<Vec<_>>(),
+        vec![66, 34]
+    );
+    assert!(targets[0][0]
+        .0
+        .as_ref()
+        .unwrap()
+        .ends_with("_ZN2vp3add17h3650604ffc5645a3E"));
+
+    let filters = ShowFilters {
+        all_functions: true,
+        value_cutoff: 1,
+        ..Default::default()
+    };
+    let report = ShowReport::new(&profile, &filters);
+    // Expected values from llvm-profdata show --value-cutoff=1
+    assert_eq!(report.below_cutoff, 2);
+    assert_eq!(report.functions.len(), 7);
+    assert!(report.functions.iter().all(|x| x.max_count >= 1));
+}
+
+#[test]
+fn raw_header_info() {
+    let misc = data_root_dir().join("misc");
+    let little = parse(misc.join("premerge_1.profraw")).unwrap();
+    let big = parse(misc.join("premerge_1_be.profraw")).unwrap();
+    let little_header = little.raw_header().unwrap();
+    let big_header = big.raw_header().unwrap();
+    assert_eq!(little_header.format, HeaderFormat::Raw64);
+    assert_eq!(little_header.endianness, Endianness::Little);
+    assert_eq!(big_header.endianness, Endianness::Big);
+    assert_eq!(little_header.fields, big_header.fields);
+    assert_eq!(little_header.variant().version, 8);
+    assert_eq!(little_header.get("data_len"), Some(2));
+    let counters = little
+        .records()
+        .iter()
+        .map(|x| x.counts().len())
+        .sum::<usize>();
+    assert_eq!(little_header.get("counters_len"), Some(counters as u64));
+    assert_eq!(little_header.get("hash_offset"), None);
+
+    let indexed = parse(misc.join("value_prof.profdata")).unwrap();
+    let header = indexed.raw_header().unwrap();
+    assert_eq!(header.format, HeaderFormat::Indexed);
+    assert_eq!(header.variant().version, 13);
+    assert!(header.variant().is_ir);
+    let names = header.fields.iter().map(|(x, _)| *x).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "hash_type",
+            "hash_offset",
+            "mem_prof_offset",
+            "binary_id_offset",
+            "temporary_prof_traces_offset",
+            "vtable_offset"
+        ]
+    );
+    // Offsets checked against the bytes of the file
+    assert_eq!(header.get("hash_offset"), Some(0x790));
+    assert_eq!(header.get("binary_id_offset"), Some(0x820));
+    assert_eq!(header.get("temporary_prof_traces_offset"), Some(0));
+    assert_eq!(header.get("vtable_offset"), Some(0x848));
+
+    let text = parse(misc.join("value_prof.proftext")).unwrap();
+    assert!(text.raw_header().is_none());
+}
+
+#[test]
+fn drop_value_profiling() {
+    let raw = data_root_dir().join("misc").join("value_prof.profraw");
+    let text = data_root_dir().join("misc").join("value_prof.proftext");
+
+    let mut profile = parse(&raw).unwrap();
+    assert!(profile.records().iter().any(|x| x.record.data.is_some()));
+    let counts = profile
+        .records()
+        .iter()
+        .map(|x| x.counts().to_vec())
+        .collect::<Vec<_>>();
+    profile.drop_value_profiling();
+    assert!(profile.records().iter().all(|x| x.record.data.is_none()));
+    assert_eq!(
+        profile
+            .records()
+            .iter()
+            .map(|x| x.counts().to_vec())
+            .collect::<Vec<_>>(),
+        counts
+    );
+
+    let merged = merge_profiles_counts_only(&[&raw, &text]).unwrap();
+    let mut expected = merge_profiles(&[&raw, &text]).unwrap();
+    assert!(expected.records().iter().any(|x| x.record.data.is_some()));
+    expected.drop_value_profiling();
+    assert!(merged.semantically_eq(&expected));
+}
+
+#[test]
+fn semantic_equality() {
+    let data = data_root_dir();
+    let text = parse(data.join("misc/value_prof.proftext")).unwrap();
+    let indexed = parse(data.join("misc/value_prof.profdata")).unwrap();
+    assert_ne!(text.records(), indexed.records());
+    assert!(text.semantically_eq(&indexed));
+    assert!(indexed.semantically_eq(&text));
+
+    let reversed = InstrumentationProfileBuilder::new()
+        .with_flags(text.flags())
+        .add_records(text.records().iter().rev().cloned())
+        .build();
+    assert!(reversed.semantically_eq(&text));
+
+    // Records are compared as a multiset so duplicates matter
+    let mut duplicated = reversed.clone();
+    duplicated.push_record(text.records()[0].clone());
+    assert!(!duplicated.semantically_eq(&text));
+
+    let mut flags = text.flags();
+    flags.is_ir = !flags.is_ir;
+    let front_end = InstrumentationProfileBuilder::new()
+        .with_flags(flags)
+        .add_records(text.records().iter().cloned())
+        .build();
+    assert!(!front_end.semantically_eq(&text));
+
+    let other = parse(data.join("llvm-19/foo3bar3-1.proftext")).unwrap();
+    assert!(!other.semantically_eq(&text));
+}
+
+#[test]
+fn top_functions() {
+    let file = data_root_dir().join("llvm-19/cutoff.proftext");
+    let profile = parse(&file).unwrap();
+    assert_eq!(
+        profile.top_functions(2),
+        vec![("main", 1000000), ("foo", 999)]
+    );
+    assert_eq!(profile.top_functions(10).len(), 3);
+    assert!(profile.top_functions(0).is_empty());
+    assert_eq!(
+        profile.top_functions_by(1, |x| x.max_count() < 1000),
+        vec![("foo", 999)]
+    );
+
+    // Ties are broken by name regardless of the record order
+    let record = |name: &str| NamedInstrProfRecord {
+        name: Some(name.to_string()),
+        name_hash: Some(compute_hash(name)),
+        hash: Some(0x1234),
+        record: InstrProfRecord {
+            counts: vec![7],
+            data: None,
+        },
+    };
+    let mut profile = InstrumentationProfile::default();
+    for name in ["c", "a", "d", "b"] {
+        profile.merge_record(&record(name));
+    }
+    assert_eq!(profile.top_functions(2), vec![("a", 7), ("b", 7)]);
+
+    let output = assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["show", "--topn=2", "-i"])
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(
+        "Top 2 functions with the largest internal block counts: \n  main, max count = 1000000\n  foo, max count = 999\n"
+    ));
+}
+
+#[test]
+fn show_sorted_by_name() {
+    let text = data_root_dir().join("llvm-19/cutoff.proftext");
+    let indexed = std::env::temp_dir().join("profparser_sorted_by_name.profdata");
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(["merge", "-o"])
+        .arg(&indexed)
+        .arg("-i")
+        .arg(&text)
+        .assert()
+        .success();
+
+    let show = |file: &PathBuf| {
+        let output = assert_cmd::Command::cargo_bin("profparser")
+            .unwrap()
+            .args([
+                "show",
+                "--all-functions",
+                "--counts",
+                "--sort-by-name",
+                "-i",
+            ])
+            .arg(file)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|x| x.starts_with("  ") && !x.starts_with("    "))
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+    };
+    let expected = vec!["  bar:", "  foo:", "  main:"];
+    assert_eq!(show(&text), expected);
+    assert_eq!(show(&indexed), expected);
+    let _ = std::fs::remove_file(indexed);
+}
+
+#[test]
+fn zstd_compressed_names() {
+    // llvm-19/compressed.profraw with the names recompressed with zstd
+    let zlib = parse(data_root_dir().join("llvm-19/compressed.profraw")).unwrap();
+    let zstd = std::fs::read(data_root_dir().join("misc/compressed_zstd.profraw")).unwrap();
+    if cfg!(feature = "zstd") {
+        let zstd = parse_bytes(&zstd).unwrap();
+        assert!(!zstd.symtab.is_empty());
+        assert_eq!(zstd.symtab, zlib.symtab);
+        assert_eq!(zstd.records(), zlib.records());
+    } else {
+        match try_parse_bytes(&zstd).unwrap_err() {
+            ProfileParseError::Invalid { message, .. } => {
+                assert!(message.contains("zstd feature"), "{}", message)
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+}